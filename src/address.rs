@@ -0,0 +1,140 @@
+use sha2::{Digest, Sha256};
+
+use crate::network::Network;
+use crate::script::{classify_script, ScriptType, OP_0, OP_1, OP_16};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_ALPHABET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+/// Decodes the address paid by a standard scriptPubKey, or by a witness program of a version
+/// that isn't defined yet. Returns `None` for scripts that have no address form, such as P2PK,
+/// bare multisig, OP_RETURN or anything else non-standard.
+pub fn script_to_address(script: &[u8], network: Network) -> Option<String> {
+    match classify_script(script) {
+        ScriptType::P2pkh => Some(base58check(network.p2pkh_prefix(), &script[3..23])),
+        ScriptType::P2sh => Some(base58check(network.p2sh_prefix(), &script[2..22])),
+        ScriptType::OpReturn | ScriptType::Multisig | ScriptType::P2pk => None,
+        _ => {
+            let (version, program) = witness_program(script)?;
+            segwit_address(network.bech32_hrp(), version, program)
+        }
+    }
+}
+
+/// Splits a scriptPubKey of the form `OP_n <program>` into its witness version and program.
+fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    match script {
+        [OP_0, length, program @ ..] if *length as usize == program.len() => Some((0, program)),
+        [opcode @ OP_1..=OP_16, length, program @ ..] if *length as usize == program.len() => {
+            Some((opcode - OP_1 + 1, program))
+        }
+        _ => None,
+    }
+}
+
+fn base58check(version: u8, payload: &[u8]) -> String {
+    let mut data = Vec::with_capacity(payload.len() + 5);
+    data.push(version);
+    data.extend_from_slice(payload);
+    let checksum = Sha256::digest(Sha256::digest(&data));
+    data.extend_from_slice(&checksum[..4]);
+
+    // Repeated division of the big-endian number by 58, least significant digit first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let leading_zeros = data.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n(b'1', leading_zeros)
+        .chain(digits.iter().rev().map(|&digit| BASE58_ALPHABET[digit as usize]))
+        .map(char::from)
+        .collect()
+}
+
+/// Encodes a witness program as a bech32 (version 0) or bech32m (versions 1 to 16) address.
+/// Returns `None` for programs no valid address can hold: outside 2 to 40 bytes, or a version 0
+/// program that isn't 20 or 32 bytes.
+pub fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> Option<String> {
+    if version > 16 || !(2..=40).contains(&program.len()) {
+        return None;
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return None;
+    }
+
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5));
+
+    // Witness version 0 uses bech32 (BIP173), later versions use bech32m (BIP350)
+    let constant = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    let checksum = bech32_checksum(hrp, &data, constant);
+
+    let mut address = String::with_capacity(hrp.len() + 1 + data.len() + 6);
+    address.push_str(hrp);
+    address.push('1');
+    address.extend(data.iter().chain(checksum.iter()).map(|&value| BECH32_ALPHABET[value as usize] as char));
+    Some(address)
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Vec<u8> {
+    let mut accumulator = 0u32;
+    let mut bits = 0u32;
+    let max_value = (1u32 << to) - 1;
+    let mut result = Vec::new();
+
+    for &value in data {
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((accumulator >> bits) & max_value) as u8);
+        }
+    }
+    if bits > 0 {
+        result.push(((accumulator << (to - bits)) & max_value) as u8);
+    }
+
+    result
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for &value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn bech32_checksum(hrp: &str, data: &[u8], constant: u32) -> [u8; 6] {
+    let mut values: Vec<u8> = hrp.bytes().map(|byte| byte >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|byte| byte & 0x1f));
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+
+    let polymod = bech32_polymod(&values) ^ constant;
+    let mut checksum = [0u8; 6];
+    for (i, value) in checksum.iter_mut().enumerate() {
+        *value = ((polymod >> (5 * (5 - i))) & 0x1f) as u8;
+    }
+    checksum
+}
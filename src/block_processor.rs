@@ -1,88 +1,291 @@
 use hex::encode;
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
+use tracing::warn;
 
+use crate::chain::MAX_MONEY;
+use crate::error::ParserError;
 use crate::models::{Block, Transaction};
+use crate::network::Network;
 
-pub async fn process_block(mut block: Block) -> Block {
-    let transactions_size: i32 = block.transactions.par_iter_mut().map(|tx| {
-        let (txid, size) = calculate_tx(tx);
-        tx.txid = txid.clone();
+const BLOCK_HEADER_SIZE: usize = 80;
+
+pub fn process_block(mut block: Block, network: Network, strict: bool) -> Result<Block, ParserError> {
+    let genesis_coinbase_txid = network.params().genesis_coinbase_txid;
+    let (transactions_size, transactions_stripped_size, rbf_tx_count, invalid_tx) = block.transactions.par_iter_mut().map(|tx| {
+        let (txid, wtxid, size, stripped_size) = calculate_tx(tx)?;
+        // Re-serializing only reproduces the bytes that were read if parsing stayed in step with the data
+        let invalid = (tx.size as usize != size)
+            .then(|| format!("Transaction {} serializes to {} bytes, but {} bytes were parsed", txid, size, tx.size))
+            .or_else(|| check_output_values(&txid, tx));
+        if txid == genesis_coinbase_txid {
+            tx.outputs.iter_mut().for_each(|output| output.unspendable = true);
+        }
+        tx.txid = txid;
+        tx.wtxid = wtxid;
         tx.size = size as i32;
-        size as i32
-    }).sum();
+        let weight = stripped_size * 3 + size;
+        tx.weight = weight as i32;
+        tx.vsize = weight.div_ceil(4) as i32;
+        Ok::<_, ParserError>((size, stripped_size, tx.signals_rbf() as i32, invalid))
+    }).try_reduce(|| (0, 0, 0, None), |a, b| Ok((a.0 + b.0, a.1 + b.1, a.2 + b.2, a.3.or(b.3))))?;
+
+    if let Some(message) = invalid_tx {
+        if strict {
+            return Err(ParserError::MalformedBlock(message));
+        }
+        warn!("{}", message);
+    }
+
+    // Weight counts non-witness bytes four times and witness bytes once (BIP141)
+    let mut tx_count = Vec::new();
+    write_var_int(&mut tx_count, block.transactions.len() as u64);
+    let base_size = BLOCK_HEADER_SIZE + tx_count.len();
+    block.rbf_tx_count = rbf_tx_count;
+    block.weight = ((base_size + transactions_stripped_size) * 3 + base_size + transactions_size) as i32;
+    block.block_hash = calculate_block_hash(&block)?;
+    block.difficulty = calculate_block_difficulty(block.bits);
+    if strict {
+        let hash = decode_hex("block hash", &block.block_hash)?;
+        if !check_pow(&hash, block.bits) {
+            return Err(ParserError::InvalidProofOfWork { block_hash: block.block_hash, bits: block.bits });
+        }
+    }
+    for (position, tx) in block.transactions.iter_mut().enumerate() {
+        tx.block_hash = block.block_hash.clone();
+        tx.position = position as i32;
+    }
+
+    let txids: Vec<String> = block.transactions.iter().map(|tx| tx.txid.clone()).collect();
+    let (merkle_root, mutated) = compute_merkle_root_mutated(&txids)?;
+    let message = if merkle_root != block.merkle_root {
+        Some(format!("Merkle root mismatch in block {}: header has {}, transactions hash to {}", block.block_hash, block.merkle_root, merkle_root))
+    } else {
+        mutated.then(|| format!("Block {} repeats transactions its merkle root only covers once (CVE-2012-2459)", block.block_hash))
+    };
+    if let Some(message) = message {
+        if strict {
+            return Err(ParserError::MalformedBlock(message));
+        }
+        warn!("{}", message);
+    }
+
+    Ok(block)
+}
+
+/// Checks output values the way Core's `CheckTransaction` does: each must be within
+/// 0..=`MAX_MONEY`, and so must their running total. A corrupt value with the high bit set
+/// would otherwise be stored as a negative amount.
+fn check_output_values(txid: &str, tx: &Transaction) -> Option<String> {
+    let mut total: i64 = 0;
+    for output in &tx.outputs {
+        if !(0..=MAX_MONEY).contains(&output.value) {
+            return Some(format!("Output {}:{} has value {}, outside 0..={}", txid, output.output_index, output.value, MAX_MONEY));
+        }
+        total += output.value;
+        if total > MAX_MONEY {
+            return Some(format!("Outputs of transaction {} add up to more than {}", txid, MAX_MONEY));
+        }
+    }
+    None
+}
+
+/// Computes the merkle root of hex txids (display order), duplicating the last hash of odd-sized levels.
+/// Because of that duplication a block whose last transactions repeat (CVE-2012-2459) produces the same root
+/// as the block without the repeats; `compute_merkle_root_mutated` tells the two apart.
+pub fn compute_merkle_root(txids: &[String]) -> Result<String, ParserError> {
+    Ok(compute_merkle_root_mutated(txids)?.0)
+}
+
+/// Like `compute_merkle_root`, and also whether the tree is mutated the way Core's `ComputeMerkleRoot`
+/// detects it: some level pairs a hash with an identical one that wasn't added as padding.
+pub fn compute_merkle_root_mutated(txids: &[String]) -> Result<(String, bool), ParserError> {
+    if txids.is_empty() {
+        return Ok((hash_to_hex(&[0; 32]), false));
+    }
 
-    let block_header_size: i32 = 4 + 32 + 32 + 4 + 4 + 4;
-    block.size = block_header_size + transactions_size;
-    block.block_hash = calculate_block_hash(&block);
+    let mut level: Vec<Vec<u8>> = txids.iter()
+        .map(|txid| Ok(decode_hex("txid", txid)?.into_iter().rev().collect()))
+        .collect::<Result<_, ParserError>>()?;
 
-    block
+    let mut mutated = false;
+    while level.len() > 1 {
+        mutated |= level.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1].clone());
+        }
+        level = level.chunks(2).map(|pair| double_sha256(&[pair[0].as_slice(), pair[1].as_slice()].concat())).collect();
+    }
+
+    Ok((hash_to_hex(&level[0]), mutated))
 }
 
-pub fn calculate_block_hash(block: &Block) -> String {
+pub fn calculate_block_hash(block: &Block) -> Result<String, ParserError> {
     let mut hasher = Sha256::new();
-    hasher.update(&block.version.to_le_bytes());
-    hasher.update(&hex::decode(&block.previous_block).unwrap().iter().rev().cloned().collect::<Vec<u8>>());
-    hasher.update(&hex::decode(&block.merkle_root).unwrap().iter().rev().cloned().collect::<Vec<u8>>());
-    hasher.update(&(block.time.unix_timestamp() as u32).to_le_bytes());
-    hasher.update(&u32::from_str_radix(&block.bits, 16).unwrap().to_le_bytes());
-    hasher.update(&(block.nonce as u32).to_le_bytes());
+    hasher.update(block.version.to_le_bytes());
+    hasher.update(decode_hex("previous block", &block.previous_block)?.iter().rev().cloned().collect::<Vec<u8>>());
+    hasher.update(decode_hex("merkle root", &block.merkle_root)?.iter().rev().cloned().collect::<Vec<u8>>());
+    hasher.update((block.time.unix_timestamp() as u32).to_le_bytes());
+    hasher.update(block.bits.to_le_bytes());
+    hasher.update((block.nonce as u32).to_le_bytes());
     let first_hash = hasher.finalize();
 
     let mut hasher = Sha256::new();
     hasher.update(first_hash);
-    encode(hasher.finalize().iter().rev().cloned().collect::<Vec<u8>>())
+    Ok(encode(hasher.finalize().iter().rev().cloned().collect::<Vec<u8>>()))
 }
 
-pub fn calculate_tx(tx: &Transaction) -> (String, usize) {
-    let inputs_size: usize = tx.inputs.iter().map(|input| {
-        32 + 4 + varint_size(input.script_sig.len() as u64) + (input.script_sig.len() / 2) + 4
-    }).sum();
+/// Hashes a serialized 80-byte block header, returning the block hash in display order.
+pub fn header_hash(header: &[u8]) -> String {
+    hash_to_hex(&double_sha256(header))
+}
 
-    let outputs_size: usize = tx.outputs.iter().map(|output| {
-        8 + varint_size(output.script_pub_key.len() as u64) + (output.script_pub_key.len() / 2)
-    }).sum();
+/// Checks that a block hash, in big-endian (display) byte order, is at or below the target encoded
+/// in `bits`. Negative, zero and overflowing targets never pass, as in Core's `CheckProofOfWork`.
+pub fn check_pow(block_hash: &[u8], bits: u32) -> bool {
+    let exponent = (bits >> 24) as usize;
+    let mut mantissa = bits & 0x007fffff;
+    let negative = mantissa != 0 && bits & 0x00800000 != 0;
+    let overflow = mantissa != 0 && (exponent > 34 || (mantissa > 0xff && exponent > 33) || (mantissa > 0xffff && exponent > 32));
+    if negative || overflow {
+        return false;
+    }
 
-    let witness_size: usize = if let Some(witness) = &tx.witness {
-        witness.iter().map(|witnesses| {
-            witnesses.iter().map(|w| varint_size(w.len() as u64) + w.len()).sum::<usize>()
-        }).sum()
+    let mut target = [0u8; 32];
+    if exponent <= 3 {
+        mantissa >>= 8 * (3 - exponent);
+        target[28..].copy_from_slice(&mantissa.to_be_bytes());
     } else {
-        0
-    };
+        // The least significant mantissa byte sits `exponent - 3` bytes from the end of the target
+        for (i, &byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+            if let Some(index) = (32 + i).checked_sub(exponent) {
+                target[index] = byte;
+            }
+        }
+    }
 
-    let size = 4 + varint_size(tx.inputs.len() as u64) + inputs_size + varint_size(tx.outputs.len() as u64) + outputs_size + 4 + witness_size;
+    target != [0; 32] && block_hash <= &target[..]
+}
+
+/// Difficulty relative to the minimum target (0x1d00ffff), computed the same way as Bitcoin Core's `GetDifficulty`.
+pub fn calculate_block_difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
 
+/// Returns the txid, the wtxid, the serialized size and the size without witness data. The
+/// transaction is serialized once, with its witness, for the hashes and the sizes alike.
+pub fn calculate_tx(tx: &Transaction) -> Result<(String, String, usize, usize), ParserError> {
+    let (full, witness_start) = write_tx(tx, true)?;
+    if tx.witness.is_none() {
+        let txid = hash_to_hex(&double_sha256(&full));
+        return Ok((txid.clone(), txid, full.len(), full.len()));
+    }
+    // The txid leaves out the marker and flag after the version, and the witness stacks before the locktime
+    let stripped = [&full[..4], &full[6..witness_start], &full[full.len() - 4..]];
     let mut hasher = Sha256::new();
-    hasher.update(&tx.version.to_le_bytes());
+    stripped.iter().for_each(|part| hasher.update(part));
+    let txid = hash_to_hex(&Sha256::digest(hasher.finalize()));
+    let stripped_size = stripped.iter().map(|part| part.len()).sum();
+    let wtxid = hash_to_hex(&double_sha256(&full));
+
+    Ok((txid, wtxid, full.len(), stripped_size))
+}
+
+/// The witness txid commits to the full serialization, including marker, flag and witness stacks.
+/// For transactions without witness data it is identical to the txid.
+pub fn calculate_wtxid(tx: &Transaction) -> Result<String, ParserError> {
+    Ok(hash_to_hex(&double_sha256(&serialize_tx(tx, true)?)))
+}
+
+/// Serializes a transaction back to its wire format. Its hashes and scripts are stored as hex, and
+/// fail with `ParserError::InvalidHex` if they were changed into something that isn't.
+pub fn serialize_tx(tx: &Transaction, include_witness: bool) -> Result<Vec<u8>, ParserError> {
+    Ok(write_tx(tx, include_witness)?.0)
+}
+
+/// Like `serialize_tx`, also returning the offset the witness stacks start at, which is where the
+/// locktime starts when there are none.
+fn write_tx(tx: &Transaction, include_witness: bool) -> Result<(Vec<u8>, usize), ParserError> {
+    let witness = tx.witness.as_ref().filter(|_| include_witness);
+    let mut data = Vec::new();
+
+    data.extend_from_slice(&tx.version.to_le_bytes());
+    if witness.is_some() {
+        data.extend_from_slice(&[0x00, 0x01]);
+    }
 
+    write_var_int(&mut data, tx.inputs.len() as u64);
     for input in &tx.inputs {
-        hasher.update(&hex::decode(&input.previous_txid).unwrap().iter().rev().cloned().collect::<Vec<u8>>());
-        hasher.update(&(input.previous_output_index as u32).to_le_bytes());
-        hasher.update(&hex::decode(&input.script_sig).unwrap());
-        hasher.update(&(input.sequence as u32).to_le_bytes());
+        data.extend(decode_hex("previous txid", &input.previous_txid)?.iter().rev());
+        data.extend_from_slice(&(input.previous_output_index as u32).to_le_bytes());
+        let script_sig = decode_hex("scriptSig", &input.script_sig)?;
+        write_var_int(&mut data, script_sig.len() as u64);
+        data.extend_from_slice(&script_sig);
+        data.extend_from_slice(&(input.sequence as u32).to_le_bytes());
     }
 
+    write_var_int(&mut data, tx.outputs.len() as u64);
     for output in &tx.outputs {
-        hasher.update(&output.value.to_le_bytes());
-        hasher.update(&hex::decode(&output.script_pub_key).unwrap());
+        data.extend_from_slice(&output.value.to_le_bytes());
+        let script_pub_key = decode_hex("scriptPubKey", &output.script_pub_key)?;
+        write_var_int(&mut data, script_pub_key.len() as u64);
+        data.extend_from_slice(&script_pub_key);
     }
 
-    hasher.update(&(tx.locktime as u32).to_le_bytes());
+    let witness_start = data.len();
+    if let Some(witness) = witness {
+        for stack in witness {
+            write_var_int(&mut data, stack.len() as u64);
+            for item in stack {
+                write_var_int(&mut data, item.len() as u64);
+                data.extend_from_slice(item);
+            }
+        }
+    }
 
-    let first_hash = hasher.finalize();
-    let mut hasher = Sha256::new();
-    hasher.update(first_hash);
-    let txid = encode(hasher.finalize().iter().rev().cloned().collect::<Vec<u8>>());
+    data.extend_from_slice(&(tx.locktime as u32).to_le_bytes());
+    Ok((data, witness_start))
+}
+
+fn decode_hex(field: &'static str, value: &str) -> Result<Vec<u8>, ParserError> {
+    hex::decode(value).map_err(|_| ParserError::InvalidHex { field, value: value.to_string() })
+}
+
+fn double_sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(Sha256::digest(data)).to_vec()
+}
 
-    (txid, size)
+/// Hex in display order, reversed from the internal byte order that `hash` is computed in.
+fn hash_to_hex(hash: &[u8]) -> String {
+    encode(hash.iter().rev().cloned().collect::<Vec<u8>>())
 }
 
-fn varint_size(value: u64) -> usize {
+fn write_var_int(data: &mut Vec<u8>, value: u64) {
     match value {
-        0..=0xFC => 1,
-        0xFD..=0xFFFF => 3,
-        0x10000..=0xFFFFFFFF => 5,
-        _ => 9,
+        0..=0xFC => data.push(value as u8),
+        0xFD..=0xFFFF => {
+            data.push(0xFD);
+            data.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x10000..=0xFFFFFFFF => {
+            data.push(0xFE);
+            data.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            data.push(0xFF);
+            data.extend_from_slice(&value.to_le_bytes());
+        }
     }
 }
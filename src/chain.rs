@@ -0,0 +1,331 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use time::{Duration, OffsetDateTime};
+use tracing::warn;
+
+use crate::models::{Block, BlockHeader, StoredBlock};
+use crate::network::Network;
+
+pub const GENESIS_PREVIOUS_BLOCK: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Height given to blocks whose parent has not been seen yet.
+pub const PENDING_HEIGHT: i32 = -1;
+
+/// Satoshis per bitcoin.
+pub const COIN: i64 = 100_000_000;
+
+/// Most satoshis a single output, or all outputs of a transaction together, may carry.
+pub const MAX_MONEY: i64 = 21_000_000 * COIN;
+
+/// Number of blocks, a block's parent and its ancestors, whose median time the block must exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of the clock a block's time may be.
+const MAX_FUTURE_BLOCK_TIME: Duration = Duration::hours(2);
+
+struct Entry {
+    previous_block: String,
+    height: i32,
+    /// Block time as a Unix timestamp.
+    time: i64,
+    chainwork: u128,
+    total_supply: i64,
+    active: bool,
+}
+
+/// Tracks every block seen so far so that blocks arriving out of order, within a file or across
+/// files, can be linked once their parent shows up, and so that only the branch with the most
+/// cumulative work is marked active. Along the way each block's `total_supply` is summed up from
+/// its ancestors, so blocks on competing branches get the supply of their own branch.
+pub struct Chain {
+    network: Network,
+    entries: HashMap<String, Entry>,
+    tip: Option<String>,
+}
+
+impl Chain {
+    pub fn new(network: Network) -> Self {
+        Self { network, entries: HashMap::new(), tip: None }
+    }
+
+    /// Records a block that was linked in an earlier run. Blocks must be passed parents first.
+    pub fn insert_known(&mut self, block: StoredBlock) {
+        let chainwork = self.chainwork(&block.previous_block).saturating_add(block_work(block.bits));
+        if block.active && chainwork > self.tip_chainwork() {
+            self.tip = Some(block.block_hash.clone());
+        }
+        self.entries.insert(block.block_hash, Entry {
+            previous_block: block.previous_block,
+            height: block.height,
+            time: block.time,
+            chainwork,
+            total_supply: block.total_supply,
+            active: block.active,
+        });
+    }
+
+    /// New coins a block at `height` may create: the network's initial subsidy, 50 BTC on
+    /// Bitcoin, halved every `halving_interval` blocks.
+    pub fn block_subsidy(&self, height: i32) -> i64 {
+        let params = self.network.params();
+        let halvings = height / params.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        params.initial_subsidy >> halvings
+    }
+
+    /// Coins a block adds to the supply: the subsidy at its height, or what its coinbase claims if
+    /// that is less, since unclaimed subsidy is lost for good. Fees are assumed to be claimed in
+    /// full. The genesis coinbase can't be spent, so the genesis block adds nothing. Coins burnt
+    /// later, to unspendable outputs or overwritten coinbases, still count as issued.
+    fn issued(&self, block: &Block, height: i32) -> i64 {
+        if block.previous_block == GENESIS_PREVIOUS_BLOCK {
+            return 0;
+        }
+
+        let subsidy = self.block_subsidy(height);
+        let claimed = block.transactions.first()
+            .filter(|tx| tx.inputs.first().is_some_and(|input| input.is_coinbase))
+            .map_or(0, |coinbase| coinbase.outputs.iter().fold(0i64, |sum, output| sum.saturating_add(output.value)));
+        if claimed < subsidy {
+            warn!(block_hash = %block.block_hash, height, claimed, subsidy, "Coinbase claims less than the block subsidy");
+        }
+
+        claimed.min(subsidy)
+    }
+
+    /// Median time of a block and up to 10 of its ancestors, as a Unix timestamp, or `None` if
+    /// the block isn't in the chain.
+    pub fn median_time_past(&self, block_hash: &str) -> Option<i64> {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut hash = block_hash;
+        while let Some(entry) = self.entries.get(hash).filter(|_| times.len() < MEDIAN_TIME_SPAN) {
+            times.push(entry.time);
+            hash = &entry.previous_block;
+        }
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    /// Checks a block's time the way Core does: it must be later than the median time past of its
+    /// parent, which has to be in the chain for this to be checked, and no more than two hours
+    /// ahead of `now`. A corrupt header can hold any time a `u32` fits, all of them valid
+    /// timestamps. Returns what is wrong with it, if anything.
+    pub fn check_time(&self, block: &Block, now: OffsetDateTime) -> Option<String> {
+        if let Some(median) = self.median_time_past(&block.previous_block) {
+            if block.time.unix_timestamp() <= median {
+                return Some(format!("Block {} has time {}, not after the median time {} of the blocks before it", block.block_hash, block.time, median));
+            }
+        }
+        (block.time > now + MAX_FUTURE_BLOCK_TIME)
+            .then(|| format!("Block {} has time {}, more than {} ahead of the clock", block.block_hash, block.time, MAX_FUTURE_BLOCK_TIME))
+    }
+
+    /// Checks that a block at `height` starts its coinbase scriptSig with that height, which
+    /// BIP34 requires from the network's `bip34_height` on. Earlier coinbases can start with
+    /// anything. Returns what is wrong with it, if anything.
+    pub fn check_coinbase_height(&self, block: &Block, height: i32) -> Option<String> {
+        if height < self.network.bip34_height() {
+            return None;
+        }
+        match block.coinbase_height() {
+            Some(coinbase_height) if coinbase_height == height as i64 => None,
+            Some(coinbase_height) => Some(format!("Block {} at height {} has coinbase height {}", block.block_hash, height, coinbase_height)),
+            None => Some(format!("Block {} at height {} has no coinbase height", block.block_hash, height)),
+        }
+    }
+
+    /// Whether a block has already been linked into the chain.
+    pub fn contains(&self, block_hash: &str) -> bool {
+        self.entries.contains_key(block_hash)
+    }
+
+    /// Orders block headers, read ahead of their blocks, parents first, so that every block can be
+    /// linked as soon as it is read, however the blk files were shuffled. Blocks already in the
+    /// chain and repeats of a block are dropped, keeping the first copy. Returns the ordered
+    /// headers, and those whose ancestry reaches neither the genesis block nor the chain.
+    pub fn order_headers(&self, headers: Vec<BlockHeader>) -> (Vec<BlockHeader>, Vec<BlockHeader>) {
+        let mut seen = HashSet::new();
+        let mut children: HashMap<String, Vec<BlockHeader>> = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        for header in headers {
+            if self.contains(&header.block_hash) || !seen.insert(header.block_hash.clone()) {
+                continue;
+            }
+            if header.previous_block == GENESIS_PREVIOUS_BLOCK || self.contains(&header.previous_block) {
+                queue.push_back(header);
+            } else {
+                children.entry(header.previous_block.clone()).or_default().push(header);
+            }
+        }
+
+        let mut ordered = Vec::with_capacity(seen.len());
+        while let Some(header) = queue.pop_front() {
+            if let Some(child_headers) = children.remove(&header.block_hash) {
+                queue.extend(child_headers);
+            }
+            ordered.push(header);
+        }
+
+        (ordered, children.into_values().flatten().collect())
+    }
+
+    /// Gives headers ordered by `order_headers` their heights, from parents earlier in `headers`
+    /// or already in the chain. Headers whose parent is in neither keep `PENDING_HEIGHT`.
+    pub fn assign_header_heights(&self, headers: &mut [BlockHeader]) {
+        let mut heights: HashMap<String, i32> = HashMap::new();
+        for header in headers {
+            let parent_height = if header.previous_block == GENESIS_PREVIOUS_BLOCK {
+                Some(-1)
+            } else {
+                heights.get(&header.previous_block).copied().or_else(|| self.entries.get(&header.previous_block).map(|entry| entry.height))
+            };
+            if let Some(parent_height) = parent_height {
+                header.height = parent_height + 1;
+                heights.insert(header.block_hash.clone(), header.height);
+            }
+        }
+    }
+
+    /// Assigns heights and active flags to every block whose ancestry is known and marks the rest
+    /// with `PENDING_HEIGHT`. Pending blocks should be passed in again with later batches.
+    ///
+    /// Returns the active flag changes of blocks from earlier batches, which happen when a block
+    /// in this batch makes a competing branch the one with the most work.
+    pub fn assign_heights(&mut self, blocks: &mut [Block]) -> Vec<(String, bool)> {
+        let mut children: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut changes = HashMap::new();
+        let now = OffsetDateTime::now_utc();
+
+        for (index, block) in blocks.iter_mut().enumerate() {
+            if let Some(entry) = self.entries.get(&block.block_hash) {
+                block.height = entry.height;
+                block.total_supply = entry.total_supply;
+            } else if block.previous_block == GENESIS_PREVIOUS_BLOCK {
+                if block.block_hash != self.network.params().genesis_hash {
+                    warn!(block_hash = %block.block_hash, network = %self.network, "Block without a parent isn't the genesis block of the network");
+                }
+                queue.push_back((index, 0));
+            } else if let Some(parent) = self.entries.get(&block.previous_block) {
+                queue.push_back((index, parent.height + 1));
+            } else {
+                block.height = PENDING_HEIGHT;
+                children.entry(block.previous_block.clone()).or_default().push(index);
+            }
+        }
+
+        while let Some((index, height)) = queue.pop_front() {
+            let parent_supply = self.entries.get(&blocks[index].previous_block).map_or(0, |parent| parent.total_supply);
+            let total_supply = parent_supply + self.issued(&blocks[index], height);
+            for message in [self.check_time(&blocks[index], now), self.check_coinbase_height(&blocks[index], height)].into_iter().flatten() {
+                warn!("{}", message);
+            }
+
+            let block = &mut blocks[index];
+            block.height = height;
+            block.total_supply = total_supply;
+
+            let chainwork = self.chainwork(&block.previous_block).saturating_add(block_work(block.bits));
+            self.entries.insert(block.block_hash.clone(), Entry {
+                previous_block: block.previous_block.clone(),
+                height,
+                time: block.time.unix_timestamp(),
+                chainwork,
+                total_supply,
+                active: false,
+            });
+            if chainwork > self.tip_chainwork() {
+                changes.extend(self.set_tip(&block.block_hash));
+            }
+
+            if let Some(child_indexes) = children.remove(&block.block_hash) {
+                queue.extend(child_indexes.into_iter().map(|child| (child, height + 1)));
+            }
+        }
+
+        for block in blocks.iter_mut() {
+            if let Some(entry) = self.entries.get(&block.block_hash) {
+                block.active = entry.active;
+                changes.remove(&block.block_hash);
+            }
+        }
+
+        changes.into_iter().collect()
+    }
+
+    /// Makes `new_tip` the active tip, deactivating the old branch back to the fork point and
+    /// activating the new one. Returns every block whose active flag changed.
+    fn set_tip(&mut self, new_tip: &str) -> Vec<(String, bool)> {
+        let mut changes = Vec::new();
+
+        let mut new_branch = Vec::new();
+        let mut fork = Some(new_tip.to_string());
+        while let Some(entry) = fork.as_ref().and_then(|hash| self.entries.get(hash)) {
+            if entry.active {
+                break;
+            }
+            new_branch.push(fork.take().unwrap());
+            fork = Some(entry.previous_block.clone());
+        }
+
+        let mut old = self.tip.take();
+        while let Some(hash) = old.filter(|hash| Some(hash) != fork.as_ref()) {
+            match self.entries.get_mut(&hash) {
+                Some(entry) => {
+                    entry.active = false;
+                    old = Some(entry.previous_block.clone());
+                    changes.push((hash, false));
+                }
+                None => break,
+            }
+        }
+
+        for hash in new_branch {
+            if let Some(entry) = self.entries.get_mut(&hash) {
+                entry.active = true;
+            }
+            changes.push((hash, true));
+        }
+        self.tip = Some(new_tip.to_string());
+
+        changes
+    }
+
+    /// Total work of the chain up to and including `block_hash`, or 0 for a block that isn't linked.
+    pub fn chainwork(&self, block_hash: &str) -> u128 {
+        self.entries.get(block_hash).map_or(0, |entry| entry.chainwork)
+    }
+
+    fn tip_chainwork(&self) -> u128 {
+        self.tip.as_deref().map_or(0, |tip| self.chainwork(tip))
+    }
+}
+
+/// Expected number of hashes needed to find a block at the compact target `bits`, i.e. 2^256 / target.
+/// Saturates for targets too small to represent, which no real chain gets anywhere near.
+pub fn block_work(bits: u32) -> u128 {
+    let exponent = (bits >> 24) as i32;
+    let mut mantissa = (bits & 0x007fffff) as u128;
+    if exponent < 3 {
+        mantissa >>= 8 * (3 - exponent);
+    }
+    if mantissa == 0 || bits & 0x00800000 != 0 {
+        return 0;
+    }
+
+    // target = mantissa * 2^(8 * (exponent - 3)), so the work is 2^shift / mantissa
+    let shift = 256 - 8 * (exponent.max(3) - 3);
+    if shift < 0 {
+        return 0;
+    }
+    if shift < 128 {
+        return (1u128 << shift) / mantissa;
+    }
+    let work = u128::MAX / mantissa;
+    if work.leading_zeros() < (shift - 128) as u32 {
+        return u128::MAX;
+    }
+    work << (shift - 128)
+}
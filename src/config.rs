@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio_postgres::config::SslMode;
+
+use crate::database::is_valid_table_prefix;
+use crate::file_reader::ParseLimits;
+use crate::network::Network;
+
+/// What the binary was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Import the blk files under `blocks_path`, the default.
+    Import,
+    /// Check every block without storing anything, also chosen by `VERIFY=true`.
+    Verify,
+    /// Delete every stored block above the height, then stop.
+    Rewind(i32),
+    Help,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("{0} is required")]
+    Missing(&'static str),
+    #[error("Invalid {name} {value:?}: {message}")]
+    Invalid { name: &'static str, value: String, message: String },
+    #[error("{0}, see --help")]
+    Usage(String),
+}
+
+/// Command line flags, the environment variable each one overrides, the name of its value, or
+/// `None` for switches that set it to `true`, and what it does. Every other setting is only read
+/// from the environment.
+const FLAGS: [(&str, &str, Option<&str>, &str); 16] = [
+    ("--blocks-path", "BLOCKS_PATH", Some("PATH"), "Directory of blk files, or a single blk file"),
+    ("--output", "OUTPUT", Some("BACKEND"), "postgres, json or parquet"),
+    ("--database-url", "DATABASE_URL", Some("URL"), "PostgreSQL connection string"),
+    ("--table-prefix", "TABLE_PREFIX", Some("PREFIX"), "Prefix of every table name"),
+    ("--output-path", "OUTPUT_PATH", Some("PATH"), "Where the json and parquet outputs are written"),
+    ("--network", "NETWORK", Some("NETWORK"), "mainnet, testnet3, signet or regtest"),
+    ("--start-height", "START_HEIGHT", Some("HEIGHT"), "Lowest height stored"),
+    ("--end-height", "END_HEIGHT", Some("HEIGHT"), "Highest height stored"),
+    ("--batch-size", "BATCH_SIZE", Some("BLOCKS"), "Most blocks per insert transaction"),
+    ("--read-workers", "READ_WORKERS", Some("FILES"), "Files read at once"),
+    ("--insert-workers", "INSERT_WORKERS", Some("BATCHES"), "Batches inserted at once"),
+    ("--reset", "RESET", None, "Drop all tables and start over"),
+    ("--dry-run", "DRY_RUN", None, "Parse everything and store nothing"),
+    ("--headers-only", "HEADERS_ONLY", None, "Store block headers only, skipping transactions"),
+    ("--strict", "STRICT", None, "Skip blocks that fail verification"),
+    ("--verbose", "VERBOSE", None, "Log debug events and progress"),
+];
+
+/// Everything the binary reads from its command line and environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub command: Command,
+    /// Empty for `rewind`, which reads no files.
+    pub blocks_path: PathBuf,
+    pub output: String,
+    pub database_url: Option<String>,
+    pub table_prefix: String,
+    pub output_path: Option<String>,
+    pub parquet_blocks_per_file: usize,
+    pub parquet_file_size_mb: usize,
+    pub network: Network,
+    pub verbose: bool,
+    pub reset: bool,
+    pub dry_run: bool,
+    pub strict: bool,
+    pub metrics_addr: Option<String>,
+    pub foreign_keys: bool,
+    pub defer_primary_keys: bool,
+    pub script_asm: bool,
+    pub utxo_set: bool,
+    pub address_stats: bool,
+    pub store_raw: bool,
+    pub undo_data: bool,
+    pub quarantine_path: Option<PathBuf>,
+    pub db_max_attempts: u32,
+    pub channel_capacity: usize,
+    pub read_workers: usize,
+    /// Files whose blocks may be held at once, read or being read but not yet all queued.
+    pub files_in_memory: usize,
+    pub start_height: i32,
+    pub end_height: i32,
+    pub rewind_to_height: Option<i32>,
+    pub batch_size: usize,
+    pub hash_threads: usize,
+    pub progress: bool,
+    pub file_checksums: bool,
+    pub watch: bool,
+    pub headers_first: bool,
+    pub headers_only: bool,
+    pub watch_interval: Duration,
+    pub count_op_returns: bool,
+    pub copy_flush_kb: usize,
+    pub insert_workers: usize,
+    pub limits: ParseLimits,
+}
+
+impl Config {
+    /// Reads the command line, without the program name, with `env` looking up environment
+    /// variables. Flags take precedence over the variables they stand for.
+    pub fn load<I, F>(args: I, env: F) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut command = None;
+        let mut flags: HashMap<&'static str, String> = HashMap::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                if command.is_some() {
+                    return Err(ConfigError::Usage(format!("Unexpected argument {:?}", arg)));
+                }
+                command = Some(match arg.as_str() {
+                    "import" => Command::Import,
+                    "verify" => Command::Verify,
+                    "rewind" => {
+                        let height = args.next().ok_or_else(|| ConfigError::Usage("rewind needs a height".to_string()))?;
+                        Command::Rewind(parse_value("height", &height)?)
+                    }
+                    "help" => Command::Help,
+                    _ => return Err(ConfigError::Usage(format!("Unknown command {:?}", arg))),
+                });
+                continue;
+            }
+            if arg == "--help" || arg == "-h" {
+                command = Some(Command::Help);
+                continue;
+            }
+
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let Some(&(_, var, value_name, _)) = FLAGS.iter().find(|(flag, ..)| *flag == name) else {
+                return Err(ConfigError::Usage(format!("Unknown option {}", name)));
+            };
+            let value = match (value_name, inline_value) {
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => args.next().ok_or_else(|| ConfigError::Usage(format!("{} needs a value", name)))?,
+                (None, None) => "true".to_string(),
+                (None, Some(_)) => return Err(ConfigError::Usage(format!("{} takes no value", name))),
+            };
+            flags.insert(var, value);
+        }
+
+        let vars = Vars { flags, env };
+        let var = |name: &str| vars.get(name);
+        let switch = |name: &str| vars.get(name).is_some_and(|value| value == "true");
+
+        let command = command.unwrap_or(if switch("VERIFY") { Command::Verify } else { Command::Import });
+        let default_limits = ParseLimits::default();
+        let read_workers = vars.parse::<usize>("READ_WORKERS", "1")?.max(1);
+        let config = Config {
+            command,
+            blocks_path: match (var("BLOCKS_PATH"), command) {
+                (Some(path), _) => PathBuf::from(path),
+                (None, Command::Rewind(_) | Command::Help) => PathBuf::new(),
+                (None, _) => return Err(ConfigError::Missing("BLOCKS_PATH")),
+            },
+            output: var("OUTPUT").unwrap_or_else(|| "postgres".to_string()),
+            database_url: var("DATABASE_URL"),
+            table_prefix: var("TABLE_PREFIX").unwrap_or_default(),
+            output_path: var("OUTPUT_PATH"),
+            parquet_blocks_per_file: vars.parse("PARQUET_BLOCKS_PER_FILE", "100000")?,
+            parquet_file_size_mb: vars.parse("PARQUET_FILE_SIZE_MB", "512")?,
+            network: vars.parse("NETWORK", "mainnet")?,
+            verbose: switch("VERBOSE"),
+            reset: switch("RESET"),
+            dry_run: switch("DRY_RUN"),
+            strict: switch("STRICT"),
+            metrics_addr: var("METRICS_ADDR"),
+            foreign_keys: switch("FOREIGN_KEYS"),
+            defer_primary_keys: switch("DEFER_PRIMARY_KEYS"),
+            script_asm: switch("SCRIPT_ASM"),
+            utxo_set: switch("UTXO_SET"),
+            address_stats: switch("ADDRESS_STATS"),
+            store_raw: switch("STORE_RAW"),
+            undo_data: switch("UNDO_DATA"),
+            quarantine_path: var("QUARANTINE_PATH").map(PathBuf::from),
+            db_max_attempts: vars.parse("DB_MAX_ATTEMPTS", "5")?,
+            channel_capacity: vars.parse("CHANNEL_CAPACITY", "1000")?,
+            read_workers,
+            // One more than the files being read, for the one whose blocks are being queued
+            files_in_memory: vars.parse_optional::<usize>("MAX_FILES_IN_MEMORY")?.unwrap_or(read_workers + 1).max(1),
+            start_height: vars.parse("START_HEIGHT", "0")?,
+            end_height: vars.parse_optional("END_HEIGHT")?.unwrap_or(i32::MAX),
+            rewind_to_height: vars.parse_optional("REWIND_TO_HEIGHT")?,
+            batch_size: vars.parse_optional::<usize>("BATCH_SIZE")?.unwrap_or(usize::MAX).max(1),
+            hash_threads: vars.parse("HASH_THREADS", "0")?,
+            progress: switch("PROGRESS"),
+            file_checksums: switch("FILE_CHECKSUMS"),
+            watch: switch("WATCH"),
+            headers_first: switch("HEADERS_FIRST"),
+            headers_only: switch("HEADERS_ONLY"),
+            watch_interval: Duration::from_secs(vars.parse("WATCH_INTERVAL_SECS", "10")?),
+            count_op_returns: switch("COUNT_OP_RETURNS"),
+            copy_flush_kb: vars.parse("COPY_FLUSH_KB", "64")?,
+            insert_workers: vars.parse::<usize>("INSERT_WORKERS", "10")?.max(1),
+            limits: ParseLimits {
+                max_script_size: vars.parse_optional("MAX_SCRIPT_SIZE")?.unwrap_or(default_limits.max_script_size),
+                max_transactions: vars.parse_optional("MAX_BLOCK_TRANSACTIONS")?.unwrap_or(default_limits.max_transactions),
+                max_inputs: vars.parse_optional("MAX_TX_INPUTS")?.unwrap_or(default_limits.max_inputs),
+                max_outputs: vars.parse_optional("MAX_TX_OUTPUTS")?.unwrap_or(default_limits.max_outputs),
+            },
+        };
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that can't be combined.
+    fn check(&self) -> Result<(), ConfigError> {
+        let conflict = |message: &str| Err(ConfigError::Usage(message.to_string()));
+        if !is_valid_table_prefix(&self.table_prefix) {
+            return Err(ConfigError::Invalid {
+                name: "TABLE_PREFIX",
+                value: self.table_prefix.clone(),
+                message: "expected at most 32 lowercase letters, digits and underscores, not starting with a digit".to_string(),
+            });
+        }
+        let uses_postgres = !self.dry_run && self.output == "postgres";
+        match self.command {
+            Command::Import => {
+                // The other outputs are closed by `finish`, which runs before watching starts
+                if self.watch && !self.dry_run && self.output != "postgres" {
+                    return conflict("WATCH=true needs OUTPUT=postgres");
+                }
+                if self.watch && self.headers_first {
+                    return conflict("WATCH=true can't be combined with HEADERS_FIRST=true");
+                }
+                if self.headers_only && !self.dry_run && self.output != "postgres" {
+                    return conflict("HEADERS_ONLY=true needs OUTPUT=postgres");
+                }
+                if self.watch && self.headers_only {
+                    return conflict("WATCH=true can't be combined with HEADERS_ONLY=true");
+                }
+                if self.undo_data && self.headers_first {
+                    return conflict("UNDO_DATA=true can't be combined with HEADERS_FIRST=true");
+                }
+                if self.rewind_to_height.is_some() && !uses_postgres {
+                    return conflict("REWIND_TO_HEIGHT needs OUTPUT=postgres");
+                }
+            }
+            Command::Rewind(_) if !uses_postgres => return conflict("rewind needs OUTPUT=postgres"),
+            _ => {}
+        }
+        if uses_postgres && matches!(self.command, Command::Import | Command::Rewind(_)) && self.database_url.is_none() {
+            return Err(ConfigError::Missing("DATABASE_URL"));
+        }
+        // Connections are made without TLS, which the pool would retry until it times out
+        let pg_config = self.database_url.as_deref().and_then(|url| url.parse::<tokio_postgres::Config>().ok());
+        if uses_postgres && pg_config.is_some_and(|pg_config| pg_config.get_ssl_mode() == SslMode::Require) {
+            return Err(ConfigError::Invalid {
+                name: "DATABASE_URL",
+                value: "sslmode=require".to_string(),
+                message: "TLS connections aren't supported, use sslmode=disable or sslmode=prefer".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Flags given on the command line, by the environment variable they override, in front of the environment.
+struct Vars<F> {
+    flags: HashMap<&'static str, String>,
+    env: F,
+}
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    fn get(&self, name: &str) -> Option<String> {
+        self.flags.get(name).cloned().or_else(|| (self.env)(name))
+    }
+
+    fn parse<T: FromStr>(&self, name: &'static str, default: &str) -> Result<T, ConfigError>
+    where
+        T::Err: Display,
+    {
+        parse_value(name, &self.get(name).unwrap_or_else(|| default.to_string()))
+    }
+
+    fn parse_optional<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, ConfigError>
+    where
+        T::Err: Display,
+    {
+        self.get(name).map(|value| parse_value(name, &value)).transpose()
+    }
+}
+
+fn parse_value<T: FromStr>(name: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Invalid { name, value: value.to_string(), message: e.to_string() })
+}
+
+/// The `--help` text.
+pub fn usage() -> String {
+    let mut usage = String::from(concat!(
+        "Usage: bitcoin_postgres_parser [COMMAND] [OPTIONS]\n\n",
+        "Commands:\n",
+        "  import           Import the blk files under BLOCKS_PATH (the default)\n",
+        "  verify           Check every block's merkle root, proof of work and parent, storing nothing\n",
+        "  rewind <HEIGHT>  Delete every stored block above HEIGHT\n",
+        "  help             Print this message\n\n",
+        "Options, each overriding the environment variable shown:\n",
+    ));
+    let width = FLAGS.iter().map(|(flag, _, value_name, _)| flag.len() + value_name.map_or(0, |name| name.len() + 3)).max().unwrap_or(0);
+    for (flag, var, value_name, help) in FLAGS {
+        let flag = match value_name {
+            Some(value_name) => format!("{} <{}>", flag, value_name),
+            None => flag.to_string(),
+        };
+        usage.push_str(&format!("  {:width$}  {:22} {}\n", flag, var, help, width = width));
+    }
+    usage.push_str("\nEvery other setting is read from the environment; see the README.\n");
+    usage
+}
@@ -1,123 +1,1463 @@
+use async_trait::async_trait;
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(not(feature = "csv-copy"))]
+use futures::pin_mut;
+#[cfg(feature = "csv-copy")]
+use bytes::{Bytes, BytesMut};
+#[cfg(feature = "csv-copy")]
 use futures::SinkExt;
+#[cfg(feature = "csv-copy")]
+use std::pin::Pin;
+#[cfg(feature = "csv-copy")]
+use time::format_description::well_known::Rfc3339;
+#[cfg(feature = "csv-copy")]
+use time::OffsetDateTime;
+#[cfg(not(feature = "csv-copy"))]
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+#[cfg(not(feature = "csv-copy"))]
+use tokio_postgres::types::Type;
+#[cfg(feature = "csv-copy")]
 use tokio_postgres::CopyInSink;
-use tokio_postgres::NoTls;
+use tokio::time::sleep;
+use tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres::{NoTls, Socket, Transaction};
+use tracing::{info, warn};
 
-use crate::models::Block;
+use crate::error::ParserError;
+use crate::metrics::Metrics;
+use crate::sink::BlockSink;
+use crate::models::{self, Block, BlockHeader, RelativeLocktime, StoredBlock};
+use crate::script::{extract_p2sh_multisig, extract_scriptsig_pubkey, extract_taproot_spend, extract_witness_pubkey, op_return_data, parse_multisig, script_asm, KeySpend, Multisig, ScriptType, TaprootPath, TaprootSpend};
 
-pub async fn setup_database(pool: &Pool<PostgresConnectionManager<NoTls>>) -> Result<(), Box<dyn std::error::Error>> {
-    let schema = "
+/// Delay before the first retry of a failed database operation, doubled after every attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Size of the chunks CSV COPY data is sent in unless `with_copy_flush_bytes` says otherwise.
+const DEFAULT_COPY_FLUSH_BYTES: usize = 64 * 1024;
+
+/// Connectors the pool can open connections with. Only `NoTls` is used for now, since no TLS
+/// connector is a dependency; tokio-postgres-rustls' `MakeRustlsConnect` and postgres-openssl's
+/// `MakeTlsConnector` meet these bounds, so adding one needs no change here.
+pub trait Connector:
+    MakeTlsConnect<Socket, Stream: Send + Sync, TlsConnect: Send + TlsConnect<Socket, Future: Send>> + Clone + Send + Sync + 'static
+{
+}
+
+impl<T> Connector for T where
+    T: MakeTlsConnect<Socket, Stream: Send + Sync, TlsConnect: Send + TlsConnect<Socket, Future: Send>> + Clone + Send + Sync + 'static
+{
+}
+
+/// The Postgres backend: a connection pool plus the options used when finishing an import.
+pub struct Database<Tls: Connector = NoTls> {
+    pool: Pool<PostgresConnectionManager<Tls>>,
+    foreign_keys: bool,
+    max_attempts: u32,
+    with_asm: bool,
+    utxo_set: bool,
+    address_stats: bool,
+    table_prefix: String,
+    copy_flush_bytes: usize,
+    defer_primary_keys: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl<Tls: Connector> Database<Tls> {
+    pub fn new(pool: Pool<PostgresConnectionManager<Tls>>, foreign_keys: bool, max_attempts: u32) -> Self {
+        Self { pool, foreign_keys, max_attempts: max_attempts.max(1), with_asm: false, utxo_set: false, address_stats: false, table_prefix: String::new(), copy_flush_bytes: DEFAULT_COPY_FLUSH_BYTES, defer_primary_keys: false, metrics: Arc::default() }
+    }
+
+    /// Also fills the `asm` columns of inputs and outputs with each script's assembly.
+    pub fn with_asm(mut self, with_asm: bool) -> Self {
+        self.with_asm = with_asm;
+        self
+    }
+
+    /// Rebuilds the `utxos` table once the import finishes.
+    pub fn with_utxo_set(mut self, utxo_set: bool) -> Self {
+        self.utxo_set = utxo_set;
+        self
+    }
+
+    /// Adds the outputs of every inserted batch's active blocks to the `address_stats` table, and
+    /// keeps it in step as reorganizations flip blocks between branches.
+    pub fn with_address_stats(mut self, address_stats: bool) -> Self {
+        self.address_stats = address_stats;
+        self
+    }
+
+    /// Sends CSV COPY data to Postgres in chunks of at least this many bytes. Without the
+    /// `csv-copy` feature, the binary writer sends its own chunks of 4 KB.
+    pub fn with_copy_flush_bytes(mut self, copy_flush_bytes: usize) -> Self {
+        self.copy_flush_bytes = copy_flush_bytes.max(1);
+        self
+    }
+
+    /// Creates the tables that take the most rows, such as `inputs` and `outputs`, without their
+    /// primary keys, which `finalize` adds once the import is done. Loading is faster without
+    /// them, but duplicates are only caught then. Only applies to tables `setup` creates.
+    pub fn with_deferred_primary_keys(mut self, defer_primary_keys: bool) -> Self {
+        self.defer_primary_keys = defer_primary_keys;
+        self
+    }
+
+    /// Counts the rows inserted and the time each table's COPY takes in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Puts `prefix` in front of every table name, and of the names of their indexes and
+    /// constraints, e.g. `testnet_blocks`, so that several imports can share one database.
+    /// Panics unless `is_valid_table_prefix(prefix)`.
+    pub fn with_table_prefix(mut self, prefix: &str) -> Self {
+        assert!(is_valid_table_prefix(prefix), "Invalid table prefix {:?}", prefix);
+        self.table_prefix = prefix.to_string();
+        self
+    }
+
+    /// Adds what bulk loading goes without: the primary keys deferred by
+    /// `with_deferred_primary_keys`, the secondary indexes, and the foreign keys when requested.
+    /// Called by `finish`, and safe to call again.
+    pub async fn finalize(&self) -> Result<(), ParserError> {
+        info!("Creating indexes");
+        let started = Instant::now();
+        self.retry(|| add_primary_keys(&self.pool, &self.table_prefix)).await?;
+        self.retry(|| create_indexes(&self.pool, &self.table_prefix, self.foreign_keys)).await?;
+        info!(elapsed_secs = format_args!("{:.1}", started.elapsed().as_secs_f64()), "Indexes created");
+        Ok(())
+    }
+
+    /// See `check_chain_integrity`.
+    pub async fn check_chain_integrity(&self) -> Result<ChainIntegrity, ParserError> {
+        self.retry(|| check_chain_integrity(&self.pool, &self.table_prefix)).await
+    }
+
+    /// See `rewind_to_height`.
+    pub async fn rewind_to_height(&self, height: i32) -> Result<u64, ParserError> {
+        self.retry(|| rewind_to_height(&self.pool, &self.table_prefix, height)).await
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that isn't transient, or has been
+    /// tried `max_attempts` times, backing off exponentially in between. Every operation takes a
+    /// fresh connection from the pool, which drops the broken ones, and runs in its own
+    /// transaction, so a retry starts over cleanly.
+    async fn retry<T, F, Fut>(&self, mut operation: F) -> Result<T, ParserError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ParserError>>,
+    {
+        let mut delay = INITIAL_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if e.is_transient() && attempt < self.max_attempts => {
+                    warn!(error = %e, ?delay, attempt, max_attempts = self.max_attempts, "Database error, retrying");
+                    sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<Tls: Connector> BlockSink for Database<Tls> {
+    async fn setup(&self, reset: bool) -> Result<(), ParserError> {
+        self.retry(|| setup_database(&self.pool, &self.table_prefix, reset, self.defer_primary_keys)).await?;
+        info!("Database schema setup complete");
+        Ok(())
+    }
+
+    async fn check_schema(&self) -> Result<Vec<String>, ParserError> {
+        self.retry(|| check_schema(&self.pool, &self.table_prefix)).await
+    }
+
+    async fn insert_blocks(&self, blocks: &[Block]) -> Result<(), ParserError> {
+        self.retry(|| insert_blocks(&self.pool, &self.table_prefix, blocks, self.with_asm, self.address_stats, self.copy_flush_bytes, &self.metrics)).await
+    }
+
+    async fn insert_headers(&self, headers: &[BlockHeader]) -> Result<(), ParserError> {
+        self.retry(|| insert_headers(&self.pool, &self.table_prefix, headers)).await
+    }
+
+    async fn load_chain(&self) -> Result<Vec<StoredBlock>, ParserError> {
+        self.retry(|| load_chain(&self.pool, &self.table_prefix)).await
+    }
+
+    async fn last_checkpoint(&self) -> Result<Option<(usize, i64)>, ParserError> {
+        self.retry(|| last_checkpoint(&self.pool, &self.table_prefix)).await
+    }
+
+    async fn save_checkpoint(&self, file_index: usize, block_count: i64) -> Result<(), ParserError> {
+        self.retry(|| save_checkpoint(&self.pool, &self.table_prefix, file_index, block_count)).await
+    }
+
+    async fn load_file_checksums(&self) -> Result<Vec<(String, String)>, ParserError> {
+        self.retry(|| load_file_checksums(&self.pool, &self.table_prefix)).await
+    }
+
+    async fn save_file_checksum(&self, file_name: &str, sha256: &str) -> Result<(), ParserError> {
+        self.retry(|| save_file_checksum(&self.pool, &self.table_prefix, file_name, sha256)).await
+    }
+
+    async fn update_active_flags(&self, changes: &[(String, bool)]) -> Result<(), ParserError> {
+        self.retry(|| update_active_flags(&self.pool, &self.table_prefix, changes, self.address_stats)).await
+    }
+
+    async fn finish(&self) -> Result<(), ParserError> {
+        self.finalize().await?;
+
+        let resolved = self.retry(|| resolve_spent_outputs(&self.pool, &self.table_prefix)).await?;
+        info!(resolved, "Resolved spent outputs");
+
+        let fees = self.retry(|| compute_fees(&self.pool, &self.table_prefix)).await?;
+        info!(fees, "Computed transaction fees");
+
+        if self.utxo_set {
+            let utxos = self.retry(|| build_utxo_set(&self.pool, &self.table_prefix)).await?;
+            info!(utxos, "Built the UTXO set");
+        }
+
+        let integrity = self.check_chain_integrity().await?;
+        if !integrity.is_intact() {
+            warn!(missing_parents = ?integrity.missing_parents, height_gaps = ?integrity.height_gaps, "The active chain is incomplete");
+        }
+        Ok(())
+    }
+}
+
+/// Every table the import writes to, created by `setup_database`. The primary keys of the
+/// tables that take the most rows are in `PRIMARY_KEYS` instead.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS blocks (
+        block_hash VARCHAR(64) PRIMARY KEY,
+        height INT,
+        time TIMESTAMPTZ,
+        difficulty DOUBLE PRECISION,
+        merkle_root VARCHAR(64),
+        nonce BIGINT,
+        size INT,
+        weight INT,
+        version BIGINT,
+        bits BIGINT,
+        previous_block VARCHAR(64),
+        active BOOLEAN,
+        total_supply BIGINT,
+        rbf_tx_count INT,
+        coinbase_tag TEXT,
+        blk_file TEXT,
+        byte_offset BIGINT,
+        raw BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS headers (
+        block_hash VARCHAR(64) PRIMARY KEY,
+        version BIGINT,
+        previous_block VARCHAR(64),
+        merkle_root VARCHAR(64),
+        time TIMESTAMPTZ,
+        bits BIGINT,
+        nonce BIGINT,
+        height INT
+    );
+
+    CREATE TABLE IF NOT EXISTS transactions (
+        txid VARCHAR(64),
+        wtxid VARCHAR(64),
+        block_hash VARCHAR(64),
+        position INT,
+        size INT,
+        weight INT,
+        vsize INT,
+        version BIGINT,
+        locktime INT,
+        locktime_type VARCHAR(6),
+        locktime_time TIMESTAMPTZ,
+        fee BIGINT,
+        raw BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS inputs (
+        txid VARCHAR(64),
+        input_index INT,
+        previous_txid VARCHAR(64),
+        previous_output_index BIGINT,
+        script_sig TEXT,
+        asm TEXT,
+        sequence BIGINT,
+        is_coinbase BOOLEAN,
+        rbf_signaled BOOLEAN,
+        relative_locktime_blocks INT,
+        relative_locktime_time INT,
+        spent_value BIGINT,
+        spent_address TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS outputs (
+        txid VARCHAR(64),
+        output_index INT,
+        value BIGINT,
+        script_pub_key TEXT,
+        asm TEXT,
+        address TEXT,
+        script_type VARCHAR(16),
+        unspendable BOOLEAN
+    );
+
+    CREATE TABLE IF NOT EXISTS witnesses (
+        txid VARCHAR(64),
+        input_index INT,
+        item_index INT,
+        item BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS op_returns (
+        txid VARCHAR(64),
+        output_index INT,
+        push_index INT,
+        data BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS signatures (
+        txid VARCHAR(64),
+        input_index INT,
+        pubkey BYTEA,
+        signature BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS taproot_spends (
+        txid VARCHAR(64),
+        input_index INT,
+        spend_type VARCHAR(6),
+        signature BYTEA,
+        leaf_version INT,
+        internal_key BYTEA,
+        script BYTEA,
+        annex BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS multisig (
+        txid VARCHAR(64),
+        source VARCHAR(6),
+        source_index INT,
+        key_index INT,
+        required INT,
+        total INT,
+        pubkey BYTEA
+    );
+
+    CREATE TABLE IF NOT EXISTS utxos (
+        txid VARCHAR(64),
+        output_index INT,
+        value BIGINT,
+        address TEXT,
+        script_type VARCHAR(16),
+        height INT,
+        is_coinbase BOOLEAN,
+        mature BOOLEAN,
+        PRIMARY KEY (txid, output_index)
+    );
+
+    CREATE TABLE IF NOT EXISTS address_stats (
+        address TEXT PRIMARY KEY,
+        total_received BIGINT NOT NULL,
+        output_count BIGINT NOT NULL,
+        first_seen_height INT NOT NULL,
+        last_seen_height INT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS progress (
+        id INT PRIMARY KEY,
+        file_index INT,
+        block_count BIGINT
+    );
+
+    CREATE TABLE IF NOT EXISTS files (
+        file_name VARCHAR(255) PRIMARY KEY,
+        sha256 CHAR(64) NOT NULL,
+        imported_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    );
+";
+
+/// Primary keys of the tables bulk loading writes most rows to, as table and columns. Created by
+/// `setup_database`, or by `add_primary_keys` once the rows are in when they are deferred.
+const PRIMARY_KEYS: [(&str, &str); 8] = [
+    ("transactions", "txid"),
+    ("inputs", "txid, input_index"),
+    ("outputs", "txid, output_index"),
+    ("witnesses", "txid, input_index, item_index"),
+    ("op_returns", "txid, output_index, push_index"),
+    ("signatures", "txid, input_index"),
+    ("taproot_spends", "txid, input_index"),
+    ("multisig", "txid, source, source_index, key_index"),
+];
+
+/// Creates any missing tables, after dropping them all with `reset`. With `defer_primary_keys`,
+/// tables created now get no `PRIMARY_KEYS` until `add_primary_keys` runs.
+pub async fn setup_database<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, reset: bool, defer_primary_keys: bool) -> Result<(), ParserError> {
+    let drop_tables = "
+        DROP TABLE IF EXISTS progress;
+        DROP TABLE IF EXISTS files;
+        DROP TABLE IF EXISTS utxos;
+        DROP TABLE IF EXISTS address_stats;
+        DROP TABLE IF EXISTS signatures;
+        DROP TABLE IF EXISTS taproot_spends;
+        DROP TABLE IF EXISTS multisig;
+        DROP TABLE IF EXISTS op_returns;
+        DROP TABLE IF EXISTS witnesses;
         DROP TABLE IF EXISTS inputs;
         DROP TABLE IF EXISTS outputs;
         DROP TABLE IF EXISTS transactions;
+        DROP TABLE IF EXISTS headers;
         DROP TABLE IF EXISTS blocks;
+    ";
 
-        CREATE TABLE IF NOT EXISTS blocks (
-            block_hash VARCHAR(64) PRIMARY KEY,
-            height INT,
-            time TIMESTAMP,
-            difficulty DOUBLE PRECISION,
-            merkle_root VARCHAR(64),
-            nonce DOUBLE PRECISION,
-            size INT,
-            version INT,
-            bits VARCHAR(16),
-            previous_block VARCHAR(64),
-            active BOOLEAN
-        );
-
-        CREATE TABLE IF NOT EXISTS transactions (
-            txid VARCHAR(64) PRIMARY KEY,
-            block_hash VARCHAR(64),
-            size INT,
-            version INT,
-            locktime INT
-        );
-
-        CREATE TABLE IF NOT EXISTS inputs (
-            txid VARCHAR(64) REFERENCES transactions(txid),
-            input_index INT,
-            previous_txid VARCHAR(64),
-            previous_output_index INT,
-            script_sig TEXT,
-            sequence BIGINT,
-            PRIMARY KEY (txid, input_index)
-        );
-
-        CREATE TABLE IF NOT EXISTS outputs (
-            txid VARCHAR(64) REFERENCES transactions(txid),
-            output_index INT,
-            value DOUBLE PRECISION,
-            script_pub_key TEXT,
-            PRIMARY KEY (txid, output_index)
-        );
+
+    let conn = pool.get().await?;
+    if reset {
+        conn.batch_execute(&prefixed(drop_tables, prefix)).await?;
+    }
+    conn.batch_execute(&prefixed(SCHEMA, prefix)).await?;
+    if !defer_primary_keys {
+        add_primary_keys(pool, prefix).await?;
+    }
+    Ok(())
+}
+
+/// Adds the `PRIMARY_KEYS` that are missing, each named as an inline primary key would be, so
+/// tables created with them by older versions are left as they are. Rows loaded without them may
+/// repeat, e.g. a transaction included by a stale block as well as by the block that replaced it,
+/// since only batches are checked for those then. Of each set of repeated rows the first one
+/// loaded is kept.
+pub async fn add_primary_keys<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<(), ParserError> {
+    let conn = pool.get().await?;
+    for (table, columns) in PRIMARY_KEYS {
+        let name = prefixed(&format!("{}_pkey", table), prefix).into_owned();
+        let exists = conn.query_opt("SELECT 1 FROM pg_constraint WHERE conname = $1", &[&name]).await?.is_some();
+        if !exists {
+            let repeated = columns.split(", ").map(|column| format!("later.{0} = earlier.{0}", column)).collect::<Vec<_>>().join(" AND ");
+            let statement = format!(
+                "DELETE FROM {0} later USING {0} earlier WHERE {1} AND later.ctid > earlier.ctid;
+                 ALTER TABLE {0} ADD CONSTRAINT {2} PRIMARY KEY ({3})",
+                table, repeated, name, columns,
+            );
+            conn.batch_execute(&prefixed(&statement, prefix)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The columns `SCHEMA` defines, as table, column and type.
+fn schema_columns() -> Vec<(&'static str, &'static str, &'static str)> {
+    let mut columns = Vec::new();
+    let mut table = None;
+    for line in SCHEMA.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("CREATE TABLE IF NOT EXISTS ") {
+            table = name.split_whitespace().next();
+        } else if line.starts_with(')') {
+            table = None;
+        } else if let (Some(table), Some((column, definition))) = (table, line.split_once(' ')) {
+            if column == "PRIMARY" {
+                continue;
+            }
+            let definition = definition.trim_end_matches(',');
+            let end = [" PRIMARY KEY", " NOT NULL", " DEFAULT"].iter().filter_map(|clause| definition.find(clause)).min();
+            columns.push((table, column, &definition[..end.unwrap_or(definition.len())]));
+        }
+    }
+    columns
+}
+
+/// Compares the tables with `SCHEMA`, returning every column that is missing or has another
+/// type, as tables created by an older version would. Lengths of `VARCHAR` and `CHAR` columns
+/// aren't compared. Extra columns are fine, since nothing writes to them.
+pub async fn check_schema<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<Vec<String>, ParserError> {
+    let columns = schema_columns();
+    let tables: Vec<String> = columns.iter().map(|(table, _, _)| format!("{}{}", prefix, table)).collect();
+    let names: Vec<&str> = columns.iter().map(|(_, column, _)| *column).collect();
+    let types: Vec<&str> = columns.iter().map(|(_, _, column_type)| *column_type).collect();
+
+    let conn = pool.get().await?;
+    let rows = conn.query(
+        "SELECT expected.table_name, expected.column_name, format_type(to_regtype(expected.type_name), NULL), format_type(attribute.atttypid, NULL)
+         FROM unnest($1::TEXT[], $2::TEXT[], $3::TEXT[]) WITH ORDINALITY AS expected (table_name, column_name, type_name, position)
+         LEFT JOIN pg_attribute attribute
+           ON attribute.attrelid = to_regclass(expected.table_name)
+          AND attribute.attname = expected.column_name
+          AND NOT attribute.attisdropped
+         WHERE attribute.atttypid IS DISTINCT FROM to_regtype(expected.type_name)
+         ORDER BY expected.position",
+        &[&tables, &names, &types],
+    ).await?;
+
+    Ok(rows.iter().map(|row| {
+        let (table, column, expected): (String, String, String) = (row.get(0), row.get(1), row.get(2));
+        match row.get::<_, Option<String>>(3) {
+            Some(found) => format!("{}.{} is {}, expected {}", table, column, found, expected),
+            None => format!("{}.{} is missing", table, column),
+        }
+    }).collect())
+}
+
+/// Every table `setup_database` creates, as named without a prefix.
+const TABLES: [&str; 14] = [
+    "blocks", "headers", "transactions", "inputs", "outputs", "witnesses", "op_returns", "signatures", "taproot_spends", "multisig", "utxos",
+    "progress", "files", "address_stats",
+];
+
+/// Longest table prefix accepted, which keeps every prefixed name within Postgres' 63 bytes.
+const MAX_TABLE_PREFIX_LENGTH: usize = 32;
+
+/// Table prefixes are interpolated into SQL, so only lowercase letters, digits and underscores are
+/// allowed, and no leading digit, which would make the table names invalid identifiers.
+pub fn is_valid_table_prefix(prefix: &str) -> bool {
+    prefix.len() <= MAX_TABLE_PREFIX_LENGTH
+        && !prefix.starts_with(|c: char| c.is_ascii_digit())
+        && prefix.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_')
+}
+
+/// Puts `prefix` in front of every identifier in `sql` that is a table name, or that starts with
+/// one followed by `_` as the index and constraint names do.
+fn prefixed<'a>(sql: &'a str, prefix: &str) -> Cow<'a, str> {
+    if prefix.is_empty() {
+        return Cow::Borrowed(sql);
+    }
+
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(sql.len() + 8 * prefix.len());
+    let mut rest = sql;
+    while let Some(start) = rest.find(is_identifier) {
+        let (before, from_start) = rest.split_at(start);
+        let end = from_start.find(|c: char| !is_identifier(c)).unwrap_or(from_start.len());
+        let (identifier, after) = from_start.split_at(end);
+
+        result.push_str(before);
+        let is_table = TABLES.iter().any(|table| {
+            identifier.strip_prefix(table).is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('_'))
+        });
+        if is_table {
+            result.push_str(prefix);
+        }
+        result.push_str(identifier);
+        rest = after;
+    }
+    result.push_str(rest);
+
+    Cow::Owned(result)
+}
+
+/// Blocks a coinbase output has to wait before it can be spent.
+const COINBASE_MATURITY: i32 = 100;
+
+/// Foreign keys added by `create_indexes` when requested, as constraint name, table and definition.
+const FOREIGN_KEYS: [(&str, &str, &str); 8] = [
+    ("transactions_block_hash_fkey", "transactions", "FOREIGN KEY (block_hash) REFERENCES blocks(block_hash)"),
+    ("inputs_txid_fkey", "inputs", "FOREIGN KEY (txid) REFERENCES transactions(txid)"),
+    ("outputs_txid_fkey", "outputs", "FOREIGN KEY (txid) REFERENCES transactions(txid)"),
+    ("witnesses_txid_fkey", "witnesses", "FOREIGN KEY (txid) REFERENCES transactions(txid)"),
+    ("multisig_txid_fkey", "multisig", "FOREIGN KEY (txid) REFERENCES transactions(txid)"),
+    ("op_returns_output_fkey", "op_returns", "FOREIGN KEY (txid, output_index) REFERENCES outputs(txid, output_index)"),
+    ("signatures_input_fkey", "signatures", "FOREIGN KEY (txid, input_index) REFERENCES inputs(txid, input_index)"),
+    ("taproot_spends_input_fkey", "taproot_spends", "FOREIGN KEY (txid, input_index) REFERENCES inputs(txid, input_index)"),
+];
+
+/// Creates the secondary indexes, and optionally the foreign keys, that `setup_database` leaves out
+/// so that bulk loading doesn't have to maintain them. Safe to call after every import.
+/// Lookups of outputs by txid are already served by the outputs primary key.
+pub async fn create_indexes<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, foreign_keys: bool) -> Result<(), ParserError> {
+    let indexes = "
+        CREATE INDEX IF NOT EXISTS transactions_block_hash_idx ON transactions (block_hash, position);
+        CREATE INDEX IF NOT EXISTS inputs_previous_output_idx ON inputs (previous_txid, previous_output_index);
     ";
 
     let conn = pool.get().await?;
-    conn.batch_execute(schema).await?;
+    conn.batch_execute(&prefixed(indexes, prefix)).await?;
+
+    if foreign_keys {
+        for (name, table, definition) in FOREIGN_KEYS {
+            let name = prefixed(name, prefix);
+            let exists = conn.query_opt("SELECT 1 FROM pg_constraint WHERE conname = $1", &[&name]).await?.is_some();
+            if !exists {
+                let statement = format!("ALTER TABLE {} ADD CONSTRAINT {} {}", table, name, definition);
+                conn.batch_execute(&prefixed(&statement, prefix)).await?;
+            }
+        }
+    }
+
     Ok(())
 }
 
-fn is_bip30_conflict(txid: &str) -> bool {
-    txid == "ef412cf1f8ff44bbf0bede1ea30a0ce741d625425edbf53883d53f7c682a0548" ||
-    txid == "4a4780f0046f0f69d429a32b0307aabaf2fd437685ee18d28274f4cda1e3d40b"
+/// Fills `inputs.spent_value` and `inputs.spent_address` from the outputs they spend. Only inputs
+/// that are still unresolved are looked at, so it can run after every import. Coinbase inputs
+/// spend nothing and are left NULL, as are inputs whose previous output hasn't been imported or
+/// is unspendable.
+pub async fn resolve_spent_outputs<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<u64, ParserError> {
+    let conn = pool.get().await?;
+    let resolved = conn.execute(
+        &*prefixed("UPDATE inputs SET spent_value = outputs.value, spent_address = outputs.address
+         FROM outputs
+         WHERE inputs.spent_value IS NULL
+           AND NOT inputs.is_coinbase
+           AND NOT outputs.unspendable
+           AND outputs.txid = inputs.previous_txid
+           AND outputs.output_index = inputs.previous_output_index", prefix),
+        &[],
+    ).await?;
+    Ok(resolved)
 }
 
-pub async fn insert_block(pool: &Pool<PostgresConnectionManager<NoTls>>, block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+/// Fills `transactions.fee` for transactions that don't have one yet, so it can run after every
+/// import. A transaction's fee is what its inputs spend minus what its outputs pay, and is only
+/// set once every input is resolved. A coinbase's fee is what it claims beyond the subsidy its
+/// block issued, taken from the `total_supply` difference to the parent block, which must be
+/// stored. The genesis coinbase is left NULL. Returns the number of transactions updated.
+pub async fn compute_fees<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<u64, ParserError> {
     let mut conn = pool.get().await?;
     let transaction = conn.build_transaction().start().await?;
 
-    let mut block_sink: std::pin::Pin<Box<CopyInSink<bytes::Bytes>>> = Box::pin(transaction.copy_in("COPY blocks (block_hash, height, time, difficulty, merkle_root, nonce, size, version, bits, previous_block, active) FROM STDIN WITH DELIMITER ',' CSV").await?);
-    let block_line = format!("{},{},{},{},{},{},{},{},{},{},{}\n", block.block_hash, block.height, block.time, block.difficulty as f64, block.merkle_root, block.nonce as f64, block.size, block.version, block.bits, block.previous_block, block.active);
-    block_sink.as_mut().send(block_line.into()).await?;
-    block_sink.as_mut().close().await?;
-    
-    let mut tx_lines = Vec::new();
-    let mut input_lines = Vec::new();
-    let mut output_lines = Vec::new();
-
-    for tx in &block.transactions {
-        if is_bip30_conflict(&tx.txid) {
-            continue;
+    let spends = transaction.execute(
+        &*prefixed("UPDATE transactions SET fee = spent.value - (SELECT SUM(outputs.value) FROM outputs WHERE outputs.txid = transactions.txid)
+         FROM (
+             SELECT inputs.txid, SUM(inputs.spent_value) AS value
+             FROM inputs
+             JOIN transactions ON transactions.txid = inputs.txid AND transactions.fee IS NULL
+             WHERE NOT inputs.is_coinbase
+             GROUP BY inputs.txid
+             HAVING COUNT(inputs.spent_value) = COUNT(*)
+         ) spent
+         WHERE transactions.txid = spent.txid", prefix),
+        &[],
+    ).await?;
+
+    let coinbases = transaction.execute(
+        &*prefixed("UPDATE transactions
+         SET fee = (SELECT SUM(outputs.value) FROM outputs WHERE outputs.txid = transactions.txid)
+                   - (blocks.total_supply - parent.total_supply)
+         FROM blocks
+         JOIN blocks parent ON parent.block_hash = blocks.previous_block
+         WHERE transactions.fee IS NULL
+           AND transactions.block_hash = blocks.block_hash
+           AND EXISTS (SELECT 1 FROM inputs WHERE inputs.txid = transactions.txid AND inputs.is_coinbase)", prefix),
+        &[],
+    ).await?;
+
+    transaction.commit().await?;
+    Ok(spends + coinbases)
+}
+
+/// Replaces the contents of `utxos` with the outputs of active blocks that no input of an active
+/// block spends, leaving out unspendable outputs. Coinbase outputs are `mature` once the next
+/// block could spend them, i.e. `COINBASE_MATURITY` blocks after their own.
+pub async fn build_utxo_set<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<u64, ParserError> {
+    let mut conn = pool.get().await?;
+    let transaction = conn.build_transaction().start().await?;
+
+    transaction.batch_execute(&prefixed("TRUNCATE utxos", prefix)).await?;
+    let utxos = transaction.execute(
+        &*prefixed("WITH tip AS (SELECT COALESCE(MAX(height), -1) AS height FROM blocks WHERE active),
+              unspent AS (
+                  SELECT outputs.txid, outputs.output_index, outputs.value, outputs.address, outputs.script_type, blocks.height,
+                         EXISTS (SELECT 1 FROM inputs WHERE inputs.txid = outputs.txid AND inputs.is_coinbase) AS is_coinbase
+                  FROM outputs
+                  JOIN transactions ON transactions.txid = outputs.txid
+                  JOIN blocks ON blocks.block_hash = transactions.block_hash AND blocks.active
+                  WHERE NOT outputs.unspendable
+                    AND NOT EXISTS (
+                        SELECT 1 FROM inputs
+                        JOIN transactions spending ON spending.txid = inputs.txid
+                        JOIN blocks spending_block ON spending_block.block_hash = spending.block_hash AND spending_block.active
+                        WHERE inputs.previous_txid = outputs.txid
+                          AND inputs.previous_output_index = outputs.output_index
+                    )
+              )
+         INSERT INTO utxos (txid, output_index, value, address, script_type, height, is_coinbase, mature)
+         SELECT unspent.txid, unspent.output_index, unspent.value, unspent.address, unspent.script_type, unspent.height,
+                unspent.is_coinbase, NOT unspent.is_coinbase OR tip.height + 1 - unspent.height >= $1
+         FROM unspent, tip", prefix),
+        &[&COINBASE_MATURITY],
+    ).await?;
+
+    transaction.commit().await?;
+    Ok(utxos)
+}
+
+/// Returns the index of the last blk file whose blocks are all committed, and the number of blocks committed when it was saved.
+pub async fn last_checkpoint<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<Option<(usize, i64)>, ParserError> {
+    let conn = pool.get().await?;
+    let row = conn.query_opt(&*prefixed("SELECT file_index, block_count FROM progress WHERE id = 1", prefix), &[]).await?;
+    Ok(row.map(|row| (row.get::<_, i32>(0) as usize, row.get(1))))
+}
+
+pub async fn save_checkpoint<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, file_index: usize, block_count: i64) -> Result<(), ParserError> {
+    let conn = pool.get().await?;
+    conn.execute(
+        &*prefixed("INSERT INTO progress (id, file_index, block_count) VALUES (1, $1, $2)
+         ON CONFLICT (id) DO UPDATE SET file_index = EXCLUDED.file_index, block_count = EXCLUDED.block_count", prefix),
+        &[&(file_index as i32), &block_count],
+    ).await?;
+    Ok(())
+}
+
+/// The SHA256 of every blk file whose blocks were all committed, by file name.
+pub async fn load_file_checksums<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<Vec<(String, String)>, ParserError> {
+    let conn = pool.get().await?;
+    let rows = conn.query(&*prefixed("SELECT file_name, sha256 FROM files", prefix), &[]).await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+pub async fn save_file_checksum<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, file_name: &str, sha256: &str) -> Result<(), ParserError> {
+    let conn = pool.get().await?;
+    conn.execute(
+        &*prefixed("INSERT INTO files (file_name, sha256) VALUES ($1, $2)
+         ON CONFLICT (file_name) DO UPDATE SET sha256 = EXCLUDED.sha256, imported_at = now()", prefix),
+        &[&file_name, &sha256],
+    ).await?;
+    Ok(())
+}
+
+/// Every block already imported, parents first, used to rebuild the chain when resuming.
+pub async fn load_chain<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<Vec<StoredBlock>, ParserError> {
+    let conn = pool.get().await?;
+    let rows = conn.query(&*prefixed("SELECT block_hash, previous_block, height, EXTRACT(EPOCH FROM time)::BIGINT, bits, total_supply, active FROM blocks ORDER BY height", prefix), &[]).await?;
+    Ok(rows.iter().map(|row| StoredBlock {
+        block_hash: row.get(0),
+        previous_block: row.get(1),
+        height: row.get(2),
+        time: row.get(3),
+        bits: row.get::<_, i64>(4) as u32,
+        total_supply: row.get(5),
+        active: row.get(6),
+    }).collect())
+}
+
+/// Applies active flag changes caused by a reorganization to blocks that were already inserted.
+/// With `address_stats`, the outputs of the blocks leaving the active chain are taken out of
+/// `address_stats` and those of the blocks joining it are added.
+pub async fn update_active_flags<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, changes: &[(String, bool)], address_stats: bool) -> Result<(), ParserError> {
+    let mut conn = pool.get().await?;
+    let transaction = conn.build_transaction().start().await?;
+
+    let statement = transaction.prepare(&prefixed("UPDATE blocks SET active = $2 WHERE block_hash = $1", prefix)).await?;
+    for (block_hash, active) in changes {
+        transaction.execute(&statement, &[block_hash, active]).await?;
+    }
+
+    if address_stats {
+        let (activated, deactivated): (Vec<_>, Vec<_>) = changes.iter().partition(|(_, active)| *active);
+        let deactivated: Vec<&str> = deactivated.iter().map(|(block_hash, _)| block_hash.as_str()).collect();
+        transaction.execute(
+            &*prefixed("UPDATE address_stats
+             SET total_received = address_stats.total_received - removed.value,
+                 output_count = address_stats.output_count - removed.count
+             FROM (
+                 SELECT outputs.address, SUM(outputs.value)::BIGINT AS value, COUNT(*) AS count
+                 FROM transactions JOIN outputs ON outputs.txid = transactions.txid
+                 WHERE transactions.block_hash = ANY($1) AND outputs.address IS NOT NULL
+                 GROUP BY outputs.address
+             ) removed
+             WHERE address_stats.address = removed.address", prefix),
+            &[&deactivated],
+        ).await?;
+        transaction.execute(&*prefixed("DELETE FROM address_stats WHERE output_count = 0", prefix), &[]).await?;
+
+        // Sorted by address, like the batches inserted, so that they lock shared rows in the same order
+        let activated: Vec<&str> = activated.iter().map(|(block_hash, _)| block_hash.as_str()).collect();
+        transaction.execute(
+            &*prefixed("INSERT INTO address_stats (address, total_received, output_count, first_seen_height, last_seen_height)
+             SELECT outputs.address, SUM(outputs.value)::BIGINT, COUNT(*), MIN(blocks.height), MAX(blocks.height)
+             FROM blocks
+             JOIN transactions ON transactions.block_hash = blocks.block_hash
+             JOIN outputs ON outputs.txid = transactions.txid
+             WHERE blocks.block_hash = ANY($1) AND outputs.address IS NOT NULL
+             GROUP BY outputs.address
+             ORDER BY outputs.address
+             ON CONFLICT (address) DO UPDATE SET
+                 total_received = address_stats.total_received + EXCLUDED.total_received,
+                 output_count = address_stats.output_count + EXCLUDED.output_count,
+                 first_seen_height = LEAST(address_stats.first_seen_height, EXCLUDED.first_seen_height),
+                 last_seen_height = GREATEST(address_stats.last_seen_height, EXCLUDED.last_seen_height)", prefix),
+            &[&activated],
+        ).await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Problems with the active chain found by `check_chain_integrity`, typically left by a partial
+/// import. Empty when the active blocks form a single chain from height 0 to the tip.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChainIntegrity {
+    /// Parents of active blocks, other than the genesis block, that aren't in `blocks`.
+    pub missing_parents: Vec<String>,
+    /// First and last height of every run of heights below the tip without an active block.
+    pub height_gaps: Vec<(i32, i32)>,
+}
+
+impl ChainIntegrity {
+    pub fn is_intact(&self) -> bool {
+        self.missing_parents.is_empty() && self.height_gaps.is_empty()
+    }
+}
+
+/// Checks that every active block but the genesis block has its parent stored, and that active
+/// heights run from 0 to the tip without gaps.
+pub async fn check_chain_integrity<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str) -> Result<ChainIntegrity, ParserError> {
+    let conn = pool.get().await?;
+    let missing_parents = conn.query(
+        &*prefixed("SELECT DISTINCT blocks.previous_block FROM blocks
+         WHERE blocks.active
+           AND blocks.height > 0
+           AND NOT EXISTS (SELECT 1 FROM blocks parent WHERE parent.block_hash = blocks.previous_block)
+         ORDER BY blocks.previous_block", prefix),
+        &[],
+    ).await?;
+    let height_gaps = conn.query(
+        &*prefixed("SELECT previous + 1, height - 1 FROM (
+             SELECT height, LAG(height, 1, -1) OVER (ORDER BY height) AS previous FROM blocks WHERE active
+         ) heights
+         WHERE height > previous + 1
+         ORDER BY height", prefix),
+        &[],
+    ).await?;
+
+    Ok(ChainIntegrity {
+        missing_parents: missing_parents.iter().map(|row| row.get(0)).collect(),
+        height_gaps: height_gaps.iter().map(|row| (row.get(0), row.get(1))).collect(),
+    })
+}
+
+/// Deletes every block above `height` in one transaction, along with the rows of its transactions
+/// in every table, e.g. to import a range again or to undo a reorganization. Blocks at or below
+/// `height` are kept, whichever branch they are on.
+///
+/// The removed outputs of active blocks are subtracted from `address_stats`, whose
+/// `last_seen_height` is capped at `height` rather than recomputed. `utxos` is emptied, since outputs the removed blocks spent
+/// would have to come back; the next import with `UTXO_SET=true` rebuilds it. The checkpoint and
+/// the checksums of the files the removed blocks were read from are cleared, so the next run
+/// reads those files again. Returns the number of blocks deleted.
+pub async fn rewind_to_height<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, height: i32) -> Result<u64, ParserError> {
+    let mut conn = pool.get().await?;
+    let transaction = conn.build_transaction().start().await?;
+
+    let rewound_txids = "SELECT transactions.txid FROM transactions JOIN blocks ON blocks.block_hash = transactions.block_hash WHERE blocks.height > $1";
+    transaction.execute(
+        &*prefixed(&format!("UPDATE address_stats
+         SET total_received = address_stats.total_received - removed.value,
+             output_count = address_stats.output_count - removed.count,
+             last_seen_height = LEAST(address_stats.last_seen_height, $1)
+         FROM (
+             SELECT outputs.address, SUM(outputs.value) AS value, COUNT(*) AS count
+             FROM outputs
+             WHERE outputs.address IS NOT NULL AND outputs.txid IN ({} AND blocks.active)
+             GROUP BY outputs.address
+         ) removed
+         WHERE address_stats.address = removed.address", rewound_txids), prefix),
+        &[&height],
+    ).await?;
+    transaction.execute(&*prefixed("DELETE FROM address_stats WHERE output_count = 0", prefix), &[]).await?;
+
+    for table in ["op_returns", "signatures", "taproot_spends", "multisig", "witnesses", "inputs", "outputs", "transactions"] {
+        let statement = format!("DELETE FROM {} WHERE txid IN ({})", table, rewound_txids);
+        transaction.execute(&*prefixed(&statement, prefix), &[&height]).await?;
+    }
+    transaction.execute(&*prefixed("DELETE FROM files WHERE file_name IN (SELECT blk_file FROM blocks WHERE height > $1)", prefix), &[&height]).await?;
+    transaction.execute(&*prefixed("DELETE FROM headers WHERE height > $1", prefix), &[&height]).await?;
+    let blocks = transaction.execute(&*prefixed("DELETE FROM blocks WHERE height > $1", prefix), &[&height]).await?;
+    if blocks > 0 {
+        transaction.batch_execute(&prefixed("TRUNCATE utxos; DELETE FROM progress;", prefix)).await?;
+    }
+
+    transaction.commit().await?;
+    Ok(blocks)
+}
+
+/// Coinbase transactions that occur twice in the main chain (BIP30), as the txid, the height of
+/// the first copy and the height of the duplicate that overwrote it.
+pub const BIP30_DUPLICATES: [(&str, i32, i32); 2] = [
+    ("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468", 91722, 91880),
+    ("d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599", 91812, 91842),
+];
+
+/// Handles BIP30 duplicates the way Core does: the later copy replaces the earlier one. Rows left
+/// by an earlier copy are deleted before the later one is written, and an earlier copy is not
+/// written at all once the later one is in the database or in this batch.
+/// Returns the `(block_hash, txid)` pairs to leave out of the COPY.
+async fn resolve_bip30_duplicates<'a>(transaction: &Transaction<'_>, prefix: &str, blocks: &[&'a Block]) -> Result<HashSet<(&'a str, &'a str)>, ParserError> {
+    let mut skipped = HashSet::new();
+
+    for block in blocks {
+        for tx in &block.transactions {
+            let Some(&(txid, _, duplicate_height)) = BIP30_DUPLICATES.iter().find(|(txid, _, _)| *txid == tx.txid) else {
+                continue;
+            };
+
+            if block.height == duplicate_height {
+                transaction.execute(&*prefixed("DELETE FROM op_returns WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM signatures WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM taproot_spends WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM multisig WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM witnesses WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM inputs WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM outputs WHERE txid = $1", prefix), &[&txid]).await?;
+                transaction.execute(&*prefixed("DELETE FROM transactions WHERE txid = $1", prefix), &[&txid]).await?;
+                continue;
+            }
+
+            let duplicate_in_batch = blocks.iter().any(|other| {
+                other.height == duplicate_height && other.transactions.iter().any(|other_tx| other_tx.txid == txid)
+            });
+            let in_database = transaction.query_opt(&*prefixed("SELECT 1 FROM transactions WHERE txid = $1", prefix), &[&txid]).await?.is_some();
+            if duplicate_in_batch || in_database {
+                skipped.insert((block.block_hash.as_str(), tx.txid.as_str()));
+            }
         }
+    }
 
-        let tx_line = format!("{},{},{},{},{}\n", tx.txid, block.block_hash, tx.size, tx.version, tx.locktime);
-        tx_lines.push(tx_line);
+    Ok(skipped)
+}
 
-        for input in &tx.inputs {
-            let input_line = format!("{},{},{},{},{},{}\n", tx.txid, input.input_index, input.previous_txid, input.previous_output_index, input.script_sig, input.sequence);
-            input_lines.push(input_line);
+/// Leaves out transactions stored before, or held by another block of the batch, as happens when a
+/// stale block and the block that replaced it include the same transactions. `transactions` is
+/// keyed by txid, so each is stored once, preferably under an active block: a copy stored under an
+/// inactive block is moved to an active block of the batch that holds it too. BIP30 duplicates are
+/// left to `resolve_bip30_duplicates`. While `transactions` has no primary key to look txids up
+/// by, only the batch itself is checked, and `add_primary_keys` drops the repeats.
+async fn resolve_shared_transactions<'a>(transaction: &Transaction<'_>, prefix: &str, blocks: &[&'a Block], skipped: &mut HashSet<(&'a str, &'a str)>) -> Result<(), ParserError> {
+    // Active blocks claim the transactions they share first
+    let mut claimed: HashMap<&'a str, (&'a Block, &'a models::Transaction)> = HashMap::new();
+    let mut by_activity = blocks.to_vec();
+    by_activity.sort_by_key(|block| !block.active);
+    for block in by_activity {
+        for tx in &block.transactions {
+            if BIP30_DUPLICATES.iter().any(|(txid, _, _)| *txid == tx.txid) || skipped.contains(&(block.block_hash.as_str(), tx.txid.as_str())) {
+                continue;
+            }
+            if claimed.contains_key(tx.txid.as_str()) {
+                skipped.insert((block.block_hash.as_str(), tx.txid.as_str()));
+            } else {
+                claimed.insert(tx.txid.as_str(), (block, tx));
+            }
         }
+    }
 
-        for output in &tx.outputs {
-            let output_line = format!("{},{},{},{}\n", tx.txid, output.output_index, output.value as f64, output.script_pub_key);
-            output_lines.push(output_line);
+    let primary_key = prefixed("transactions_pkey", prefix).into_owned();
+    if transaction.query_opt("SELECT 1 FROM pg_constraint WHERE conname = $1", &[&primary_key]).await?.is_none() {
+        return Ok(());
+    }
+    let txids: Vec<&str> = claimed.keys().copied().collect();
+    let stored = transaction.query(
+        &*prefixed("SELECT transactions.txid, blocks.active FROM transactions
+         LEFT JOIN blocks ON blocks.block_hash = transactions.block_hash
+         WHERE transactions.txid = ANY($1)", prefix),
+        &[&txids],
+    ).await?;
+    for row in stored {
+        let Some((&txid, &(block, tx))) = claimed.get_key_value(row.get::<_, &str>(0)) else {
+            continue;
+        };
+        skipped.insert((block.block_hash.as_str(), txid));
+        let stored_active: Option<bool> = row.get(1);
+        if block.active && stored_active != Some(true) {
+            transaction.execute(
+                &*prefixed("UPDATE transactions SET block_hash = $2, position = $3 WHERE txid = $1", prefix),
+                &[&txid, &block.block_hash, &tx.position],
+            ).await?;
         }
     }
 
+    Ok(())
+}
+
+/// Inserts a batch of blocks in a single database transaction. Blocks that are already in the
+/// database, e.g. from overlapping blk files after a reindex, are skipped along with their
+/// transactions, inputs and outputs.
+pub async fn insert_blocks<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, blocks: &[Block], with_asm: bool, address_stats: bool, copy_flush_bytes: usize, metrics: &Metrics) -> Result<(), ParserError> {
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut conn = pool.get().await?;
+    let transaction = conn.build_transaction().start().await?;
+
+    let existing = existing_blocks(&transaction, prefix, blocks).await?;
+    let blocks: Vec<&Block> = blocks.iter().filter(|block| !existing.contains(&block.block_hash)).collect();
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut skipped = resolve_bip30_duplicates(&transaction, prefix, &blocks).await?;
+    resolve_shared_transactions(&transaction, prefix, &blocks, &mut skipped).await?;
+    copy_blocks(&transaction, prefix, &blocks, &skipped, with_asm, copy_flush_bytes, metrics).await?;
+    let hashes: Vec<&str> = blocks.iter().map(|block| block.block_hash.as_str()).collect();
+    transaction.execute(
+        &*prefixed("INSERT INTO headers (block_hash, version, previous_block, merkle_root, time, bits, nonce, height)
+         SELECT block_hash, version, previous_block, merkle_root, time, bits, nonce, height FROM blocks WHERE block_hash = ANY($1)
+         ON CONFLICT (block_hash) DO NOTHING", prefix),
+        &[&hashes],
+    ).await?;
+    if address_stats {
+        update_address_stats(&transaction, prefix, &blocks, &skipped).await?;
+    }
+
+    transaction.commit().await?;
+
+    let inserted = blocks.iter()
+        .flat_map(|block| &block.transactions)
+        .filter(|tx| !skipped.contains(&(tx.block_hash.as_str(), tx.txid.as_str())));
+    let (inputs, outputs) = inserted.fold((0, 0), |(inputs, outputs), tx| (inputs + tx.inputs.len(), outputs + tx.outputs.len()));
+    metrics.inputs_inserted.fetch_add(inputs as u64, Ordering::Relaxed);
+    metrics.outputs_inserted.fetch_add(outputs as u64, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Inserts headers read without their blocks, for a headers-only import, in a single database
+/// transaction. Headers already stored, by a full import or an earlier run, are left as they are.
+pub async fn insert_headers<Tls: Connector>(pool: &Pool<PostgresConnectionManager<Tls>>, prefix: &str, headers: &[BlockHeader]) -> Result<(), ParserError> {
+    if headers.is_empty() {
+        return Ok(());
+    }
+
+    let hashes: Vec<&str> = headers.iter().map(|header| header.block_hash.as_str()).collect();
+    let versions: Vec<i64> = headers.iter().map(|header| header.version as i64).collect();
+    let previous_blocks: Vec<&str> = headers.iter().map(|header| header.previous_block.as_str()).collect();
+    let merkle_roots: Vec<&str> = headers.iter().map(|header| header.merkle_root.as_str()).collect();
+    let times: Vec<_> = headers.iter().map(|header| header.time).collect();
+    let bits: Vec<i64> = headers.iter().map(|header| header.bits as i64).collect();
+    let nonces: Vec<i64> = headers.iter().map(|header| header.nonce).collect();
+    let heights: Vec<i32> = headers.iter().map(|header| header.height).collect();
+
+    let conn = pool.get().await?;
+    conn.execute(
+        &*prefixed("INSERT INTO headers (block_hash, version, previous_block, merkle_root, time, bits, nonce, height)
+         SELECT * FROM UNNEST($1::VARCHAR[], $2::BIGINT[], $3::VARCHAR[], $4::VARCHAR[], $5::TIMESTAMPTZ[], $6::BIGINT[], $7::BIGINT[], $8::INT[])
+         ON CONFLICT (block_hash) DO NOTHING", prefix),
+        &[&hashes, &versions, &previous_blocks, &merkle_roots, &times, &bits, &nonces, &heights],
+    ).await?;
+    Ok(())
+}
+
+/// Adds the outputs of the active `blocks` to `address_stats`, one row per address. Outputs
+/// without an address, e.g. OP_RETURN and bare multisig, are left out, and so are stale blocks,
+/// until `update_active_flags` brings them onto the active chain.
+async fn update_address_stats(transaction: &Transaction<'_>, prefix: &str, blocks: &[&Block], skipped: &HashSet<(&str, &str)>) -> Result<(), ParserError> {
+    // Sorted by address, so that concurrent batches lock the rows they share in the same order
+    let mut stats: BTreeMap<&str, (i64, i64, i32, i32)> = BTreeMap::new();
+    for block in blocks.iter().filter(|block| block.active) {
+        for tx in block.transactions.iter().filter(|tx| !skipped.contains(&(block.block_hash.as_str(), tx.txid.as_str()))) {
+            for output in &tx.outputs {
+                let Some(address) = &output.address else {
+                    continue;
+                };
+                let (total_received, output_count, first_seen_height, last_seen_height) =
+                    stats.entry(address.as_str()).or_insert((0, 0, block.height, block.height));
+                *total_received += output.value;
+                *output_count += 1;
+                *first_seen_height = (*first_seen_height).min(block.height);
+                *last_seen_height = (*last_seen_height).max(block.height);
+            }
+        }
+    }
+    if stats.is_empty() {
+        return Ok(());
+    }
+
+    let addresses: Vec<&str> = stats.keys().copied().collect();
+    let total_received: Vec<i64> = stats.values().map(|stat| stat.0).collect();
+    let output_counts: Vec<i64> = stats.values().map(|stat| stat.1).collect();
+    let first_seen_heights: Vec<i32> = stats.values().map(|stat| stat.2).collect();
+    let last_seen_heights: Vec<i32> = stats.values().map(|stat| stat.3).collect();
+    transaction.execute(
+        &*prefixed("INSERT INTO address_stats (address, total_received, output_count, first_seen_height, last_seen_height)
+         SELECT * FROM UNNEST($1::TEXT[], $2::BIGINT[], $3::BIGINT[], $4::INT[], $5::INT[])
+         ON CONFLICT (address) DO UPDATE SET
+             total_received = address_stats.total_received + EXCLUDED.total_received,
+             output_count = address_stats.output_count + EXCLUDED.output_count,
+             first_seen_height = LEAST(address_stats.first_seen_height, EXCLUDED.first_seen_height),
+             last_seen_height = GREATEST(address_stats.last_seen_height, EXCLUDED.last_seen_height)", prefix),
+        &[&addresses, &total_received, &output_counts, &first_seen_heights, &last_seen_heights],
+    ).await?;
+    Ok(())
+}
+
+async fn existing_blocks(transaction: &Transaction<'_>, prefix: &str, blocks: &[Block]) -> Result<HashSet<String>, ParserError> {
+    let hashes: Vec<&str> = blocks.iter().map(|block| block.block_hash.as_str()).collect();
+    let rows = transaction.query(&*prefixed("SELECT block_hash FROM blocks WHERE block_hash = ANY($1)", prefix), &[&hashes]).await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// `BinaryCopyInWriter` sends its rows in chunks of its own size, so there is no flush threshold to apply.
+#[cfg(not(feature = "csv-copy"))]
+async fn copy_blocks(transaction: &Transaction<'_>, prefix: &str, blocks: &[&Block], skipped: &HashSet<(&str, &str)>, with_asm: bool, _copy_flush_bytes: usize, metrics: &Metrics) -> Result<(), ParserError> {
+    let started = Instant::now();
+    let block_writer = binary_writer(transaction, prefix, "COPY blocks (block_hash, height, time, difficulty, merkle_root, nonce, size, weight, version, bits, previous_block, active, total_supply, rbf_tx_count, coinbase_tag, blk_file, byte_offset, raw) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::TIMESTAMPTZ, Type::FLOAT8, Type::VARCHAR, Type::INT8, Type::INT4, Type::INT4, Type::INT8, Type::INT8, Type::VARCHAR, Type::BOOL, Type::INT8, Type::INT4, Type::TEXT, Type::TEXT, Type::INT8, Type::BYTEA]).await?;
+    pin_mut!(block_writer);
+    for block in blocks {
+        let (version, bits) = (block.version as i64, block.bits as i64);
+        let coinbase_tag = block.coinbase_tag();
+        block_writer.as_mut().write(&[&block.block_hash, &block.height, &block.time, &block.difficulty, &block.merkle_root, &block.nonce, &block.size, &block.weight, &version, &bits, &block.previous_block, &block.active, &block.total_supply, &block.rbf_tx_count, &coinbase_tag, &block.blk_file, &block.byte_offset, &block.raw]).await?;
+    }
+    block_writer.finish().await?;
+    metrics.record_copy("blocks", started.elapsed());
+
+    let transactions: Vec<_> = blocks.iter()
+        .flat_map(|block| &block.transactions)
+        .filter(|tx| !skipped.contains(&(tx.block_hash.as_str(), tx.txid.as_str())))
+        .collect();
+
     // Process transactions
-    let mut tx_sink: std::pin::Pin<Box<CopyInSink<bytes::Bytes>>> = Box::pin(transaction.copy_in("COPY transactions (txid, block_hash, size, version, locktime) FROM STDIN WITH DELIMITER ',' CSV").await?);
-    for line in tx_lines {
-        tx_sink.as_mut().send(line.into()).await?;
+    let started = Instant::now();
+    let tx_writer = binary_writer(transaction, prefix, "COPY transactions (txid, wtxid, block_hash, position, size, weight, vsize, version, locktime, locktime_type, locktime_time, raw) FROM STDIN BINARY", &[Type::VARCHAR, Type::VARCHAR, Type::VARCHAR, Type::INT4, Type::INT4, Type::INT4, Type::INT4, Type::INT8, Type::INT4, Type::VARCHAR, Type::TIMESTAMPTZ, Type::BYTEA]).await?;
+    pin_mut!(tx_writer);
+    for tx in &transactions {
+        let version = tx.version as i64;
+        let locktime_time = tx.locktime_time();
+        tx_writer.as_mut().write(&[&tx.txid, &tx.wtxid, &tx.block_hash, &tx.position, &tx.size, &tx.weight, &tx.vsize, &version, &tx.locktime, &tx.locktime_type(), &locktime_time, &tx.raw]).await?;
     }
-    tx_sink.as_mut().close().await?;
+    tx_writer.finish().await?;
+    metrics.record_copy("transactions", started.elapsed());
 
     // Process inputs
-    let mut input_sink: std::pin::Pin<Box<CopyInSink<bytes::Bytes>>> = Box::pin(transaction.copy_in("COPY inputs (txid, input_index, previous_txid, previous_output_index, script_sig, sequence) FROM STDIN WITH DELIMITER ',' CSV").await?);
-    for line in input_lines {
-        input_sink.as_mut().send(line.into()).await?;
+    let started = Instant::now();
+    let input_writer = binary_writer(transaction, prefix, "COPY inputs (txid, input_index, previous_txid, previous_output_index, script_sig, asm, sequence, is_coinbase, rbf_signaled, relative_locktime_blocks, relative_locktime_time, spent_value, spent_address) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::VARCHAR, Type::INT8, Type::TEXT, Type::TEXT, Type::INT8, Type::BOOL, Type::BOOL, Type::INT4, Type::INT4, Type::INT8, Type::TEXT]).await?;
+    pin_mut!(input_writer);
+    for tx in &transactions {
+        for input in &tx.inputs {
+            let asm = asm_column(&input.script_sig, with_asm);
+            let relative_locktime = input.relative_locktime(tx.version);
+            let relative_locktime_blocks = relative_locktime.and_then(RelativeLocktime::blocks);
+            let relative_locktime_time = relative_locktime.and_then(RelativeLocktime::seconds);
+            input_writer.as_mut().write(&[&tx.txid, &input.input_index, &input.previous_txid, &input.previous_output_index, &input.script_sig, &asm, &input.sequence, &input.is_coinbase, &input.rbf_signaled(), &relative_locktime_blocks, &relative_locktime_time, &input.spent_value, &input.spent_address]).await?;
+        }
     }
-    input_sink.as_mut().close().await?;
+    input_writer.finish().await?;
+    metrics.record_copy("inputs", started.elapsed());
 
     // Process outputs
-    let mut output_sink: std::pin::Pin<Box<CopyInSink<bytes::Bytes>>> = Box::pin(transaction.copy_in("COPY outputs (txid, output_index, value, script_pub_key) FROM STDIN WITH DELIMITER ',' CSV").await?);
-    for line in output_lines {
-        output_sink.as_mut().send(line.into()).await?;
+    let started = Instant::now();
+    let output_writer = binary_writer(transaction, prefix, "COPY outputs (txid, output_index, value, script_pub_key, asm, address, script_type, unspendable) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::INT8, Type::TEXT, Type::TEXT, Type::TEXT, Type::VARCHAR, Type::BOOL]).await?;
+    pin_mut!(output_writer);
+    for tx in &transactions {
+        for output in &tx.outputs {
+            let script_type = output.script_type.as_str();
+            let asm = asm_column(&output.script_pub_key, with_asm);
+            output_writer.as_mut().write(&[&tx.txid, &output.output_index, &output.value, &output.script_pub_key, &asm, &output.address, &script_type, &output.unspendable]).await?;
+        }
     }
-    output_sink.as_mut().close().await?;
+    output_writer.finish().await?;
+    metrics.record_copy("outputs", started.elapsed());
 
-    transaction.commit().await?;
+    // Process witnesses
+    let started = Instant::now();
+    let witness_writer = binary_writer(transaction, prefix, "COPY witnesses (txid, input_index, item_index, item) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::INT4, Type::BYTEA]).await?;
+    pin_mut!(witness_writer);
+    for tx in &transactions {
+        for (input_index, item_index, item) in witness_items(tx) {
+            witness_writer.as_mut().write(&[&tx.txid, &input_index, &item_index, &item]).await?;
+        }
+    }
+    witness_writer.finish().await?;
+    metrics.record_copy("witnesses", started.elapsed());
+
+    // Process OP_RETURN data
+    let started = Instant::now();
+    let op_return_writer = binary_writer(transaction, prefix, "COPY op_returns (txid, output_index, push_index, data) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::INT4, Type::BYTEA]).await?;
+    pin_mut!(op_return_writer);
+    for tx in &transactions {
+        for (output_index, push_index, data) in op_return_pushes(tx) {
+            op_return_writer.as_mut().write(&[&tx.txid, &output_index, &push_index, &data]).await?;
+        }
+    }
+    op_return_writer.finish().await?;
+    metrics.record_copy("op_returns", started.elapsed());
+
+    // Process signatures
+    let started = Instant::now();
+    let signature_writer = binary_writer(transaction, prefix, "COPY signatures (txid, input_index, pubkey, signature) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::BYTEA, Type::BYTEA]).await?;
+    pin_mut!(signature_writer);
+    for tx in &transactions {
+        for (input_index, spend) in key_spends(tx) {
+            signature_writer.as_mut().write(&[&tx.txid, &input_index, &spend.pubkey, &spend.signature]).await?;
+        }
+    }
+    signature_writer.finish().await?;
+    metrics.record_copy("signatures", started.elapsed());
+
+    // Process Taproot spends
+    let started = Instant::now();
+    let taproot_writer = binary_writer(transaction, prefix, "COPY taproot_spends (txid, input_index, spend_type, signature, leaf_version, internal_key, script, annex) FROM STDIN BINARY", &[Type::VARCHAR, Type::INT4, Type::VARCHAR, Type::BYTEA, Type::INT4, Type::BYTEA, Type::BYTEA, Type::BYTEA]).await?;
+    pin_mut!(taproot_writer);
+    for tx in &transactions {
+        for (input_index, spend) in taproot_spends(tx) {
+            let (spend_type, signature, leaf_version, internal_key, script) = taproot_columns(&spend.path);
+            taproot_writer.as_mut().write(&[&tx.txid, &input_index, &spend_type, &signature, &leaf_version, &internal_key, &script, &spend.annex]).await?;
+        }
+    }
+    taproot_writer.finish().await?;
+    metrics.record_copy("taproot_spends", started.elapsed());
+
+    // Process multisig keys
+    let started = Instant::now();
+    let multisig_writer = binary_writer(transaction, prefix, "COPY multisig (txid, source, source_index, key_index, required, total, pubkey) FROM STDIN BINARY", &[Type::VARCHAR, Type::VARCHAR, Type::INT4, Type::INT4, Type::INT4, Type::INT4, Type::BYTEA]).await?;
+    pin_mut!(multisig_writer);
+    for tx in &transactions {
+        for (source, source_index, multisig) in multisigs(tx) {
+            let (required, total) = (multisig.required as i32, multisig.pubkeys.len() as i32);
+            for (key_index, pubkey) in multisig.pubkeys.iter().enumerate() {
+                multisig_writer.as_mut().write(&[&tx.txid, &source, &source_index, &(key_index as i32), &required, &total, pubkey]).await?;
+            }
+        }
+    }
+    multisig_writer.finish().await?;
+    metrics.record_copy("multisig", started.elapsed());
 
     Ok(())
 }
+
+/// Flattens a transaction's witness stacks into (input_index, item_index, item) rows.
+/// Inputs with an empty stack, and transactions without witness data, produce no rows.
+fn witness_items(tx: &models::Transaction) -> impl Iterator<Item = (i32, i32, &Vec<u8>)> {
+    tx.witness.iter().flatten().enumerate().flat_map(|(input_index, stack)| {
+        stack.iter().enumerate().map(move |(item_index, item)| (input_index as i32, item_index as i32, item))
+    })
+}
+
+/// The assembly stored for a hex script, or NULL when it isn't requested.
+fn asm_column(script: &str, with_asm: bool) -> Option<String> {
+    with_asm.then(|| script_asm(&hex::decode(script).unwrap_or_default()))
+}
+
+/// Flattens the data pushed by a transaction's OP_RETURN outputs into (output_index, push_index, data) rows.
+fn op_return_pushes(tx: &models::Transaction) -> impl Iterator<Item = (i32, i32, Vec<u8>)> + '_ {
+    tx.outputs.iter()
+        .filter(|output| output.script_type == ScriptType::OpReturn)
+        .flat_map(|output| {
+            let script = hex::decode(&output.script_pub_key).unwrap_or_default();
+            let pushes = op_return_data(&script).unwrap_or_default();
+            pushes.into_iter().enumerate().map(move |(push_index, data)| (output.output_index, push_index as i32, data))
+        })
+}
+
+/// The signature and public key of every input that spends a P2PKH or P2WPKH output, by input index.
+fn key_spends(tx: &models::Transaction) -> impl Iterator<Item = (i32, KeySpend)> + '_ {
+    tx.inputs.iter().filter(|input| !input.is_coinbase).filter_map(|input| {
+        let script_sig = hex::decode(&input.script_sig).unwrap_or_default();
+        let stack = tx.witness.as_ref().and_then(|witness| witness.get(input.input_index as usize));
+        let spend = match stack {
+            Some(stack) if !stack.is_empty() => extract_witness_pubkey(&script_sig, stack),
+            _ => extract_scriptsig_pubkey(&script_sig),
+        };
+        spend.map(|spend| (input.input_index, spend))
+    })
+}
+
+/// The Taproot key-path and script-path spends among a transaction's inputs, by input index.
+fn taproot_spends(tx: &models::Transaction) -> impl Iterator<Item = (i32, TaprootSpend)> + '_ {
+    tx.inputs.iter().filter(|input| !input.is_coinbase).filter_map(|input| {
+        let stack = tx.witness.as_ref()?.get(input.input_index as usize)?;
+        let script_sig = hex::decode(&input.script_sig).unwrap_or_default();
+        extract_taproot_spend(&script_sig, stack).map(|spend| (input.input_index, spend))
+    })
+}
+
+/// The multisig scripts of a transaction as (source, index, multisig): the redeem scripts revealed
+/// by its P2SH inputs, as `input`, and its bare multisig outputs, as `output`.
+fn multisigs(tx: &models::Transaction) -> impl Iterator<Item = (&'static str, i32, Multisig)> + '_ {
+    let inputs = tx.inputs.iter().filter(|input| !input.is_coinbase).filter_map(|input| {
+        extract_p2sh_multisig(&hex::decode(&input.script_sig).unwrap_or_default()).map(|multisig| ("input", input.input_index, multisig))
+    });
+    let outputs = tx.outputs.iter().filter(|output| output.script_type == ScriptType::Multisig).filter_map(|output| {
+        parse_multisig(&hex::decode(&output.script_pub_key).unwrap_or_default()).map(|multisig| ("output", output.output_index, multisig))
+    });
+    inputs.chain(outputs)
+}
+
+/// The spend_type, signature, leaf_version, internal_key and script columns of a Taproot spend.
+type TaprootColumns<'a> = (&'static str, Option<&'a [u8]>, Option<i32>, Option<&'a [u8]>, Option<&'a [u8]>);
+
+/// Key-path spends only fill in the signature, script-path spends the other three.
+fn taproot_columns(path: &TaprootPath) -> TaprootColumns<'_> {
+    match path {
+        TaprootPath::Key { signature } => ("key", Some(signature), None, None, None),
+        TaprootPath::Script { leaf_version, internal_key, script } => {
+            ("script", None, Some(*leaf_version as i32), Some(internal_key), Some(script))
+        }
+    }
+}
+
+#[cfg(not(feature = "csv-copy"))]
+async fn binary_writer(transaction: &Transaction<'_>, prefix: &str, statement: &str, types: &[Type]) -> Result<BinaryCopyInWriter, tokio_postgres::Error> {
+    let sink = transaction.copy_in(&*prefixed(statement, prefix)).await?;
+    Ok(BinaryCopyInWriter::new(sink, types))
+}
+
+#[cfg(feature = "csv-copy")]
+async fn copy_blocks(transaction: &Transaction<'_>, prefix: &str, blocks: &[&Block], skipped: &HashSet<(&str, &str)>, with_asm: bool, copy_flush_bytes: usize, metrics: &Metrics) -> Result<(), ParserError> {
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY blocks (block_hash, height, time, difficulty, merkle_root, nonce, size, weight, version, bits, previous_block, active, total_supply, rbf_tx_count, coinbase_tag, blk_file, byte_offset, raw) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for block in blocks {
+        copy.send(&format!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n", csv_field(&block.block_hash), block.height, csv_timestamp(block.time), block.difficulty, csv_field(&block.merkle_root), block.nonce, block.size, block.weight, block.version, block.bits, csv_field(&block.previous_block), block.active, block.total_supply, block.rbf_tx_count, block.coinbase_tag().as_deref().map(csv_field).unwrap_or_default(), csv_field(&block.blk_file), block.byte_offset, csv_bytea(block.raw.as_deref()))).await?;
+    }
+    copy.finish(metrics).await?;
+
+    let transactions: Vec<_> = blocks.iter()
+        .flat_map(|block| &block.transactions)
+        .filter(|tx| !skipped.contains(&(tx.block_hash.as_str(), tx.txid.as_str())))
+        .collect();
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY transactions (txid, wtxid, block_hash, position, size, weight, vsize, version, locktime, locktime_type, locktime_time, raw) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let locktime_time = tx.locktime_time().map(csv_timestamp).unwrap_or_default();
+        copy.send(&format!("{},{},{},{},{},{},{},{},{},{},{},{}\n", csv_field(&tx.txid), csv_field(&tx.wtxid), csv_field(&tx.block_hash), tx.position, tx.size, tx.weight, tx.vsize, tx.version, tx.locktime, tx.locktime_type(), locktime_time, csv_bytea(tx.raw.as_deref()))).await?;
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY inputs (txid, input_index, previous_txid, previous_output_index, script_sig, asm, sequence, is_coinbase, rbf_signaled, relative_locktime_blocks, relative_locktime_time, spent_value, spent_address) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for input in &tx.inputs {
+            let asm = asm_column(&input.script_sig, with_asm).as_deref().map(csv_field).unwrap_or_default();
+            let relative_locktime = input.relative_locktime(tx.version);
+            let relative_locktime_blocks = relative_locktime.and_then(RelativeLocktime::blocks).map(|blocks| blocks.to_string()).unwrap_or_default();
+            let relative_locktime_time = relative_locktime.and_then(RelativeLocktime::seconds).map(|seconds| seconds.to_string()).unwrap_or_default();
+            let spent_value = input.spent_value.map(|value| value.to_string()).unwrap_or_default();
+            let spent_address = input.spent_address.as_deref().map(csv_field).unwrap_or_default();
+            copy.send(&format!("{},{},{},{},{},{},{},{},{},{},{},{},{}\n", txid, input.input_index, csv_field(&input.previous_txid), input.previous_output_index, csv_field(&input.script_sig), asm, input.sequence, input.is_coinbase, input.rbf_signaled(), relative_locktime_blocks, relative_locktime_time, spent_value, spent_address)).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY outputs (txid, output_index, value, script_pub_key, asm, address, script_type, unspendable) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for output in &tx.outputs {
+            let address = output.address.as_deref().map(csv_field).unwrap_or_default();
+            let asm = asm_column(&output.script_pub_key, with_asm).as_deref().map(csv_field).unwrap_or_default();
+            copy.send(&format!("{},{},{},{},{},{},{},{}\n", txid, output.output_index, output.value, csv_field(&output.script_pub_key), asm, address, csv_field(output.script_type.as_str()), output.unspendable)).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY witnesses (txid, input_index, item_index, item) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for (input_index, item_index, item) in witness_items(tx) {
+            copy.send(&format!("{},{},{},{}\n", txid, input_index, item_index, csv_bytea(Some(item)))).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY op_returns (txid, output_index, push_index, data) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for (output_index, push_index, data) in op_return_pushes(tx) {
+            copy.send(&format!("{},{},{},{}\n", txid, output_index, push_index, csv_bytea(Some(&data)))).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY signatures (txid, input_index, pubkey, signature) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for (input_index, spend) in key_spends(tx) {
+            copy.send(&format!("{},{},{},{}\n", txid, input_index, csv_bytea(Some(&spend.pubkey)), csv_bytea(Some(&spend.signature)))).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY taproot_spends (txid, input_index, spend_type, signature, leaf_version, internal_key, script, annex) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for (input_index, spend) in taproot_spends(tx) {
+            let (spend_type, signature, leaf_version, internal_key, script) = taproot_columns(&spend.path);
+            let leaf_version = leaf_version.map(|version| version.to_string()).unwrap_or_default();
+            copy.send(&format!("{},{},{},{},{},{},{},{}\n", txid, input_index, spend_type, csv_bytea(signature), leaf_version, csv_bytea(internal_key), csv_bytea(script), csv_bytea(spend.annex.as_deref()))).await?;
+        }
+    }
+    copy.finish(metrics).await?;
+
+    let mut copy = CsvCopy::start(transaction, prefix, "COPY multisig (txid, source, source_index, key_index, required, total, pubkey) FROM STDIN WITH DELIMITER ',' CSV", copy_flush_bytes).await?;
+    for tx in &transactions {
+        let txid = csv_field(&tx.txid);
+        for (source, source_index, multisig) in multisigs(tx) {
+            for (key_index, pubkey) in multisig.pubkeys.iter().enumerate() {
+                copy.send(&format!("{},{},{},{},{},{},{}\n", txid, source, source_index, key_index, multisig.required, multisig.pubkeys.len(), csv_bytea(Some(pubkey)))).await?;
+            }
+        }
+    }
+    copy.finish(metrics).await?;
+
+    Ok(())
+}
+
+/// A CSV COPY into one table. Lines are gathered in a buffer that is sent whenever it reaches
+/// `flush_bytes`, so Postgres starts on the rows while the rest are still being formatted, and a
+/// batch's lines are never all held in memory at once. Only one COPY can run on a connection at a
+/// time, so each table's COPY is finished before the next one starts, all in the batch's transaction.
+#[cfg(feature = "csv-copy")]
+struct CsvCopy {
+    sink: Pin<Box<CopyInSink<Bytes>>>,
+    buffer: BytesMut,
+    flush_bytes: usize,
+    table: String,
+    started: Instant,
+}
+
+#[cfg(feature = "csv-copy")]
+impl CsvCopy {
+    async fn start(transaction: &Transaction<'_>, prefix: &str, statement: &str, flush_bytes: usize) -> Result<Self, tokio_postgres::Error> {
+        let started = Instant::now();
+        let sink = Box::pin(transaction.copy_in(&*prefixed(statement, prefix)).await?);
+        // Every statement starts with `COPY <table>`
+        let table = statement.split_whitespace().nth(1).unwrap_or_default().to_string();
+        Ok(Self { sink, buffer: BytesMut::with_capacity(flush_bytes), flush_bytes, table, started })
+    }
+
+    async fn send(&mut self, line: &str) -> Result<(), tokio_postgres::Error> {
+        self.buffer.extend_from_slice(line.as_bytes());
+        if self.buffer.len() >= self.flush_bytes {
+            self.sink.as_mut().send(self.buffer.split().freeze()).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self, metrics: &Metrics) -> Result<(), tokio_postgres::Error> {
+        if !self.buffer.is_empty() {
+            self.sink.as_mut().send(self.buffer.split().freeze()).await?;
+        }
+        self.sink.as_mut().close().await?;
+        metrics.record_copy(&self.table, self.started.elapsed());
+        Ok(())
+    }
+}
+
+/// Quotes a text value for CSV COPY, doubling any embedded quotes.
+/// A quoted empty string stays an empty string rather than being read as NULL.
+#[cfg(feature = "csv-copy")]
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Formats a TIMESTAMPTZ value for CSV COPY as RFC 3339, with its UTC offset. Block and locktime
+/// times come from `u32` seconds since the epoch, so their years always have four digits.
+#[cfg(feature = "csv-copy")]
+fn csv_timestamp(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).expect("Times within a u32 of the epoch format as RFC 3339")
+}
+
+/// Formats a BYTEA value for CSV COPY in hex escape form, or an unquoted NULL for `None`.
+#[cfg(feature = "csv-copy")]
+fn csv_bytea(data: Option<&[u8]>) -> String {
+    data.map(|data| csv_field(&format!("\\x{}", hex::encode(data)))).unwrap_or_default()
+}
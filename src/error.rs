@@ -0,0 +1,73 @@
+use std::error::Error as _;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::network::Network;
+
+/// Upper bound Bitcoin Core places on any CompactSize-encoded length or count.
+pub const MAX_VAR_INT: u64 = 0x02000000;
+
+#[derive(Debug, Error)]
+pub enum ParserError {
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] tokio_postgres::Error),
+    #[error("Connection pool error: {0}")]
+    Pool(#[from] bb8::RunError<tokio_postgres::Error>),
+    #[error("Malformed block: {0}")]
+    MalformedBlock(String),
+    #[error("Malformed undo data: {0}")]
+    MalformedUndo(String),
+    #[error("Unexpected end of data")]
+    UnexpectedEof,
+    #[error("Block at offset {offset} in {file:?} is cut off by the end of the file")]
+    TruncatedBlock { file: PathBuf, offset: u64 },
+    #[error("Variable-length integer {0} exceeds the maximum of {MAX_VAR_INT}")]
+    VarIntOverflow(u64),
+    #[error("Variable-length integer {0} isn't in its shortest encoding")]
+    NonCanonicalVarInt(u64),
+    #[error("Block {block_hash} does not meet its target {bits:#010x}")]
+    InvalidProofOfWork { block_hash: String, bits: u32 },
+    #[error("{field} {value:?} isn't valid hex")]
+    InvalidHex { field: &'static str, value: String },
+    #[error("Preflight check failed: {0}")]
+    Preflight(String),
+    #[error("Magic mismatch at offset {offset}: expected {expected} magic {:#010x}, found {found:#010x}", .expected.magic())]
+    MagicMismatch { expected: Network, found: u32, offset: u64 },
+}
+
+impl ParserError {
+    /// Database failures abort the import; everything else only affects the block or file being parsed.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ParserError::Database(_) | ParserError::Pool(_))
+    }
+
+    /// Database failures that may go away on their own, such as a dropped connection, a server
+    /// restart or a serialization conflict. Constraint violations and other errors in the statements
+    /// themselves would fail the same way again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // Covers both timeouts waiting for a connection and failures to open one
+            ParserError::Pool(_) => true,
+            ParserError::Database(e) => match e.code() {
+                Some(state) => {
+                    let code = state.code();
+                    code.starts_with("08") || code.starts_with("57P") || matches!(code, "40001" | "40P01" | "53300")
+                }
+                None => e.is_closed() || e.source().is_some_and(|source| source.is::<io::Error>()),
+            },
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for ParserError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ParserError::UnexpectedEof,
+            _ => ParserError::Io(e),
+        }
+    }
+}
@@ -1,103 +1,653 @@
 use byteorder::{LittleEndian, ReadBytesExt};
+use futures::stream::{self, Stream};
 use hex::encode;
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{self, BufReader, Read, Seek};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Instant;
 use time::OffsetDateTime;
+use tokio::sync::mpsc;
 use tokio::task::spawn_blocking;
+use tracing::{debug, info, warn};
+
+use crate::address::script_to_address;
+use crate::block_processor::header_hash;
+use crate::chain::PENDING_HEIGHT;
+use crate::error::{ParserError, MAX_VAR_INT};
+use crate::metrics::{add_elapsed, Metrics};
+use crate::models::{Block, BlockHeader, Transaction, Input, Output, COINBASE_PREVIOUS_OUTPUT_INDEX, COINBASE_PREVIOUS_TXID};
+use crate::network::Network;
+use crate::rev_reader::{rev_path, UndoFile};
+use crate::script::{classify_script, ScriptType};
+
+const BLOCK_HEADER_SIZE: usize = 80;
+
+/// Bytes in front of every block in a blk file: the network magic and the block size.
+pub const RECORD_HEADER_SIZE: u64 = 8;
+
+/// Blocks `read_file_streaming` parses ahead of its consumer.
+const STREAM_BUFFER_BLOCKS: usize = 16;
+
+/// Endings of the file names read from a blocks directory.
+const BLOCK_FILE_SUFFIXES: &[&str] = &[
+    ".dat",
+    #[cfg(feature = "gzip")]
+    ".dat.gz",
+    #[cfg(feature = "xz")]
+    ".dat.xz",
+];
+
+/// Most items reserved up front for a list whose length comes from a varint. Longer lists grow as
+/// they are read, so a corrupt count fails at the end of the data instead of allocating for it.
+const MAX_PREALLOCATION: u64 = 4096;
+
+/// Bounds on what a single block may contain before it is rejected as malformed. The defaults are
+/// the most any consensus-valid block can hold: no script is longer than the 1 MB of non-witness
+/// data a block allows, and the counts follow from the smallest possible transaction (60 bytes),
+/// input (41 bytes) and output (9 bytes).
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    pub max_script_size: usize,
+    pub max_transactions: u64,
+    pub max_inputs: u64,
+    pub max_outputs: u64,
+}
 
-use crate::models::{Block, Transaction, Input, Output};
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_script_size: 1_000_000,
+            max_transactions: 1_000_000 / 60,
+            max_inputs: 1_000_000 / 41,
+            max_outputs: 1_000_000 / 9,
+        }
+    }
+}
 
 pub struct FileReader {
     path: PathBuf,
-    verbose: bool,
+    network: Network,
+    metrics: Arc<Metrics>,
+    limits: ParseLimits,
+    store_raw: bool,
+    quarantine: Option<PathBuf>,
+    undo_data: bool,
     pub file_paths: Vec<PathBuf>,
 }
 
 impl FileReader {
-    pub fn new(path: PathBuf, verbose: bool) -> Self {
-        let mut file_reader = Self { path, verbose, file_paths: Vec::new() };
+    pub fn new(path: PathBuf, network: Network, metrics: Arc<Metrics>) -> Self {
+        let mut file_reader = Self { path, metrics, ..Self::in_memory(network) };
         file_reader.index_files().expect("Failed to index files");
         file_reader
     }
 
+    /// A reader with no files behind it, for parsing blocks that are already in memory with
+    /// `read_block_from_bytes`.
+    pub fn in_memory(network: Network) -> Self {
+        Self {
+            path: PathBuf::new(),
+            network,
+            metrics: Arc::default(),
+            limits: ParseLimits::default(),
+            store_raw: false,
+            quarantine: None,
+            undo_data: false,
+            file_paths: Vec::new(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keeps the serialized bytes of every block and transaction in their `raw` fields.
+    pub fn with_raw_bytes(mut self, store_raw: bool) -> Self {
+        self.store_raw = store_raw;
+        self
+    }
+
+    /// Writes the record of every block that fails to parse into `dir`, see `quarantine_block`.
+    pub fn with_quarantine(mut self, dir: Option<PathBuf>) -> Self {
+        self.quarantine = dir;
+        self
+    }
+
+    /// Fills the spent values and addresses of inputs from the rev file next to each blk file,
+    /// see `UndoFile::apply`. Not used by `read_blocks_at`.
+    pub fn with_undo_data(mut self, undo_data: bool) -> Self {
+        self.undo_data = undo_data;
+        self
+    }
+
+    /// The blocks directory, or the single file, the reader was created with.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn is_single_file(&self) -> bool {
+        self.path.is_file()
+    }
+
+    /// Lists the `blk*.dat` files under `path` in name order, or just `path` itself when it
+    /// points at a single file. Compressed `blk*.dat.gz` and `blk*.dat.xz` files are included
+    /// when the `gzip` and `xz` features are enabled.
     fn index_files(&mut self) -> io::Result<()> {
+        self.file_paths = self.list_files()?;
+        Ok(())
+    }
+
+    /// Lists the block files under `path` again, the way `new` does, to find files that were
+    /// added since. `file_paths` is left as it was.
+    pub fn list_files(&self) -> io::Result<Vec<PathBuf>> {
+        if self.path.is_file() {
+            return Ok(vec![self.path.clone()]);
+        }
+
         let mut paths: Vec<_> = std::fs::read_dir(&self.path)?.collect::<Result<Vec<_>, io::Error>>()?;
-        paths.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        paths.sort_by_key(|entry| entry.file_name());
 
-        self.file_paths = paths.into_iter()
+        Ok(paths.into_iter()
             .filter(|entry| {
-                let path = entry.path();
-                path.file_name().unwrap_or_default().to_str().unwrap().starts_with("blk")
-                    && path.extension().unwrap_or_default() == "dat"
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("blk") && BLOCK_FILE_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
             })
             .map(|entry| entry.path())
-            .collect();
+            .collect())
+    }
 
-        Ok(())
+    /// The SHA256 of a file's raw bytes as hex, compressed files included as they are on disk.
+    pub fn file_checksum(&self, file_index: usize) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(&self.file_paths[file_index])?, &mut hasher)?;
+        Ok(encode(hasher.finalize()))
+    }
+
+    /// Reads and parses every block of a file. This is blocking work, so async callers should run it
+    /// on the blocking pool.
+    pub fn read_file(&self, file_index: usize) -> Result<Vec<Block>, ParserError> {
+        let mut blocks = Vec::new();
+        self.for_each_block(file_index, |block| {
+            blocks.push(block);
+            ControlFlow::Continue(())
+        })?;
+        Ok(blocks)
     }
 
-    pub async fn read_file(&self, file_index: usize) -> io::Result<Vec<Block>> {
+    /// Reads the blocks of an uncompressed blk file from the record starting at `offset`, e.g. the
+    /// ones a node appended since the file was last read. Returns them along with the offset just
+    /// past the last complete record, where the next read should resume: in front of any trailing
+    /// padding, and of a block the node is still writing. A block that fails to parse may be one
+    /// whose body the node hasn't flushed yet, so it is only skipped once a block after it parses;
+    /// until then the next read resumes in front of it. Blocking, like `read_file`.
+    pub fn read_file_from(&self, path: &Path, offset: u64) -> Result<(Vec<Block>, u64), ParserError> {
+        let start_time = Instant::now();
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut undo = self.read_undo(path);
+        let mut blocks = Vec::new();
+        let end = self.read_blocks(&mut BufReader::new(file), path, true, &mut |mut block| {
+            if let Some(undo) = &mut undo {
+                undo.apply(&mut block);
+            }
+            blocks.push(block);
+            ControlFlow::Continue(())
+        })?;
+        add_elapsed(&self.metrics.read_micros, start_time.elapsed());
+        Ok((blocks, end))
+    }
+
+    /// Reads the header of every block in a file, seeking past the transactions, so that blocks
+    /// can be ordered before any of them is parsed. Only uncompressed files can be read this way,
+    /// since `read_blocks_at` has to seek back to each block. Blocking, like `read_file`.
+    pub fn read_headers(&self, file_index: usize) -> Result<Vec<BlockHeader>, ParserError> {
+        let path = self.file_paths.get(file_index)
+            .ok_or_else(|| ParserError::Io(io::Error::new(io::ErrorKind::InvalidInput, "File index out of bounds")))?;
+        if path.extension().is_none_or(|extension| extension != "dat") {
+            return Err(ParserError::Io(io::Error::new(io::ErrorKind::Unsupported, format!("Can't seek in compressed file {}", path.display()))));
+        }
+
+        let start_time = Instant::now();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut headers = Vec::new();
+        loop {
+            match self.skip_padding(&mut reader) {
+                Ok(_) => {}
+                Err(ParserError::UnexpectedEof) => break,
+                Err(e) => return Err(e),
+            }
+
+            let byte_offset = reader.stream_position()?;
+            let mut record = [0u8; RECORD_HEADER_SIZE as usize + BLOCK_HEADER_SIZE];
+            match reader.read_exact(&mut record) {
+                Ok(()) => {}
+                // A block the node was still writing, which `read_file` skips as well
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let size = u32::from_le_bytes(record[4..8].try_into().unwrap());
+            if !(BLOCK_HEADER_SIZE as u32..=self.network.max_block_size()).contains(&size) {
+                // Like `read_file`, carry on from the next block magic
+                warn!(path = %path.display(), offset = byte_offset, size, "Skipping block with a size out of range");
+                self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                match self.find_magic(&mut reader, byte_offset + 4) {
+                    Ok(()) => continue,
+                    Err(ParserError::UnexpectedEof) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let header = &record[RECORD_HEADER_SIZE as usize..];
+            let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+            headers.push(BlockHeader {
+                block_hash: header_hash(header),
+                version: read_u32(0),
+                previous_block: encode(header[4..36].iter().rev().cloned().collect::<Vec<u8>>()),
+                merkle_root: encode(header[36..68].iter().rev().cloned().collect::<Vec<u8>>()),
+                time: OffsetDateTime::from_unix_timestamp(read_u32(68) as i64).map_err(|e| ParserError::MalformedBlock(e.to_string()))?,
+                bits: read_u32(72),
+                nonce: read_u32(76) as i64,
+                height: PENDING_HEIGHT,
+                file_index,
+                byte_offset,
+            });
+            reader.seek_relative(size as i64 - BLOCK_HEADER_SIZE as i64)?;
+        }
+
+        add_elapsed(&self.metrics.read_micros, start_time.elapsed());
+        self.metrics.files_read.fetch_add(1, Ordering::Relaxed);
+        Ok(headers)
+    }
+
+    /// Reads and parses the blocks at the given file indexes and record offsets, as found by
+    /// `read_headers`, in the order given. Malformed blocks are logged and left out, like
+    /// `read_file` does. Blocking, like `read_file`.
+    pub fn read_blocks_at(&self, locations: &[(usize, u64)]) -> Result<Vec<Block>, ParserError> {
+        let start_time = Instant::now();
+        let mut open: Option<(usize, BufReader<File>)> = None;
+        let mut blocks = Vec::with_capacity(locations.len());
+        for &(file_index, byte_offset) in locations {
+            let path = self.file_paths.get(file_index)
+                .ok_or_else(|| ParserError::Io(io::Error::new(io::ErrorKind::InvalidInput, "File index out of bounds")))?;
+            let reader = match &mut open {
+                Some((open_index, reader)) if *open_index == file_index => reader,
+                _ => &mut open.insert((file_index, BufReader::new(File::open(path)?))).1,
+            };
+
+            reader.seek(SeekFrom::Start(byte_offset))?;
+            let result = self.read_record_size(reader, byte_offset).and_then(|size| self.read_block(reader, path, byte_offset, size));
+            match result {
+                Ok(mut block) => {
+                    self.metrics.bytes_read.fetch_add(RECORD_HEADER_SIZE as usize + block.size as usize, Ordering::Relaxed);
+                    block.blk_file = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    block.byte_offset = byte_offset as i64;
+                    blocks.push(block);
+                }
+                Err(e) => {
+                    warn!(path = %path.display(), offset = byte_offset, error = %e, "Skipping malformed block");
+                    self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        add_elapsed(&self.metrics.read_micros, start_time.elapsed());
+        Ok(blocks)
+    }
+
+    /// Reads a file on the blocking pool and yields its blocks as they are parsed, so that only a
+    /// few of them are held in memory at a time. A failure to read the file ends the stream with
+    /// its error; dropping the stream stops the read. Must be called within a tokio runtime.
+    pub fn read_file_streaming(self: &Arc<Self>, file_index: usize) -> impl Stream<Item = Result<Block, ParserError>> {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_BLOCKS);
+        let file_reader = Arc::clone(self);
+        spawn_blocking(move || {
+            let result = file_reader.for_each_block(file_index, |block| match sender.blocking_send(Ok(block)) {
+                Ok(()) => ControlFlow::Continue(()),
+                Err(_) => ControlFlow::Break(()),
+            });
+            if let Err(e) = result {
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+
+        stream::unfold(receiver, |mut receiver| async move { receiver.recv().await.map(|item| (item, receiver)) })
+    }
+
+    /// Reads the first block of a file that parses, skipping padding and malformed blocks in
+    /// front of it, or `None` if there is none. Unlike `read_file`, undo data isn't applied and
+    /// the file isn't counted as read. Blocking, like `read_file`.
+    pub fn read_first_block(&self, file_index: usize) -> Result<Option<Block>, ParserError> {
+        let path = self.file_paths.get(file_index)
+            .ok_or_else(|| ParserError::Io(io::Error::new(io::ErrorKind::InvalidInput, "File index out of bounds")))?;
+        let mut first = None;
+        self.process_file(path, &mut |block| {
+            first = Some(block);
+            ControlFlow::Break(())
+        })?;
+        Ok(first)
+    }
+
+    /// Reads a file and passes each block to `f` as soon as it is parsed, stopping early if `f`
+    /// breaks. Blocking, like `read_file`.
+    pub fn for_each_block<F>(&self, file_index: usize, mut f: F) -> Result<(), ParserError>
+    where
+        F: FnMut(Block) -> ControlFlow<()>,
+    {
         if file_index >= self.file_paths.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "File index out of bounds"));
+            return Err(ParserError::Io(io::Error::new(io::ErrorKind::InvalidInput, "File index out of bounds")));
         }
 
         let path = &self.file_paths[file_index];
-        if self.verbose {
-            println!("Processing file: {:?}", path);
-        }
+        debug!(path = %path.display(), "Reading file");
 
         let start_time = Instant::now();
-        let file_blocks = self.process_file(path).await?;
+        let mut undo = self.read_undo(path);
+        let mut block_count = 0;
+        let mut undo_blocks = 0;
+        self.process_file(path, &mut |mut block| {
+            block_count += 1;
+            if undo.as_mut().is_some_and(|undo| undo.apply(&mut block)) {
+                undo_blocks += 1;
+            }
+            f(block)
+        })?;
         let read_time = start_time.elapsed();
+        add_elapsed(&self.metrics.read_micros, read_time);
+        self.metrics.files_read.fetch_add(1, Ordering::Relaxed);
 
-        if self.verbose {
-            println!("Time taken to read file {:?}: {:?}", path, read_time);
-        }
+        debug!(blocks = block_count, undo_blocks, ?read_time, "Read file");
+        Ok(())
+    }
 
-        Ok(file_blocks)
+    /// Reads the rev file belonging to the blk file at `path`, when undo data is used. A missing
+    /// or unreadable rev file only means inputs are left for the database to resolve.
+    fn read_undo(&self, path: &Path) -> Option<UndoFile> {
+        if !self.undo_data {
+            return None;
+        }
+        let Some(rev_path) = rev_path(path).filter(|rev_path| rev_path.is_file()) else {
+            debug!(path = %path.display(), "No rev file to read undo data from");
+            return None;
+        };
+        match UndoFile::read(&rev_path, self.network) {
+            Ok(undo) => {
+                debug!(path = %rev_path.display(), records = undo.len(), "Read undo data");
+                Some(undo)
+            }
+            Err(e) => {
+                warn!(path = %rev_path.display(), error = %e, "Failed to read undo data");
+                None
+            }
+        }
     }
 
-    async fn process_file(&self, path: &Path) -> io::Result<Vec<Block>> {
+    fn process_file<F: FnMut(Block) -> ControlFlow<()>>(&self, path: &Path, f: &mut F) -> Result<(), ParserError> {
         let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
 
-        let mut blocks = Vec::new();
-        let mut block_tasks = Vec::new();
+        // Decoders can't seek, so compressed files are decompressed into memory first
+        #[cfg(feature = "gzip")]
+        if path.extension().is_some_and(|extension| extension == "gz") {
+            return self.read_compressed(flate2::read::GzDecoder::new(file), path, f);
+        }
+        #[cfg(feature = "xz")]
+        if path.extension().is_some_and(|extension| extension == "xz") {
+            return self.read_compressed(xz2::read::XzDecoder::new(file), path, f);
+        }
 
-        while let Ok(block) = self.read_block(&mut reader) {
-            let block_task = spawn_blocking(move || block);
-            block_tasks.push(block_task);
+        #[cfg(feature = "mmap")]
+        {
+            // SAFETY: the map is only read while this function runs. If another process truncates the
+            // file meanwhile, reads past the new end fault; blk files are only ever appended to.
+            match unsafe { memmap2::Mmap::map(&file) } {
+                Ok(map) => return self.read_blocks(&mut Cursor::new(&map[..]), path, false, f).map(|_| ()),
+                Err(e) => warn!(error = %e, "Failed to map file, reading it through a buffer instead"),
+            }
         }
 
-        for task in block_tasks {
-            match task.await {
-                Ok(block) => blocks.push(block),
-                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        self.read_blocks(&mut BufReader::new(file), path, false, f).map(|_| ())
+    }
+
+    #[cfg(any(feature = "gzip", feature = "xz"))]
+    fn read_compressed<R: Read, F: FnMut(Block) -> ControlFlow<()>>(&self, mut decoder: R, path: &Path, f: &mut F) -> Result<(), ParserError> {
+        let mut bytes = Vec::new();
+        decoder.read_to_end(&mut bytes)?;
+        self.read_blocks(&mut Cursor::new(&bytes[..]), path, false, f).map(|_| ())
+    }
+
+    /// Reads records from the reader's position on, and returns the offset just past the last one
+    /// that was read in full.
+    fn read_blocks<R: Read + Seek, F: FnMut(Block) -> ControlFlow<()>>(&self, reader: &mut R, path: &Path, live: bool, f: &mut F) -> Result<u64, ParserError> {
+        let start = reader.stream_position()?;
+        let mut end = start;
+        let mut padding_bytes = 0;
+        // In a file the node is still writing, the blocks that failed to parse since the last one that did
+        let mut unflushed: Vec<(u64, ParserError)> = Vec::new();
+
+        loop {
+            let position = reader.stream_position()?;
+            match self.skip_padding(reader) {
+                Ok(skipped) => padding_bytes += skipped,
+                Err(ParserError::UnexpectedEof) => {
+                    // Trailing padding runs all the way to the end of the file
+                    padding_bytes += reader.stream_position()? - position;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+
+            let block_start = reader.stream_position()?;
+            let size = match self.read_record_size(reader, block_start) {
+                Ok(size) => size,
+                Err(ParserError::UnexpectedEof) => {
+                    // The file ends inside the magic and size, which leaves nothing to skip
+                    let e = ParserError::TruncatedBlock { file: path.to_path_buf(), offset: block_start };
+                    warn!(error = %e, "Skipping truncated block");
+                    self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(e @ ParserError::MalformedBlock(_)) => {
+                    warn!(offset = block_start, error = %e, "Skipping malformed block");
+                    self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                    // A size out of range can't be trusted to skip the record, so carry on from the next magic
+                    match self.find_magic(reader, block_start + 4) {
+                        Ok(()) => {}
+                        Err(ParserError::UnexpectedEof) => {
+                            // Nothing that looks like a block follows
+                            end = reader.stream_position()?;
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    end = reader.stream_position()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let record_end = block_start + RECORD_HEADER_SIZE + size as u64;
+            match self.read_block(reader, path, block_start, size) {
+                Ok(mut block) => {
+                    for (offset, e) in unflushed.drain(..) {
+                        warn!(offset, error = %e, "Skipping malformed block");
+                        self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    end = record_end;
+                    block.blk_file = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    block.byte_offset = block_start as i64;
+                    if f(block).is_break() {
+                        break;
+                    }
+                }
+                Err(ParserError::UnexpectedEof) => {
+                    // The magic was found, so a block starts here but the file ends before it does, as
+                    // happens with the last file of a node that was still writing it
+                    let e = ParserError::TruncatedBlock { file: path.to_path_buf(), offset: block_start };
+                    warn!(error = %e, "Skipping truncated block");
+                    self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(e) => {
+                    // Whatever made parsing give up, the size says where the next record starts
+                    reader.seek(SeekFrom::Start(record_end))?;
+                    if !live {
+                        warn!(offset = block_start, error = %e, "Skipping malformed block");
+                        self.metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                        end = record_end;
+                    } else {
+                        unflushed.push((block_start, e));
+                    }
+                }
             }
         }
+        if let Some((offset, e)) = unflushed.first() {
+            debug!(offset, error = %e, "Block doesn't parse yet, reading it again next time");
+        }
 
-        Ok(blocks)
+        self.metrics.bytes_read.fetch_add((reader.stream_position()? - start) as usize, Ordering::Relaxed);
+
+        if padding_bytes > 0 {
+            debug!(padding_bytes, "Skipped padding");
+        }
+
+        Ok(end)
+    }
+
+    /// Positions the reader at the next block magic, skipping any zero padding in front of it.
+    /// Returns the number of padding bytes skipped.
+    fn skip_padding<R: Read + Seek>(&self, reader: &mut R) -> Result<u64, ParserError> {
+        let offset = reader.stream_position()?;
+        let magic = reader.read_u32::<LittleEndian>()?;
+        if magic != 0 {
+            reader.seek(SeekFrom::Current(-4))?;
+            self.check_magic(magic, offset)?;
+            return Ok(0);
+        }
+
+        let expected = self.network.magic().to_le_bytes();
+        let mut window = [0u8; 4];
+        let mut skipped = 0;
+        while window != expected {
+            window.rotate_left(1);
+            window[3] = reader.read_u8()?;
+            skipped += 1;
+        }
+        reader.seek(SeekFrom::Current(-4))?;
+
+        Ok(skipped)
+    }
+
+    /// Positions the reader at the first block magic from `offset` on, whatever lies in front of it.
+    fn find_magic<R: Read + Seek>(&self, reader: &mut R, offset: u64) -> Result<(), ParserError> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let expected = self.network.magic().to_le_bytes();
+        let mut window = [0u8; 4];
+        reader.read_exact(&mut window)?;
+        while window != expected {
+            window.rotate_left(1);
+            window[3] = reader.read_u8()?;
+        }
+        reader.seek(SeekFrom::Current(-4))?;
+        Ok(())
+    }
+
+    /// Reads the magic and size of the record at the reader's position, which starts at `offset`,
+    /// leaving the reader at the block itself.
+    fn read_record_size<R: Read>(&self, reader: &mut R, offset: u64) -> Result<u32, ParserError> {
+        let magic = reader.read_u32::<LittleEndian>()?;
+        self.check_magic(magic, offset)?;
+        let size = reader.read_u32::<LittleEndian>()?;
+        if size > self.network.max_block_size() {
+            return Err(ParserError::MalformedBlock(format!("Block size {} exceeds maximum", size)));
+        }
+        Ok(size)
     }
 
-    fn read_block<R: Read + Seek + Send + 'static>(&self, reader: &mut R) -> io::Result<Block> {
-        let _magic = reader.read_u32::<LittleEndian>()?;
-        let _size = reader.read_u32::<LittleEndian>()?;
+    /// Reads the `size` bytes of the block at the reader's position, whose record starts at `offset`
+    /// in the file at `path`.
+    fn read_block<R: Read>(&self, reader: &mut R, path: &Path, offset: u64, size: u32) -> Result<Block, ParserError> {
+        let mut body = vec![0; size as usize];
+        reader.read_exact(&mut body)?;
+
+        self.read_block_from_bytes(&body).inspect_err(|e| {
+            if let Some(dir) = &self.quarantine {
+                if let Err(write_error) = self.quarantine_block(dir, path, offset, &body, e) {
+                    warn!(dir = %dir.display(), error = %write_error, "Failed to quarantine block");
+                }
+            }
+        })
+    }
 
-        let version = reader.read_i32::<LittleEndian>()?;
+    /// Writes a block that failed to parse to `<dir>/<file name>.<offset>.bin`, as the blk file
+    /// record it was read from, so that pointing `BLOCKS_PATH` at it reproduces the failure. Next to
+    /// it, `<file name>.<offset>.txt` names the file, the offset and the error.
+    fn quarantine_block(&self, dir: &Path, path: &Path, offset: u64, body: &[u8], error: &ParserError) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = format!("{}.{}", file_name, offset);
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + body.len());
+        record.extend_from_slice(&self.network.magic().to_le_bytes());
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(body);
+        std::fs::write(dir.join(format!("{}.bin", name)), record)?;
+        std::fs::write(
+            dir.join(format!("{}.txt", name)),
+            format!("file: {}\noffset: {}\nerror: {}\nvariant: {:?}\n", path.display(), offset, error, error),
+        )?;
+
+        info!(file = %file_name, offset, dir = %dir.display(), "Quarantined block that failed to parse");
+        Ok(())
+    }
+
+    /// Parses a serialized block, i.e. a blk file record without its magic and size prefix.
+    pub fn read_block_from_bytes(&self, bytes: &[u8]) -> Result<Block, ParserError> {
+        let mut block = self.parse_block(bytes).map_err(|e| match e {
+            ParserError::UnexpectedEof => ParserError::MalformedBlock("Block data is shorter than its declared size".to_string()),
+            _ => e,
+        })?;
+        block.size = bytes.len() as i32;
+        block.raw = self.store_raw.then(|| bytes.to_vec());
+        Ok(block)
+    }
+
+    fn parse_block(&self, bytes: &[u8]) -> Result<Block, ParserError> {
+        let reader = &mut Cursor::new(bytes);
+        let version = reader.read_u32::<LittleEndian>()?;
         let previous_block = self.read_hash(reader)?;
         let merkle_root = self.read_hash(reader)?;
         let time = OffsetDateTime::from_unix_timestamp(reader.read_u32::<LittleEndian>()? as i64)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let bits = format!("{:x}", reader.read_u32::<LittleEndian>()?);
+            .map_err(|e| ParserError::MalformedBlock(e.to_string()))?;
+        let bits = reader.read_u32::<LittleEndian>()?;
         let nonce = reader.read_u32::<LittleEndian>()? as i64;
 
         let tx_count = self.read_var_int(reader)?;
-        let mut transactions = Vec::with_capacity(tx_count.min(1_000_000) as usize);
+        // Every block has a coinbase; zeros the node hasn't overwritten yet read as a block without one
+        if tx_count == 0 {
+            return Err(ParserError::MalformedBlock("Block has no transactions".to_string()));
+        }
+        if tx_count > self.limits.max_transactions {
+            return Err(ParserError::MalformedBlock(format!("Transaction count {} too large", tx_count)));
+        }
+
+        let mut transactions = Vec::with_capacity(tx_count.min(MAX_PREALLOCATION) as usize);
 
         for _ in 0..tx_count {
-            let tx = self.read_transaction(reader)?;
+            let start = reader.position() as usize;
+            let mut tx = self.read_transaction(reader)?;
+            if self.store_raw {
+                tx.raw = Some(bytes[start..reader.position() as usize].to_vec());
+            }
             transactions.push(tx);
         }
 
@@ -108,46 +658,59 @@ impl FileReader {
             difficulty: 0.0,
             merkle_root,
             nonce,
-            size: 0,
+            size: 0, // Set from the record size once parsed
+            weight: 0,
             version,
             bits,
             previous_block,
             active: true,
+            total_supply: 0, // Summed up once the block is linked to the chain
+            rbf_tx_count: 0, // Counted when the block is processed
+            blk_file: String::new(), // Set once the block is read from a file
+            byte_offset: 0,
+            raw: None, // Kept from the record's bytes when storing raw bytes
             transactions,
         })
     }
 
-    fn read_transaction<R: Read + Seek + Send + 'static>(&self, reader: &mut R) -> io::Result<Transaction> {
-        let version = reader.read_i32::<LittleEndian>()?;
+    /// Checks the magic read at `offset` in the file.
+    fn check_magic(&self, magic: u32, offset: u64) -> Result<(), ParserError> {
+        if magic == self.network.magic() {
+            Ok(())
+        } else {
+            Err(ParserError::MagicMismatch { expected: self.network, found: magic, offset })
+        }
+    }
+
+    fn read_transaction<R: Read + Seek>(&self, reader: &mut R) -> Result<Transaction, ParserError> {
+        let start = reader.stream_position()?;
+        let version = reader.read_u32::<LittleEndian>()?;
 
         let mut inputs = Vec::new();
         let mut outputs = Vec::new();
         let mut witness_data = None;
-        let mut segwit = false;
+        let segwit = self.read_segwit_marker(reader)?;
 
-        let marker = reader.read_u8()?;
-        if marker == 0 {
-            let flag = reader.read_u8()?;
-            if flag != 1 {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SegWit flag"));
-            }
-            segwit = true;
-        } else {
-            reader.seek(io::SeekFrom::Current(-1))?;
+        let input_count = self.read_var_int(reader)?;
+        if input_count > self.limits.max_inputs {
+            return Err(ParserError::MalformedBlock(format!("Input count {} too large", input_count)));
         }
 
-        let input_count = self.read_var_int(reader)?;
         for i in 0..input_count {
             inputs.push(self.read_input(reader, i as i32)?);
         }
 
         let output_count = self.read_var_int(reader)?;
+        if output_count > self.limits.max_outputs {
+            return Err(ParserError::MalformedBlock(format!("Output count {} too large", output_count)));
+        }
+
         for i in 0..output_count {
             outputs.push(self.read_output(reader, i as i32)?);
         }
 
         if segwit {
-            let mut witnesses = Vec::with_capacity(input_count as usize);
+            let mut witnesses = Vec::with_capacity(input_count.min(MAX_PREALLOCATION) as usize);
             for _ in 0..input_count {
                 witnesses.push(self.read_witness_data(reader)?);
             }
@@ -155,45 +718,65 @@ impl FileReader {
         }
 
         let locktime = reader.read_u32::<LittleEndian>()?;
+        let size = reader.stream_position()? - start;
 
         Ok(Transaction {
             txid: String::new(), // Placeholder, to be calculated later
+            wtxid: String::new(),
             block_hash: String::new(),
-            size: 0, // Placeholder, to be recalculated later
+            position: 0, // Set once the block is processed
+            size: size as i32, // Bytes parsed, checked against the serialized size later
+            weight: 0,
+            vsize: 0,
             version,
             locktime: locktime as i32,
             inputs,
             outputs,
             witness: witness_data,
+            raw: None,
         })
     }
 
-    fn read_witness_data<R: Read + Seek + Send + 'static>(&self, reader: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    /// Consumes the SegWit marker and flag if the transaction has them. A legacy transaction with no
+    /// inputs also starts with a 0x00 input count, so the bytes only count as a marker when the flag
+    /// is 0x01 and a nonzero input count follows; otherwise the reader is left where it started.
+    /// A zero-input legacy transaction with exactly one output still reads as SegWit, as in Core,
+    /// but such transactions are invalid anyway.
+    fn read_segwit_marker<R: Read + Seek>(&self, reader: &mut R) -> Result<bool, ParserError> {
+        let start = reader.stream_position()?;
+        if reader.read_u8()? == 0 && reader.read_u8()? == 1 && self.read_var_int(reader)? != 0 {
+            reader.seek(SeekFrom::Start(start + 2))?;
+            return Ok(true);
+        }
+
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(false)
+    }
+
+    fn read_witness_data<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<Vec<u8>>, ParserError> {
         let witness_count = self.read_var_int(reader)?;
-        let mut witness_fields = Vec::with_capacity(witness_count as usize);
+        let mut witness_fields = Vec::with_capacity(witness_count.min(MAX_PREALLOCATION) as usize);
 
         for _ in 0..witness_count {
             let length = self.read_var_int(reader)? as usize;
-            let mut field = vec![0; length];
-            reader.read_exact(&mut field)?;
-            witness_fields.push(field);
+            witness_fields.push(self.read_bytes(reader, length)?);
         }
 
         Ok(witness_fields)
     }
 
-    fn read_input<R: Read + Seek + Send + 'static>(&self, reader: &mut R, index: i32) -> io::Result<Input> {
+    fn read_input<R: Read + Seek>(&self, reader: &mut R, index: i32) -> Result<Input, ParserError> {
         let previous_txid = self.read_hash(reader)?;
-        let previous_output_index = reader.read_i32::<LittleEndian>()?;
+        let previous_output_index = reader.read_u32::<LittleEndian>()? as i64;
         let script_sig_length = self.read_var_int(reader)? as usize;
 
-        if script_sig_length > 1_000_000 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "scriptSig length too large"));
+        if script_sig_length > self.limits.max_script_size {
+            return Err(ParserError::MalformedBlock(format!("scriptSig length {} too large", script_sig_length)));
         }
 
-        let mut script_sig = vec![0; script_sig_length];
-        reader.read_exact(&mut script_sig)?;
+        let script_sig = self.read_bytes(reader, script_sig_length)?;
         let sequence = reader.read_u32::<LittleEndian>()? as i64;
+        let is_coinbase = previous_txid == COINBASE_PREVIOUS_TXID && previous_output_index == COINBASE_PREVIOUS_OUTPUT_INDEX;
 
         Ok(Input {
             input_index: index,
@@ -201,40 +784,70 @@ impl FileReader {
             previous_output_index,
             script_sig: encode(script_sig),
             sequence,
+            is_coinbase,
+            spent_value: None, // Filled from undo data, or by the database
+            spent_address: None,
         })
     }
 
-    fn read_output<R: Read + Seek + Send + 'static>(&self, reader: &mut R, index: i32) -> io::Result<Output> {
+    fn read_output<R: Read + Seek>(&self, reader: &mut R, index: i32) -> Result<Output, ParserError> {
         let value = reader.read_i64::<LittleEndian>()?;
         let script_pub_key_length = self.read_var_int(reader)? as usize;
 
-        if script_pub_key_length > 1_000_000 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "scriptPubKey length too large"));
+        if script_pub_key_length > self.limits.max_script_size {
+            return Err(ParserError::MalformedBlock(format!("scriptPubKey length {} too large", script_pub_key_length)));
         }
 
-        let mut script_pub_key = vec![0; script_pub_key_length];
-        reader.read_exact(&mut script_pub_key)?;
+        let script_pub_key = self.read_bytes(reader, script_pub_key_length)?;
+        let script_type = classify_script(&script_pub_key);
 
         Ok(Output {
             output_index: index,
             value,
+            address: script_to_address(&script_pub_key, self.network),
+            unspendable: script_type == ScriptType::OpReturn, // The genesis coinbase is flagged once its txid is known
+            script_type,
             script_pub_key: encode(script_pub_key),
         })
     }
 
-    fn read_var_int<R: Read + Seek + Send + 'static>(&self, reader: &mut R) -> io::Result<u64> {
+    fn read_var_int<R: Read + Seek>(&self, reader: &mut R) -> Result<u64, ParserError> {
         let mut first = [0; 1];
         reader.read_exact(&mut first)?;
 
-        match first[0] {
-            0xFD => Ok(reader.read_u16::<LittleEndian>()? as u64),
-            0xFE => Ok(reader.read_u32::<LittleEndian>()? as u64),
-            0xFF => Ok(reader.read_u64::<LittleEndian>()?),
-            _ => Ok(first[0] as u64),
+        let (value, min) = match first[0] {
+            0xFD => (reader.read_u16::<LittleEndian>()? as u64, 0xFD),
+            0xFE => (reader.read_u32::<LittleEndian>()? as u64, 0x1_0000),
+            0xFF => (reader.read_u64::<LittleEndian>()?, 0x1_0000_0000),
+            _ => (first[0] as u64, 0),
+        };
+
+        // Core only accepts the shortest encoding of each value
+        if value < min {
+            return Err(ParserError::NonCanonicalVarInt(value));
+        }
+
+        // Every varint in a block is a count or a length, so anything beyond Core's limit is corrupt
+        if value > MAX_VAR_INT {
+            return Err(ParserError::VarIntOverflow(value));
+        }
+
+        Ok(value)
+    }
+
+    /// Reads `length` bytes, growing the buffer as data arrives rather than trusting `length` up front.
+    fn read_bytes<R: Read + Seek>(&self, reader: &mut R, length: usize) -> Result<Vec<u8>, ParserError> {
+        let mut bytes = Vec::with_capacity(length.min(MAX_PREALLOCATION as usize));
+        reader.take(length as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < length {
+            return Err(ParserError::UnexpectedEof);
         }
+        Ok(bytes)
     }
 
-    fn read_hash<R: Read + Seek + Send + 'static>(&self, reader: &mut R) -> io::Result<String> {
+    /// Reads a 32-byte hash in internal byte order and returns it as display-order hex, the
+    /// order every hash is kept in.
+    fn read_hash<R: Read + Seek>(&self, reader: &mut R) -> Result<String, ParserError> {
         let mut hash = [0; 32];
         reader.read_exact(&mut hash)?;
         Ok(encode(hash.iter().rev().cloned().collect::<Vec<u8>>()))
@@ -0,0 +1,43 @@
+use async_trait::async_trait;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::error::ParserError;
+use crate::models::Block;
+use crate::sink::BlockSink;
+
+/// Writes blocks as newline-delimited JSON, one block per line with its transactions, inputs and
+/// outputs nested inside. Hashes, scripts and witness items are hex strings.
+///
+/// Lines are written as batches arrive, so a block whose active flag changes in a later reorg
+/// keeps the flag it was written with.
+pub struct JsonSink {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl JsonSink {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self { writer: Mutex::new(BufWriter::new(File::create(path)?)) })
+    }
+}
+
+#[async_trait]
+impl BlockSink for JsonSink {
+    async fn insert_blocks(&self, blocks: &[Block]) -> Result<(), ParserError> {
+        let mut lines = Vec::new();
+        for block in blocks {
+            serde_json::to_writer(&mut lines, block).map_err(io::Error::from)?;
+            lines.push(b'\n');
+        }
+
+        self.writer.lock().unwrap().write_all(&lines)?;
+        Ok(())
+    }
+
+    async fn finish(&self) -> Result<(), ParserError> {
+        self.writer.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
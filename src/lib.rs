@@ -0,0 +1,56 @@
+//! Parses Bitcoin Core `blk*.dat` files into blocks, transactions, inputs and outputs, and writes
+//! them to PostgreSQL, newline-delimited JSON or Parquet through a [`BlockSink`].
+//!
+//! Blocks that are already in memory can be parsed without any files:
+//!
+//! ```
+//! use bitcoin_postgres_parser::{process_block, FileReader, Network};
+//!
+//! // The mainnet genesis block, without the magic and size prefix of a blk file record
+//! let bytes = hex::decode(concat!(
+//!     "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e",
+//!     "67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c01010000000100000000000000000000",
+//!     "00000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f",
+//!     "4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420",
+//!     "666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e039",
+//!     "09a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+//! )).unwrap();
+//!
+//! let reader = FileReader::in_memory(Network::Mainnet);
+//! let block = process_block(reader.read_block_from_bytes(&bytes)?, Network::Mainnet, true)?;
+//!
+//! assert_eq!(block.block_hash, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+//! assert_eq!(block.transactions[0].outputs[0].value, 50 * 100_000_000);
+//! # Ok::<(), bitcoin_postgres_parser::ParserError>(())
+//! ```
+
+pub mod address;
+pub mod block_processor;
+pub mod chain;
+pub mod config;
+pub mod database;
+pub mod error;
+pub mod file_reader;
+pub mod json_sink;
+pub mod metrics;
+pub mod models;
+pub mod network;
+#[cfg(feature = "parquet")]
+pub mod parquet_sink;
+pub mod preflight;
+pub mod processing;
+#[cfg(feature = "progress")]
+pub mod progress;
+pub mod rev_reader;
+pub mod script;
+pub mod sink;
+pub mod verify;
+pub mod visitor;
+
+pub use block_processor::process_block;
+pub use error::ParserError;
+pub use file_reader::{FileReader, ParseLimits};
+pub use models::{Block, Input, Output, Transaction};
+pub use network::Network;
+pub use sink::BlockSink;
+pub use visitor::TransactionVisitor;
@@ -1,130 +1,543 @@
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
-use chrono::Local;
 use dotenv::dotenv;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
+use rayon::prelude::*;
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
-use tokio::sync::Semaphore;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::interval;
 use tokio_postgres::NoTls;
+use tracing::{debug, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
-mod block_processor;
-mod database;
-mod file_reader;
-mod models;
-
-use database::{setup_database, insert_block};
-use file_reader::FileReader;
+use bitcoin_postgres_parser::chain::{Chain, PENDING_HEIGHT};
+use bitcoin_postgres_parser::config::{usage, Command, Config, ConfigError};
+use bitcoin_postgres_parser::database::Database;
+use bitcoin_postgres_parser::json_sink::JsonSink;
+use bitcoin_postgres_parser::metrics::{self, add_elapsed, seconds, Metrics};
+#[cfg(feature = "parquet")]
+use bitcoin_postgres_parser::parquet_sink::ParquetSink;
+#[cfg(feature = "progress")]
+use bitcoin_postgres_parser::progress::spawn_progress_bar;
+use bitcoin_postgres_parser::file_reader::RECORD_HEADER_SIZE;
+use bitcoin_postgres_parser::preflight::preflight;
+use bitcoin_postgres_parser::processing::{
+    drop_seen_blocks, partition_by_height, spawn_ordered_reader, spawn_reader, spawn_watcher, CheckpointTracker, Message,
+};
+use bitcoin_postgres_parser::sink::NullSink;
+use bitcoin_postgres_parser::verify::verify_files;
+use bitcoin_postgres_parser::visitor::{visit_block, OpReturnCounter};
+use bitcoin_postgres_parser::{Block, BlockSink, FileReader, ParserError, TransactionVisitor};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL")?;
-    let blocks_path = env::var("BLOCKS_PATH")?;
-    let verbose = env::var("VERBOSE").unwrap_or_else(|_| "false".to_string()) == "true";
-
-    println!("Connecting to the database...");
-    let config = database_url.parse::<tokio_postgres::Config>()?;
-    let manager = PostgresConnectionManager::new(config, NoTls);
-    let pool = Pool::builder().max_size(100).build(manager).await?;
-
-    println!("Connected to the database.");
-    setup_database(&pool).await?;
-    println!("Database schema setup complete.");
-
-    let file_reader = FileReader::new(PathBuf::from(blocks_path), verbose);
-
-    // Metrics tracking
-    let total_blocks = Arc::new(AtomicUsize::new(0));
-    let total_txs = Arc::new(AtomicUsize::new(0));
-    let total_files_read = Arc::new(AtomicUsize::new(0));
-    let runtime = Instant::now();
-
-    let total_blocks_clone = Arc::clone(&total_blocks);
-    let total_txs_clone = Arc::clone(&total_txs);
-    let total_files_read_clone = Arc::clone(&total_files_read);
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(3));
-        loop {
-            interval.tick().await;
-
-            let total_blocks = total_blocks_clone.load(Ordering::Relaxed);
-            let total_txs = total_txs_clone.load(Ordering::Relaxed);
-            let total_files_read = total_files_read_clone.load(Ordering::Relaxed);
-            let elapsed = runtime.elapsed().as_secs();
-            let tx_per_second = if elapsed > 0 { total_txs as f64 / elapsed as f64 } else { 0.0 };
-
-            println!(
-                "{} Files Read: {} Blocks: {} Tx: {} Tx/s {:.2} Runtime {}s",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                total_files_read,
-                total_blocks,
-                total_txs,
-                tx_per_second,
-                elapsed
-            );
+    let config = Config::load(env::args().skip(1), |name| env::var(name).ok())?;
+    if config.command == Command::Help {
+        print!("{}", usage());
+        return Ok(());
+    }
+    let Config {
+        command, blocks_path, output, database_url, table_prefix, output_path, parquet_blocks_per_file,
+        parquet_file_size_mb, network, verbose, reset, dry_run, strict, metrics_addr, foreign_keys,
+        defer_primary_keys, script_asm, utxo_set, address_stats, store_raw, undo_data, quarantine_path,
+        db_max_attempts, channel_capacity, read_workers, files_in_memory, start_height, end_height,
+        rewind_to_height, batch_size, hash_threads, progress, file_checksums, watch, headers_first,
+        headers_only, watch_interval, count_op_returns, copy_flush_kb, insert_workers, limits,
+    } = config;
+    #[cfg(not(feature = "parquet"))]
+    let _ = (parquet_blocks_per_file, parquet_file_size_mb);
+    // The rewind command is REWIND_TO_HEIGHT without the import that follows it
+    let rewind_to_height = match command {
+        Command::Rewind(height) => Some(height),
+        _ => rewind_to_height,
+    };
+
+    // RUST_LOG takes precedence; otherwise VERBOSE turns on this crate's debug events, such as per-file
+    // timings and progress, without the debug output of dependencies
+    let default_filter = if verbose { "info,bitcoin_postgres_parser=debug" } else { "info" };
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter)))
+        .init();
+
+    // Zero leaves the thread count to rayon, which uses one per CPU
+    let hash_pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(hash_threads)
+            .thread_name(|index| format!("hash-{}", index))
+            .build()?,
+    );
+
+    if command == Command::Verify {
+        let metrics = Arc::new(Metrics::default());
+        let file_reader = FileReader::new(blocks_path.clone(), network, Arc::clone(&metrics))
+            .with_limits(limits)
+            .with_quarantine(quarantine_path);
+        let report = hash_pool.install(|| verify_files(&file_reader, &metrics));
+        info!(
+            files = report.files,
+            blocks = report.blocks,
+            parse_errors = report.parse_errors,
+            merkle_mismatches = report.merkle_mismatches,
+            pow_failures = report.pow_failures,
+            missing_parents = report.missing_parents,
+            "Verification finished"
+        );
+        if report.failures() > 0 {
+            return Err(format!("{} verification checks failed", report.failures()).into());
         }
-    });
+        return Ok(());
+    }
 
-    // Initialize semaphore with 10 permits
-    let semaphore = Arc::new(Semaphore::new(10));
+    let started = Instant::now();
+    let metrics = Arc::new(Metrics::default());
+    let sink: Arc<dyn BlockSink> = match output.as_str() {
+        _ if dry_run => Arc::new(NullSink),
+        "postgres" => {
+            let database_url = database_url.ok_or(ConfigError::Missing("DATABASE_URL"))?;
+            info!("Connecting to the database");
+            let pg_config = database_url.parse::<tokio_postgres::Config>()?;
+            let manager = PostgresConnectionManager::new(pg_config, NoTls);
+            let pool = Pool::builder().max_size(100).build(manager).await?;
 
-    for file_index in 0..file_reader.file_paths.len() {
-        match file_reader.read_file(file_index).await {
-            Ok(blocks) => {
-                
+            info!("Connected to the database");
+            let database = Database::new(pool, foreign_keys, db_max_attempts)
+                .with_asm(script_asm)
+                .with_utxo_set(utxo_set)
+                .with_address_stats(address_stats)
+                .with_table_prefix(&table_prefix)
+                .with_deferred_primary_keys(defer_primary_keys)
+                .with_copy_flush_bytes(copy_flush_kb * 1024)
+                .with_metrics(Arc::clone(&metrics));
+            if let Some(height) = rewind_to_height {
+                let removed = database.rewind_to_height(height).await?;
+                info!(height, removed, "Rewound the database");
+            }
+            Arc::new(database)
+        }
+        "json" => {
+            let output_path = output_path.unwrap_or_else(|| "blocks.ndjson".to_string());
+            Arc::new(JsonSink::create(Path::new(&output_path))?)
+        }
+        #[cfg(feature = "parquet")]
+        "parquet" => {
+            let output_path = output_path.unwrap_or_else(|| "parquet".to_string());
+            Arc::new(ParquetSink::new(output_path.into(), parquet_blocks_per_file, parquet_file_size_mb * 1024 * 1024))
+        }
+        _ => return Err(format!("Unknown OUTPUT {:?}, expected postgres, json or parquet", output).into()),
+    };
+    if let Command::Rewind(_) = command {
+        return Ok(());
+    }
+    let mut visitors: Vec<Box<dyn TransactionVisitor>> = Vec::new();
+    if count_op_returns {
+        visitors.push(Box::new(OpReturnCounter::default()));
+    }
 
-                let start_time = Instant::now();
-                let mut processed_blocks = Vec::new();
+    let file_reader = Arc::new(
+        FileReader::new(blocks_path.clone(), network, Arc::clone(&metrics))
+            .with_limits(limits)
+            .with_raw_bytes(store_raw)
+            .with_undo_data(undo_data)
+            .with_quarantine(quarantine_path),
+    );
+    preflight(&file_reader, sink.as_ref(), reset).await?;
 
-                for block in blocks {
-                    total_txs.fetch_add(block.transactions.len(), Ordering::Relaxed);
-                    total_blocks.fetch_add(1, Ordering::Relaxed);
+    // Checkpoints index into the blocks directory, so a single reprocessed file neither resumes from nor moves them.
+    // Blocks outside a height range aren't stored, so a run resumed from one couldn't link its blocks to them.
+    // Reading headers first reads blocks in chain order rather than file by file, so there is no file to resume after.
+    let height_range = start_height..=end_height;
+    let save_checkpoints = !file_reader.is_single_file() && height_range == (0..=i32::MAX) && !headers_first;
+    let checkpoint = if save_checkpoints { sink.last_checkpoint().await? } else { None };
 
-                    let processed_block = block_processor::process_block(block).await;
-                    processed_blocks.push(processed_block);
-                }
+    if let Some(metrics_addr) = metrics_addr {
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&metrics_addr, metrics).await {
+                warn!(addr = %metrics_addr, error = %e, "Metrics endpoint stopped");
+            }
+        });
+    }
+
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        let runtime = Instant::now();
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(3));
+            loop {
+                interval.tick().await;
+
+                let total_blocks = metrics.blocks.load(Ordering::Relaxed);
+                let total_txs = metrics.transactions.load(Ordering::Relaxed);
+                let total_files_read = metrics.files_read.load(Ordering::Relaxed);
+                let skipped_blocks = metrics.skipped_blocks.load(Ordering::Relaxed);
+                let inputs_inserted = metrics.inputs_inserted.load(Ordering::Relaxed);
+                let outputs_inserted = metrics.outputs_inserted.load(Ordering::Relaxed);
+                let elapsed = runtime.elapsed().as_secs();
+                let per_second = |count: f64| if elapsed > 0 { count / elapsed as f64 } else { 0.0 };
+
+                debug!(
+                    files_read = total_files_read,
+                    blocks = total_blocks,
+                    transactions = total_txs,
+                    tx_per_second = format_args!("{:.2}", per_second(total_txs as f64)),
+                    inputs_inserted,
+                    inputs_per_second = format_args!("{:.2}", per_second(inputs_inserted as f64)),
+                    outputs_inserted,
+                    outputs_per_second = format_args!("{:.2}", per_second(outputs_inserted as f64)),
+                    skipped_blocks,
+                    read_secs = format_args!("{:.2}", seconds(&metrics.read_micros)),
+                    process_secs = format_args!("{:.2}", seconds(&metrics.process_micros)),
+                    insert_secs = format_args!("{:.2}", seconds(&metrics.insert_micros)),
+                    reader_wait_secs = format_args!("{:.2}", seconds(&metrics.reader_wait_micros)),
+                    queued_messages = metrics.queued_messages.load(Ordering::Relaxed),
+                    inserts_in_flight = metrics.inserts_in_flight.load(Ordering::Relaxed),
+                    files_in_memory = metrics.files_in_memory.load(Ordering::Relaxed),
+                    copy_secs = %metrics.copy_times(),
+                    runtime_secs = elapsed,
+                    "Progress"
+                );
+            }
+        });
+    }
 
-                total_files_read.fetch_add(1, Ordering::Relaxed);
+    // Blocks whose parent hasn't been read yet are held back, with the index of the file they
+    // came from, until it shows up
+    let mut chain = Chain::new(network);
+    let mut pending_blocks: Vec<(usize, Block)> = Vec::new();
 
-                if verbose {
-                    println!("Time taken to process blocks: {:?}", start_time.elapsed());
+    for block in sink.load_chain().await? {
+        chain.insert_known(block);
+    }
+
+    if headers_only {
+        // Every file is read again, since headers stored by an earlier headers-only run aren't in the chain
+        let headers = hash_pool.install(|| {
+            (0..file_reader.file_paths.len()).into_par_iter().map(|file_index| file_reader.read_headers(file_index)).collect::<Result<Vec<_>, _>>()
+        })?.concat();
+        let header_count = headers.len();
+        let (mut ordered, unlinked) = chain.order_headers(headers);
+        if !unlinked.is_empty() {
+            warn!(headers = unlinked.len(), "Headers could not be linked to the chain and were not inserted");
+        }
+        chain.assign_header_heights(&mut ordered);
+        ordered.retain(|header| height_range.contains(&header.height));
+        for batch in ordered.chunks(batch_size) {
+            sink.insert_headers(batch).await?;
+        }
+        info!(
+            headers = header_count,
+            inserted = ordered.len(),
+            elapsed_secs = format_args!("{:.1}", started.elapsed().as_secs_f64()),
+            "Imported block headers"
+        );
+        return Ok(());
+    }
+
+    let mut start_index = 0;
+    let mut committed_blocks = 0;
+    if let Some((file_index, block_count)) = checkpoint {
+        info!(file_index, block_count, "Resuming after checkpoint");
+        start_index = file_index + 1;
+        committed_blocks = block_count;
+    }
+    let mut checkpoint = CheckpointTracker::new(start_index, committed_blocks);
+    // The newest file is still being written to when watching, so a restart has to read it again
+    let newest_file = file_reader.file_paths.len().saturating_sub(1);
+    if watch {
+        checkpoint.hold_at(newest_file);
+    }
+
+    // Checksums are kept by file name, for every file whose blocks were all committed. Files behind the
+    // checkpoint are only read again when their checksum changed, and unchanged files after it are skipped.
+    let mut file_indexes: Vec<usize> = (start_index..file_reader.file_paths.len()).collect();
+    let mut checksums: Vec<Option<(String, String)>> = Vec::new();
+    if file_checksums && !save_checkpoints {
+        warn!("FILE_CHECKSUMS=true only applies to full imports of a blocks directory, ignoring it");
+    } else if file_checksums {
+        let hashes = hash_pool.install(|| {
+            (0..file_reader.file_paths.len())
+                .into_par_iter()
+                .map(|file_index| file_reader.file_checksum(file_index))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        let stored: HashMap<String, String> = sink.load_file_checksums().await?.into_iter().collect();
+        let files: Vec<(String, String)> = file_reader.file_paths.iter()
+            .map(|path| path.file_name().unwrap_or_default().to_string_lossy().into_owned())
+            .zip(hashes)
+            .collect();
+
+        file_indexes.clear();
+        let (mut unchanged, mut changed) = (0, 0);
+        for (file_index, (file_name, sha256)) in files.iter().enumerate() {
+            match stored.get(file_name) {
+                Some(stored_sha256) if stored_sha256 == sha256 => {
+                    unchanged += 1;
+                    if file_index >= start_index {
+                        checkpoint.file_read(file_index, 0);
+                    }
                 }
+                Some(_) => {
+                    warn!(file = %file_name, "File changed since it was imported, reprocessing it");
+                    changed += 1;
+                    file_indexes.push(file_index);
+                }
+                None if file_index >= start_index => file_indexes.push(file_index),
+                None => {}
+            }
+        }
+        info!(unchanged, changed, "Verified file checksums");
+        checksums = files.into_iter().map(Some).collect();
+        if watch {
+            if let Some(checksum) = checksums.get_mut(newest_file) {
+                *checksum = None;
+            }
+        }
 
-                let insert_futures = FuturesUnordered::new();
-                for block in processed_blocks.iter() {
-                    let pool = pool.clone();
-                    let block = block.clone();
-                    let semaphore = semaphore.clone();
+        // The skipped files are already stored, so only the checkpoint has to catch up with them
+        checkpoint.take_completed();
+        if let Some((file_index, block_count)) = checkpoint.advance() {
+            sink.save_checkpoint(file_index, block_count).await?;
+        }
+    }
 
-                    insert_futures.push(tokio::task::spawn(async move {
-                        let _permit = semaphore.acquire().await.unwrap();
+    #[cfg(feature = "progress")]
+    let mut progress_bar = progress.then(|| spawn_progress_bar(Arc::clone(&metrics), file_indexes.len()));
+    #[cfg(not(feature = "progress"))]
+    if progress {
+        warn!("PROGRESS=true needs the progress feature, ignoring it");
+    }
+
+    let (sender, mut receiver) = mpsc::channel(channel_capacity);
+    let mut reader = if headers_first {
+        // Ordering every header up front means no block has to wait for its parent, whatever order a
+        // reindex left the files in, and repeated blocks are dropped before they are read
+        let headers = hash_pool.install(|| {
+            file_indexes.par_iter().map(|&file_index| file_reader.read_headers(file_index)).collect::<Result<Vec<_>, _>>()
+        })?.concat();
+        let header_count = headers.len();
+        let (ordered, unlinked) = chain.order_headers(headers);
+        info!(headers = header_count, blocks = ordered.len(), "Ordered block headers");
+        if !unlinked.is_empty() {
+            warn!(blocks = unlinked.len(), "Blocks could not be linked to the chain and were not inserted");
+        }
+        metrics.skipped_blocks.fetch_add(header_count - ordered.len(), Ordering::Relaxed);
+        spawn_ordered_reader(
+            Arc::clone(&file_reader),
+            ordered,
+            sender,
+            Arc::clone(&metrics),
+            Arc::clone(&hash_pool),
+            strict,
+        )
+    } else {
+        spawn_reader(
+            Arc::clone(&file_reader),
+            file_indexes,
+            read_workers,
+            files_in_memory,
+            sender,
+            Arc::clone(&metrics),
+            Arc::clone(&hash_pool),
+            strict,
+        )
+    };
 
-                        if let Err(e) = insert_block(&pool, &block).await {
-                            eprintln!("Failed to insert block: {}", e);
+    let mut inserts: FuturesUnordered<JoinHandle<InsertResult>> = FuturesUnordered::new();
+    let mut file_blocks: Vec<Block> = Vec::new();
+    // Watching picks up the newest file just past the last block read from it
+    let mut watch_offset = 0;
+    let mut watching = false;
+
+    // Reads the files found at startup, then, when watching, whatever the node appends after that
+    loop {
+        while let Some(message) = receiver.recv().await {
+            metrics.queued_messages.store(receiver.len(), Ordering::Relaxed);
+            match message {
+                Message::Block(block) => file_blocks.push(block),
+                Message::FileDone(file_index, rejected_blocks) => {
+                    if watch {
+                        checkpoint.hold_at(file_index);
+                    }
+                    if !watching && file_index == newest_file {
+                        let ends = file_blocks.iter().map(|block| block.byte_offset as u64 + RECORD_HEADER_SIZE + block.size as u64);
+                        watch_offset = ends.max().unwrap_or(0);
+                    }
+
+                    // A file with invalid blocks keeps its old checksum, so it's read again on the next run
+                    if rejected_blocks > 0 {
+                        if let Some(checksum) = checksums.get_mut(file_index) {
+                            *checksum = None;
                         }
-                    }));
-                }
+                    }
 
-                insert_futures.collect::<Vec<_>>().await;
+                    let skipped = drop_seen_blocks(&mut file_blocks, &chain, &pending_blocks);
+                    metrics.skipped_blocks.fetch_add(skipped, Ordering::Relaxed);
+                    checkpoint.file_read(file_index, file_blocks.len());
 
-                if verbose {
-                    println!("Done in {:?}", start_time.elapsed());
-                }
+                    // Files can complete in any order, so the parent of a block may only arrive with a later message
+                    let (block_files, mut blocks): (Vec<usize>, Vec<Block>) = std::mem::take(&mut pending_blocks)
+                        .into_iter()
+                        .chain(file_blocks.drain(..).map(|block| (file_index, block)))
+                        .unzip();
+                    let reorged = chain.assign_heights(&mut blocks);
+                    if !reorged.is_empty() {
+                        // Reorgs are rare, so wait for earlier inserts to land before flipping their blocks
+                        while let Some(result) = inserts.next().await {
+                            finish_insert(sink.as_ref(), result?, &mut checkpoint, save_checkpoints, &checksums).await?;
+                        }
+                        sink.update_active_flags(&reorged).await?;
+                    }
 
+                    let (ready, pending): (Vec<_>, Vec<_>) = block_files
+                        .into_iter()
+                        .zip(blocks)
+                        .partition(|(_, block)| block.height != PENDING_HEIGHT);
+                    pending_blocks = pending;
+
+                    let (ready, out_of_range) = partition_by_height(ready, &height_range);
+                    if !out_of_range.is_empty() {
+                        metrics.skipped_blocks.fetch_add(out_of_range.len(), Ordering::Relaxed);
+                        checkpoint.blocks_committed(&out_of_range.iter().map(|(file_index, _)| *file_index).collect::<Vec<_>>());
+                    }
+
+                    let (block_files, blocks): (Vec<usize>, Vec<Block>) = ready.into_iter().unzip();
+                    for block in &blocks {
+                        visit_block(&mut visitors, block);
+                    }
+
+                    // A file with nothing left to insert still gets an empty batch, so its checkpoint can advance
+                    let mut batches = block_files.into_iter().zip(blocks).peekable();
+                    loop {
+                        let (batch_files, batch): (Vec<usize>, Vec<Block>) = batches.by_ref().take(batch_size).unzip();
+                        let (batch_sink, batch_metrics) = (Arc::clone(&sink), Arc::clone(&metrics));
+                        let span = info_span!("batch", files = ?batch_files, blocks = batch.len());
+                        inserts.push(tokio::spawn(
+                            async move {
+                                batch_metrics.inserts_in_flight.fetch_add(1, Ordering::Relaxed);
+                                let started = Instant::now();
+                                let result = batch_sink.insert_blocks(&batch).await;
+                                add_elapsed(&batch_metrics.insert_micros, started.elapsed());
+                                batch_metrics.inserts_in_flight.fetch_sub(1, Ordering::Relaxed);
+                                (batch_files, result)
+                            }.instrument(span),
+                        ));
+
+                        while inserts.len() >= insert_workers {
+                            if let Some(result) = inserts.next().await {
+                                finish_insert(sink.as_ref(), result?, &mut checkpoint, save_checkpoints, &checksums).await?;
+                            }
+                        }
+
+                        if batches.peek().is_none() {
+                            break;
+                        }
+                    }
+                }
+                Message::FileFailed(file_index, e) => {
+                    warn!(file_index, error = %e, "Failed to read file");
+                    metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                }
             }
-            Err(e) => {
-                eprintln!("Failed to read file at index {}: {}", file_index, e);
-            }
+        }
+
+        while let Some(result) = inserts.next().await {
+            finish_insert(sink.as_ref(), result?, &mut checkpoint, save_checkpoints, &checksums).await?;
+        }
+        reader.await?;
+        if watching {
+            break;
+        }
+
+        // Blocks still pending when watching may be linked once their parents are appended
+        if !pending_blocks.is_empty() && !watch {
+            warn!(blocks = pending_blocks.len(), "Blocks could not be linked to the chain and were not inserted");
+        }
+
+        #[cfg(feature = "progress")]
+        if let Some(progress_bar) = progress_bar.take() {
+            progress_bar.finish();
+        }
+        info!("All blocks processed");
+
+        if dry_run {
+            info!(
+                files = metrics.files_read.load(Ordering::Relaxed),
+                bytes = metrics.bytes_read.load(Ordering::Relaxed),
+                blocks = metrics.blocks.load(Ordering::Relaxed),
+                transactions = metrics.transactions.load(Ordering::Relaxed),
+                parse_errors = metrics.parse_errors.load(Ordering::Relaxed),
+                skipped_blocks = metrics.skipped_blocks.load(Ordering::Relaxed),
+                elapsed_secs = format_args!("{:.1}", started.elapsed().as_secs_f64()),
+                "Dry run finished"
+            );
+        }
+
+        for visitor in &mut visitors {
+            visitor.finish();
+        }
+        sink.finish().await?;
+
+        if !watch {
+            break;
+        }
+        info!(file_index = newest_file, offset = watch_offset, interval_secs = watch_interval.as_secs(), "Watching for new blocks");
+        let (sender, watch_receiver) = mpsc::channel(channel_capacity);
+        receiver = watch_receiver;
+        reader = spawn_watcher(
+            Arc::clone(&file_reader),
+            (newest_file, watch_offset),
+            watch_interval,
+            sender,
+            Arc::clone(&metrics),
+            Arc::clone(&hash_pool),
+            strict,
+        );
+        watching = true;
+    }
+
+    Ok(())
+}
+
+/// Files the inserted blocks were read from, along with the outcome of the insert.
+type InsertResult = (Vec<usize>, Result<(), ParserError>);
+
+/// Records a finished insert, saves the checkpoint if it could move forward and stores the checksum,
+/// as file name and SHA256 by file index, of every file that is now done.
+async fn finish_insert(
+    sink: &dyn BlockSink,
+    (block_files, result): InsertResult,
+    checkpoint: &mut CheckpointTracker,
+    save_checkpoints: bool,
+    checksums: &[Option<(String, String)>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match result {
+        Ok(()) => {
+            debug!(files = ?block_files, "Committed batch");
+            checkpoint.blocks_committed(&block_files);
+        }
+        Err(e) if e.is_fatal() => return Err(e.into()),
+        // The files of a failed batch never complete, which keeps the checkpoint in front of them
+        Err(e) => warn!(files = ?block_files, error = %e, "Failed to insert blocks"),
+    }
+
+    if let (Some((file_index, block_count)), true) = (checkpoint.advance(), save_checkpoints) {
+        sink.save_checkpoint(file_index, block_count).await?;
+    }
+    for file_index in checkpoint.take_completed() {
+        if let Some((file_name, sha256)) = checksums.get(file_index).and_then(Option::as_ref) {
+            sink.save_file_checksum(file_name, sha256).await?;
         }
     }
 
-    println!("All blocks processed.");
     Ok(())
 }
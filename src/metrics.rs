@@ -0,0 +1,159 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Tables whose COPY is timed, in the order of `Metrics::copy_micros`.
+pub const COPY_TABLES: [&str; 9] = ["blocks", "transactions", "inputs", "outputs", "witnesses", "op_returns", "signatures", "taproot_spends", "multisig"];
+
+/// Import progress counters, shared by the reader, the inserters and the metrics endpoint.
+/// Times are in microseconds and summed over workers, so with several of them they can add up
+/// to more than the time the import took.
+#[derive(Default)]
+pub struct Metrics {
+    pub files_read: AtomicUsize,
+    pub bytes_read: AtomicUsize,
+    pub blocks: AtomicUsize,
+    pub transactions: AtomicUsize,
+    pub inputs: AtomicUsize,
+    pub outputs: AtomicUsize,
+    /// Malformed blocks and unreadable files.
+    pub parse_errors: AtomicUsize,
+    /// Parsed blocks that were not inserted because they failed verification or were duplicates.
+    pub skipped_blocks: AtomicUsize,
+    pub pow_failures: AtomicUsize,
+    /// Rows committed to the `inputs` and `outputs` tables.
+    pub inputs_inserted: AtomicU64,
+    pub outputs_inserted: AtomicU64,
+    /// Time spent in each table's COPY, by index into `COPY_TABLES`.
+    pub copy_micros: [AtomicU64; COPY_TABLES.len()],
+    /// Time spent reading and parsing blk files, hashing and checking blocks, and inserting them.
+    pub read_micros: AtomicU64,
+    pub process_micros: AtomicU64,
+    pub insert_micros: AtomicU64,
+    /// Time the reader spent waiting for room in the channel to the inserters, i.e. held back by
+    /// a sink slower than parsing.
+    pub reader_wait_micros: AtomicU64,
+    /// Messages waiting in the channel between the reader and the inserters, and insert batches
+    /// running. Gauges rather than counters.
+    pub queued_messages: AtomicUsize,
+    pub inserts_in_flight: AtomicUsize,
+    /// Files being read, or read with blocks not yet all in the channel. A gauge.
+    pub files_in_memory: AtomicUsize,
+}
+
+impl Metrics {
+    /// Renders every counter in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("files_read", "blk files read", &self.files_read),
+            ("bytes_read", "Bytes of blk files read", &self.bytes_read),
+            ("blocks", "Blocks parsed", &self.blocks),
+            ("transactions", "Transactions parsed", &self.transactions),
+            ("inputs", "Transaction inputs parsed", &self.inputs),
+            ("outputs", "Transaction outputs parsed", &self.outputs),
+            ("parse_errors", "Malformed blocks and unreadable files", &self.parse_errors),
+            ("skipped_blocks", "Blocks skipped as invalid or duplicate", &self.skipped_blocks),
+            ("pow_failures", "Blocks that failed the proof-of-work check", &self.pow_failures),
+        ];
+
+        let rows = [
+            ("inputs_inserted", "Transaction inputs inserted", &self.inputs_inserted),
+            ("outputs_inserted", "Transaction outputs inserted", &self.outputs_inserted),
+        ];
+        let times = [
+            ("read", "Seconds spent reading and parsing blk files", &self.read_micros),
+            ("process", "Seconds spent hashing and checking blocks", &self.process_micros),
+            ("insert", "Seconds spent inserting blocks", &self.insert_micros),
+            ("reader_wait", "Seconds the reader waited for the inserters to catch up", &self.reader_wait_micros),
+        ];
+        let gauges = [
+            ("queued_messages", "Messages waiting between the reader and the inserters", &self.queued_messages),
+            ("inserts_in_flight", "Insert batches running", &self.inserts_in_flight),
+            ("files_in_memory", "blk files being read or queued", &self.files_in_memory),
+        ];
+
+        let mut body = String::new();
+        for (name, help, counter) in counters {
+            let _ = writeln!(body, "# HELP bitcoin_parser_{}_total {}", name, help);
+            let _ = writeln!(body, "# TYPE bitcoin_parser_{}_total counter", name);
+            let _ = writeln!(body, "bitcoin_parser_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        for (name, help, counter) in rows {
+            let _ = writeln!(body, "# HELP bitcoin_parser_{}_total {}", name, help);
+            let _ = writeln!(body, "# TYPE bitcoin_parser_{}_total counter", name);
+            let _ = writeln!(body, "bitcoin_parser_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        for (name, help, counter) in times {
+            let _ = writeln!(body, "# HELP bitcoin_parser_{}_seconds_total {}", name, help);
+            let _ = writeln!(body, "# TYPE bitcoin_parser_{}_seconds_total counter", name);
+            let _ = writeln!(body, "bitcoin_parser_{}_seconds_total {}", name, seconds(counter));
+        }
+        for (name, help, gauge) in gauges {
+            let _ = writeln!(body, "# HELP bitcoin_parser_{} {}", name, help);
+            let _ = writeln!(body, "# TYPE bitcoin_parser_{} gauge", name);
+            let _ = writeln!(body, "bitcoin_parser_{} {}", name, gauge.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(body, "# HELP bitcoin_parser_copy_seconds_total Seconds spent copying rows into each table");
+        let _ = writeln!(body, "# TYPE bitcoin_parser_copy_seconds_total counter");
+        for (table, counter) in COPY_TABLES.iter().zip(&self.copy_micros) {
+            let _ = writeln!(body, "bitcoin_parser_copy_seconds_total{{table=\"{}\"}} {}", table, seconds(counter));
+        }
+        body
+    }
+
+    /// Adds the time a table's COPY took. Tables not in `COPY_TABLES` aren't tracked.
+    pub fn record_copy(&self, table: &str, elapsed: Duration) {
+        if let Some(index) = COPY_TABLES.iter().position(|&name| name == table) {
+            add_elapsed(&self.copy_micros[index], elapsed);
+        }
+    }
+
+    /// The COPY time of every table in seconds, as `table:seconds` pairs separated by commas, so
+    /// that they fit in a single log field.
+    pub fn copy_times(&self) -> String {
+        COPY_TABLES.iter()
+            .zip(&self.copy_micros)
+            .map(|(table, counter)| format!("{}:{:.2}", table, seconds(counter)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Adds `elapsed` to a counter of microseconds.
+pub fn add_elapsed(counter: &AtomicU64, elapsed: Duration) {
+    counter.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Reads a counter of microseconds as seconds.
+pub fn seconds(counter: &AtomicU64) -> f64 {
+    counter.load(Ordering::Relaxed) as f64 / 1_000_000.0
+}
+
+/// Serves the metrics over plain HTTP on `address`. Every request gets the full metrics page,
+/// whatever its path, which is all a Prometheus scraper needs.
+pub async fn serve(address: &str, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+
+        tokio::spawn(async move {
+            // The request itself doesn't matter, but read it so the client isn't reset mid-send
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
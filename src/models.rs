@@ -1,43 +1,253 @@
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+
+use crate::script::{bip34_height, extract_coinbase_tag, ScriptType};
+
+/// Outpoint a coinbase input refers to, since it spends no previous output.
+pub const COINBASE_PREVIOUS_TXID: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+pub const COINBASE_PREVIOUS_OUTPUT_INDEX: i64 = 0xffff_ffff;
+
+/// Locktimes below this are block heights, those at or above it unix timestamps.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Sequence numbers up to this one opt in to replacement (BIP125).
+const MAX_BIP125_RBF_SEQUENCE: i64 = 0xfffffffd;
+
+/// Sequence bits BIP68 gives meaning to.
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+/// Time-based relative locktimes count in units of 2^9 = 512 seconds.
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// One stack of witness items per input.
+pub type Witness = Vec<Vec<Vec<u8>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub block_hash: String,
     pub height: i32,
+    #[serde(with = "time::serde::rfc3339")]
     pub time: time::OffsetDateTime,
     pub difficulty: f64,
     pub merkle_root: String,
     pub nonce: i64,
     pub size: i32,
-    pub version: i32,
-    pub bits: String,
+    pub weight: i32,
+    pub version: u32,
+    pub bits: u32,
     pub previous_block: String,
     pub active: bool,
+    /// Satoshis issued up to and including this block on its branch, leaving out the unspendable
+    /// genesis coinbase and any subsidy a coinbase didn't claim.
+    pub total_supply: i64,
+    /// Transactions with an input signalling replace-by-fee (BIP125).
+    pub rbf_tx_count: i32,
+    /// Name of the blk file the block was read from, and the offset of its record (magic bytes
+    /// included) within it. Empty and 0 for blocks parsed from memory.
+    pub blk_file: String,
+    pub byte_offset: i64,
+    /// The serialized block, kept when the reader stores raw bytes.
+    #[serde(default, with = "hex_bytes", skip_serializing_if = "Option::is_none")]
+    pub raw: Option<Vec<u8>>,
     pub transactions: Vec<Transaction>,
 }
 
-#[derive(Debug, Clone)]
+/// A block's header and where the block is stored, read without parsing its transactions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub block_hash: String,
+    pub version: u32,
+    pub previous_block: String,
+    pub merkle_root: String,
+    pub time: time::OffsetDateTime,
+    pub bits: u32,
+    pub nonce: i64,
+    /// `PENDING_HEIGHT` until `Chain::assign_header_heights` links the header.
+    pub height: i32,
+    /// Index into `FileReader::file_paths` of the file the block was read from, and the offset of
+    /// its record within it.
+    pub file_index: usize,
+    pub byte_offset: u64,
+}
+
+/// A block stored by an earlier run, as much of it as is needed to link new blocks to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredBlock {
+    pub block_hash: String,
+    pub previous_block: String,
+    pub height: i32,
+    /// Unix timestamp.
+    pub time: i64,
+    pub bits: u32,
+    pub total_supply: i64,
+    pub active: bool,
+}
+
+impl Block {
+    /// The height encoded at the start of the coinbase scriptSig (BIP34), if it has one.
+    pub fn coinbase_height(&self) -> Option<i64> {
+        let coinbase = self.transactions.first()?.inputs.first().filter(|input| input.is_coinbase)?;
+        bip34_height(&hex::decode(&coinbase.script_sig).ok()?)
+    }
+
+    /// Printable text from the coinbase scriptSig, typically a mining pool tag.
+    pub fn coinbase_tag(&self) -> Option<String> {
+        let coinbase = self.transactions.first()?.inputs.first().filter(|input| input.is_coinbase)?;
+        extract_coinbase_tag(&hex::decode(&coinbase.script_sig).ok()?)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub txid: String,
+    pub wtxid: String,
     pub block_hash: String,
+    /// Index of the transaction within its block, 0 for the coinbase.
+    pub position: i32,
     pub size: i32,
-    pub version: i32,
+    pub weight: i32,
+    pub vsize: i32,
+    pub version: u32,
     pub locktime: i32,
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>,
-    pub witness: Option<Vec<Vec<Vec<u8>>>>, // Optional witness data for SegWit transactions
+    #[serde(with = "hex_witness")]
+    pub witness: Option<Witness>, // Optional witness data for SegWit transactions
+    /// The serialized transaction, witness included, kept when the reader stores raw bytes.
+    #[serde(default, with = "hex_bytes", skip_serializing_if = "Option::is_none")]
+    pub raw: Option<Vec<u8>>,
+}
+
+impl Transaction {
+    /// Whether any input signals that the transaction may be replaced (BIP125).
+    pub fn signals_rbf(&self) -> bool {
+        self.inputs.iter().any(Input::rbf_signaled)
+    }
+
+    /// How `locktime` is interpreted: `none` when it is 0, otherwise `height` or `time`.
+    pub fn locktime_type(&self) -> &'static str {
+        match self.locktime as u32 {
+            0 => "none",
+            locktime if locktime < LOCKTIME_THRESHOLD => "height",
+            _ => "time",
+        }
+    }
+
+    /// The locktime as a UTC time, for time-based locktimes.
+    pub fn locktime_time(&self) -> Option<time::OffsetDateTime> {
+        let locktime = self.locktime as u32;
+        if locktime < LOCKTIME_THRESHOLD {
+            return None;
+        }
+        time::OffsetDateTime::from_unix_timestamp(locktime as i64).ok()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Input {
     pub input_index: i32,
     pub previous_txid: String,
-    pub previous_output_index: i32,
+    pub previous_output_index: i64,
     pub script_sig: String,
     pub sequence: i64,
+    pub is_coinbase: bool,
+    /// Value and address of the output spent, when read from the node's undo data. Otherwise
+    /// they are filled in by the database once the spent output is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spent_value: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spent_address: Option<String>,
+}
+
+impl Input {
+    /// Whether the input signals that its transaction may be replaced (BIP125).
+    pub fn rbf_signaled(&self) -> bool {
+        !self.is_coinbase && self.sequence <= MAX_BIP125_RBF_SEQUENCE
+    }
+
+    /// The relative locktime the input's sequence number encodes (BIP68). Only transactions of
+    /// version 2 and up are subject to it, and coinbase inputs never are.
+    pub fn relative_locktime(&self, tx_version: u32) -> Option<RelativeLocktime> {
+        let sequence = self.sequence as u32;
+        if self.is_coinbase || tx_version < 2 || sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        Some(if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            RelativeLocktime::Seconds(value << SEQUENCE_LOCKTIME_GRANULARITY)
+        } else {
+            RelativeLocktime::Blocks(value)
+        })
+    }
+}
+
+/// A BIP68 relative locktime, counted from the block that confirmed the output being spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLocktime {
+    Blocks(u32),
+    Seconds(u32),
+}
+
+impl RelativeLocktime {
+    pub fn blocks(self) -> Option<i32> {
+        match self {
+            RelativeLocktime::Blocks(blocks) => Some(blocks as i32),
+            RelativeLocktime::Seconds(_) => None,
+        }
+    }
+
+    pub fn seconds(self) -> Option<i32> {
+        match self {
+            RelativeLocktime::Seconds(seconds) => Some(seconds as i32),
+            RelativeLocktime::Blocks(_) => None,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Output {
     pub output_index: i32,
     pub value: i64,
     pub script_pub_key: String,
+    pub address: Option<String>, // None for scripts without a standard address form
+    pub script_type: ScriptType,
+    /// OP_RETURN outputs and the genesis coinbase output, which can never be spent.
+    pub unspendable: bool,
+}
+
+/// Serializes witness stacks with each item as a hex string rather than an array of numbers.
+mod hex_witness {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Witness;
+
+    pub fn serialize<S: Serializer>(witness: &Option<Witness>, serializer: S) -> Result<S::Ok, S::Error> {
+        let witness: Option<Vec<Vec<String>>> = witness.as_ref().map(|stacks| {
+            stacks.iter().map(|stack| stack.iter().map(hex::encode).collect()).collect()
+        });
+        witness.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Witness>, D::Error> {
+        let witness: Option<Vec<Vec<String>>> = Option::deserialize(deserializer)?;
+        witness.map(|stacks| {
+            stacks.into_iter()
+                .map(|stack| stack.into_iter().map(|item| hex::decode(item).map_err(serde::de::Error::custom)).collect())
+                .collect()
+        }).transpose()
+    }
+}
+
+/// Serializes raw bytes as a hex string.
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes.as_ref().map(hex::encode).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        let bytes: Option<String> = Option::deserialize(deserializer)?;
+        bytes.map(|bytes| hex::decode(bytes).map_err(serde::de::Error::custom)).transpose()
+    }
 }
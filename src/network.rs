@@ -0,0 +1,163 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAINNET_MAGIC: u32 = 0xD9B4BEF9;
+pub const TESTNET3_MAGIC: u32 = 0x0709110B;
+pub const SIGNET_MAGIC: u32 = 0x40CF030A;
+pub const REGTEST_MAGIC: u32 = 0xDAB5BFFA;
+
+/// What sets one chain's blocks apart from another's. Bitcoin's networks are built in, and forks
+/// that kept Bitcoin's block format, such as Litecoin, can be read with `Network::Custom`. The
+/// `STRICT` proof-of-work check takes the block hash as the proof of work, so it only passes for
+/// forks that mine on double SHA256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Shown in logs and errors.
+    pub name: &'static str,
+    /// Start of every record in a blk file, as a little-endian `u32`.
+    pub magic: u32,
+    /// Hash of the genesis block, in display order.
+    pub genesis_hash: &'static str,
+    /// Txid of the genesis block's coinbase. Core never adds it to the UTXO set, so its output
+    /// can't be spent and doesn't count towards the spendable supply.
+    pub genesis_coinbase_txid: &'static str,
+    /// Subsidy of the first blocks, in the chain's smallest unit.
+    pub initial_subsidy: i64,
+    /// Blocks between halvings of the block subsidy.
+    pub halving_interval: i32,
+    /// Height from which BIP34 requires the coinbase scriptSig to start with the block height.
+    pub bip34_height: i32,
+    /// Largest serialized block, in bytes. Records in blk files declaring a larger size are
+    /// skipped as malformed.
+    pub max_block_size: u32,
+    pub p2pkh_prefix: u8,
+    pub p2sh_prefix: u8,
+    pub bech32_hrp: &'static str,
+}
+
+pub const MAINNET_PARAMS: ChainParams = ChainParams {
+    name: "mainnet",
+    magic: MAINNET_MAGIC,
+    genesis_hash: "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    genesis_coinbase_txid: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+    initial_subsidy: 50 * 100_000_000,
+    halving_interval: 210_000,
+    bip34_height: 227_931,
+    max_block_size: 4_000_000,
+    p2pkh_prefix: 0x00,
+    p2sh_prefix: 0x05,
+    bech32_hrp: "bc",
+};
+
+pub const TESTNET3_PARAMS: ChainParams = ChainParams {
+    name: "testnet3",
+    magic: TESTNET3_MAGIC,
+    genesis_hash: "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+    bip34_height: 21_111,
+    p2pkh_prefix: 0x6f,
+    p2sh_prefix: 0xc4,
+    bech32_hrp: "tb",
+    ..MAINNET_PARAMS
+};
+
+pub const SIGNET_PARAMS: ChainParams = ChainParams {
+    name: "signet",
+    magic: SIGNET_MAGIC,
+    genesis_hash: "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+    bip34_height: 1,
+    ..TESTNET3_PARAMS
+};
+
+pub const REGTEST_PARAMS: ChainParams = ChainParams {
+    name: "regtest",
+    magic: REGTEST_MAGIC,
+    genesis_hash: "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+    halving_interval: 150,
+    bech32_hrp: "bcrt",
+    ..SIGNET_PARAMS
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet3,
+    Signet,
+    Regtest,
+    /// Another chain with Bitcoin's block format.
+    Custom(&'static ChainParams),
+}
+
+impl Network {
+    pub fn params(&self) -> &'static ChainParams {
+        match self {
+            Network::Mainnet => &MAINNET_PARAMS,
+            Network::Testnet3 => &TESTNET3_PARAMS,
+            Network::Signet => &SIGNET_PARAMS,
+            Network::Regtest => &REGTEST_PARAMS,
+            Network::Custom(params) => params,
+        }
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.params().magic
+    }
+
+    pub fn p2pkh_prefix(&self) -> u8 {
+        self.params().p2pkh_prefix
+    }
+
+    pub fn p2sh_prefix(&self) -> u8 {
+        self.params().p2sh_prefix
+    }
+
+    pub fn bech32_hrp(&self) -> &'static str {
+        self.params().bech32_hrp
+    }
+
+    /// Height from which BIP34 requires the coinbase scriptSig to start with the block height.
+    pub fn bip34_height(&self) -> i32 {
+        self.params().bip34_height
+    }
+
+    /// Blocks between halvings of the block subsidy.
+    pub fn halving_interval(&self) -> i32 {
+        self.params().halving_interval
+    }
+
+    /// Largest serialized block, in bytes.
+    pub fn max_block_size(&self) -> u32 {
+        self.params().max_block_size
+    }
+
+    /// The built-in network using `magic`. Custom networks are never returned.
+    pub fn from_magic(magic: u32) -> Option<Network> {
+        match magic {
+            MAINNET_MAGIC => Some(Network::Mainnet),
+            TESTNET3_MAGIC => Some(Network::Testnet3),
+            SIGNET_MAGIC => Some(Network::Signet),
+            REGTEST_MAGIC => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.params().name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mainnet" | "main" | "bitcoin" => Ok(Network::Mainnet),
+            "testnet3" | "testnet" | "test" => Ok(Network::Testnet3),
+            "signet" => Ok(Network::Signet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("Unknown network: {}", s)),
+        }
+    }
+}
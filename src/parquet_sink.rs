@@ -0,0 +1,285 @@
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, FixedSizeBinaryArray, Float64Array, Int32Array, Int64Array, RecordBatch,
+    StringArray, TimestampSecondArray, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use async_trait::async_trait;
+use parquet::arrow::ArrowWriter;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::error::ParserError;
+use crate::models::{Block, RelativeLocktime};
+use crate::sink::BlockSink;
+
+/// Writes `blocks`, `transactions`, `inputs` and `outputs` Parquet files into a directory.
+/// Hashes are stored as 32-byte binary in display order, scripts as binary, values as int64
+/// satoshis and block times as UTC timestamps.
+///
+/// A new set of files, `<table>-<part>.parquet`, is started once `blocks_per_file` blocks have
+/// been written to the current one or any of its tables has grown past `max_file_bytes`.
+pub struct ParquetSink {
+    directory: PathBuf,
+    blocks_per_file: usize,
+    max_file_bytes: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    part: usize,
+    blocks_in_part: usize,
+    writers: Option<Writers>,
+}
+
+struct Writers {
+    blocks: ArrowWriter<File>,
+    transactions: ArrowWriter<File>,
+    inputs: ArrowWriter<File>,
+    outputs: ArrowWriter<File>,
+}
+
+impl ParquetSink {
+    pub fn new(directory: PathBuf, blocks_per_file: usize, max_file_bytes: usize) -> Self {
+        Self { directory, blocks_per_file: blocks_per_file.max(1), max_file_bytes, state: Mutex::default() }
+    }
+
+    fn open_writers(&self, part: usize) -> Result<Writers, ParserError> {
+        let writer = |table: &str, schema: SchemaRef| -> Result<ArrowWriter<File>, ParserError> {
+            let file = File::create(self.directory.join(format!("{}-{:05}.parquet", table, part)))?;
+            ArrowWriter::try_new(file, schema, None).map_err(output_error)
+        };
+
+        Ok(Writers {
+            blocks: writer("blocks", blocks_schema())?,
+            transactions: writer("transactions", transactions_schema())?,
+            inputs: writer("inputs", inputs_schema())?,
+            outputs: writer("outputs", outputs_schema())?,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockSink for ParquetSink {
+    async fn setup(&self, _reset: bool) -> Result<(), ParserError> {
+        fs::create_dir_all(&self.directory)?;
+        Ok(())
+    }
+
+    async fn insert_blocks(&self, blocks: &[Block]) -> Result<(), ParserError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.writers.is_none() {
+            state.writers = Some(self.open_writers(state.part)?);
+        }
+
+        let writers = state.writers.as_mut().unwrap();
+        writers.blocks.write(&blocks_batch(blocks)?).map_err(output_error)?;
+        writers.transactions.write(&transactions_batch(blocks)?).map_err(output_error)?;
+        writers.inputs.write(&inputs_batch(blocks)?).map_err(output_error)?;
+        writers.outputs.write(&outputs_batch(blocks)?).map_err(output_error)?;
+
+        let largest_file = [&writers.blocks, &writers.transactions, &writers.inputs, &writers.outputs]
+            .iter()
+            .map(|writer| writer.bytes_written() + writer.in_progress_size())
+            .max()
+            .unwrap_or(0);
+
+        state.blocks_in_part += blocks.len();
+        if state.blocks_in_part >= self.blocks_per_file || largest_file >= self.max_file_bytes {
+            close_writers(state.writers.take())?;
+            state.part += 1;
+            state.blocks_in_part = 0;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(&self) -> Result<(), ParserError> {
+        let mut state = self.state.lock().unwrap();
+        close_writers(state.writers.take())
+    }
+}
+
+fn close_writers(writers: Option<Writers>) -> Result<(), ParserError> {
+    if let Some(writers) = writers {
+        for writer in [writers.blocks, writers.transactions, writers.inputs, writers.outputs] {
+            writer.close().map_err(output_error)?;
+        }
+    }
+    Ok(())
+}
+
+fn output_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> ParserError {
+    ParserError::Io(io::Error::other(e))
+}
+
+fn hash_field(name: &str) -> Field {
+    Field::new(name, DataType::FixedSizeBinary(32), false)
+}
+
+fn hash_array<'a>(hashes: impl Iterator<Item = &'a String>) -> Result<ArrayRef, ParserError> {
+    let bytes = hashes
+        .map(hex::decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ParserError::MalformedBlock(e.to_string()))?;
+    Ok(Arc::new(FixedSizeBinaryArray::try_from_iter(bytes.into_iter()).map_err(output_error)?))
+}
+
+fn script_array<'a>(scripts: impl Iterator<Item = &'a String>) -> Result<ArrayRef, ParserError> {
+    let bytes = scripts
+        .map(hex::decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ParserError::MalformedBlock(e.to_string()))?;
+    Ok(Arc::new(BinaryArray::from_iter_values(bytes)))
+}
+
+fn blocks_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        hash_field("block_hash"),
+        Field::new("height", DataType::Int32, false),
+        Field::new("time", DataType::Timestamp(TimeUnit::Second, Some("UTC".into())), false),
+        Field::new("difficulty", DataType::Float64, false),
+        hash_field("merkle_root"),
+        Field::new("nonce", DataType::Int64, false),
+        Field::new("size", DataType::Int32, false),
+        Field::new("weight", DataType::Int32, false),
+        Field::new("version", DataType::UInt32, false),
+        Field::new("bits", DataType::UInt32, false),
+        hash_field("previous_block"),
+        Field::new("active", DataType::Boolean, false),
+        Field::new("total_supply", DataType::Int64, false),
+        Field::new("rbf_tx_count", DataType::Int32, false),
+        Field::new("blk_file", DataType::Utf8, false),
+        Field::new("byte_offset", DataType::Int64, false),
+    ]))
+}
+
+fn blocks_batch(blocks: &[Block]) -> Result<RecordBatch, ParserError> {
+    let columns: Vec<ArrayRef> = vec![
+        hash_array(blocks.iter().map(|block| &block.block_hash))?,
+        Arc::new(Int32Array::from_iter_values(blocks.iter().map(|block| block.height))),
+        Arc::new(TimestampSecondArray::from_iter_values(blocks.iter().map(|block| block.time.unix_timestamp())).with_timezone("UTC")),
+        Arc::new(Float64Array::from_iter_values(blocks.iter().map(|block| block.difficulty))),
+        hash_array(blocks.iter().map(|block| &block.merkle_root))?,
+        Arc::new(Int64Array::from_iter_values(blocks.iter().map(|block| block.nonce))),
+        Arc::new(Int32Array::from_iter_values(blocks.iter().map(|block| block.size))),
+        Arc::new(Int32Array::from_iter_values(blocks.iter().map(|block| block.weight))),
+        Arc::new(UInt32Array::from_iter_values(blocks.iter().map(|block| block.version))),
+        Arc::new(UInt32Array::from_iter_values(blocks.iter().map(|block| block.bits))),
+        hash_array(blocks.iter().map(|block| &block.previous_block))?,
+        Arc::new(BooleanArray::from(blocks.iter().map(|block| block.active).collect::<Vec<_>>())),
+        Arc::new(Int64Array::from_iter_values(blocks.iter().map(|block| block.total_supply))),
+        Arc::new(Int32Array::from_iter_values(blocks.iter().map(|block| block.rbf_tx_count))),
+        Arc::new(StringArray::from_iter_values(blocks.iter().map(|block| block.blk_file.as_str()))),
+        Arc::new(Int64Array::from_iter_values(blocks.iter().map(|block| block.byte_offset))),
+    ];
+    RecordBatch::try_new(blocks_schema(), columns).map_err(output_error)
+}
+
+fn transactions_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        hash_field("txid"),
+        hash_field("wtxid"),
+        hash_field("block_hash"),
+        Field::new("position", DataType::Int32, false),
+        Field::new("size", DataType::Int32, false),
+        Field::new("weight", DataType::Int32, false),
+        Field::new("vsize", DataType::Int32, false),
+        Field::new("version", DataType::UInt32, false),
+        Field::new("locktime", DataType::Int32, false),
+        Field::new("locktime_type", DataType::Utf8, false),
+        Field::new("locktime_time", DataType::Timestamp(TimeUnit::Second, Some("UTC".into())), true),
+    ]))
+}
+
+fn transactions_batch(blocks: &[Block]) -> Result<RecordBatch, ParserError> {
+    let transactions: Vec<_> = blocks.iter().flat_map(|block| &block.transactions).collect();
+    let columns: Vec<ArrayRef> = vec![
+        hash_array(transactions.iter().map(|tx| &tx.txid))?,
+        hash_array(transactions.iter().map(|tx| &tx.wtxid))?,
+        hash_array(transactions.iter().map(|tx| &tx.block_hash))?,
+        Arc::new(Int32Array::from_iter_values(transactions.iter().map(|tx| tx.position))),
+        Arc::new(Int32Array::from_iter_values(transactions.iter().map(|tx| tx.size))),
+        Arc::new(Int32Array::from_iter_values(transactions.iter().map(|tx| tx.weight))),
+        Arc::new(Int32Array::from_iter_values(transactions.iter().map(|tx| tx.vsize))),
+        Arc::new(UInt32Array::from_iter_values(transactions.iter().map(|tx| tx.version))),
+        Arc::new(Int32Array::from_iter_values(transactions.iter().map(|tx| tx.locktime))),
+        Arc::new(StringArray::from_iter_values(transactions.iter().map(|tx| tx.locktime_type()))),
+        Arc::new(TimestampSecondArray::from_iter(transactions.iter().map(|tx| tx.locktime_time().map(|time| time.unix_timestamp()))).with_timezone("UTC")),
+    ];
+    RecordBatch::try_new(transactions_schema(), columns).map_err(output_error)
+}
+
+fn inputs_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        hash_field("txid"),
+        Field::new("input_index", DataType::Int32, false),
+        hash_field("previous_txid"),
+        Field::new("previous_output_index", DataType::Int64, false),
+        Field::new("script_sig", DataType::Binary, false),
+        Field::new("sequence", DataType::Int64, false),
+        Field::new("is_coinbase", DataType::Boolean, false),
+        Field::new("rbf_signaled", DataType::Boolean, false),
+        Field::new("relative_locktime_blocks", DataType::Int32, true),
+        Field::new("relative_locktime_time", DataType::Int32, true),
+        Field::new("spent_value", DataType::Int64, true),
+        Field::new("spent_address", DataType::Utf8, true),
+    ]))
+}
+
+fn inputs_batch(blocks: &[Block]) -> Result<RecordBatch, ParserError> {
+    let inputs: Vec<_> = blocks.iter()
+        .flat_map(|block| &block.transactions)
+        .flat_map(|tx| tx.inputs.iter().map(move |input| (tx, input)))
+        .collect();
+    let columns: Vec<ArrayRef> = vec![
+        hash_array(inputs.iter().map(|(tx, _)| &tx.txid))?,
+        Arc::new(Int32Array::from_iter_values(inputs.iter().map(|(_, input)| input.input_index))),
+        hash_array(inputs.iter().map(|(_, input)| &input.previous_txid))?,
+        Arc::new(Int64Array::from_iter_values(inputs.iter().map(|(_, input)| input.previous_output_index))),
+        script_array(inputs.iter().map(|(_, input)| &input.script_sig))?,
+        Arc::new(Int64Array::from_iter_values(inputs.iter().map(|(_, input)| input.sequence))),
+        Arc::new(BooleanArray::from(inputs.iter().map(|(_, input)| input.is_coinbase).collect::<Vec<_>>())),
+        Arc::new(BooleanArray::from(inputs.iter().map(|(_, input)| input.rbf_signaled()).collect::<Vec<_>>())),
+        Arc::new(Int32Array::from_iter(inputs.iter().map(|(tx, input)| input.relative_locktime(tx.version).and_then(RelativeLocktime::blocks)))),
+        Arc::new(Int32Array::from_iter(inputs.iter().map(|(tx, input)| input.relative_locktime(tx.version).and_then(RelativeLocktime::seconds)))),
+        Arc::new(Int64Array::from_iter(inputs.iter().map(|(_, input)| input.spent_value))),
+        Arc::new(StringArray::from_iter(inputs.iter().map(|(_, input)| input.spent_address.as_deref()))),
+    ];
+    RecordBatch::try_new(inputs_schema(), columns).map_err(output_error)
+}
+
+fn outputs_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        hash_field("txid"),
+        Field::new("output_index", DataType::Int32, false),
+        Field::new("value", DataType::Int64, false),
+        Field::new("script_pub_key", DataType::Binary, false),
+        Field::new("address", DataType::Utf8, true),
+        Field::new("script_type", DataType::Utf8, false),
+        Field::new("unspendable", DataType::Boolean, false),
+    ]))
+}
+
+fn outputs_batch(blocks: &[Block]) -> Result<RecordBatch, ParserError> {
+    let outputs: Vec<_> = blocks.iter()
+        .flat_map(|block| &block.transactions)
+        .flat_map(|tx| tx.outputs.iter().map(move |output| (&tx.txid, output)))
+        .collect();
+    let columns: Vec<ArrayRef> = vec![
+        hash_array(outputs.iter().map(|(txid, _)| *txid))?,
+        Arc::new(Int32Array::from_iter_values(outputs.iter().map(|(_, output)| output.output_index))),
+        Arc::new(Int64Array::from_iter_values(outputs.iter().map(|(_, output)| output.value))),
+        script_array(outputs.iter().map(|(_, output)| &output.script_pub_key))?,
+        Arc::new(StringArray::from(outputs.iter().map(|(_, output)| output.address.as_deref()).collect::<Vec<_>>())),
+        Arc::new(StringArray::from_iter_values(outputs.iter().map(|(_, output)| output.script_type.as_str()))),
+        Arc::new(BooleanArray::from(outputs.iter().map(|(_, output)| output.unspendable).collect::<Vec<_>>())),
+    ];
+    RecordBatch::try_new(outputs_schema(), columns).map_err(output_error)
+}
@@ -0,0 +1,44 @@
+use tracing::info;
+
+use crate::block_processor::process_block;
+use crate::error::ParserError;
+use crate::file_reader::FileReader;
+use crate::sink::BlockSink;
+
+/// What `preflight` found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightReport {
+    pub files: usize,
+    /// Hash of the first block of the first file.
+    pub first_block: String,
+}
+
+/// Checks what a long import depends on before it starts: that `file_reader` found blk files
+/// whose first block parses and passes the `STRICT` checks, then that the sink can be set up,
+/// which connects to the database and creates any missing tables, and that its schema matches
+/// this version. The files are checked first so that a wrong path doesn't get as far as `reset`.
+/// Sets up the sink, so it replaces calling `setup`. The first block is read on the calling
+/// thread. Fails with a `ParserError::Preflight` saying what is wrong.
+pub async fn preflight(file_reader: &FileReader, sink: &dyn BlockSink, reset: bool) -> Result<PreflightReport, ParserError> {
+    let Some(first_file) = file_reader.file_paths.first() else {
+        return Err(ParserError::Preflight(format!("No blk*.dat files in {}", file_reader.path().display())));
+    };
+    let block = file_reader.read_first_block(0)
+        .map_err(|e| ParserError::Preflight(format!("Reading {} failed: {}", first_file.display(), e)))?
+        .ok_or_else(|| ParserError::Preflight(format!("{} holds no {} block that parses", first_file.display(), file_reader.network())))?;
+    let block = process_block(block, file_reader.network(), true)
+        .map_err(|e| ParserError::Preflight(format!("The first block of {} fails its checks: {}", first_file.display(), e)))?;
+
+    sink.setup(reset).await.map_err(|e| ParserError::Preflight(format!("Setting up the output failed: {}", e)))?;
+    let problems = sink.check_schema().await.map_err(|e| ParserError::Preflight(format!("Checking the schema failed: {}", e)))?;
+    if !problems.is_empty() {
+        return Err(ParserError::Preflight(format!(
+            "The tables were created by another version, set RESET=true to recreate them: {}",
+            problems.join("; "),
+        )));
+    }
+
+    let report = PreflightReport { files: file_reader.file_paths.len(), first_block: block.block_hash };
+    info!(files = report.files, first_block = %report.first_block, "Preflight checks passed");
+    Ok(report)
+}
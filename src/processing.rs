@@ -0,0 +1,377 @@
+use futures::stream::{self, StreamExt};
+use rayon::ThreadPool;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::interval;
+use tracing::{debug, debug_span, warn};
+
+use crate::block_processor::process_block;
+use crate::chain::Chain;
+use crate::error::ParserError;
+use crate::file_reader::FileReader;
+use crate::metrics::{add_elapsed, Metrics};
+use crate::models::{Block, BlockHeader};
+use crate::network::Network;
+
+/// Blocks `spawn_ordered_reader` reads and processes in one go.
+const ORDERED_CHUNK_BLOCKS: usize = 500;
+
+/// Messages sent from the reader task to the inserters. Blocks of a file are sent in the order
+/// they appear in the file, followed by exactly one `FileDone` or `FileFailed` for that file.
+/// `FileDone` carries the number of blocks that were rejected as invalid and not sent.
+pub enum Message {
+    Block(Block),
+    FileDone(usize, usize),
+    FileFailed(usize, ParserError),
+}
+
+/// Spawns the producer side of the pipeline. Up to `read_workers` files are read and processed
+/// at once, each in a single task on the blocking pool; whichever finishes first has its blocks pushed into the bounded
+/// channel, so files may arrive out of order but the blocks of one file are never interleaved.
+/// Transactions are hashed on `hash_pool` rather than rayon's global pool.
+///
+/// A file takes one of `files_in_memory` permits before it is read and gives it back once all of
+/// its blocks are in the channel, so no more than that many files' blocks are held at once,
+/// whatever `read_workers` and the number of inserters are.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_reader(
+    file_reader: Arc<FileReader>,
+    file_indexes: Vec<usize>,
+    read_workers: usize,
+    files_in_memory: usize,
+    sender: Sender<Message>,
+    metrics: Arc<Metrics>,
+    hash_pool: Arc<ThreadPool>,
+    strict: bool,
+) -> JoinHandle<()> {
+    let permits = Arc::new(Semaphore::new(files_in_memory.max(1)));
+    tokio::spawn(async move {
+        let mut files = stream::iter(file_indexes)
+            .map(|file_index| {
+                let file_reader = Arc::clone(&file_reader);
+                let metrics = Arc::clone(&metrics);
+                let hash_pool = Arc::clone(&hash_pool);
+                let permits = Arc::clone(&permits);
+                async move {
+                    let permit = FilePermit::acquire(permits, Arc::clone(&metrics)).await;
+                    let task = spawn_blocking(move || {
+                        hash_pool.install(|| process_file(&file_reader, file_index, &metrics, strict))
+                    });
+                    (file_index, task.await, permit)
+                }
+            })
+            .buffer_unordered(read_workers);
+
+        // The permit is held until the end of the iteration, after the file's last message
+        while let Some((file_index, result, _permit)) = files.next().await {
+            let (blocks, rejected_blocks) = match result {
+                Ok(Ok(blocks)) => blocks,
+                Ok(Err(e)) => {
+                    if !send(&sender, Message::FileFailed(file_index, e), &metrics).await {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    let e = ParserError::Io(io::Error::other(e));
+                    if !send(&sender, Message::FileFailed(file_index, e), &metrics).await {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            for block in blocks {
+                if !send(&sender, Message::Block(block), &metrics).await {
+                    // The consumer has gone away, nothing left to read for
+                    return;
+                }
+            }
+            if !send(&sender, Message::FileDone(file_index, rejected_blocks), &metrics).await {
+                return;
+            }
+        }
+    })
+}
+
+/// Spawns a reader for blocks ordered by `Chain::order_headers`, which reads them
+/// `ORDERED_CHUNK_BLOCKS` at a time, in that order, jumping between files as needed. Messages are those of
+/// `spawn_reader`, with `FileDone` sent after every chunk for the file of its last block; the
+/// chunks don't line up with files, so the checkpoint can't be kept this way.
+pub fn spawn_ordered_reader(
+    file_reader: Arc<FileReader>,
+    headers: Vec<BlockHeader>,
+    sender: Sender<Message>,
+    metrics: Arc<Metrics>,
+    hash_pool: Arc<ThreadPool>,
+    strict: bool,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let locations: Vec<(usize, u64)> = headers.iter().map(|header| (header.file_index, header.byte_offset)).collect();
+        for chunk in locations.chunks(ORDERED_CHUNK_BLOCKS) {
+            let file_index = chunk[chunk.len() - 1].0;
+            let (chunk, file_reader, task_metrics, hash_pool) = (chunk.to_vec(), Arc::clone(&file_reader), Arc::clone(&metrics), Arc::clone(&hash_pool));
+            let task = spawn_blocking(move || {
+                hash_pool.install(|| Ok::<_, ParserError>(process_blocks(file_reader.read_blocks_at(&chunk)?, file_reader.network(), &task_metrics, strict)))
+            });
+            let message = match task.await {
+                Ok(Ok((blocks, rejected_blocks))) => {
+                    for block in blocks {
+                        if !send(&sender, Message::Block(block), &metrics).await {
+                            return;
+                        }
+                    }
+                    Message::FileDone(file_index, rejected_blocks)
+                }
+                Ok(Err(e)) => Message::FileFailed(file_index, e),
+                Err(e) => Message::FileFailed(file_index, ParserError::Io(io::Error::other(e))),
+            };
+            if !send(&sender, message, &metrics).await {
+                return;
+            }
+        }
+    })
+}
+
+/// Spawns a reader that follows a blocks directory a node is still writing to, starting at the
+/// record at `offset` in the file at `file_index`. Every `poll_interval`, the file is read again
+/// from where the last read stopped if its size or modification time changed, since nodes
+/// preallocate blk files and write blocks into the space they reserved. Once a newer file shows up
+/// the current one is read one last time and the newer one followed from its start. Messages are
+/// the same as those of `spawn_reader`, with `FileDone` sent after every read that found blocks.
+/// Runs until the consumer goes away.
+pub fn spawn_watcher(
+    file_reader: Arc<FileReader>,
+    (mut file_index, mut offset): (usize, u64),
+    poll_interval: Duration,
+    sender: Sender<Message>,
+    metrics: Arc<Metrics>,
+    hash_pool: Arc<ThreadPool>,
+    strict: bool,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_seen: Option<(u64, SystemTime)> = None;
+        let mut ticker = interval(poll_interval);
+        loop {
+            // The first tick is immediate, to pick up what was appended before watching started
+            ticker.tick().await;
+            let paths = match file_reader.list_files() {
+                Ok(paths) => paths,
+                Err(e) => {
+                    warn!(error = %e, "Failed to list block files");
+                    continue;
+                }
+            };
+
+            while let Some(path) = paths.get(file_index) {
+                let seen = std::fs::metadata(path).and_then(|metadata| Ok((metadata.len(), metadata.modified()?)));
+                if seen.as_ref().ok() != last_seen.as_ref() {
+                    last_seen = seen.ok();
+
+                    let (path, file_reader, task_metrics, hash_pool) = (path.clone(), Arc::clone(&file_reader), Arc::clone(&metrics), Arc::clone(&hash_pool));
+                    let task = spawn_blocking(move || {
+                        hash_pool.install(|| {
+                            let _span = debug_span!("file", index = file_index).entered();
+                            let (blocks, end) = file_reader.read_file_from(&path, offset)?;
+                            Ok::<_, ParserError>((process_blocks(blocks, file_reader.network(), &task_metrics, strict), end))
+                        })
+                    });
+                    let message = match task.await {
+                        Ok(Ok(((blocks, rejected_blocks), end))) => {
+                            debug!(file_index, offset, end, blocks = blocks.len(), "Read appended blocks");
+                            offset = end;
+                            for block in blocks {
+                                if !send(&sender, Message::Block(block), &metrics).await {
+                                    return;
+                                }
+                            }
+                            Message::FileDone(file_index, rejected_blocks)
+                        }
+                        Ok(Err(e)) => Message::FileFailed(file_index, e),
+                        Err(e) => Message::FileFailed(file_index, ParserError::Io(io::Error::other(e))),
+                    };
+                    if !send(&sender, message, &metrics).await {
+                        return;
+                    }
+                }
+
+                // Nodes only start a new file once the current one is full
+                if file_index + 1 >= paths.len() {
+                    break;
+                }
+                file_index += 1;
+                offset = 0;
+                last_seen = None;
+            }
+        }
+    })
+}
+
+/// One of the permits of `spawn_reader`, counted in `Metrics::files_in_memory` while held.
+struct FilePermit {
+    _permit: OwnedSemaphorePermit,
+    metrics: Arc<Metrics>,
+}
+
+impl FilePermit {
+    async fn acquire(permits: Arc<Semaphore>, metrics: Arc<Metrics>) -> Self {
+        let permit = permits.acquire_owned().await.expect("The semaphore is never closed");
+        metrics.files_in_memory.fetch_add(1, Ordering::Relaxed);
+        Self { _permit: permit, metrics }
+    }
+}
+
+impl Drop for FilePermit {
+    fn drop(&mut self) {
+        self.metrics.files_in_memory.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sends a message to the inserters, waiting for room in the channel while they are behind, and
+/// records how long that took and how full the channel is. Returns `false` once the consumer has
+/// gone away.
+async fn send(sender: &Sender<Message>, message: Message, metrics: &Metrics) -> bool {
+    let sent = match sender.try_send(message) {
+        Ok(()) => true,
+        Err(TrySendError::Closed(_)) => false,
+        Err(TrySendError::Full(message)) => {
+            let started = Instant::now();
+            let sent = sender.send(message).await.is_ok();
+            add_elapsed(&metrics.reader_wait_micros, started.elapsed());
+            sent
+        }
+    };
+    metrics.queued_messages.store(sender.max_capacity() - sender.capacity(), Ordering::Relaxed);
+    sent
+}
+
+fn process_file(
+    file_reader: &FileReader,
+    file_index: usize,
+    metrics: &Metrics,
+    strict: bool,
+) -> Result<(Vec<Block>, usize), ParserError> {
+    let _span = debug_span!("file", index = file_index).entered();
+    let blocks = file_reader.read_file(file_index)?;
+    Ok(process_blocks(blocks, file_reader.network(), metrics, strict))
+}
+
+/// Counts and processes the blocks read from a file, returning the valid ones and how many were
+/// rejected.
+fn process_blocks(blocks: Vec<Block>, network: Network, metrics: &Metrics, strict: bool) -> (Vec<Block>, usize) {
+    let mut processed_blocks = Vec::with_capacity(blocks.len());
+    let mut rejected_blocks = 0;
+    for block in blocks {
+        metrics.blocks.fetch_add(1, Ordering::Relaxed);
+        metrics.transactions.fetch_add(block.transactions.len(), Ordering::Relaxed);
+        for tx in &block.transactions {
+            metrics.inputs.fetch_add(tx.inputs.len(), Ordering::Relaxed);
+            metrics.outputs.fetch_add(tx.outputs.len(), Ordering::Relaxed);
+        }
+
+        let started = Instant::now();
+        let result = process_block(block, network, strict);
+        add_elapsed(&metrics.process_micros, started.elapsed());
+        match result {
+            Ok(processed_block) => processed_blocks.push(processed_block),
+            Err(e) => {
+                if let ParserError::InvalidProofOfWork { .. } = e {
+                    metrics.pow_failures.fetch_add(1, Ordering::Relaxed);
+                }
+                metrics.skipped_blocks.fetch_add(1, Ordering::Relaxed);
+                rejected_blocks += 1;
+                warn!(error = %e, "Skipping invalid block");
+            }
+        }
+    }
+
+    (processed_blocks, rejected_blocks)
+}
+
+/// Blocks, each along with the index of the file it was read from.
+pub type FileBlocks = Vec<(usize, Block)>;
+
+/// Splits blocks into those within `height_range`, boundaries included, and those outside it.
+pub fn partition_by_height(blocks: FileBlocks, height_range: &RangeInclusive<i32>) -> (FileBlocks, FileBlocks) {
+    blocks.into_iter().partition(|(_, block)| height_range.contains(&block.height))
+}
+
+/// Drops the blocks of a file that were already read, because they are in `chain`, waiting in
+/// `pending` for their parent, or earlier in the same file, keeping the first copy. Overlapping
+/// blk files can repeat blocks, in one run or across runs. Returns how many were dropped.
+pub fn drop_seen_blocks(file_blocks: &mut Vec<Block>, chain: &Chain, pending: &[(usize, Block)]) -> usize {
+    let mut seen: HashSet<String> = pending.iter().map(|(_, block)| block.block_hash.clone()).collect();
+    let read_blocks = file_blocks.len();
+    file_blocks.retain(|block| !chain.contains(&block.block_hash) && seen.insert(block.block_hash.clone()));
+    read_blocks - file_blocks.len()
+}
+
+/// Decides how far the `progress` checkpoint may advance when files are read and inserted out of
+/// order. A file counts as done once every block read from it has been committed, and the
+/// checkpoint only moves across a contiguous run of done files. Files that failed to read or
+/// insert never become done, which holds the checkpoint in front of them.
+pub struct CheckpointTracker {
+    next_file: usize,
+    hold: Option<usize>,
+    outstanding: BTreeMap<usize, usize>,
+    committed_blocks: i64,
+    completed: Vec<usize>,
+}
+
+impl CheckpointTracker {
+    pub fn new(start_file: usize, committed_blocks: i64) -> Self {
+        Self { next_file: start_file, hold: None, outstanding: BTreeMap::new(), committed_blocks, completed: Vec::new() }
+    }
+
+    /// Keeps the checkpoint in front of `file_index`, e.g. a file a node is still appending to, which
+    /// has to be read again when resuming. Only ever moves the hold forward.
+    pub fn hold_at(&mut self, file_index: usize) {
+        self.hold = Some(self.hold.map_or(file_index, |hold| hold.max(file_index)));
+    }
+
+    /// Records that `block_count` blocks were read from a file and still have to be committed. A
+    /// file can be read more than once, in parts, when it is being watched.
+    pub fn file_read(&mut self, file_index: usize, block_count: usize) {
+        let outstanding = self.outstanding.entry(file_index).or_insert(0);
+        *outstanding += block_count;
+        if *outstanding == 0 {
+            self.completed.push(file_index);
+        }
+    }
+
+    /// Records a committed batch, given the file each of its blocks was read from.
+    pub fn blocks_committed(&mut self, block_files: &[usize]) {
+        for file_index in block_files {
+            if let Some(count) = self.outstanding.get_mut(file_index) {
+                *count -= 1;
+                if *count == 0 {
+                    self.completed.push(*file_index);
+                }
+            }
+        }
+        self.committed_blocks += block_files.len() as i64;
+    }
+
+    /// Files that became done since the last call, in any order.
+    pub fn take_completed(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.completed)
+    }
+
+    /// Moves past every leading file that is done and returns the new checkpoint, if it moved.
+    pub fn advance(&mut self) -> Option<(usize, i64)> {
+        let start = self.next_file;
+        while self.outstanding.get(&self.next_file) == Some(&0) && self.hold.is_none_or(|hold| self.next_file < hold) {
+            self.outstanding.remove(&self.next_file);
+            self.next_file += 1;
+        }
+        (self.next_file > start).then(|| (self.next_file - 1, self.committed_blocks))
+    }
+}
@@ -0,0 +1,36 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shows the files read out of `total_files`, with the transaction rate and an ETA, on stderr.
+/// The bar is hidden when stderr isn't a terminal, so piped and logged runs get no escape codes.
+/// It is redrawn from `metrics` until `finish` is called on the returned bar.
+pub fn spawn_progress_bar(metrics: Arc<Metrics>, total_files: usize) -> ProgressBar {
+    let bar = ProgressBar::new(total_files as u64);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} files, {msg}, ETA {eta}")
+            .expect("Progress bar template is valid"),
+    );
+
+    let progress = bar.clone();
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        while !progress.is_finished() {
+            interval.tick().await;
+
+            let blocks = metrics.blocks.load(Ordering::Relaxed);
+            let transactions = metrics.transactions.load(Ordering::Relaxed);
+            let tx_per_second = transactions as f64 / started.elapsed().as_secs_f64().max(1.0);
+            progress.set_position(metrics.files_read.load(Ordering::Relaxed) as u64);
+            progress.set_message(format!("{} blocks, {:.0} tx/s", blocks, tx_per_second));
+        }
+    });
+
+    bar
+}
@@ -0,0 +1,281 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use sha2::{Digest, Sha256};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::address::script_to_address;
+use crate::error::{ParserError, MAX_VAR_INT};
+use crate::models::Block;
+use crate::network::Network;
+
+/// Bytes behind every record in a rev file: the double SHA256 of the parent block hash and the
+/// undo data, which ties the record to its block.
+const CHECKSUM_SIZE: usize = 32;
+
+/// Scripts longer than this are stored by Core as a single OP_RETURN, since they can't be spent.
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+/// Number of special script encodings in front of the lengths of scripts stored as they are.
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// An output spent by an input, as Core records it to undo the spend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpentOutput {
+    /// Height of the block that created the output.
+    pub height: u32,
+    pub is_coinbase: bool,
+    pub value: i64,
+    /// `None` for scripts without a standard address form, including P2PK.
+    pub address: Option<String>,
+}
+
+/// The undo data of every block in one rev file, to be matched with the blocks of its blk file.
+/// Core writes a block's undo data when it connects the block, so records are in connection
+/// order rather than in the order of the blk file.
+pub struct UndoFile {
+    records: Vec<UndoRecord>,
+    /// Where the next match is looked for first, just past the previous one.
+    next: usize,
+}
+
+struct UndoRecord {
+    bytes: Vec<u8>,
+    checksum: [u8; CHECKSUM_SIZE],
+    /// Taken once the record is matched with its block.
+    spent: Option<Vec<Vec<SpentOutput>>>,
+}
+
+/// The rev file holding the undo data of the blocks in an uncompressed `blkNNNNN.dat`.
+pub fn rev_path(blk_path: &Path) -> Option<PathBuf> {
+    let name = blk_path.file_name()?.to_str()?;
+    let number = name.strip_prefix("blk")?.strip_suffix(".dat")?;
+    Some(blk_path.with_file_name(format!("rev{}.dat", number)))
+}
+
+impl UndoFile {
+    /// Reads every record of a rev file. Blocking, like `FileReader::read_file`.
+    pub fn read(path: &Path, network: Network) -> Result<Self, ParserError> {
+        Self::from_bytes(&std::fs::read(path)?, network)
+    }
+
+    /// Reads records up to the zero padding at the end of the file, or up to a record the node
+    /// is still writing. Records whose undo data doesn't parse are logged and left out.
+    pub fn from_bytes(bytes: &[u8], network: Network) -> Result<Self, ParserError> {
+        let reader = &mut Cursor::new(bytes);
+        let mut records = Vec::new();
+        loop {
+            let offset = reader.position();
+            let magic = match reader.read_u32::<LittleEndian>() {
+                Ok(0) => break,
+                Ok(magic) => magic,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            if magic != network.magic() {
+                return Err(ParserError::MagicMismatch { expected: network, found: magic, offset });
+            }
+
+            let mut record = || -> io::Result<(Vec<u8>, [u8; CHECKSUM_SIZE])> {
+                let size = reader.read_u32::<LittleEndian>()? as usize;
+                let start = reader.position() as usize;
+                let undo = bytes.get(start..start + size).ok_or(io::ErrorKind::UnexpectedEof)?.to_vec();
+                reader.set_position((start + size) as u64);
+                let mut checksum = [0; CHECKSUM_SIZE];
+                reader.read_exact(&mut checksum)?;
+                Ok((undo, checksum))
+            };
+            let (undo, checksum) = match record() {
+                Ok(record) => record,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            match parse_block_undo(&undo, network) {
+                Ok(spent) => records.push(UndoRecord { bytes: undo, checksum, spent: Some(spent) }),
+                Err(e) => warn!(offset, error = %e, "Skipping malformed undo record"),
+            }
+        }
+
+        Ok(Self { records, next: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Finds the undo record of `block` and fills `spent_value` and `spent_address` of its inputs
+    /// from it. A record matches when its checksum does and it has an output for every input
+    /// but the coinbase. Returns whether one was found; the genesis block, and blocks the node
+    /// never connected, have none. Each record is only used once.
+    pub fn apply(&mut self, block: &mut Block) -> bool {
+        let Some(previous_block) = hex::decode(&block.previous_block).ok() else {
+            return false;
+        };
+        let previous_block: Vec<u8> = previous_block.into_iter().rev().collect();
+        let spends: Vec<usize> = block.transactions.iter().skip(1).map(|tx| tx.inputs.len()).collect();
+
+        let count = self.records.len();
+        let found = (self.next..count).chain(0..self.next).find(|&index| {
+            let record = &self.records[index];
+            record.spent.as_ref().is_some_and(|spent| {
+                spent.len() == spends.len() && spent.iter().zip(&spends).all(|(outputs, &inputs)| outputs.len() == inputs)
+            }) && checksum(&previous_block, &record.bytes) == record.checksum
+        });
+        let Some(index) = found else {
+            return false;
+        };
+
+        self.next = index + 1;
+        let spent = self.records[index].spent.take().unwrap_or_default();
+        for (tx, outputs) in block.transactions.iter_mut().skip(1).zip(spent) {
+            for (input, output) in tx.inputs.iter_mut().zip(outputs) {
+                input.spent_value = Some(output.value);
+                input.spent_address = output.address;
+            }
+        }
+        true
+    }
+}
+
+fn checksum(previous_block: &[u8], undo: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(previous_block);
+    hasher.update(undo);
+    Sha256::digest(hasher.finalize()).into()
+}
+
+/// Parses the undo data of one block (Core's `CBlockUndo`), without the record framing: for each
+/// transaction but the coinbase, the outputs its inputs spent, in input order.
+pub fn parse_block_undo(bytes: &[u8], network: Network) -> Result<Vec<Vec<SpentOutput>>, ParserError> {
+    let reader = &mut Cursor::new(bytes);
+    let tx_count = read_compact_size(reader)?;
+    let mut transactions = Vec::with_capacity(tx_count.min(bytes.len() as u64) as usize);
+    for _ in 0..tx_count {
+        let output_count = read_compact_size(reader)?;
+        let mut outputs = Vec::with_capacity(output_count.min(bytes.len() as u64) as usize);
+        for _ in 0..output_count {
+            outputs.push(read_spent_output(reader, network)?);
+        }
+        transactions.push(outputs);
+    }
+
+    if reader.position() != bytes.len() as u64 {
+        return Err(ParserError::MalformedUndo(format!("{} bytes left over", bytes.len() as u64 - reader.position())));
+    }
+    Ok(transactions)
+}
+
+fn read_spent_output(reader: &mut Cursor<&[u8]>, network: Network) -> Result<SpentOutput, ParserError> {
+    let code = read_var_int(reader)?;
+    let height = u32::try_from(code >> 1).map_err(|_| ParserError::MalformedUndo(format!("Height {} out of range", code >> 1)))?;
+    if height > 0 {
+        // Transaction version, no longer written but kept in the format
+        read_var_int(reader)?;
+    }
+
+    let value = decompress_amount(read_var_int(reader)?)?;
+    let script = read_compressed_script(reader)?;
+    Ok(SpentOutput {
+        height,
+        is_coinbase: code & 1 == 1,
+        value,
+        address: script.and_then(|script| script_to_address(&script, network)),
+    })
+}
+
+/// Reads a scriptPubKey in Core's compressed form. Returns `None` for P2PK with an uncompressed
+/// key, which is stored as its x coordinate and would take elliptic curve math to restore, and
+/// for scripts too long to be spent; neither has an address.
+fn read_compressed_script(reader: &mut Cursor<&[u8]>) -> Result<Option<Vec<u8>>, ParserError> {
+    let kind = read_var_int(reader)?;
+    let script = match kind {
+        0 => [&[0x76, 0xa9, 20][..], &read_array::<20>(reader)?, &[0x88, 0xac]].concat(),
+        1 => [&[0xa9, 20][..], &read_array::<20>(reader)?, &[0x87]].concat(),
+        2 | 3 => [&[33, kind as u8][..], &read_array::<32>(reader)?, &[0xac]].concat(),
+        4 | 5 => {
+            read_array::<32>(reader)?;
+            return Ok(None);
+        }
+        _ => {
+            let size = kind - SPECIAL_SCRIPTS;
+            let remaining = reader.get_ref().len() as u64 - reader.position();
+            if size > remaining {
+                return Err(ParserError::UnexpectedEof);
+            }
+            let start = reader.position() as usize;
+            reader.set_position(reader.position() + size);
+            if size > MAX_SCRIPT_SIZE {
+                return Ok(None);
+            }
+            reader.get_ref()[start..start + size as usize].to_vec()
+        }
+    };
+    Ok(Some(script))
+}
+
+fn read_array<const N: usize>(reader: &mut Cursor<&[u8]>) -> Result<[u8; N], ParserError> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads a count in the CompactSize encoding transactions use.
+fn read_compact_size(reader: &mut Cursor<&[u8]>) -> Result<u64, ParserError> {
+    let (value, min) = match reader.read_u8()? {
+        0xFD => (reader.read_u16::<LittleEndian>()? as u64, 0xFD),
+        0xFE => (reader.read_u32::<LittleEndian>()? as u64, 0x1_0000),
+        0xFF => (reader.read_u64::<LittleEndian>()?, 0x1_0000_0000),
+        first => (first as u64, 0),
+    };
+    if value < min {
+        return Err(ParserError::NonCanonicalVarInt(value));
+    }
+    if value > MAX_VAR_INT {
+        return Err(ParserError::VarIntOverflow(value));
+    }
+    Ok(value)
+}
+
+/// Reads Core's `VARINT`: base 128 digits, most significant first, with the high bit set on all
+/// but the last and one subtracted from every digit but the last, so each number has one encoding.
+fn read_var_int(reader: &mut Cursor<&[u8]>) -> Result<u64, ParserError> {
+    let mut value: u64 = 0;
+    loop {
+        let byte = reader.read_u8()?;
+        if value > u64::MAX >> 7 {
+            return Err(ParserError::MalformedUndo("VARINT too large".to_string()));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        value = value.checked_add(1).ok_or_else(|| ParserError::MalformedUndo("VARINT too large".to_string()))?;
+    }
+}
+
+/// Reverses Core's `CompressAmount`, which stores trailing decimal zeros as an exponent.
+fn decompress_amount(compressed: u64) -> Result<i64, ParserError> {
+    if compressed == 0 {
+        return Ok(0);
+    }
+    let mut x = compressed - 1;
+    let exponent = x % 10;
+    x /= 10;
+    let mut value = if exponent < 9 {
+        let digit = x % 9 + 1;
+        x /= 9;
+        x.checked_mul(10).and_then(|x| x.checked_add(digit))
+    } else {
+        x.checked_add(1)
+    };
+    for _ in 0..exponent {
+        value = value.and_then(|value| value.checked_mul(10));
+    }
+    value.and_then(|value| i64::try_from(value).ok())
+        .ok_or_else(|| ParserError::MalformedUndo(format!("Compressed amount {} out of range", compressed)))
+}
@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Multisig,
+    OpReturn,
+    #[serde(rename = "nonstandard")]
+    NonStandard,
+}
+
+impl ScriptType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::Multisig => "multisig",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::NonStandard => "nonstandard",
+        }
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+pub fn classify_script(script: &[u8]) -> ScriptType {
+    match script {
+        [0x21, key @ .., OP_CHECKSIG] if key.len() == 33 => ScriptType::P2pk,
+        [0x41, key @ .., OP_CHECKSIG] if key.len() == 65 => ScriptType::P2pk,
+        [OP_DUP, OP_HASH160, 0x14, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => ScriptType::P2pkh,
+        [OP_HASH160, 0x14, hash @ .., OP_EQUAL] if hash.len() == 20 => ScriptType::P2sh,
+        [OP_0, 0x14, program @ ..] if program.len() == 20 => ScriptType::P2wpkh,
+        [OP_0, 0x20, program @ ..] if program.len() == 32 => ScriptType::P2wsh,
+        [OP_1, 0x20, program @ ..] if program.len() == 32 => ScriptType::P2tr,
+        [OP_RETURN, ..] => ScriptType::OpReturn,
+        _ if parse_multisig(script).is_some() => ScriptType::Multisig,
+        _ => ScriptType::NonStandard,
+    }
+}
+
+/// The m-of-n parameters and public keys of a multisig script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multisig {
+    /// Signatures needed, `m`.
+    pub required: u8,
+    /// Keys that may sign, `n`, in script order. Each is 33 bytes (compressed) or 65 (uncompressed).
+    pub pubkeys: Vec<Vec<u8>>,
+}
+
+/// Matches the multisig template `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`, used by bare multisig
+/// outputs and as a P2SH redeem script. Keys are judged by their push length alone.
+pub fn parse_multisig(script: &[u8]) -> Option<Multisig> {
+    let (&first, rest) = script.split_first()?;
+    let (&last, rest) = rest.split_last()?;
+    let (&total, mut keys) = rest.split_last()?;
+
+    if last != OP_CHECKMULTISIG || !(OP_1..=OP_16).contains(&first) || !(OP_1..=OP_16).contains(&total) {
+        return None;
+    }
+
+    let required = first - OP_1 + 1;
+    let total = total - OP_1 + 1;
+    if required > total {
+        return None;
+    }
+
+    let mut pubkeys = Vec::with_capacity(total as usize);
+    while let Some((&length, remainder)) = keys.split_first() {
+        if (length != 33 && length != 65) || remainder.len() < length as usize {
+            return None;
+        }
+        pubkeys.push(remainder[..length as usize].to_vec());
+        keys = &remainder[length as usize..];
+    }
+
+    (pubkeys.len() == total as usize).then_some(Multisig { required, pubkeys })
+}
+
+/// Matches a P2SH spend of a multisig redeem script: a scriptSig that only pushes data, the last
+/// push being the redeem script. The output being spent isn't known while parsing, so any
+/// push-only scriptSig ending in a multisig script counts.
+pub fn extract_p2sh_multisig(script_sig: &[u8]) -> Option<Multisig> {
+    let (pushes, remainder) = read_pushes(script_sig);
+    if !remainder.is_empty() {
+        return None;
+    }
+    parse_multisig(pushes.last()?)
+}
+
+/// Returns the data an OP_RETURN script pushes after the opcode, one item per push, as read by
+/// `read_pushes`. `None` means the script isn't an OP_RETURN at all.
+pub fn op_return_data(script: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let (&OP_RETURN, rest) = script.split_first()? else {
+        return None;
+    };
+    Some(read_pushes(rest).0)
+}
+
+/// Shortest run of printable characters `extract_coinbase_tag` keeps, so that stray bytes of
+/// extranonces and heights that happen to be printable don't show up as text.
+const MIN_TAG_LENGTH: usize = 4;
+
+/// Pulls the printable ASCII text out of a coinbase scriptSig, such as the `/ViaBTC/` or
+/// `/F2Pool/` tags pools put there. Pushed data is searched rather than the raw script, so push
+/// lengths that happen to be printable aren't mistaken for text. Runs of at least four printable
+/// characters are trimmed and joined with a space.
+pub fn extract_coinbase_tag(script_sig: &[u8]) -> Option<String> {
+    let (pushes, remainder) = read_pushes(script_sig);
+    let runs: Vec<String> = pushes
+        .iter()
+        .map(Vec::as_slice)
+        .chain(std::iter::once(remainder))
+        .flat_map(|data| data.split(|byte| !(0x20..=0x7e).contains(byte)))
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|run| run.len() >= MIN_TAG_LENGTH)
+        .collect();
+
+    if runs.is_empty() {
+        None
+    } else {
+        Some(runs.join(" "))
+    }
+}
+
+/// Reads the data a script pushes, one item per push, and returns it along with whatever is left
+/// unread. `OP_0` pushes an empty item and `OP_1NEGATE`/`OP_1`..`OP_16` push their number as a
+/// one-byte script number, as they would on the stack. Reading stops at the first opcode that
+/// isn't a push or at a push that runs past the end of the script.
+fn read_pushes(mut script: &[u8]) -> (Vec<Vec<u8>>, &[u8]) {
+    let mut pushes = Vec::new();
+    while let Some((op, rest)) = read_op(script) {
+        let data = match op {
+            Op::Push(data) => data,
+            Op::Code(OP_0) => Vec::new(),
+            Op::Code(OP_1NEGATE) => vec![0x81],
+            Op::Code(opcode @ OP_1..=OP_16) => vec![opcode - OP_1 + 1],
+            _ => break,
+        };
+        pushes.push(data);
+        script = rest;
+    }
+
+    (pushes, script)
+}
+
+/// One step of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Data pushed directly (1 to 75 bytes) or with `OP_PUSHDATA1`/`2`/`4`.
+    Push(Vec<u8>),
+    /// Any other opcode, including `OP_0` and the small-number opcodes.
+    Code(u8),
+    /// A push whose length or data runs past the end of the script, holding everything from its opcode on.
+    Truncated(Vec<u8>),
+}
+
+/// Splits a script into its opcodes and pushes. A truncated push ends the script as `Op::Truncated`
+/// instead of failing, since output scripts are never checked when they are created.
+pub fn disassemble(mut script: &[u8]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    while let Some((op, rest)) = read_op(script) {
+        ops.push(op);
+        script = rest;
+    }
+    ops
+}
+
+/// Renders a script as assembly, e.g. `OP_DUP OP_HASH160 <hex> OP_EQUALVERIFY OP_CHECKSIG`, with
+/// pushed data in hex. A truncated push is shown as `[error]`, as Bitcoin Core does.
+pub fn script_asm(script: &[u8]) -> String {
+    disassemble(script)
+        .iter()
+        .map(|op| match op {
+            Op::Push(data) if data.is_empty() => "OP_0".to_string(),
+            Op::Push(data) => hex::encode(data),
+            Op::Code(opcode) => opcode_name(*opcode).to_string(),
+            Op::Truncated(_) => "[error]".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn read_op(script: &[u8]) -> Option<(Op, &[u8])> {
+    let (&opcode, rest) = script.split_first()?;
+    let (length, rest) = match opcode {
+        0x01..=0x4b => (Some(opcode as usize), rest),
+        OP_PUSHDATA1 => match rest {
+            [length, rest @ ..] => (Some(*length as usize), rest),
+            _ => (None, rest),
+        },
+        OP_PUSHDATA2 => match rest {
+            [a, b, rest @ ..] => (Some(u16::from_le_bytes([*a, *b]) as usize), rest),
+            _ => (None, rest),
+        },
+        OP_PUSHDATA4 => match rest {
+            [a, b, c, d, rest @ ..] => (Some(u32::from_le_bytes([*a, *b, *c, *d]) as usize), rest),
+            _ => (None, rest),
+        },
+        _ => return Some((Op::Code(opcode), rest)),
+    };
+
+    match length.and_then(|length| Some((rest.get(..length)?, &rest[length..]))) {
+        Some((data, rest)) => Some((Op::Push(data.to_vec()), rest)),
+        None => Some((Op::Truncated(script.to_vec()), &[])),
+    }
+}
+
+/// The name Bitcoin Core gives an opcode that isn't a data push.
+pub fn opcode_name(opcode: u8) -> &'static str {
+    const SMALL_NUMBERS: [&str; 16] = [
+        "OP_1", "OP_2", "OP_3", "OP_4", "OP_5", "OP_6", "OP_7", "OP_8",
+        "OP_9", "OP_10", "OP_11", "OP_12", "OP_13", "OP_14", "OP_15", "OP_16",
+    ];
+    const NAMES: [&str; (0xba - 0x61 + 1) as usize] = [
+        "OP_NOP", "OP_VER", "OP_IF", "OP_NOTIF", "OP_VERIF", "OP_VERNOTIF", "OP_ELSE", "OP_ENDIF",
+        "OP_VERIFY", "OP_RETURN", "OP_TOALTSTACK", "OP_FROMALTSTACK", "OP_2DROP", "OP_2DUP", "OP_3DUP", "OP_2OVER",
+        "OP_2ROT", "OP_2SWAP", "OP_IFDUP", "OP_DEPTH", "OP_DROP", "OP_DUP", "OP_NIP", "OP_OVER",
+        "OP_PICK", "OP_ROLL", "OP_ROT", "OP_SWAP", "OP_TUCK", "OP_CAT", "OP_SUBSTR", "OP_LEFT",
+        "OP_RIGHT", "OP_SIZE", "OP_INVERT", "OP_AND", "OP_OR", "OP_XOR", "OP_EQUAL", "OP_EQUALVERIFY",
+        "OP_RESERVED1", "OP_RESERVED2", "OP_1ADD", "OP_1SUB", "OP_2MUL", "OP_2DIV", "OP_NEGATE", "OP_ABS",
+        "OP_NOT", "OP_0NOTEQUAL", "OP_ADD", "OP_SUB", "OP_MUL", "OP_DIV", "OP_MOD", "OP_LSHIFT",
+        "OP_RSHIFT", "OP_BOOLAND", "OP_BOOLOR", "OP_NUMEQUAL", "OP_NUMEQUALVERIFY", "OP_NUMNOTEQUAL", "OP_LESSTHAN", "OP_GREATERTHAN",
+        "OP_LESSTHANOREQUAL", "OP_GREATERTHANOREQUAL", "OP_MIN", "OP_MAX", "OP_WITHIN", "OP_RIPEMD160", "OP_SHA1", "OP_SHA256",
+        "OP_HASH160", "OP_HASH256", "OP_CODESEPARATOR", "OP_CHECKSIG", "OP_CHECKSIGVERIFY", "OP_CHECKMULTISIG", "OP_CHECKMULTISIGVERIFY", "OP_NOP1",
+        "OP_CHECKLOCKTIMEVERIFY", "OP_CHECKSEQUENCEVERIFY", "OP_NOP4", "OP_NOP5", "OP_NOP6", "OP_NOP7", "OP_NOP8", "OP_NOP9",
+        "OP_NOP10", "OP_CHECKSIGADD",
+    ];
+
+    match opcode {
+        OP_0 => "OP_0",
+        OP_PUSHDATA1 => "OP_PUSHDATA1",
+        OP_PUSHDATA2 => "OP_PUSHDATA2",
+        OP_PUSHDATA4 => "OP_PUSHDATA4",
+        OP_1NEGATE => "OP_1NEGATE",
+        0x50 => "OP_RESERVED",
+        OP_1..=OP_16 => SMALL_NUMBERS[(opcode - OP_1) as usize],
+        0x61..=0xba => NAMES[(opcode - 0x61) as usize],
+        _ => "OP_UNKNOWN",
+    }
+}
+
+/// The signature and public key revealed by an input that spends a single-key template.
+/// The signature is kept exactly as it appears, DER encoding plus sighash byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySpend {
+    pub signature: Vec<u8>,
+    pub pubkey: Vec<u8>,
+}
+
+/// Matches the P2PKH spending template `<signature> <pubkey>` in a scriptSig.
+pub fn extract_scriptsig_pubkey(script_sig: &[u8]) -> Option<KeySpend> {
+    let ops = disassemble(script_sig);
+    let [Op::Push(signature), Op::Push(pubkey)] = ops.as_slice() else {
+        return None;
+    };
+    (is_signature(signature) && is_pubkey(pubkey)).then(|| KeySpend { signature: signature.clone(), pubkey: pubkey.clone() })
+}
+
+/// Matches a P2WPKH spend, native (empty scriptSig) or nested in P2SH (a scriptSig that only pushes
+/// the `OP_0 <20 bytes>` program), whose witness is `<signature> <compressed pubkey>`.
+pub fn extract_witness_pubkey(script_sig: &[u8], witness: &[Vec<u8>]) -> Option<KeySpend> {
+    let native = script_sig.is_empty();
+    let nested = matches!(disassemble(script_sig).as_slice(), [Op::Push(program)] if program.len() == 22 && program[..2] == [OP_0, 0x14]);
+    let [signature, pubkey] = witness else {
+        return None;
+    };
+    ((native || nested) && is_signature(signature) && is_pubkey(pubkey) && pubkey.len() == 33)
+        .then(|| KeySpend { signature: signature.clone(), pubkey: pubkey.clone() })
+}
+
+/// Leaf version of tapscript (BIP342), the only one defined so far.
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+/// First byte of the optional annex that may end a Taproot witness (BIP341).
+const ANNEX_TAG: u8 = 0x50;
+/// Control blocks hold the internal key and up to 128 hashes of the script tree path.
+const MAX_TAPROOT_PATH_LENGTH: usize = 128;
+
+/// How an input spends a Taproot output, as laid out in its witness (BIP341).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaprootSpend {
+    pub path: TaprootPath,
+    pub annex: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaprootPath {
+    /// A Schnorr signature for the output key: 64 bytes, plus a sighash byte unless it is the default.
+    Key { signature: Vec<u8> },
+    /// A tapscript revealed along with the internal key from the control block that commits to it.
+    Script { leaf_version: u8, internal_key: Vec<u8>, script: Vec<u8> },
+}
+
+/// Matches the witness of a Taproot key-path or script-path spend. The output being spent isn't
+/// known while parsing, so this goes by the witness alone: Taproot spends are always native (empty
+/// scriptSig), a key-path witness is a lone Schnorr signature, and a script-path witness ends in a
+/// script and a control block for a tapscript leaf. Leaf versions other than tapscript aren't
+/// recognized, since nothing distinguishes their control blocks from other witness items.
+pub fn extract_taproot_spend(script_sig: &[u8], witness: &[Vec<u8>]) -> Option<TaprootSpend> {
+    if !script_sig.is_empty() {
+        return None;
+    }
+
+    // With at least two items, a last one starting with 0x50 is the annex rather than part of the spend
+    let (stack, annex) = match witness {
+        [stack @ .., annex] if !stack.is_empty() && annex.first() == Some(&ANNEX_TAG) => (stack, Some(annex.clone())),
+        _ => (witness, None),
+    };
+
+    let path = match stack {
+        [signature] if is_schnorr_signature(signature) => TaprootPath::Key { signature: signature.clone() },
+        [.., script, control] if is_control_block(control) => TaprootPath::Script {
+            leaf_version: control[0] & 0xfe,
+            internal_key: control[1..33].to_vec(),
+            script: script.clone(),
+        },
+        _ => return None,
+    };
+    Some(TaprootSpend { path, annex })
+}
+
+/// 64 bytes with the default sighash, or 65 with an explicit one other than 0x00.
+fn is_schnorr_signature(data: &[u8]) -> bool {
+    match data.len() {
+        64 => true,
+        65 => matches!(data[64], 0x01..=0x03 | 0x81..=0x83),
+        _ => false,
+    }
+}
+
+/// A leaf version byte (with the output key parity in its lowest bit), a 32-byte internal key and
+/// the 32-byte hashes of the path to the leaf.
+fn is_control_block(data: &[u8]) -> bool {
+    data.len() >= 33
+        && (data.len() - 33).is_multiple_of(32)
+        && (data.len() - 33) / 32 <= MAX_TAPROOT_PATH_LENGTH
+        && data[0] & 0xfe == TAPSCRIPT_LEAF_VERSION
+}
+
+/// A DER-encoded ECDSA signature followed by its sighash byte, judged by its shape only.
+fn is_signature(data: &[u8]) -> bool {
+    (9..=73).contains(&data.len()) && data[0] == 0x30 && data[1] as usize == data.len() - 3
+}
+
+fn is_pubkey(data: &[u8]) -> bool {
+    matches!(data, [0x02 | 0x03, ..] if data.len() == 33) || matches!(data, [0x04, ..] if data.len() == 65)
+}
+
+/// Reads the block height a coinbase scriptSig starts with under BIP34: a minimally pushed
+/// script number. Coinbases from before BIP34 start with arbitrary data, so a result for those
+/// is meaningless and callers should only trust it from `Network::bip34_height` onwards.
+pub fn bip34_height(script_sig: &[u8]) -> Option<i64> {
+    let (&first, rest) = script_sig.split_first()?;
+    match first {
+        OP_0 => Some(0),
+        OP_1..=OP_16 => Some((first - OP_1 + 1) as i64),
+        1..=8 => {
+            let bytes = rest.get(..first as usize)?;
+            let (&last, _) = bytes.split_last()?;
+            if last & 0x80 != 0 {
+                // Negative script numbers can't be heights
+                return None;
+            }
+            Some(bytes.iter().rev().fold(0i64, |height, &byte| (height << 8) | byte as i64))
+        }
+        _ => None,
+    }
+}
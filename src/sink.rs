@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+
+use crate::error::ParserError;
+use crate::models::{Block, BlockHeader, StoredBlock};
+
+/// A destination for parsed blocks. Inserts of different batches may run concurrently, so
+/// implementations take `&self` and synchronize internally.
+///
+/// The import awaits `insert_blocks` before handing out more batches than it runs at once, and
+/// the reader waits for room in a bounded channel in front of the inserters, so a sink slower
+/// than parsing holds the reader back instead of blocks piling up in memory.
+///
+/// Only `insert_blocks` is required. The other methods let a sink that persists state across
+/// runs take part in resuming and reorg handling, and default to doing nothing.
+#[async_trait]
+pub trait BlockSink: Send + Sync {
+    /// Prepares the sink before anything is read, e.g. by creating tables. With `reset`, anything
+    /// stored by earlier runs is discarded first.
+    async fn setup(&self, _reset: bool) -> Result<(), ParserError> {
+        Ok(())
+    }
+
+    /// Differences between what `setup` left in place and what the sink writes, such as columns
+    /// added since the tables were created, one message each. Called after `setup`.
+    async fn check_schema(&self) -> Result<Vec<String>, ParserError> {
+        Ok(Vec::new())
+    }
+
+    async fn insert_blocks(&self, blocks: &[Block]) -> Result<(), ParserError>;
+
+    /// Stores headers read without their blocks, linked and given their heights, for a
+    /// headers-only import.
+    async fn insert_headers(&self, _headers: &[BlockHeader]) -> Result<(), ParserError> {
+        Ok(())
+    }
+
+    /// Blocks stored by earlier runs, parents first.
+    async fn load_chain(&self) -> Result<Vec<StoredBlock>, ParserError> {
+        Ok(Vec::new())
+    }
+
+    /// The last blk file index whose blocks are all stored, and the block count at that point.
+    async fn last_checkpoint(&self) -> Result<Option<(usize, i64)>, ParserError> {
+        Ok(None)
+    }
+
+    async fn save_checkpoint(&self, _file_index: usize, _block_count: i64) -> Result<(), ParserError> {
+        Ok(())
+    }
+
+    /// The SHA256 of every blk file that was fully imported by earlier runs, by file name.
+    async fn load_file_checksums(&self) -> Result<Vec<(String, String)>, ParserError> {
+        Ok(Vec::new())
+    }
+
+    async fn save_file_checksum(&self, _file_name: &str, _sha256: &str) -> Result<(), ParserError> {
+        Ok(())
+    }
+
+    /// Applies active flag changes to blocks from earlier batches after a reorganization.
+    async fn update_active_flags(&self, _changes: &[(String, bool)]) -> Result<(), ParserError> {
+        Ok(())
+    }
+
+    /// Called once after every block has been inserted.
+    async fn finish(&self) -> Result<(), ParserError> {
+        Ok(())
+    }
+}
+
+/// Discards every block, for parsing a blocks directory without writing anything.
+pub struct NullSink;
+
+#[async_trait]
+impl BlockSink for NullSink {
+    async fn insert_blocks(&self, _blocks: &[Block]) -> Result<(), ParserError> {
+        Ok(())
+    }
+}
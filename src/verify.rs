@@ -0,0 +1,101 @@
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use tracing::warn;
+
+use crate::block_processor::{calculate_block_hash, calculate_tx, check_pow, compute_merkle_root_mutated};
+use crate::error::ParserError;
+use crate::file_reader::FileReader;
+use crate::metrics::Metrics;
+use crate::models::Block;
+
+/// Failures found by `verify_files`, counted per check.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files: usize,
+    pub blocks: usize,
+    /// Malformed blocks and unreadable files.
+    pub parse_errors: usize,
+    pub merkle_mismatches: usize,
+    pub pow_failures: usize,
+    /// Blocks whose `previous_block` is not among the blocks read.
+    pub missing_parents: usize,
+}
+
+impl VerifyReport {
+    pub fn failures(&self) -> usize {
+        self.parse_errors + self.merkle_mismatches + self.pow_failures + self.missing_parents
+    }
+}
+
+/// Reads every file of `file_reader` and checks each block's merkle root and proof of work, then
+/// that every block's parent was read as well. Nothing is stored; each failure is logged with the
+/// block it concerns.
+pub fn verify_files(file_reader: &FileReader, metrics: &Metrics) -> VerifyReport {
+    let results: Vec<_> = (0..file_reader.file_paths.len())
+        .into_par_iter()
+        .map(|file_index| match file_reader.read_file(file_index) {
+            Ok(blocks) => blocks
+                .par_iter()
+                .filter_map(|block| {
+                    verify_block(block)
+                        .inspect_err(|e| {
+                            warn!(block_hash = %block.block_hash, error = %e, "Failed to hash block");
+                            metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                        })
+                        .ok()
+                })
+                .collect(),
+            Err(e) => {
+                warn!(path = %file_reader.file_paths[file_index].display(), error = %e, "Failed to read file");
+                metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        })
+        .collect();
+
+    let mut report = VerifyReport { files: file_reader.file_paths.len(), ..VerifyReport::default() };
+    let block_hashes: HashSet<&str> = results.iter().flatten().map(|block| block.block_hash.as_str()).collect();
+    for block in results.iter().flatten() {
+        report.blocks += 1;
+        report.merkle_mismatches += !block.merkle_root_matches as usize;
+        report.pow_failures += !block.pow_valid as usize;
+
+        // Only the genesis block has no parent
+        let is_genesis = block.previous_block.bytes().all(|byte| byte == b'0');
+        if !is_genesis && !block_hashes.contains(block.previous_block.as_str()) {
+            warn!(block_hash = %block.block_hash, previous_block = %block.previous_block, "Parent block not found");
+            report.missing_parents += 1;
+        }
+    }
+    report.parse_errors = metrics.parse_errors.load(Ordering::Relaxed);
+
+    report
+}
+
+struct VerifiedBlock {
+    block_hash: String,
+    previous_block: String,
+    merkle_root_matches: bool,
+    pow_valid: bool,
+}
+
+fn verify_block(block: &Block) -> Result<VerifiedBlock, ParserError> {
+    let block_hash = calculate_block_hash(block)?;
+
+    let txids = block.transactions.iter().map(|tx| Ok(calculate_tx(tx)?.0)).collect::<Result<Vec<String>, ParserError>>()?;
+    let (merkle_root, mutated) = compute_merkle_root_mutated(&txids)?;
+    let merkle_root_matches = merkle_root == block.merkle_root && !mutated;
+    if merkle_root != block.merkle_root {
+        warn!(%block_hash, header = %block.merkle_root, transactions = %merkle_root, "Merkle root mismatch");
+    } else if mutated {
+        warn!(%block_hash, "Merkle tree repeats transactions (CVE-2012-2459)");
+    }
+
+    let pow_valid = check_pow(&hex::decode(&block_hash).expect("Block hashes are hex"), block.bits);
+    if !pow_valid {
+        warn!(%block_hash, bits = format_args!("{:#010x}", block.bits), "Block does not meet its target");
+    }
+
+    Ok(VerifiedBlock { block_hash, previous_block: block.previous_block.clone(), merkle_root_matches, pow_valid })
+}
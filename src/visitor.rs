@@ -0,0 +1,53 @@
+use tracing::info;
+
+use crate::models::{Block, Transaction};
+use crate::script::ScriptType;
+
+/// Custom processing run on every transaction during an import, e.g. to aggregate statistics in
+/// the same pass that writes the blocks out.
+///
+/// Visitors are called from the task that links blocks to the chain, one block at a time, once
+/// its height is known and before it goes to the sink. Blocks come in the order they were read,
+/// which is only roughly height order, and blocks outside `START_HEIGHT`/`END_HEIGHT` are not
+/// visited. A slow visitor slows down the whole import.
+pub trait TransactionVisitor: Send {
+    fn visit(&mut self, block: &Block, tx: &Transaction);
+
+    /// Called once after every block has been visited, e.g. to report or store results.
+    fn finish(&mut self) {}
+}
+
+/// Runs every visitor over every transaction of `block`.
+pub fn visit_block(visitors: &mut [Box<dyn TransactionVisitor>], block: &Block) {
+    for tx in &block.transactions {
+        for visitor in visitors.iter_mut() {
+            visitor.visit(block, tx);
+        }
+    }
+}
+
+/// Counts OP_RETURN outputs and the transactions that carry them, and logs the totals when done.
+#[derive(Debug, Default)]
+pub struct OpReturnCounter {
+    pub transactions: usize,
+    pub op_return_transactions: usize,
+    pub op_return_outputs: usize,
+}
+
+impl TransactionVisitor for OpReturnCounter {
+    fn visit(&mut self, _block: &Block, tx: &Transaction) {
+        let op_returns = tx.outputs.iter().filter(|output| output.script_type == ScriptType::OpReturn).count();
+        self.transactions += 1;
+        self.op_return_transactions += (op_returns > 0) as usize;
+        self.op_return_outputs += op_returns;
+    }
+
+    fn finish(&mut self) {
+        info!(
+            transactions = self.transactions,
+            op_return_transactions = self.op_return_transactions,
+            op_return_outputs = self.op_return_outputs,
+            "OP_RETURN count"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use bitcoin_postgres_parser::address::{script_to_address, segwit_address};
+use bitcoin_postgres_parser::Network;
+
+fn address(script: &str, network: Network) -> Option<String> {
+    script_to_address(&hex::decode(script).unwrap(), network)
+}
+
+/// The valid segwit addresses of BIP173 and BIP350 that have a mainnet or testnet prefix, with
+/// the scriptPubKey each one pays to.
+const VALID: [(&str, &str, Network); 8] = [
+    ("0014751e76e8199196d454941c45d1b3a323f1433bd6", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", Network::Mainnet),
+    (
+        "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+        Network::Testnet3,
+    ),
+    (
+        "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+        "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+        Network::Mainnet,
+    ),
+    ("6002751e", "bc1sw50qgdz25j", Network::Mainnet),
+    ("5210751e76e8199196d454941c45d1b3a323", "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", Network::Mainnet),
+    (
+        "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+        "tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy",
+        Network::Testnet3,
+    ),
+    (
+        "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+        "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+        Network::Testnet3,
+    ),
+    (
+        "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+        Network::Mainnet,
+    ),
+];
+
+#[test]
+fn encodes_bip173_and_bip350_vectors() {
+    for (script, expected, network) in VALID {
+        assert_eq!(address(script, network).as_deref(), Some(expected), "scriptPubKey {}", script);
+    }
+}
+
+#[test]
+fn uses_the_checksum_of_the_witness_version() {
+    // BIP350 invalid vectors: version 0 with a bech32m checksum, and version 16 with bech32
+    let v0 = address("0014751e76e8199196d454941c45d1b3a323f1433bd6", Network::Mainnet).unwrap();
+    assert_ne!(v0, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh");
+    let v16 = address("602079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798", Network::Mainnet).unwrap();
+    assert_ne!(v16, "bc1s0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq54well");
+    assert_eq!(v16, "bc1s0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqpf746a");
+}
+
+#[test]
+fn rejects_invalid_witness_programs() {
+    // Version 0 with a 16 byte program (BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P)
+    assert_eq!(address("0010751e76e8199196d454941c45d1b3a323", Network::Mainnet), None);
+    // Version 0 with a program of neither 20 nor 32 bytes, even within 2 to 40 bytes
+    assert_eq!(address("0015751e76e8199196d454941c45d1b3a323f1433bd600", Network::Mainnet), None);
+    // Version 1 with a 1 byte program (bc1pw5dgrnzv)
+    assert_eq!(address("510175", Network::Mainnet), None);
+    // Version 1 with a 41 byte program
+    assert_eq!(
+        address("512979be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798751e76e8199196d454", Network::Mainnet),
+        None,
+    );
+    // There is no version 17 (bc1rw5uspcuh)
+    assert_eq!(segwit_address("bc", 17, &[0x75, 0x1e]), None);
+    // A push length that doesn't match the rest of the script
+    assert_eq!(address("0015751e76e8199196d454941c45d1b3a323f1433bd6", Network::Mainnet), None);
+}
+
+#[test]
+fn base58check_addresses() {
+    // The genesis block's key hash, and a script hash, under each network's version byte
+    let p2pkh = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+    let p2sh = "a914748284390f9e263a4b766a75d0633c50426eb87587";
+    assert_eq!(address(p2pkh, Network::Mainnet).as_deref(), Some("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"));
+    assert_eq!(address(p2sh, Network::Mainnet).as_deref(), Some("3CK4fEwbMP7heJarmU4eqA3sMbVJyEnU3V"));
+    assert_eq!(address(p2pkh, Network::Testnet3).as_deref(), Some("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt"));
+    assert_eq!(address(p2sh, Network::Testnet3).as_deref(), Some("2N3sGiyscxqd3r6DQSbgXT738ZwhUpBqkej"));
+}
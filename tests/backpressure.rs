@@ -0,0 +1,125 @@
+mod common;
+
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::processing::{spawn_reader, Message};
+use bitcoin_postgres_parser::{Block, BlockSink, FileReader, Network, ParserError};
+use tokio::sync::mpsc;
+
+use common::blk_record;
+
+/// A sink that takes its time with every batch, like a remote database.
+#[derive(Default)]
+struct SlowSink {
+    inserted: AtomicUsize,
+}
+
+#[async_trait]
+impl BlockSink for SlowSink {
+    async fn insert_blocks(&self, blocks: &[Block]) -> Result<(), ParserError> {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        self.inserted.fetch_add(blocks.len(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn slow_sink_holds_the_reader_back() {
+    const FILES: usize = 20;
+    const CAPACITY: usize = 2;
+
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-backpressure-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for index in 0..FILES {
+        fs::write(dir.join(format!("blk{:05}.dat", index)), blk_record("genesis")).unwrap();
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(CAPACITY);
+    let reader = spawn_reader(file_reader, (0..FILES).collect(), 1, 2, sender, Arc::clone(&metrics), hash_pool, true);
+
+    // With nothing consuming, the reader fills the channel, reads one more file and then waits
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!reader.is_finished());
+    assert_eq!(metrics.queued_messages.load(Ordering::Relaxed), CAPACITY);
+    assert!(metrics.blocks.load(Ordering::Relaxed) <= CAPACITY + 1);
+
+    let slow_sink = Arc::new(SlowSink::default());
+    // Driven as a trait object, the way an import holds its sink
+    let sink: Arc<dyn BlockSink> = slow_sink.clone();
+    let mut ahead = 0;
+    while let Some(message) = receiver.recv().await {
+        if let Message::Block(block) = message {
+            sink.insert_blocks(&[block]).await.unwrap();
+        }
+        let read = metrics.blocks.load(Ordering::Relaxed);
+        ahead = ahead.max(read - slow_sink.inserted.load(Ordering::Relaxed));
+    }
+    reader.await.unwrap();
+
+    // The reader never got further ahead of the sink than the channel and one file allow
+    assert!(ahead <= CAPACITY + 1, "Reader ran {} blocks ahead", ahead);
+    assert_eq!(slow_sink.inserted.load(Ordering::Relaxed), FILES);
+    assert!(metrics.reader_wait_micros.load(Ordering::Relaxed) > 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Reads files with `files_in_memory` permits and more read workers than that, checking that
+/// the permits are what bounds the files held at once.
+async fn read_with_permits(files_in_memory: usize) {
+    const FILES: usize = 20;
+    const READ_WORKERS: usize = 8;
+
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-permits-{}-{}", files_in_memory, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for index in 0..FILES {
+        fs::write(dir.join(format!("blk{:05}.dat", index)), blk_record("genesis")).unwrap();
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(READ_WORKERS).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(1);
+    let reader = spawn_reader(file_reader, (0..FILES).collect(), READ_WORKERS, files_in_memory, sender, Arc::clone(&metrics), hash_pool, true);
+
+    // With nothing consuming, the first file waits for room in the channel and the others holding
+    // a permit are read, while the rest of the read workers wait for one
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(metrics.files_read.load(Ordering::Relaxed), files_in_memory);
+    assert_eq!(metrics.files_in_memory.load(Ordering::Relaxed), files_in_memory);
+
+    let mut blocks = 0;
+    let mut most_in_memory = 0;
+    while let Some(message) = receiver.recv().await {
+        if let Message::Block(_) = message {
+            blocks += 1;
+        }
+        most_in_memory = most_in_memory.max(metrics.files_in_memory.load(Ordering::Relaxed));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    reader.await.unwrap();
+
+    assert!(most_in_memory <= files_in_memory, "{} files were held at once", most_in_memory);
+    assert_eq!(blocks, FILES);
+    assert_eq!(metrics.files_in_memory.load(Ordering::Relaxed), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn file_permits_bound_the_files_in_memory() {
+    read_with_permits(2).await;
+}
+
+#[tokio::test]
+async fn a_single_file_permit_reads_one_file_at_a_time() {
+    read_with_permits(1).await;
+}
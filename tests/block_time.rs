@@ -0,0 +1,67 @@
+mod common;
+
+use bitcoin_postgres_parser::chain::Chain;
+use bitcoin_postgres_parser::models::StoredBlock;
+use bitcoin_postgres_parser::Network;
+use time::{Duration, OffsetDateTime};
+
+use common::parse_fixture;
+
+const BLOCK_1: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+
+#[test]
+fn skewed_block_times_are_reported() {
+    let mut chain = Chain::new(Network::Mainnet);
+    let mut blocks = vec![parse_fixture("genesis"), parse_fixture("block_1")];
+    chain.assign_heights(&mut blocks);
+    // The later of the two, as Core takes the upper median
+    assert_eq!(chain.median_time_past(BLOCK_1), Some(blocks[1].time.unix_timestamp()));
+
+    let now = OffsetDateTime::now_utc();
+    let mut block_2 = parse_fixture("block_2");
+    assert_eq!(chain.check_time(&block_2, now), None);
+
+    // Back to the time of block 1, which is the median
+    block_2.time = blocks[1].time;
+    assert!(chain.check_time(&block_2, now).is_some_and(|message| message.contains("not after the median time")));
+
+    block_2.time = now + Duration::hours(3);
+    assert!(chain.check_time(&block_2, now).is_some_and(|message| message.contains("ahead of the clock")));
+    block_2.time = now + Duration::hours(1);
+    assert_eq!(chain.check_time(&block_2, now), None);
+
+    // Only logged, so the block is still linked
+    block_2.time = blocks[0].time;
+    let mut skewed = vec![block_2];
+    chain.assign_heights(&mut skewed);
+    assert_eq!((skewed[0].height, skewed[0].active), (2, true));
+}
+
+#[test]
+fn median_time_past_spans_eleven_blocks() {
+    // Twelve blocks with their times out of order, the oldest far ahead of the rest
+    let times = [1_000, 30, 10, 110, 50, 20, 90, 40, 100, 60, 80, 70];
+    let mut chain = Chain::new(Network::Mainnet);
+    for (height, &time) in times.iter().enumerate() {
+        chain.insert_known(StoredBlock {
+            block_hash: format!("{:064x}", height + 1),
+            previous_block: format!("{:064x}", height),
+            height: height as i32,
+            time,
+            bits: 0x1d00ffff,
+            total_supply: 0,
+            active: true,
+        });
+    }
+    let tip = format!("{:064x}", times.len());
+    // The median of the last eleven, 10 to 110; counting the oldest too would give 70
+    assert_eq!(chain.median_time_past(&tip), Some(60));
+
+    let now = OffsetDateTime::now_utc();
+    let mut block = parse_fixture("block_2");
+    block.previous_block = tip;
+    block.time = OffsetDateTime::from_unix_timestamp(60).unwrap();
+    assert!(chain.check_time(&block, now).is_some_and(|message| message.contains("not after the median time 60")));
+    block.time = OffsetDateTime::from_unix_timestamp(61).unwrap();
+    assert_eq!(chain.check_time(&block, now), None);
+}
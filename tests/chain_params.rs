@@ -0,0 +1,102 @@
+mod common;
+
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::chain::{Chain, COIN};
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::network::{ChainParams, MAINNET_PARAMS};
+use bitcoin_postgres_parser::{process_block, Block, FileReader, Network, ParserError};
+
+use common::{fixture, network_blk_record};
+
+const LITECOIN_GENESIS: &str = "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2";
+
+static LITECOIN: ChainParams = ChainParams {
+    name: "litecoin",
+    magic: 0xDBB6C0FB,
+    genesis_hash: LITECOIN_GENESIS,
+    genesis_coinbase_txid: "97ddfbbae6be97fd6cdf3e7ca13232a3afff2353e29badfab7f73011edd4ced9",
+    initial_subsidy: 50 * COIN,
+    halving_interval: 840_000,
+    bip34_height: 710_000,
+    max_block_size: 4_000_000,
+    p2pkh_prefix: 0x30,
+    p2sh_prefix: 0x32,
+    bech32_hrp: "ltc",
+};
+
+#[test]
+fn a_fork_is_read_with_its_own_params() {
+    let network = Network::Custom(&LITECOIN);
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-chain-params-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("blk00000.dat"), network_blk_record(network, "litecoin_genesis")).unwrap();
+
+    let file_reader = FileReader::new(dir.clone(), network, Arc::new(Metrics::default()));
+    let blocks = file_reader.read_file(0).unwrap();
+    assert_eq!(blocks.len(), 1);
+    // Litecoin's proof of work is scrypt, so only the non-strict checks apply
+    let block = process_block(blocks.into_iter().next().unwrap(), network, false).unwrap();
+    assert_eq!(block.block_hash, LITECOIN_GENESIS);
+    assert_eq!(block.merkle_root, "97ddfbbae6be97fd6cdf3e7ca13232a3afff2353e29badfab7f73011edd4ced9");
+    assert_eq!(block.transactions[0].outputs[0].value, 50 * COIN);
+
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::new(Metrics::default()));
+    assert!(matches!(file_reader.read_file(0), Err(ParserError::MagicMismatch { found: 0xDBB6C0FB, .. })));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn chain_rules_follow_the_params() {
+    let network = Network::Custom(&LITECOIN);
+    assert_eq!(network.to_string(), "litecoin");
+    assert_eq!(network.bech32_hrp(), "ltc");
+    assert_eq!(Network::default(), Network::Mainnet);
+
+    let chain = Chain::new(network);
+    assert_eq!(chain.block_subsidy(839_999), 50 * COIN);
+    assert_eq!(chain.block_subsidy(840_000), 25 * COIN);
+}
+
+#[test]
+fn the_unspendable_genesis_coinbase_comes_from_the_params() {
+    let litecoin = Network::Custom(&LITECOIN);
+    let litecoin_genesis = FileReader::in_memory(litecoin).read_block_from_bytes(&fixture("litecoin_genesis").into_inner()).unwrap();
+    let bitcoin_genesis = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&fixture("genesis").into_inner()).unwrap();
+    let unspendable = |block: &Block, network: Network| process_block(block.clone(), network, false).unwrap().transactions[0].outputs[0].unspendable;
+
+    // Litecoin's genesis coinbase differs from Bitcoin's, and each is only unspendable on its own chain
+    assert_ne!(LITECOIN.genesis_coinbase_txid, Network::Mainnet.params().genesis_coinbase_txid);
+    assert!(unspendable(&litecoin_genesis, litecoin));
+    assert!(!unspendable(&litecoin_genesis, Network::Mainnet));
+    assert!(unspendable(&bitcoin_genesis, Network::Mainnet));
+    assert!(!unspendable(&bitcoin_genesis, litecoin));
+}
+
+#[test]
+fn records_over_the_networks_block_size_are_skipped() {
+    // The genesis block is 285 bytes and block 1 215
+    static SMALL_BLOCKS: ChainParams = ChainParams { name: "small-blocks", max_block_size: 250, ..MAINNET_PARAMS };
+    let network = Network::Custom(&SMALL_BLOCKS);
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-chain-params-size-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("blk00000.dat"), [network_blk_record(network, "genesis"), network_blk_record(network, "block_1")].concat()).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = FileReader::new(dir.clone(), network, Arc::clone(&metrics));
+    let blocks = file_reader.read_file(0).unwrap();
+    assert_eq!(blocks.iter().map(|block| block.merkle_root.as_str()).collect::<Vec<_>>(), ["0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098"]);
+    let headers = file_reader.read_headers(0).unwrap();
+    assert_eq!(headers.len(), 1);
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 2);
+
+    // Mainnet's limit takes both
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default());
+    assert_eq!(file_reader.read_file(0).unwrap().len(), 2);
+    assert_eq!(file_reader.read_headers(0).unwrap().len(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,60 @@
+use bitcoin_postgres_parser::processing::CheckpointTracker;
+
+#[test]
+fn checkpoint_advances_over_contiguous_committed_files() {
+    let mut checkpoint = CheckpointTracker::new(0, 0);
+    checkpoint.file_read(0, 2);
+    checkpoint.file_read(1, 1);
+    checkpoint.file_read(2, 1);
+
+    // File 1 finishes first, but file 0 is still outstanding
+    checkpoint.blocks_committed(&[1]);
+    assert_eq!(checkpoint.advance(), None);
+    checkpoint.blocks_committed(&[0]);
+    assert_eq!(checkpoint.advance(), None);
+
+    // The last block of file 0 completes it, and file 1 behind it
+    checkpoint.blocks_committed(&[0]);
+    assert_eq!(checkpoint.advance(), Some((1, 3)));
+    assert_eq!(checkpoint.advance(), None);
+
+    checkpoint.blocks_committed(&[2]);
+    assert_eq!(checkpoint.advance(), Some((2, 4)));
+}
+
+#[test]
+fn checkpoint_never_passes_a_failed_batch() {
+    let mut checkpoint = CheckpointTracker::new(5, 100);
+    checkpoint.file_read(5, 1);
+    checkpoint.file_read(6, 1);
+    checkpoint.file_read(7, 1);
+
+    // The batch with file 6's block failed and is never committed
+    checkpoint.blocks_committed(&[5]);
+    checkpoint.blocks_committed(&[7]);
+    // The block count is everything committed so far, file 7's block included
+    assert_eq!(checkpoint.advance(), Some((5, 102)));
+    assert_eq!(checkpoint.advance(), None);
+
+    let mut completed = checkpoint.take_completed();
+    completed.sort_unstable();
+    assert_eq!(completed, [5, 7]);
+}
+
+#[test]
+fn empty_files_are_done_once_read() {
+    let mut checkpoint = CheckpointTracker::new(0, 0);
+    checkpoint.file_read(0, 0);
+    assert_eq!(checkpoint.advance(), Some((0, 0)));
+    assert_eq!(checkpoint.take_completed(), [0]);
+}
+
+#[test]
+fn held_files_stop_the_checkpoint() {
+    let mut checkpoint = CheckpointTracker::new(0, 0);
+    checkpoint.hold_at(1);
+    checkpoint.file_read(0, 0);
+    checkpoint.file_read(1, 0);
+    assert_eq!(checkpoint.advance(), Some((0, 0)));
+    assert_eq!(checkpoint.advance(), None);
+}
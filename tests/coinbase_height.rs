@@ -0,0 +1,30 @@
+mod common;
+
+use bitcoin_postgres_parser::chain::Chain;
+use bitcoin_postgres_parser::Network;
+
+use common::parse_fixture;
+
+#[test]
+fn coinbase_heights_are_checked_from_bip34_on() {
+    // Block 1's coinbase predates BIP34 and starts with the push of 0x1d00ffff
+    let block_1 = parse_fixture("block_1");
+    assert_eq!(block_1.coinbase_height(), Some(0x1d00ffff));
+    assert_eq!(Chain::new(Network::Mainnet).check_coinbase_height(&block_1, 1), None);
+
+    // Signet enforces BIP34 from height 1
+    let chain = Chain::new(Network::Signet);
+    let message = chain.check_coinbase_height(&block_1, 1).unwrap();
+    assert_eq!(message, format!("Block {} at height 1 has coinbase height 486604799", block_1.block_hash));
+    assert_eq!(chain.check_coinbase_height(&block_1, 0x1d00ffff), None);
+}
+
+#[test]
+fn blocks_with_the_wrong_coinbase_height_are_still_linked() {
+    let mut chain = Chain::new(Network::Signet);
+    let mut blocks = vec![parse_fixture("genesis"), parse_fixture("block_1"), parse_fixture("block_2")];
+    chain.assign_heights(&mut blocks);
+    let heights: Vec<i32> = blocks.iter().map(|block| block.height).collect();
+    assert_eq!(heights, [0, 1, 2]);
+    assert!(blocks.iter().all(|block| chain.contains(&block.block_hash)));
+}
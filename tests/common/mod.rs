@@ -0,0 +1,78 @@
+// Each test crate compiles this module and only uses some of it
+#![allow(dead_code)]
+
+use std::io::Cursor;
+use std::path::Path;
+
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use bitcoin_postgres_parser::block_processor::{calculate_tx, compute_merkle_root};
+use bitcoin_postgres_parser::chain::Chain;
+use bitcoin_postgres_parser::{process_block, Block, FileReader, Network};
+use tokio_postgres::NoTls;
+
+/// Loads `tests/fixtures/<name>.hex` as the raw bytes of a serialized block.
+pub fn fixture(name: &str) -> Cursor<Vec<u8>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(format!("{}.hex", name));
+    let hex: String = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read fixture {}: {}", path.display(), e))
+        .split_whitespace()
+        .collect();
+    Cursor::new(hex::decode(hex).unwrap_or_else(|e| panic!("Fixture {} isn't valid hex: {}", name, e)))
+}
+
+/// A fixture framed the way it is stored in a blk file, behind the network magic and its size.
+pub fn blk_record(name: &str) -> Vec<u8> {
+    network_blk_record(Network::Mainnet, name)
+}
+
+/// Like `blk_record`, behind the magic of `network`.
+pub fn network_blk_record(network: Network, name: &str) -> Vec<u8> {
+    let block = fixture(name).into_inner();
+    let mut record = network.magic().to_le_bytes().to_vec();
+    record.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    record.extend_from_slice(&block);
+    record
+}
+
+/// Parses and processes a fixture the way an import does, with the `STRICT` checks on.
+pub fn parse_fixture(name: &str) -> Block {
+    let bytes = fixture(name).into_inner();
+    let block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).expect("Fixture parses");
+    process_block(block, Network::Mainnet, true).expect("Fixture passes the block checks")
+}
+
+/// Txid of the transaction `empty_scripts_block` adds, computed from its bytes independently of this crate.
+pub const EMPTY_SCRIPTS_TXID: &str = "2954e7aee84e65022906924ac72e2cbb44d28baa3d364cc5c8269790cd30c1db";
+
+/// The genesis block with a second transaction, spending a made-up output with an empty scriptSig
+/// to an output with an empty scriptPubKey, processed without the `STRICT` checks.
+pub fn empty_scripts_block() -> Block {
+    let mut bytes = fixture("genesis").into_inner();
+    // The transaction count follows the 80-byte header
+    bytes[80] = 2;
+    bytes.extend(hex::decode("010000000111111111111111111111111111111111111111111111111111111111111111110000000000ffffffff01e8030000000000000000000000").unwrap());
+    let mut block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).expect("Block parses");
+    let txids: Vec<String> = block.transactions.iter().map(|tx| calculate_tx(tx).unwrap().0).collect();
+    block.merkle_root = compute_merkle_root(&txids).unwrap();
+    process_block(block, Network::Mainnet, false).expect("Block passes the block checks")
+}
+
+/// A pool for the Postgres database in `TEST_DATABASE_URL`, or `None` when it isn't set, in
+/// which case the test should return early. Tests keep their tables apart with a prefix of their
+/// own, and leave them behind for inspection; point it at a database used only for testing.
+pub async fn test_database() -> Option<Pool<PostgresConnectionManager<NoTls>>> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL isn't set, skipping");
+        return None;
+    };
+    let manager = PostgresConnectionManager::new(url.parse().expect("TEST_DATABASE_URL is a valid connection string"), NoTls);
+    Some(Pool::builder().max_size(4).build(manager).await.expect("Test database is reachable"))
+}
+
+/// Parses the fixtures, which must form a chain from the genesis block, and gives them their heights.
+pub fn chain_of(names: &[&str]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = names.iter().map(|name| parse_fixture(name)).collect();
+    Chain::new(Network::Mainnet).assign_heights(&mut blocks);
+    blocks
+}
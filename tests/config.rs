@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use bitcoin_postgres_parser::config::{Command, Config, ConfigError};
+use bitcoin_postgres_parser::database::is_valid_table_prefix;
+
+fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+    let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    Config::load(args.iter().map(|arg| arg.to_string()), |name| vars.get(name).cloned())
+}
+
+const DATABASE: (&str, &str) = ("DATABASE_URL", "host=localhost");
+
+#[test]
+fn flags_override_the_environment() {
+    let config = load(
+        &["--blocks-path", "/flag", "--batch-size=3", "--reset"],
+        &[("BLOCKS_PATH", "/env"), ("BATCH_SIZE", "100"), ("RESET", "false"), DATABASE],
+    )
+    .unwrap();
+    assert_eq!(config.command, Command::Import);
+    assert_eq!(config.blocks_path, Path::new("/flag"));
+    assert_eq!(config.batch_size, 3);
+    assert!(config.reset);
+}
+
+#[test]
+fn the_environment_and_defaults_apply_without_flags() {
+    let config = load(&[], &[("BLOCKS_PATH", "/env"), ("START_HEIGHT", "7"), DATABASE]).unwrap();
+    assert_eq!(config.blocks_path, Path::new("/env"));
+    assert_eq!(config.start_height, 7);
+    assert_eq!(config.end_height, i32::MAX);
+    assert_eq!(config.batch_size, usize::MAX);
+    assert_eq!(config.insert_workers, 10);
+    assert_eq!(config.files_in_memory, 2);
+    assert_eq!(config.output, "postgres");
+    assert!(!config.reset);
+
+    let config = load(&[], &[("BLOCKS_PATH", "/env"), ("VERIFY", "true")]).unwrap();
+    assert_eq!(config.command, Command::Verify);
+}
+
+#[test]
+fn rewind_needs_no_blocks_path() {
+    let config = load(&["rewind", "5"], &[DATABASE]).unwrap();
+    assert_eq!(config.command, Command::Rewind(5));
+    assert!(matches!(load(&["rewind", "5"], &[]), Err(ConfigError::Missing("DATABASE_URL"))));
+    assert!(matches!(load(&["rewind", "5", "--output", "json"], &[DATABASE]), Err(ConfigError::Usage(_))));
+    assert!(matches!(load(&["rewind"], &[DATABASE]), Err(ConfigError::Usage(_))));
+}
+
+#[test]
+fn bad_arguments_are_rejected() {
+    let blocks = ("BLOCKS_PATH", "/env");
+    assert!(matches!(load(&["--bogus"], &[blocks, DATABASE]), Err(ConfigError::Usage(_))));
+    assert!(matches!(load(&["--reset=true"], &[blocks, DATABASE]), Err(ConfigError::Usage(_))));
+    assert!(matches!(load(&["--start-height"], &[blocks, DATABASE]), Err(ConfigError::Usage(_))));
+    assert!(matches!(
+        load(&["--start-height", "ten"], &[blocks, DATABASE]),
+        Err(ConfigError::Invalid { name: "START_HEIGHT", .. })
+    ));
+    assert!(matches!(load(&[], &[DATABASE]), Err(ConfigError::Missing("BLOCKS_PATH"))));
+    assert!(matches!(load(&["--table-prefix", "Bad-"], &[blocks, DATABASE]), Err(ConfigError::Invalid { name: "TABLE_PREFIX", .. })));
+    assert!(matches!(load(&[], &[blocks, ("WATCH", "true"), ("OUTPUT", "json")]), Err(ConfigError::Usage(_))));
+    assert!(matches!(load(&["--headers-only", "--output", "json"], &[blocks]), Err(ConfigError::Usage(_))));
+    assert!(matches!(load(&["--headers-only"], &[blocks, ("WATCH", "true"), DATABASE]), Err(ConfigError::Usage(_))));
+    assert_eq!(load(&["--help"], &[]).unwrap().command, Command::Help);
+}
+
+#[test]
+fn table_prefixes_are_short_lowercase_identifiers() {
+    for prefix in ["", "test_", "a1_", "_x", &"a".repeat(32)] {
+        assert!(is_valid_table_prefix(prefix), "{:?}", prefix);
+    }
+    for prefix in [&"a".repeat(33), "1test_", "Test_", "TEST", "test-", "test ", "tést_", "test;"] {
+        assert!(!is_valid_table_prefix(prefix), "{:?}", prefix);
+    }
+}
+
+#[test]
+fn tls_connections_are_rejected_up_front() {
+    let blocks = ("BLOCKS_PATH", "/blocks");
+    let error = load(&[], &[blocks, ("DATABASE_URL", "host=localhost sslmode=require")]).unwrap_err();
+    assert!(matches!(error, ConfigError::Invalid { name: "DATABASE_URL", .. }), "{}", error);
+    assert!(!error.to_string().contains("localhost"), "{}", error);
+    for url in ["host=localhost sslmode=prefer", "postgresql://localhost/db?sslmode=disable"] {
+        assert!(load(&[], &[blocks, ("DATABASE_URL", url)]).is_ok(), "{}", url);
+    }
+    // Not connecting, a dry run doesn't mind
+    assert!(load(&["--dry-run"], &[blocks, ("DATABASE_URL", "host=localhost sslmode=require")]).is_ok());
+}
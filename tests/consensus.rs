@@ -0,0 +1,121 @@
+mod common;
+
+use bitcoin_postgres_parser::block_processor::{calculate_block_difficulty, check_pow, compute_merkle_root, compute_merkle_root_mutated};
+use bitcoin_postgres_parser::chain::{block_work, Chain, COIN};
+use bitcoin_postgres_parser::{process_block, Block, FileReader, Network, ParserError};
+
+use common::{fixture, parse_fixture};
+
+fn txids(name: &str) -> Vec<String> {
+    parse_fixture(name).transactions.iter().map(|tx| tx.txid.clone()).collect()
+}
+
+#[test]
+fn merkle_roots_match_their_headers() {
+    // One transaction, whose txid is the root
+    let genesis = txids("genesis");
+    assert_eq!(compute_merkle_root(&genesis).unwrap(), "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+    assert_eq!(compute_merkle_root(&genesis).unwrap(), genesis[0]);
+
+    // An even count, and an odd one that duplicates its last hash
+    assert_eq!(txids("segwit").len(), 2);
+    assert_eq!(compute_merkle_root(&txids("segwit")).unwrap(), "f9728c4a7ad14ee3d4094b82f3eca0c9b2809e591d3c33c8d378bda91ddb13c1");
+    assert_eq!(txids("taproot").len(), 3);
+    assert_eq!(compute_merkle_root(&txids("taproot")).unwrap(), "057eae10adfa34746f61d18d775a90b3a3f54703aed8dc41df47a3fc9a23c3dd");
+}
+
+#[test]
+fn repeated_last_transactions_are_rejected() {
+    // Repeating the last of three txids gives the same root, but a mutated tree (CVE-2012-2459)
+    let mut repeated = txids("taproot");
+    let (root, mutated) = compute_merkle_root_mutated(&repeated).unwrap();
+    assert!(!mutated);
+    repeated.push(repeated[2].clone());
+    assert_eq!(compute_merkle_root_mutated(&repeated).unwrap(), (root, true));
+
+    // The block with its last transaction stored twice
+    let bytes = fixture("taproot").into_inner();
+    let block = FileReader::in_memory(Network::Mainnet).with_raw_bytes(true).read_block_from_bytes(&bytes).unwrap();
+    let mut mutated = bytes[..80].to_vec();
+    mutated.push(4);
+    for tx in block.transactions.iter().chain(block.transactions.last()) {
+        mutated.extend_from_slice(tx.raw.as_ref().unwrap());
+    }
+    let block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&mutated).unwrap();
+    assert_eq!(block.transactions.len(), 4);
+    let error = process_block(block.clone(), Network::Mainnet, true).unwrap_err();
+    assert!(matches!(&error, ParserError::MalformedBlock(message) if message.contains("CVE-2012-2459")), "{}", error);
+    // Without the strict checks it is only logged
+    assert_eq!(process_block(block, Network::Mainnet, false).unwrap().transactions.len(), 4);
+}
+
+#[test]
+fn proof_of_work_is_checked_against_the_compact_target() {
+    let genesis = hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    assert!(check_pow(&genesis, 0x1d00ffff));
+
+    // 0x1d00ffff expands to 0x00000000ffff followed by zeros, which a hash may equal but not exceed
+    let mut target = [0u8; 32];
+    target[4..6].copy_from_slice(&[0xff, 0xff]);
+    assert!(check_pow(&target, 0x1d00ffff));
+    let mut above = target;
+    above[6] = 1;
+    assert!(!check_pow(&above, 0x1d00ffff));
+    assert!(!check_pow(&genesis, 0x1b0404cb));
+
+    // Negative, overflowing and zero targets fail whatever the hash
+    let zero = [0u8; 32];
+    assert!(!check_pow(&zero, 0x1d80ffff));
+    assert!(!check_pow(&zero, 0x23000001));
+    assert!(!check_pow(&zero, 0x22000100));
+    assert!(!check_pow(&zero, 0x1d000000));
+}
+
+#[test]
+fn difficulty_is_relative_to_the_minimum_target() {
+    assert_eq!(calculate_block_difficulty(0x1d00ffff), 1.0);
+    // Block 100000, which getblock reports at difficulty 14484.1623612254
+    assert!((calculate_block_difficulty(0x1b04864c) - 14484.1623612254).abs() < 1e-9);
+}
+
+#[test]
+fn chainwork_adds_up_the_work_of_each_block() {
+    assert_eq!(block_work(0x1d00ffff), 0x100010001);
+
+    let mut blocks = vec![parse_fixture("genesis"), parse_fixture("block_1")];
+    let mut chain = Chain::new(Network::Mainnet);
+    chain.assign_heights(&mut blocks);
+    assert_eq!(chain.chainwork(&blocks[0].block_hash), 0x100010001);
+    // What getblock reports as block 1's chainwork
+    assert_eq!(chain.chainwork(&blocks[1].block_hash), 0x200020002);
+}
+
+#[test]
+fn subsidy_halves_every_210000_blocks() {
+    let chain = Chain::new(Network::Mainnet);
+    assert_eq!(chain.block_subsidy(0), 50 * COIN);
+    assert_eq!(chain.block_subsidy(209_999), 50 * COIN);
+    assert_eq!(chain.block_subsidy(210_000), 25 * COIN);
+    // 33 halvings shift the last satoshi out, long before the 64th would overflow the shift
+    assert_eq!(chain.block_subsidy(6_929_999), 1);
+    assert_eq!(chain.block_subsidy(6_930_000), 0);
+    assert_eq!(chain.block_subsidy(64 * 210_000), 0);
+    assert_eq!(chain.block_subsidy(i32::MAX), 0);
+}
+
+#[test]
+fn total_supply_counts_the_subsidy_claimed() {
+    let supply = |blocks: &mut Vec<Block>| -> Vec<i64> {
+        Chain::new(Network::Mainnet).assign_heights(blocks);
+        blocks.iter().map(|block| block.total_supply).collect()
+    };
+    let mut blocks: Vec<Block> = ["genesis", "block_1", "block_2"].iter().map(|name| parse_fixture(name)).collect();
+    // The unspendable genesis coinbase adds nothing
+    assert_eq!(supply(&mut blocks.clone()), [0, 50 * COIN, 100 * COIN]);
+
+    // Burning the subsidy still issues it, but leaving some unclaimed doesn't
+    blocks[1].transactions[0].outputs[0].script_pub_key = "6a".to_string();
+    blocks[1].transactions[0].outputs[0].unspendable = true;
+    blocks[2].transactions[0].outputs[0].value = 10 * COIN;
+    assert_eq!(supply(&mut blocks), [0, 50 * COIN, 60 * COIN]);
+}
@@ -0,0 +1,852 @@
+//! Tests against a real Postgres database, skipped unless `TEST_DATABASE_URL` is set.
+
+mod common;
+
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::block_processor::{calculate_block_hash, calculate_tx};
+use bitcoin_postgres_parser::chain::{Chain, COIN};
+use bitcoin_postgres_parser::database::{ChainIntegrity, Database, BIP30_DUPLICATES};
+use bitcoin_postgres_parser::script::ScriptType;
+use bitcoin_postgres_parser::{process_block, Block, BlockSink, FileReader, Network, Output, ParserError};
+use time::Duration;
+
+use common::{blk_record, chain_of, empty_scripts_block, fixture, parse_fixture, test_database, EMPTY_SCRIPTS_TXID};
+
+const BLOCK_1: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+
+#[tokio::test]
+async fn integrity_check_reports_a_deleted_block() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_integrity_");
+    database.setup(true).await.unwrap();
+    database.insert_blocks(&chain_of(&["genesis", "block_1", "block_2"])).await.unwrap();
+    assert!(database.check_chain_integrity().await.unwrap().is_intact());
+
+    pool.get().await.unwrap().execute("DELETE FROM test_integrity_blocks WHERE block_hash = $1", &[&BLOCK_1]).await.unwrap();
+    assert_eq!(database.check_chain_integrity().await.unwrap(), ChainIntegrity {
+        missing_parents: vec![BLOCK_1.to_string()],
+        height_gaps: vec![(1, 1)],
+    });
+}
+
+/// `template` built on top of `parent`, with its transactions renamed to `txid`, to make chains
+/// longer than the fixtures. Nothing checks the transactions once they're parsed.
+fn child_of(parent: &Block, template: &Block, txid: &str) -> Block {
+    let mut block = template.clone();
+    block.previous_block = parent.block_hash.clone();
+    block.time = parent.time + Duration::minutes(10);
+    block.block_hash = calculate_block_hash(&block).unwrap();
+    for tx in &mut block.transactions {
+        tx.txid = txid.to_string();
+        tx.block_hash = block.block_hash.clone();
+    }
+    block
+}
+
+/// `block` with a transaction `txid` appended, spending output `output_index` of `previous_txid`
+/// to an output per value, each paying the same script as the block's coinbase.
+fn with_spend(mut block: Block, txid: &str, previous_txid: &str, output_index: i64, values: &[i64]) -> Block {
+    let mut tx = block.transactions[0].clone();
+    tx.txid = txid.to_string();
+    tx.position = block.transactions.len() as i32;
+    tx.inputs[0].is_coinbase = false;
+    tx.inputs[0].previous_txid = previous_txid.to_string();
+    tx.inputs[0].previous_output_index = output_index;
+    let template = tx.outputs[0].clone();
+    tx.outputs = values.iter().enumerate().map(|(index, &value)| Output { output_index: index as i32, value, ..template.clone() }).collect();
+    block.transactions.push(tx);
+    block
+}
+
+#[tokio::test]
+async fn rewind_removes_blocks_above_the_height() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_rewind_");
+    database.setup(true).await.unwrap();
+
+    let mut blocks = vec![parse_fixture("genesis"), parse_fixture("block_1"), parse_fixture("block_2")];
+    let block_3 = child_of(&blocks[2], &blocks[2], &format!("{:064x}", 3));
+    let block_4 = child_of(&block_3, &blocks[2], &format!("{:064x}", 4));
+    blocks.extend([block_3, block_4]);
+    for (height, block) in blocks.iter_mut().enumerate() {
+        block.blk_file = format!("blk{:05}.dat", height / 3);
+    }
+    Chain::new(Network::Mainnet).assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    database.save_file_checksum("blk00000.dat", &"0".repeat(64)).await.unwrap();
+    database.save_file_checksum("blk00001.dat", &"1".repeat(64)).await.unwrap();
+    database.save_checkpoint(1, 5).await.unwrap();
+
+    assert_eq!(database.rewind_to_height(2).await.unwrap(), 2);
+
+    let conn = pool.get().await.unwrap();
+    let heights: Vec<i32> = conn.query("SELECT height FROM test_rewind_blocks ORDER BY height", &[]).await.unwrap()
+        .iter().map(|row| row.get(0)).collect();
+    assert_eq!(heights, [0, 1, 2]);
+    for table in ["transactions", "inputs", "outputs"] {
+        let count: i64 = conn.query_one(&format!("SELECT COUNT(*) FROM test_rewind_{}", table), &[]).await.unwrap().get(0);
+        assert_eq!(count, 3, "{} left", table);
+    }
+    assert!(database.check_chain_integrity().await.unwrap().is_intact());
+
+    // The file holding the removed blocks is read again by the next run
+    let checksums = database.load_file_checksums().await.unwrap();
+    assert_eq!(checksums.iter().map(|(file_name, _)| file_name.as_str()).collect::<Vec<_>>(), ["blk00000.dat"]);
+    assert_eq!(database.last_checkpoint().await.unwrap(), None);
+
+    assert_eq!(database.rewind_to_height(2).await.unwrap(), 0);
+}
+
+/// Blocks 0 to 2, a block 3 and a competing 3' that share a transaction, and a block 4' that makes
+/// the branch of 3' the heavier one.
+fn fork() -> (Vec<Block>, Block, Block, Block) {
+    let blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    let shared = format!("{:064x}", 3);
+    let block_3 = child_of(&blocks[2], &blocks[2], &shared);
+    let mut template = blocks[2].clone();
+    template.nonce += 1;
+    let block_3b = child_of(&blocks[2], &template, &shared);
+    let block_4b = child_of(&block_3b, &blocks[2], &format!("{:064x}", 4));
+    (blocks, block_3, block_3b, block_4b)
+}
+
+#[tokio::test]
+async fn transactions_shared_by_competing_blocks_are_stored_once() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_fork_");
+    database.setup(true).await.unwrap();
+
+    // Imported the way `main` does: 1-2-3 first, then 3' and 4', flipping 3 before they are inserted
+    let (mut blocks, block_3, block_3b, block_4b) = fork();
+    blocks.push(block_3.clone());
+    let mut chain = Chain::new(Network::Mainnet);
+    chain.assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    let mut branch = vec![block_3b.clone(), block_4b.clone()];
+    let reorged = chain.assign_heights(&mut branch);
+    assert_eq!(reorged, [(block_3.block_hash.clone(), false)]);
+    database.update_active_flags(&reorged).await.unwrap();
+    database.insert_blocks(&branch).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let active: Vec<(String, bool)> = conn.query("SELECT block_hash, active FROM test_fork_blocks WHERE height >= 3 ORDER BY height, active", &[]).await.unwrap()
+        .iter().map(|row| (row.get(0), row.get(1))).collect();
+    assert_eq!(active, [(block_3.block_hash.clone(), false), (block_3b.block_hash.clone(), true), (block_4b.block_hash.clone(), true)]);
+
+    // The shared transaction is kept once, under the block of the heavier branch
+    let shared = &block_3.transactions[0].txid;
+    let rows = conn.query("SELECT block_hash FROM test_fork_transactions WHERE txid = $1", &[shared]).await.unwrap();
+    let block_hashes: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    assert_eq!(block_hashes, [block_3b.block_hash.as_str()]);
+    let outputs: i64 = conn.query_one("SELECT COUNT(*) FROM test_fork_outputs WHERE txid = $1", &[shared]).await.unwrap().get(0);
+    assert_eq!(outputs, block_3.transactions[0].outputs.len() as i64);
+
+    // Both branches in one batch keep it under the active block as well
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_fork_batch_");
+    database.setup(true).await.unwrap();
+    let (mut blocks, block_3, block_3b, block_4b) = fork();
+    blocks.extend([block_3, block_3b.clone(), block_4b]);
+    Chain::new(Network::Mainnet).assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    let block_hash: String = conn.query_one("SELECT block_hash FROM test_fork_batch_transactions WHERE txid = $1", &[shared]).await.unwrap().get(0);
+    assert_eq!(block_hash, block_3b.block_hash);
+}
+
+/// Rows in each table the import fills from blocks, for the tables with `prefix`.
+async fn row_counts(conn: &tokio_postgres::Client, prefix: &str) -> Vec<(&'static str, i64)> {
+    let mut counts = Vec::new();
+    for table in ["blocks", "headers", "transactions", "inputs", "outputs", "witnesses", "op_returns", "signatures", "taproot_spends", "multisig"] {
+        let count: i64 = conn.query_one(&format!("SELECT COUNT(*) FROM {}{}", prefix, table), &[]).await.unwrap().get(0);
+        counts.push((table, count));
+    }
+    counts
+}
+
+/// Rows of `table` with the given txid.
+async fn rows_of(conn: &tokio_postgres::Client, table: &str, txid: &str) -> i64 {
+    conn.query_one(&format!("SELECT COUNT(*) FROM {} WHERE txid = $1", table), &[&txid]).await.unwrap().get(0)
+}
+
+#[tokio::test]
+async fn inserting_blocks_again_adds_no_rows() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_twice_");
+    database.setup(true).await.unwrap();
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    // Not linked to the others, but they fill the witness and Taproot tables
+    blocks.extend([parse_fixture("segwit"), parse_fixture("taproot")]);
+    database.insert_blocks(&blocks).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let counts = row_counts(&conn, "test_twice_").await;
+    assert_eq!(counts[0], ("blocks", 5));
+    database.insert_blocks(&blocks).await.unwrap();
+    // Only some of them again, along with the rest
+    database.insert_blocks(&blocks[1..3]).await.unwrap();
+    assert_eq!(row_counts(&conn, "test_twice_").await, counts);
+}
+
+#[tokio::test]
+async fn deferred_primary_keys_drop_transactions_shared_by_competing_blocks() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_fork_deferred_").with_deferred_primary_keys(true);
+    database.setup(true).await.unwrap();
+
+    // Each block in a batch of its own, so the shared transaction is only repeated in the database
+    let (blocks, block_3, block_3b, block_4b) = fork();
+    let mut chain = Chain::new(Network::Mainnet);
+    for block in blocks.into_iter().chain([block_3.clone(), block_3b, block_4b]) {
+        let mut batch = vec![block];
+        let reorged = chain.assign_heights(&mut batch);
+        database.update_active_flags(&reorged).await.unwrap();
+        database.insert_blocks(&batch).await.unwrap();
+    }
+
+    let conn = pool.get().await.unwrap();
+    let shared = &block_3.transactions[0];
+    assert_eq!(rows_of(&conn, "test_fork_deferred_transactions", &shared.txid).await, 2);
+    database.finalize().await.unwrap();
+    assert_eq!(rows_of(&conn, "test_fork_deferred_transactions", &shared.txid).await, 1);
+    assert_eq!(rows_of(&conn, "test_fork_deferred_inputs", &shared.txid).await, shared.inputs.len() as i64);
+    assert_eq!(rows_of(&conn, "test_fork_deferred_outputs", &shared.txid).await, shared.outputs.len() as i64);
+    assert!(constraints_and_indexes(&conn, "test_fork_deferred_").await.contains(&"transactions_pkey PRIMARY KEY (txid)".to_string()));
+}
+
+/// The block a transaction is stored under, and the value of its first output.
+async fn stored_copy(conn: &tokio_postgres::Client, prefix: &str, txid: &str) -> (String, i64) {
+    let row = conn.query_one(&format!(
+        "SELECT tx.block_hash, output.value FROM {0}transactions tx JOIN {0}outputs output ON output.txid = tx.txid
+         WHERE tx.txid = $1 AND output.output_index = 0",
+        prefix,
+    ), &[&txid]).await.unwrap();
+    (row.get(0), row.get(1))
+}
+
+#[tokio::test]
+async fn the_later_copy_of_a_bip30_duplicate_replaces_the_earlier_one() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_bip30_");
+    database.setup(true).await.unwrap();
+
+    // The coinbase of block 91812, repeated by block 91842, with an output value to tell them apart
+    let (txid, first_height, duplicate_height) = BIP30_DUPLICATES[1];
+    let template = parse_fixture("block_1");
+    let mut first = child_of(&parse_fixture("genesis"), &template, txid);
+    first.height = first_height;
+    let mut duplicate = child_of(&first, &template, txid);
+    duplicate.height = duplicate_height;
+    duplicate.transactions[0].outputs[0].value += 1;
+    database.insert_blocks(std::slice::from_ref(&first)).await.unwrap();
+    database.insert_blocks(std::slice::from_ref(&duplicate)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let expected = (duplicate.block_hash.clone(), duplicate.transactions[0].outputs[0].value);
+    assert_eq!(stored_copy(&conn, "test_bip30_", txid).await, expected);
+    // The earlier copy arriving after the later one is left out
+    database.insert_blocks(&[child_of(&parse_fixture("block_1"), &first, txid)]).await.unwrap();
+    assert_eq!(stored_copy(&conn, "test_bip30_", txid).await, expected);
+
+    // As it is when both are in the same batch
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_bip30_batch_");
+    database.setup(true).await.unwrap();
+    database.insert_blocks(&[first, duplicate]).await.unwrap();
+    assert_eq!(stored_copy(&conn, "test_bip30_batch_", txid).await, expected);
+}
+
+#[tokio::test]
+async fn spends_are_resolved_and_fees_computed() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_fees_");
+    database.setup(true).await.unwrap();
+
+    // Block 3 spends block 1's 50 BTC coinbase output to 30 and 19.9 BTC, leaving a 0.1 BTC fee
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    let coinbase_1 = blocks[1].transactions[0].txid.clone();
+    let spend = format!("{:064x}", 0x5e);
+    let block_3 = child_of(&blocks[2], &blocks[2], &format!("{:064x}", 3));
+    blocks.push(with_spend(block_3, &spend, &coinbase_1, 0, &[30 * COIN, 19 * COIN + 90_000_000]));
+    Chain::new(Network::Mainnet).assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    database.finish().await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let row = conn.query_one("SELECT spent_value, spent_address FROM test_fees_inputs WHERE txid = $1", &[&spend]).await.unwrap();
+    assert_eq!(row.get::<_, Option<i64>>(0), Some(50 * COIN));
+    assert_eq!(row.get::<_, Option<String>>(1), blocks[1].transactions[0].outputs[0].address);
+
+    let fees: Vec<(i32, Option<i64>)> = conn.query(
+        "SELECT blocks.height, transactions.fee FROM test_fees_transactions transactions
+         JOIN test_fees_blocks blocks ON blocks.block_hash = transactions.block_hash
+         ORDER BY blocks.height, transactions.position",
+        &[],
+    ).await.unwrap().iter().map(|row| (row.get(0), row.get(1))).collect();
+    // Coinbases claim exactly the subsidy, and the genesis coinbase has no fee at all
+    assert_eq!(fees, [(0, None), (1, Some(0)), (2, Some(0)), (3, Some(0)), (3, Some(10_000_000))]);
+}
+
+#[tokio::test]
+async fn outputs_spent_by_a_later_batch_leave_the_utxo_set() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_utxos_").with_utxo_set(true);
+    database.setup(true).await.unwrap();
+    let conn = pool.get().await.unwrap();
+    let utxos = || async {
+        conn.query("SELECT txid, output_index FROM test_utxos_utxos ORDER BY height, txid, output_index", &[]).await.unwrap()
+            .iter().map(|row| (row.get(0), row.get(1))).collect::<Vec<(String, i32)>>()
+    };
+
+    let mut chain = Chain::new(Network::Mainnet);
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    chain.assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    database.finish().await.unwrap();
+    // The genesis output can't be spent, so it never is a UTXO
+    let coinbase = |block: &Block| (block.transactions[0].txid.clone(), 0);
+    assert_eq!(utxos().await, [coinbase(&blocks[1]), coinbase(&blocks[2])]);
+
+    // Block 3 spends block 1's output in a batch of its own
+    let spend = format!("{:064x}", 0x5e);
+    let block_3 = child_of(&blocks[2], &blocks[2], &format!("{:064x}", 3));
+    let mut batch = vec![with_spend(block_3, &spend, &blocks[1].transactions[0].txid, 0, &[30 * COIN, 20 * COIN])];
+    chain.assign_heights(&mut batch);
+    database.insert_blocks(&batch).await.unwrap();
+    database.finish().await.unwrap();
+    assert_eq!(utxos().await, [coinbase(&blocks[2]), coinbase(&batch[0]), (spend.clone(), 0), (spend, 1)]);
+}
+
+#[tokio::test]
+async fn address_stats_add_up_across_batches() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_address_stats_").with_address_stats(true);
+    database.setup(true).await.unwrap();
+
+    // Early coinbases pay to bare keys, which have no address, so blocks 2 and 3 pay to a P2PKH
+    // address instead, block 3 in a batch of its own
+    let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+    let mut chain = Chain::new(Network::Mainnet);
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    let output = &mut blocks[2].transactions[0].outputs[0];
+    output.script_pub_key = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac".to_string();
+    output.script_type = ScriptType::P2pkh;
+    output.address = Some(address.to_string());
+    chain.assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    let mut batch = vec![child_of(&blocks[2], &blocks[2], &format!("{:064x}", 3))];
+    chain.assign_heights(&mut batch);
+    database.insert_blocks(&batch).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let row = conn.query_one(
+        "SELECT total_received, output_count, first_seen_height, last_seen_height FROM test_address_stats_address_stats WHERE address = $1",
+        &[&address],
+    ).await.unwrap();
+    assert_eq!((row.get::<_, i64>(0), row.get::<_, i64>(1), row.get::<_, i32>(2), row.get::<_, i32>(3)), (100 * COIN, 2, 2, 3));
+    let addresses: i64 = conn.query_one("SELECT COUNT(*) FROM test_address_stats_address_stats", &[]).await.unwrap().get(0);
+    assert_eq!(addresses, 1);
+}
+
+/// Total received, output count, and first and last seen height of `address` in the tables with `prefix`.
+async fn address_stats(conn: &tokio_postgres::Client, prefix: &str, address: &str) -> Option<(i64, i64, i32, i32)> {
+    let query = format!("SELECT total_received, output_count, first_seen_height, last_seen_height FROM {}address_stats WHERE address = $1", prefix);
+    let row = conn.query_opt(&query, &[&address]).await.unwrap()?;
+    Some((row.get(0), row.get(1), row.get(2), row.get(3)))
+}
+
+#[tokio::test]
+async fn address_stats_only_count_the_active_chain() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let prefix = "test_address_stats_reorg_";
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix(prefix).with_address_stats(true);
+    database.setup(true).await.unwrap();
+
+    // Block 2 pays to a P2PKH address, and so does every block built on it
+    let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    let output = &mut blocks[2].transactions[0].outputs[0];
+    output.script_pub_key = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac".to_string();
+    output.script_type = ScriptType::P2pkh;
+    output.address = Some(address.to_string());
+    let block_3 = child_of(&blocks[2], &blocks[2], &format!("{:064x}", 3));
+    let mut template = blocks[2].clone();
+    template.nonce += 1;
+    let block_3b = child_of(&blocks[2], &template, &format!("{:064x}", 0x3b));
+    let block_4b = child_of(&block_3b, &blocks[2], &format!("{:064x}", 4));
+
+    let mut chain = Chain::new(Network::Mainnet);
+    blocks.push(block_3.clone());
+    chain.assign_heights(&mut blocks);
+    database.insert_blocks(&blocks).await.unwrap();
+    let conn = pool.get().await.unwrap();
+    assert_eq!(address_stats(&conn, prefix, address).await, Some((100 * COIN, 2, 2, 3)));
+
+    // 3' arrives on its own and stays stale, until 4' moves the active chain onto its branch
+    let mut branch = vec![block_3b];
+    assert!(chain.assign_heights(&mut branch).is_empty());
+    database.insert_blocks(&branch).await.unwrap();
+    assert_eq!(address_stats(&conn, prefix, address).await, Some((100 * COIN, 2, 2, 3)));
+    let mut branch = vec![block_4b];
+    let reorged = chain.assign_heights(&mut branch);
+    assert_eq!(reorged.len(), 2);
+    database.update_active_flags(&reorged).await.unwrap();
+    database.insert_blocks(&branch).await.unwrap();
+    assert_eq!(address_stats(&conn, prefix, address).await, Some((150 * COIN, 3, 2, 4)));
+
+    // Rewinding takes out the active blocks it removes, and leaves the stale 3 out as before
+    database.rewind_to_height(2).await.unwrap();
+    assert_eq!(address_stats(&conn, prefix, address).await, Some((50 * COIN, 1, 2, 2)));
+    database.rewind_to_height(1).await.unwrap();
+    assert_eq!(address_stats(&conn, prefix, address).await, None);
+}
+
+#[tokio::test]
+async fn only_connection_and_concurrency_errors_are_transient() {
+    let Some(pool) = test_database().await else { return };
+    let conn = pool.get().await.unwrap();
+
+    // A Database error can only come from a server, so have it raise each SQLSTATE
+    for (code, transient) in [("08006", true), ("08001", true), ("40001", true), ("40P01", true), ("57P01", true), ("23505", false), ("42P01", false)] {
+        let statement = format!("DO $$ BEGIN RAISE EXCEPTION 'raised' USING ERRCODE = '{}'; END $$", code);
+        let error = ParserError::from(conn.batch_execute(&statement).await.unwrap_err());
+        assert_eq!(error.is_transient(), transient, "{}", code);
+        assert!(error.is_fatal());
+    }
+
+    // Closed connections have no SQLSTATE at all
+    let _ = conn.batch_execute("SELECT pg_terminate_backend(pg_backend_pid())").await;
+    let error = conn.batch_execute("SELECT 1").await.unwrap_err();
+    assert!(error.is_closed() && error.code().is_none());
+    assert!(ParserError::from(error).is_transient());
+
+    assert!(!ParserError::UnexpectedEof.is_transient());
+}
+
+#[tokio::test]
+async fn versions_are_stored_unsigned() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_version_");
+    database.setup(true).await.unwrap();
+
+    let mut blocks = chain_of(&["genesis"]);
+    blocks[0].version = 0x8000_0004;
+    blocks[0].transactions[0].version = 0x8000_0001;
+    blocks[0].transactions[0].txid = calculate_tx(&blocks[0].transactions[0]).unwrap().0;
+    database.insert_blocks(&blocks).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let block_version: i64 = conn.query_one("SELECT version FROM test_version_blocks", &[]).await.unwrap().get(0);
+    let tx_version: i64 = conn.query_one("SELECT version FROM test_version_transactions", &[]).await.unwrap().get(0);
+    assert_eq!((block_version, tx_version), (2_147_483_652, 2_147_483_649));
+}
+
+#[tokio::test]
+async fn empty_scripts_are_stored_as_empty_strings() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_empty_script_");
+    database.setup(true).await.unwrap();
+    database.insert_blocks(&[empty_scripts_block()]).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let script_sig: Option<String> = conn.query_one("SELECT script_sig FROM test_empty_script_inputs WHERE txid = $1", &[&EMPTY_SCRIPTS_TXID]).await.unwrap().get(0);
+    let script_pub_key: Option<String> = conn.query_one("SELECT script_pub_key FROM test_empty_script_outputs WHERE txid = $1", &[&EMPTY_SCRIPTS_TXID]).await.unwrap().get(0);
+    // Not NULL, which an unquoted empty CSV field would give
+    assert_eq!((script_sig.as_deref(), script_pub_key.as_deref()), (Some(""), Some("")));
+}
+
+#[tokio::test]
+async fn missing_values_are_stored_as_null() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_null_values_");
+    database.setup(true).await.unwrap();
+    let genesis = parse_fixture("genesis");
+    database.insert_blocks(std::slice::from_ref(&genesis)).await.unwrap();
+
+    // The genesis coinbase pays to a bare key, which has no address, and raw bytes weren't kept
+    let conn = pool.get().await.unwrap();
+    let txid = &genesis.transactions[0].txid;
+    let address: Option<String> = conn.query_one("SELECT address FROM test_null_values_outputs WHERE txid = $1", &[txid]).await.unwrap().get(0);
+    let raw: Option<Vec<u8>> = conn.query_one("SELECT raw FROM test_null_values_transactions WHERE txid = $1", &[txid]).await.unwrap().get(0);
+    assert_eq!((address, raw), (None, None));
+}
+
+#[tokio::test]
+async fn raw_bytes_are_stored_when_kept() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_raw_bytes_");
+    database.setup(true).await.unwrap();
+    let bytes = fixture("segwit").into_inner();
+    let block = FileReader::in_memory(Network::Mainnet).with_raw_bytes(true).read_block_from_bytes(&bytes).unwrap();
+    let block = process_block(block, Network::Mainnet, true).unwrap();
+    database.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let raw: Vec<u8> = conn.query_one("SELECT raw FROM test_raw_bytes_blocks WHERE block_hash = $1", &[&block.block_hash]).await.unwrap().get(0);
+    assert_eq!(raw, bytes);
+    for tx in &block.transactions {
+        let raw: Vec<u8> = conn.query_one("SELECT raw FROM test_raw_bytes_transactions WHERE txid = $1", &[&tx.txid]).await.unwrap().get(0);
+        assert_eq!(Some(raw), tx.raw);
+    }
+}
+
+#[tokio::test]
+async fn hashes_are_stored_in_display_order() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_byte_order_");
+    database.setup(true).await.unwrap();
+    database.insert_blocks(&chain_of(&["genesis", "block_1"])).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let row = conn
+        .query_one("SELECT decode(block_hash, 'hex'), decode(merkle_root, 'hex') FROM test_byte_order_blocks WHERE height = 0", &[])
+        .await
+        .unwrap();
+    let (block_hash, merkle_root): (Vec<u8>, Vec<u8>) = (row.get(0), row.get(1));
+    assert_eq!(hex::encode(&block_hash), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    // The only transaction's txid is the merkle root
+    let txid: String = conn.query_one("SELECT txid FROM test_byte_order_transactions WHERE block_hash = $1", &[&hex::encode(&block_hash)]).await.unwrap().get(0);
+    assert_eq!(hex::decode(txid).unwrap(), merkle_root);
+
+    // Reversed, they are the bytes block 1's header and the genesis header hold
+    let internal = |hash: &[u8]| hash.iter().rev().copied().collect::<Vec<u8>>();
+    assert_eq!(internal(&block_hash), fixture("block_1").into_inner()[4..36]);
+    assert_eq!(internal(&merkle_root), fixture("genesis").into_inner()[36..68]);
+}
+
+#[tokio::test]
+async fn coinbase_previous_output_indexes_are_stored_unsigned() {
+    // 0xffffffff in the blk file, parsed without wrapping around
+    let genesis = parse_fixture("genesis");
+    let coinbase = &genesis.transactions[0].inputs[0];
+    assert!(coinbase.is_coinbase);
+    assert_eq!(coinbase.previous_output_index, 4_294_967_295);
+
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_coinbase_index_");
+    database.setup(true).await.unwrap();
+    database.insert_blocks(std::slice::from_ref(&genesis)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let index: i64 = conn
+        .query_one("SELECT previous_output_index FROM test_coinbase_index_inputs WHERE txid = $1", &[&genesis.transactions[0].txid])
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(index, 4_294_967_295);
+}
+
+#[tokio::test]
+async fn headers_only_import_matches_a_full_import() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let full = Database::new(pool.clone(), false, 1).with_table_prefix("test_headers_full_");
+    full.setup(true).await.unwrap();
+    full.insert_blocks(&chain_of(&["genesis", "block_1", "block_2"])).await.unwrap();
+
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-headers-only-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let contents: Vec<u8> = ["block_1", "genesis", "block_2"].iter().flat_map(|fixture| blk_record(fixture)).collect();
+    std::fs::write(dir.join("blk00000.dat"), contents).unwrap();
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default());
+    let chain = Chain::new(Network::Mainnet);
+    let (mut headers, _) = chain.order_headers(file_reader.read_headers(0).unwrap());
+    chain.assign_header_heights(&mut headers);
+
+    let headers_only = Database::new(pool.clone(), false, 1).with_table_prefix("test_headers_only_");
+    headers_only.setup(true).await.unwrap();
+    headers_only.insert_headers(&headers).await.unwrap();
+    // Inserting them again, as a second run would, leaves the rows as they are
+    headers_only.insert_headers(&headers).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let mut rows = Vec::new();
+    for prefix in ["test_headers_full_", "test_headers_only_"] {
+        let query = format!("SELECT header::TEXT FROM {}headers header ORDER BY height", prefix);
+        rows.push(conn.query(&query, &[]).await.unwrap().iter().map(|row| row.get(0)).collect::<Vec<String>>());
+    }
+    assert_eq!(rows[0].len(), 3);
+    assert_eq!(rows[0], rows[1]);
+    let blocks: i64 = conn.query_one("SELECT COUNT(*) FROM test_headers_only_blocks", &[]).await.unwrap().get(0);
+    assert_eq!(blocks, 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Constraints and indexes of the tables with `prefix`, with the prefix taken out of their names
+/// and definitions.
+async fn constraints_and_indexes(conn: &tokio_postgres::Client, prefix: &str) -> Vec<String> {
+    let rows = conn.query(
+        "SELECT replace(conname || ' ' || pg_get_constraintdef(oid), $1, '') FROM pg_constraint WHERE conrelid::regclass::TEXT LIKE $1 || '%'
+         UNION ALL
+         SELECT replace(indexname || ' ' || indexdef, $1, '') FROM pg_indexes WHERE tablename LIKE $1 || '%'
+         ORDER BY 1",
+        &[&prefix],
+    ).await.unwrap();
+    rows.iter().map(|row| row.get(0)).collect()
+}
+
+#[tokio::test]
+async fn finalize_adds_the_constraints_of_the_eager_schema() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    let eager = Database::new(pool.clone(), true, 1).with_table_prefix("test_eager_");
+    eager.setup(true).await.unwrap();
+    eager.insert_blocks(&blocks).await.unwrap();
+    eager.finalize().await.unwrap();
+
+    let deferred = Database::new(pool.clone(), true, 1).with_table_prefix("test_deferred_").with_deferred_primary_keys(true);
+    deferred.setup(true).await.unwrap();
+    let conn = pool.get().await.unwrap();
+    let before = constraints_and_indexes(&conn, "test_deferred_").await;
+    assert!(!before.iter().any(|constraint| constraint.starts_with("transactions_pkey")), "{:?}", before);
+    deferred.insert_blocks(&blocks).await.unwrap();
+    deferred.finalize().await.unwrap();
+
+    let expected = constraints_and_indexes(&conn, "test_eager_").await;
+    assert!(expected.contains(&"transactions_pkey PRIMARY KEY (txid)".to_string()), "{:?}", expected);
+    assert!(expected.contains(&"inputs_txid_fkey FOREIGN KEY (txid) REFERENCES transactions(txid)".to_string()), "{:?}", expected);
+    assert_eq!(constraints_and_indexes(&conn, "test_deferred_").await, expected);
+}
+
+#[tokio::test]
+async fn tables_are_created_under_the_longest_prefix() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    // 32 characters, so the longest index and constraint names reach Postgres' 63 bytes
+    let prefix = "test_longest_table_prefix_abcde_";
+    assert_eq!(prefix.len(), 32);
+    let long = Database::new(pool.clone(), true, 1).with_table_prefix(prefix);
+    long.setup(true).await.unwrap();
+    let short = Database::new(pool.clone(), true, 1).with_table_prefix("test_short_");
+    short.setup(true).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let tables: Vec<String> = conn
+        .query("SELECT tablename FROM pg_tables WHERE tablename LIKE $1 || '%' ORDER BY 1", &[&prefix])
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(tables.len(), 14, "{:?}", tables);
+    // Nothing was cut short
+    assert_eq!(constraints_and_indexes(&conn, prefix).await, constraints_and_indexes(&conn, "test_short_").await);
+
+    long.insert_blocks(&chain_of(&["genesis", "block_1"])).await.unwrap();
+    assert_eq!(row_counts(&conn, prefix).await[0], ("blocks", 2));
+}
+
+async fn foreign_keys(conn: &tokio_postgres::Client, prefix: &str) -> Vec<String> {
+    let rows = conn.query(
+        "SELECT replace(conname, $1, '') FROM pg_constraint WHERE contype = 'f' AND conrelid::regclass::TEXT LIKE $1 || '%' ORDER BY 1",
+        &[&prefix],
+    ).await.unwrap();
+    rows.iter().map(|row| row.get(0)).collect()
+}
+
+#[tokio::test]
+async fn foreign_keys_are_only_added_when_requested() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), true, 1).with_table_prefix("test_foreign_keys_");
+    database.setup(true).await.unwrap();
+    database.finalize().await.unwrap();
+    let conn = pool.get().await.unwrap();
+    assert_eq!(foreign_keys(&conn, "test_foreign_keys_").await, [
+        "inputs_txid_fkey",
+        "multisig_txid_fkey",
+        "op_returns_output_fkey",
+        "outputs_txid_fkey",
+        "signatures_input_fkey",
+        "taproot_spends_input_fkey",
+        "transactions_block_hash_fkey",
+        "witnesses_txid_fkey",
+    ]);
+
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_no_foreign_keys_");
+    database.setup(true).await.unwrap();
+    database.finalize().await.unwrap();
+    assert_eq!(foreign_keys(&conn, "test_no_foreign_keys_").await, Vec::<String>::new());
+}
+
+async fn witness_items(conn: &tokio_postgres::Client, txid: &str) -> Vec<(i32, i32, Vec<u8>)> {
+    let rows = conn.query("SELECT input_index, item_index, item FROM test_witnesses_witnesses WHERE txid = $1 ORDER BY input_index, item_index", &[&txid]).await.unwrap();
+    rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect()
+}
+
+#[tokio::test]
+async fn witness_items_are_stored_in_stack_order() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_witnesses_");
+    database.setup(true).await.unwrap();
+    // An empty item in front of the P2WPKH spend's signature and key, like CHECKMULTISIG's dummy
+    let mut block = parse_fixture("segwit");
+    let stack = &mut block.transactions[1].witness.as_mut().unwrap()[0];
+    let (signature, key) = (stack[0].clone(), stack[1].clone());
+    stack.insert(0, Vec::new());
+    database.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    // The coinbase's witness reserved value
+    assert_eq!(witness_items(&conn, &block.transactions[0].txid).await, [(0, 0, vec![0; 32])]);
+    assert_eq!(witness_items(&conn, &block.transactions[1].txid).await, [(0, 0, Vec::new()), (0, 1, signature), (0, 2, key)]);
+}
+
+#[tokio::test]
+async fn op_return_pushes_are_stored_until_the_first_other_opcode() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_op_returns_");
+    database.setup(true).await.unwrap();
+    // OP_RETURN "omni" OP_0 OP_DUP <0304> in place of the spend's second output
+    let mut block = parse_fixture("segwit");
+    let output = &mut block.transactions[1].outputs[1];
+    output.script_pub_key = "6a046f6d6e690076020304".to_string();
+    output.script_type = ScriptType::OpReturn;
+    output.address = None;
+    database.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let rows = conn.query("SELECT txid, output_index, push_index, data FROM test_op_returns_op_returns ORDER BY txid = $1, output_index, push_index", &[&block.transactions[0].txid]).await.unwrap();
+    let pushes: Vec<(String, i32, i32, Vec<u8>)> = rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))).collect();
+    let commitment = block.transactions[0].outputs.iter().find(|output| output.script_type == ScriptType::OpReturn).unwrap();
+    let spend = block.transactions[1].txid.clone();
+    assert_eq!(pushes, [
+        (spend.clone(), 1, 0, b"omni".to_vec()),
+        (spend, 1, 1, Vec::new()),
+        // The coinbase's witness commitment, a single push
+        (block.transactions[0].txid.clone(), commitment.output_index, 0, hex::decode(&commitment.script_pub_key[4..]).unwrap()),
+    ]);
+}
+
+#[tokio::test]
+async fn only_der_signatures_are_stored() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_signatures_");
+    database.setup(true).await.unwrap();
+    let block = parse_fixture("segwit");
+    // The same P2WPKH spend with its signature's DER header broken, under another txid
+    let mut not_der = block.transactions[1].clone();
+    not_der.witness.as_mut().unwrap()[0][0][0] = 0x31;
+    not_der.txid = format!("{:064x}", 1);
+    let mut blocks = vec![block.clone()];
+    blocks[0].transactions.push(not_der);
+    database.insert_blocks(&blocks).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let rows = conn.query("SELECT txid, input_index, pubkey, signature FROM test_signatures_signatures", &[]).await.unwrap();
+    let signatures: Vec<(String, i32, Vec<u8>, Vec<u8>)> = rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))).collect();
+    let witness = &block.transactions[1].witness.as_ref().unwrap()[0];
+    // Kept as it appears, sighash byte included
+    assert_eq!(witness[0].last(), Some(&0x01));
+    assert_eq!(signatures, [(block.transactions[1].txid.clone(), 0, witness[1].clone(), witness[0].clone())]);
+}
+
+#[tokio::test]
+async fn taproot_spends_are_stored_by_path() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_taproot_");
+    database.setup(true).await.unwrap();
+    // An annex behind the key-path spend's signature
+    let mut block = parse_fixture("taproot");
+    let annex = vec![0x50, 0xaa, 0xbb];
+    block.transactions[1].witness.as_mut().unwrap()[0].push(annex.clone());
+    database.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let rows = conn.query(
+        "SELECT txid, input_index, spend_type, signature, leaf_version, internal_key, script, annex FROM test_taproot_taproot_spends ORDER BY spend_type",
+        &[],
+    ).await.unwrap();
+    type Row = (String, i32, String, Option<Vec<u8>>, Option<i32>, Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+    let spends: Vec<Row> = rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4), row.get(5), row.get(6), row.get(7))).collect();
+
+    let key_path = &block.transactions[1].witness.as_ref().unwrap()[0];
+    let script_path = &block.transactions[2].witness.as_ref().unwrap()[0];
+    let (script, control) = (&script_path[script_path.len() - 2], &script_path[script_path.len() - 1]);
+    assert_eq!(spends, [
+        (block.transactions[1].txid.clone(), 0, "key".to_string(), Some(key_path[0].clone()), None, None, None, Some(annex)),
+        (block.transactions[2].txid.clone(), 0, "script".to_string(), None, Some(0xc0), Some(control[1..33].to_vec()), Some(script.clone()), None),
+    ]);
+}
+
+#[tokio::test]
+async fn transactions_keep_their_position_in_the_block() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_position_");
+    database.setup(true).await.unwrap();
+    let block = parse_fixture("taproot");
+    database.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let rows = conn.query("SELECT txid, position FROM test_position_transactions WHERE block_hash = $1 ORDER BY position", &[&block.block_hash]).await.unwrap();
+    let stored: Vec<(String, i32)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    let expected: Vec<(String, i32)> = block.transactions.iter().enumerate().map(|(position, tx)| (tx.txid.clone(), position as i32)).collect();
+    assert_eq!(stored, expected);
+}
+
+#[tokio::test]
+async fn tables_of_an_older_version_are_reported() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_schema_");
+    database.setup(true).await.unwrap();
+    assert_eq!(database.check_schema().await.unwrap(), Vec::<String>::new());
+
+    pool.get().await.unwrap().batch_execute("
+        ALTER TABLE test_schema_blocks DROP COLUMN rbf_tx_count;
+        ALTER TABLE test_schema_transactions ALTER COLUMN version TYPE INT;
+    ").await.unwrap();
+    // Missing tables are created again, but not missing columns
+    database.setup(false).await.unwrap();
+    assert_eq!(database.check_schema().await.unwrap(), [
+        "test_schema_blocks.rbf_tx_count is missing",
+        "test_schema_transactions.version is integer, expected bigint",
+    ]);
+}
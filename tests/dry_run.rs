@@ -0,0 +1,32 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use common::blk_record;
+
+#[test]
+fn dry_runs_parse_everything_without_a_database() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-dry-run-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("blk00000.dat"), [blk_record("genesis"), blk_record("block_1")].concat()).unwrap();
+    fs::write(dir.join("blk00001.dat"), blk_record("block_2")).unwrap();
+
+    // Run from the blocks directory, away from any .env, with a database that can't be reached
+    let output = Command::new(env!("CARGO_BIN_EXE_bitcoin_postgres_parser"))
+        .current_dir(&dir)
+        .env("BLOCKS_PATH", &dir)
+        .env("DRY_RUN", "true")
+        .env("DATABASE_URL", "host=/nonexistent port=1 connect_timeout=1")
+        .env("RUST_LOG", "info")
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", log, String::from_utf8_lossy(&output.stderr));
+    assert!(log.contains("Dry run finished"), "{}", log);
+    assert!(log.contains("files=2") && log.contains("blocks=3") && log.contains("parse_errors=0"), "{}", log);
+    assert!(!log.contains("Connecting to the database"), "{}", log);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
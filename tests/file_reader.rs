@@ -0,0 +1,154 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::{FileReader, Network, ParseLimits, ParserError};
+
+use common::{blk_record, fixture};
+
+/// Writes `contents` as a blk file of its own, named after the test.
+fn blk_file(name: &str, contents: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-file-reader-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("blk00000.dat");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn wrong_magic_is_reported_with_its_offset() {
+    // Testnet magic behind a mainnet block
+    let genesis = blk_record("genesis");
+    let mut wrong = blk_record("block_1");
+    wrong[..4].copy_from_slice(&Network::Testnet3.magic().to_le_bytes());
+    let path = blk_file("magic", &[genesis.clone(), wrong].concat());
+
+    let file_reader = FileReader::new(path.clone(), Network::Mainnet, Arc::default());
+    match file_reader.read_file(0) {
+        Err(ParserError::MagicMismatch { expected, found, offset }) => {
+            assert_eq!(expected, Network::Mainnet);
+            assert_eq!(found, Network::Testnet3.magic());
+            assert_eq!(offset, genesis.len() as u64);
+        }
+        other => panic!("Expected a magic mismatch, got {:?}", other.map(|blocks| blocks.len())),
+    }
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn reading_resumes_at_the_next_magic_after_a_corrupt_size() {
+    // The genesis block claiming more than any block can hold, followed by block 1
+    let mut corrupt = blk_record("genesis");
+    corrupt[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+    let path = blk_file("corrupt-size", &[corrupt.clone(), blk_record("block_1")].concat());
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = FileReader::new(path.clone(), Network::Mainnet, Arc::clone(&metrics));
+    let blocks = file_reader.read_file(0).unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].byte_offset, corrupt.len() as i64);
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 1);
+
+    let headers = file_reader.read_headers(0).unwrap();
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers[0].block_hash, "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+/// The genesis header with a single transaction, whose one output script is `script_size` zero
+/// bytes behind a length encoded as `length`.
+fn block_with_script_length(length: &[u8], script_size: usize) -> Vec<u8> {
+    let mut bytes = fixture("genesis").into_inner()[..80].to_vec();
+    bytes.push(1);
+    bytes.extend(hex::decode("01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff00ffffffff010000000000000000").unwrap());
+    bytes.extend_from_slice(length);
+    bytes.resize(bytes.len() + script_size, 0);
+    bytes.extend_from_slice(&[0; 4]);
+    bytes
+}
+
+#[test]
+fn varints_are_read_at_each_width_in_their_shortest_encoding() {
+    let file_reader = FileReader::in_memory(Network::Mainnet);
+    let script_size = |length: &[u8], script_size| {
+        file_reader.read_block_from_bytes(&block_with_script_length(length, script_size))
+            .map(|block| block.transactions[0].outputs[0].script_pub_key.len() / 2)
+    };
+
+    assert_eq!(script_size(&[0xfc], 252).unwrap(), 252);
+    assert_eq!(script_size(&[0xfd, 0xfd, 0x00], 253).unwrap(), 253);
+    assert_eq!(script_size(&[0xfd, 0xff, 0xff], 0xffff).unwrap(), 0xffff);
+    assert_eq!(script_size(&[0xfe, 0x00, 0x00, 0x01, 0x00], 0x1_0000).unwrap(), 0x1_0000);
+    assert!(matches!(script_size(&[0xff, 0, 0, 0, 0, 1, 0, 0, 0], 0), Err(ParserError::VarIntOverflow(0x1_0000_0000))));
+
+    // Each value in a wider encoding than it needs
+    assert!(matches!(script_size(&[0xfd, 0xfc, 0x00], 252), Err(ParserError::NonCanonicalVarInt(252))));
+    assert!(matches!(script_size(&[0xfe, 0xff, 0xff, 0x00, 0x00], 0xffff), Err(ParserError::NonCanonicalVarInt(0xffff))));
+    assert!(matches!(script_size(&[0xff, 0x00, 0x00, 0x01, 0x00, 0, 0, 0, 0], 0x1_0000), Err(ParserError::NonCanonicalVarInt(0x1_0000))));
+}
+
+/// Frames a serialized block the way it is stored in a blk file.
+fn record(block: &[u8]) -> Vec<u8> {
+    [&Network::Mainnet.magic().to_le_bytes()[..], &(block.len() as u32).to_le_bytes(), block].concat()
+}
+
+/// Reads a file holding `first`, which is just over one of `limits`, and then block 1, which is
+/// within all of them.
+fn read_past_limit(name: &str, limits: ParseLimits, first: Vec<u8>) {
+    let path = blk_file(name, &[first.clone(), blk_record("block_1")].concat());
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = FileReader::new(path.clone(), Network::Mainnet, Arc::clone(&metrics)).with_limits(limits);
+
+    let blocks = file_reader.read_file(0).unwrap();
+    assert_eq!(blocks.len(), 1, "{}", name);
+    // Hashes are only computed when processing the block, so block 1 is known by its merkle root
+    assert_eq!(blocks[0].merkle_root, "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
+    assert_eq!(blocks[0].byte_offset, first.len() as i64);
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 1);
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn reading_resumes_after_a_block_over_each_limit() {
+    let limits = ParseLimits::default();
+    // The genesis coinbase's 77-byte scriptSig, against block 1's 7-byte one and 67-byte output
+    read_past_limit("script-size", ParseLimits { max_script_size: 76, ..limits }, blk_record("genesis"));
+    // Two transactions, each with two outputs
+    read_past_limit("transactions", ParseLimits { max_transactions: 1, ..limits }, blk_record("segwit"));
+    read_past_limit("outputs", ParseLimits { max_outputs: 1, ..limits }, blk_record("segwit"));
+
+    // A transaction with two empty inputs and one empty output
+    let mut two_inputs = fixture("genesis").into_inner()[..80].to_vec();
+    two_inputs.push(1);
+    two_inputs.extend(hex::decode("0100000002").unwrap());
+    for _ in 0..2 {
+        two_inputs.extend_from_slice(&[0; 36]);
+        two_inputs.extend(hex::decode("00ffffffff").unwrap());
+    }
+    two_inputs.extend(hex::decode("01000000000000000000").unwrap());
+    two_inputs.extend_from_slice(&[0; 4]);
+    read_past_limit("inputs", ParseLimits { max_inputs: 1, ..limits }, record(&two_inputs));
+}
+
+#[test]
+fn blk_files_are_listed_in_order_and_other_files_ignored() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-file-reader-listing-{}", std::process::id()));
+    fs::create_dir_all(dir.join("index")).unwrap();
+    for name in ["blk00010.dat", "blk00009.dat", "rev00009.dat", "xor.dat", ".lock", "blk00011.dat.tmp", "blk00000.dat"] {
+        fs::write(dir.join(name), []).unwrap();
+    }
+
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default());
+    let names: Vec<_> = file_reader.file_paths.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    assert_eq!(names, ["blk00000.dat", "blk00009.dat", "blk00010.dat"]);
+    assert_eq!(file_reader.list_files().unwrap(), file_reader.file_paths);
+
+    fs::remove_dir_all(dir).unwrap();
+}
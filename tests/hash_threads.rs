@@ -0,0 +1,27 @@
+mod common;
+
+use bitcoin_postgres_parser::block_processor::calculate_tx;
+use bitcoin_postgres_parser::{process_block, Network};
+
+use common::parse_fixture;
+
+#[test]
+fn transactions_keep_their_order_whatever_the_thread_count() {
+    // The segwit spend repeated with a different locktime each time, for distinct txids
+    let mut block = parse_fixture("segwit");
+    let spend = block.transactions.pop().unwrap();
+    for locktime in 0..500 {
+        let mut tx = spend.clone();
+        tx.locktime = locktime;
+        block.transactions.push(tx);
+    }
+    let expected: Vec<String> = block.transactions.iter().map(|tx| calculate_tx(tx).unwrap().0).collect();
+
+    for threads in [1, 4] {
+        let hash_pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let processed = hash_pool.install(|| process_block(block.clone(), Network::Mainnet, false)).unwrap();
+        let txids: Vec<String> = processed.transactions.iter().map(|tx| tx.txid.clone()).collect();
+        assert_eq!(txids, expected, "{} threads", threads);
+        assert!(processed.transactions.iter().enumerate().all(|(position, tx)| tx.position == position as i32));
+    }
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use bitcoin_postgres_parser::chain::Chain;
+use bitcoin_postgres_parser::processing::partition_by_height;
+use bitcoin_postgres_parser::{Block, Network};
+
+use common::chain_of;
+
+fn heights(blocks: &[(usize, Block)]) -> Vec<i32> {
+    blocks.iter().map(|(_, block)| block.height).collect()
+}
+
+#[test]
+fn height_ranges_keep_their_boundary_blocks() {
+    let mut blocks = chain_of(&["genesis", "block_1", "block_2"]);
+    Chain::new(Network::Mainnet).assign_heights(&mut blocks);
+    let blocks: Vec<_> = blocks.into_iter().enumerate().collect();
+
+    let (kept, dropped) = partition_by_height(blocks.clone(), &(1..=1));
+    assert_eq!(heights(&kept), [1]);
+    assert_eq!(heights(&dropped), [0, 2]);
+    // The file each block came from stays with it
+    assert_eq!(kept[0].0, 1);
+
+    let (kept, dropped) = partition_by_height(blocks.clone(), &(0..=1));
+    assert_eq!(heights(&kept), [0, 1]);
+    assert_eq!(heights(&dropped), [2]);
+
+    // START_HEIGHT alone runs to the end of the chain
+    let (kept, dropped) = partition_by_height(blocks, &(2..=i32::MAX));
+    assert_eq!(heights(&kept), [2]);
+    assert_eq!(heights(&dropped), [0, 1]);
+}
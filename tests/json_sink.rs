@@ -0,0 +1,33 @@
+mod common;
+
+use std::fs;
+
+use bitcoin_postgres_parser::json_sink::JsonSink;
+use bitcoin_postgres_parser::{Block, BlockSink};
+
+use common::chain_of;
+
+#[tokio::test]
+async fn blocks_are_written_one_per_line() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-json-sink-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("blocks.ndjson");
+
+    let blocks = chain_of(&["genesis", "block_1"]);
+    let sink = JsonSink::create(&path).unwrap();
+    sink.insert_blocks(&blocks[..1]).await.unwrap();
+    sink.insert_blocks(&blocks[1..]).await.unwrap();
+    sink.finish().await.unwrap();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+
+    // A line reads back as the block it was written from
+    let block: Block = serde_json::from_str(lines[1]).unwrap();
+    assert_eq!(block.block_hash, blocks[1].block_hash);
+    assert_eq!(block.transactions[0].txid, blocks[1].transactions[0].txid);
+    assert_eq!(serde_json::to_string(&block).unwrap(), lines[1]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::processing::{spawn_reader, Message};
+use bitcoin_postgres_parser::{FileReader, Network};
+use tokio::sync::mpsc;
+
+use common::blk_record;
+
+#[tokio::test]
+async fn counters_advance_as_blocks_are_processed() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-metrics-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let contents = [blk_record("genesis"), blk_record("block_1"), blk_record("block_2")].concat();
+    fs::write(dir.join("blk00000.dat"), &contents).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(10);
+    let reader = spawn_reader(file_reader, vec![0], 1, 1, sender, Arc::clone(&metrics), hash_pool, true);
+    let mut blocks = 0;
+    while let Some(message) = receiver.recv().await {
+        if let Message::Block(_) = message {
+            blocks += 1;
+        }
+    }
+    reader.await.unwrap();
+
+    assert_eq!(blocks, 3);
+    assert_eq!(metrics.files_read.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.bytes_read.load(Ordering::Relaxed), contents.len());
+    assert_eq!(metrics.blocks.load(Ordering::Relaxed), 3);
+    // One coinbase with one input and one output in each block
+    assert_eq!(metrics.transactions.load(Ordering::Relaxed), 3);
+    assert_eq!(metrics.inputs.load(Ordering::Relaxed), 3);
+    assert_eq!(metrics.outputs.load(Ordering::Relaxed), 3);
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 0);
+    assert_eq!(metrics.skipped_blocks.load(Ordering::Relaxed), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn metrics_render_in_the_exposition_format() {
+    let metrics = Metrics::default();
+    metrics.blocks.store(3, Ordering::Relaxed);
+    metrics.record_copy("outputs", Duration::from_millis(1500));
+    let body = metrics.render();
+
+    assert!(body.contains("# HELP bitcoin_parser_blocks_total Blocks parsed\n"));
+    assert!(body.contains("# TYPE bitcoin_parser_blocks_total counter\nbitcoin_parser_blocks_total 3\n"));
+    assert!(body.contains("# TYPE bitcoin_parser_files_in_memory gauge\n"));
+    assert!(body.contains("bitcoin_parser_copy_seconds_total{table=\"outputs\"} 1.5\n"));
+
+    // Every sample is a metric name, with optional labels, followed by a number
+    for line in body.lines().filter(|line| !line.starts_with('#')) {
+        let (name, value) = line.rsplit_once(' ').unwrap();
+        assert!(name.starts_with("bitcoin_parser_"), "{}", line);
+        assert!(value.parse::<f64>().is_ok(), "{}", line);
+    }
+}
@@ -0,0 +1,42 @@
+use bitcoin_postgres_parser::models::RelativeLocktime;
+use bitcoin_postgres_parser::Input;
+
+fn input(sequence: u32, is_coinbase: bool) -> Input {
+    Input {
+        input_index: 0,
+        previous_txid: "11".repeat(32),
+        previous_output_index: 0,
+        script_sig: String::new(),
+        sequence: sequence as i64,
+        is_coinbase,
+        spent_value: None,
+        spent_address: None,
+    }
+}
+
+#[test]
+fn rbf_is_signaled_below_0xfffffffe() {
+    assert!(input(0, false).rbf_signaled());
+    assert!(input(0xffff_fffd, false).rbf_signaled());
+    assert!(!input(0xffff_fffe, false).rbf_signaled());
+    assert!(!input(0xffff_ffff, false).rbf_signaled());
+    // Coinbase inputs can't be replaced, whatever their sequence
+    assert!(!input(0, true).rbf_signaled());
+    assert!(!input(0xffff_fffd, true).rbf_signaled());
+}
+
+#[test]
+fn relative_locktimes_follow_bip68() {
+    // 10 blocks, and 2 units of 512 seconds
+    assert_eq!(input(10, false).relative_locktime(2), Some(RelativeLocktime::Blocks(10)));
+    assert_eq!(input(1 << 22 | 2, false).relative_locktime(2), Some(RelativeLocktime::Seconds(1024)));
+    // Bits outside the type flag and the 16-bit value are ignored
+    assert_eq!(input(0x0040_0000 | 0x0001_0003, false).relative_locktime(2), Some(RelativeLocktime::Seconds(3 * 512)));
+    assert_eq!(input(0xffff, false).relative_locktime(3), Some(RelativeLocktime::Blocks(0xffff)));
+
+    // Version 1 transactions, the disable flag and coinbase inputs have none
+    assert_eq!(input(10, false).relative_locktime(1), None);
+    assert_eq!(input(1 << 31 | 10, false).relative_locktime(2), None);
+    assert_eq!(input(0xffff_ffff, false).relative_locktime(2), None);
+    assert_eq!(input(10, true).relative_locktime(2), None);
+}
@@ -0,0 +1,43 @@
+use bitcoin_postgres_parser::script::{classify_script, extract_p2sh_multisig, parse_multisig, Multisig, ScriptType};
+
+/// Multiples of the secp256k1 generator: G, 2G and 3G compressed, and G uncompressed.
+const G: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const G2: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+const G3: &str = "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+const G_UNCOMPRESSED: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+/// A DER-shaped signature with SIGHASH_ALL, pushed.
+const SIGNATURE_PUSH: &str = "09300602010102010101";
+
+fn bytes(parts: &[&str]) -> Vec<u8> {
+    hex::decode(parts.concat()).unwrap()
+}
+
+#[test]
+fn two_of_three_p2sh_input() {
+    // OP_2 <G> <2G> <G uncompressed> OP_3 OP_CHECKMULTISIG, 137 bytes
+    let redeem_script = bytes(&["52", "21", G, "21", G2, "41", G_UNCOMPRESSED, "53", "ae"]);
+    // OP_0 <signature> <signature> OP_PUSHDATA1 <redeem script>
+    let script_sig = bytes(&["00", SIGNATURE_PUSH, SIGNATURE_PUSH, "4c89", &hex::encode(&redeem_script)]);
+
+    let multisig = extract_p2sh_multisig(&script_sig).expect("Redeem script is multisig");
+    assert_eq!(multisig, Multisig { required: 2, pubkeys: vec![bytes(&[G]), bytes(&[G2]), bytes(&[G_UNCOMPRESSED])] });
+    assert_eq!(multisig.pubkeys.iter().map(Vec::len).collect::<Vec<_>>(), [33, 33, 65]);
+
+    // Anything after the redeem script that isn't a push means it's not a P2SH scriptSig
+    assert_eq!(extract_p2sh_multisig(&[script_sig.as_slice(), &[0xac]].concat()), None);
+    // A P2PKH scriptSig ends in a key, not a script
+    assert_eq!(extract_p2sh_multisig(&bytes(&[SIGNATURE_PUSH, "21", G])), None);
+}
+
+#[test]
+fn bare_multisig_output() {
+    // OP_1 <3G> <G uncompressed> OP_2 OP_CHECKMULTISIG
+    let script = bytes(&["51", "21", G3, "41", G_UNCOMPRESSED, "52", "ae"]);
+    assert_eq!(classify_script(&script), ScriptType::Multisig);
+    assert_eq!(parse_multisig(&script), Some(Multisig { required: 1, pubkeys: vec![bytes(&[G3]), bytes(&[G_UNCOMPRESSED])] }));
+
+    // n has to match the number of keys, and m can't exceed it
+    assert_eq!(parse_multisig(&bytes(&["51", "21", G3, "53", "ae"])), None);
+    assert_eq!(parse_multisig(&bytes(&["52", "21", G3, "51", "ae"])), None);
+}
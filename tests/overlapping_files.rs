@@ -0,0 +1,60 @@
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use bitcoin_postgres_parser::chain::Chain;
+use bitcoin_postgres_parser::processing::drop_seen_blocks;
+use bitcoin_postgres_parser::{Block, Network};
+
+use common::{blk_record, chain_of, parse_fixture, test_database};
+
+fn hashes(blocks: &[Block]) -> Vec<&str> {
+    blocks.iter().map(|block| block.block_hash.as_str()).collect()
+}
+
+#[test]
+fn blocks_read_before_are_dropped() {
+    let mut chain = Chain::new(Network::Mainnet);
+    chain.assign_heights(&mut chain_of(&["genesis"]));
+    let pending = vec![(0, parse_fixture("block_2"))];
+
+    // The genesis block is in the chain, block 2 is waiting for its parent, and block 1 is repeated
+    let block_1 = parse_fixture("block_1");
+    let mut file_blocks = vec![parse_fixture("genesis"), block_1.clone(), parse_fixture("block_2"), block_1.clone()];
+    assert_eq!(drop_seen_blocks(&mut file_blocks, &chain, &pending), 3);
+    assert_eq!(hashes(&file_blocks), [block_1.block_hash.as_str()]);
+
+    let mut file_blocks = vec![parse_fixture("segwit")];
+    assert_eq!(drop_seen_blocks(&mut file_blocks, &chain, &pending), 0);
+}
+
+#[tokio::test]
+async fn a_block_in_two_files_is_stored_once() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-overlapping-files-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("blk00000.dat"), [blk_record("genesis"), blk_record("block_1")].concat()).unwrap();
+    fs::write(dir.join("blk00001.dat"), [blk_record("block_1"), blk_record("block_2")].concat()).unwrap();
+
+    // Run from the blocks directory, away from any .env
+    let output = Command::new(env!("CARGO_BIN_EXE_bitcoin_postgres_parser"))
+        .current_dir(&dir)
+        .env("BLOCKS_PATH", &dir)
+        .env("DATABASE_URL", std::env::var("TEST_DATABASE_URL").unwrap())
+        .env("TABLE_PREFIX", "test_overlapping_")
+        .env("RESET", "true")
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+
+    let conn = pool.get().await.unwrap();
+    let rows = conn.query("SELECT height, COUNT(*) FROM test_overlapping_blocks GROUP BY height ORDER BY height", &[]).await.unwrap();
+    let counts: Vec<(i32, i64)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    assert_eq!(counts, [(0, 1), (1, 1), (2, 1)]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,32 @@
+#![cfg(feature = "parquet")]
+
+mod common;
+
+use std::fs::{self, File};
+
+use bitcoin_postgres_parser::parquet_sink::ParquetSink;
+use bitcoin_postgres_parser::BlockSink;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+
+use common::parse_fixture;
+
+#[tokio::test]
+async fn a_block_is_written_to_one_file_per_table() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-parquet-sink-{}", std::process::id()));
+    let block = parse_fixture("taproot");
+    let sink = ParquetSink::new(dir.clone(), 1000, usize::MAX);
+    sink.setup(false).await.unwrap();
+    sink.insert_blocks(std::slice::from_ref(&block)).await.unwrap();
+    sink.finish().await.unwrap();
+
+    let rows = |table: &str| {
+        let file = File::open(dir.join(format!("{}-00000.parquet", table))).unwrap();
+        SerializedFileReader::new(file).unwrap().metadata().file_metadata().num_rows() as usize
+    };
+    assert_eq!(rows("blocks"), 1);
+    assert_eq!(rows("transactions"), 3);
+    assert_eq!(rows("inputs"), block.transactions.iter().map(|tx| tx.inputs.len()).sum::<usize>());
+    assert_eq!(rows("outputs"), block.transactions.iter().map(|tx| tx.outputs.len()).sum::<usize>());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,243 @@
+mod common;
+
+use bitcoin_postgres_parser::block_processor::{calculate_tx, calculate_wtxid, compute_merkle_root, serialize_tx};
+use bitcoin_postgres_parser::script::{extract_taproot_spend, ScriptType, TaprootPath};
+use bitcoin_postgres_parser::{process_block, FileReader, Network, ParserError};
+
+use common::{empty_scripts_block, fixture, parse_fixture, EMPTY_SCRIPTS_TXID};
+
+#[test]
+fn genesis_block() {
+    let block = parse_fixture("genesis");
+
+    assert_eq!(block.block_hash, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    assert_eq!(block.previous_block, "0000000000000000000000000000000000000000000000000000000000000000");
+    assert_eq!(block.merkle_root, "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+    assert_eq!(block.time.unix_timestamp(), 1231006505);
+    assert_eq!(block.bits, 0x1d00ffff);
+    assert_eq!(block.nonce, 2083236893);
+    assert_eq!(block.difficulty, 1.0);
+    assert_eq!(block.size, 285);
+    assert_eq!(block.weight, 1140);
+
+    let [tx] = block.transactions.as_slice() else {
+        panic!("Expected one transaction, got {}", block.transactions.len());
+    };
+    assert_eq!(tx.txid, "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+    assert_eq!(tx.wtxid, tx.txid);
+    assert_eq!(tx.size, 204);
+    assert_eq!(tx.weight, 816);
+    assert!(tx.inputs[0].is_coinbase);
+    assert_eq!(tx.outputs[0].value, 50 * 100_000_000);
+    assert_eq!(tx.outputs[0].script_type, ScriptType::P2pk);
+    assert!(tx.outputs[0].unspendable);
+}
+
+#[test]
+fn early_block() {
+    let block = parse_fixture("block_1");
+
+    assert_eq!(block.block_hash, "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+    assert_eq!(block.previous_block, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    assert_eq!(block.merkle_root, "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
+    assert_eq!(block.time.unix_timestamp(), 1231469665);
+    assert_eq!(block.nonce, 2573394689);
+    assert_eq!(block.difficulty, 1.0);
+    assert_eq!(block.size, 215);
+    assert_eq!(block.weight, 860);
+
+    assert_eq!(block.transactions.len(), 1);
+    assert_eq!(block.transactions[0].txid, "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
+    assert_eq!(block.transactions[0].size, 134);
+    assert!(!block.transactions[0].outputs[0].unspendable);
+}
+
+#[test]
+fn segwit_block() {
+    let block = parse_fixture("segwit");
+
+    assert_eq!(block.block_hash, "68a72d1b5ee03cd7ca4b182e6c81d0f491fdc21a6635311bdda4b2aa73d7da84");
+    assert_eq!(block.merkle_root, "f9728c4a7ad14ee3d4094b82f3eca0c9b2809e591d3c33c8d378bda91ddb13c1");
+    assert_eq!(block.bits, 0x207fffff);
+    // Regtest's minimum difficulty, as getdifficulty reports it, up to rounding
+    assert!((block.difficulty - 4.656542373906925e-10).abs() < 1e-24);
+    assert_eq!(block.size, 483);
+    assert_eq!(block.weight, 1494);
+
+    let txids: Vec<_> = block.transactions.iter().map(|tx| (tx.txid.as_str(), tx.wtxid.as_str(), tx.size, tx.weight, tx.vsize)).collect();
+    assert_eq!(txids, [
+        (
+            "4f5b971f2382dcd437ffae5135905538c3a5c7cf92f0d5b72789cd91f12ceb82",
+            "4ea077898ab62a93b4e51680d7b7e73e7e15fc4557a2f64844cbe821c1cb15f4",
+            178, 604, 151,
+        ),
+        (
+            "352348128d84c48271cd10a54856b3efe65004f3b6192ae841d41c4aba1282cd",
+            "ce6e422dac5bf8e53321e0d20584fc0746cd52ae1be38881d44c17e89e5d284c",
+            224, 566, 142,
+        ),
+    ]);
+
+    let spend = &block.transactions[1];
+    assert_eq!(spend.witness.as_ref().map(|witness| witness[0].len()), Some(2));
+    assert_eq!(spend.outputs[0].script_type, ScriptType::P2wpkh);
+    assert_eq!(spend.outputs[0].address.as_deref(), Some("bc1qd29nyl2rhtfttuu07vqsc4lcq3w02v8twtgrc4"));
+    assert_eq!(spend.outputs[1].script_type, ScriptType::P2sh);
+    assert!(spend.inputs[0].rbf_signaled());
+}
+
+#[test]
+fn taproot_block() {
+    let block = parse_fixture("taproot");
+
+    assert_eq!(block.block_hash, "4b7e050a24dd597904d92b349de1046b4eef9b1eb5dd9d8630486b45502cd133");
+    assert_eq!(block.merkle_root, "057eae10adfa34746f61d18d775a90b3a3f54703aed8dc41df47a3fc9a23c3dd");
+    assert_eq!(block.size, 683);
+    assert_eq!(block.weight, 2009);
+
+    let positions: Vec<_> = block.transactions.iter().map(|tx| tx.position).collect();
+    assert_eq!(positions, [0, 1, 2]);
+    assert!(block.transactions[0].inputs[0].is_coinbase);
+
+    let txids: Vec<_> = block.transactions.iter().map(|tx| (tx.txid.as_str(), tx.wtxid.as_str(), tx.size, tx.weight, tx.vsize)).collect();
+    assert_eq!(txids, [
+        (
+            "9db94536d6000ba84d08f0186470db28c53025446595a8919830af3be33c2eeb",
+            "6d3f1a6b60bbbe9ce95150de0910e07ae74191d53dae739346a5b168b101e0a8",
+            178, 604, 151,
+        ),
+        (
+            "f3fcc707418b90b285dafa985c18f94ad417cfa07060b4044abc6280edf5f3cc",
+            "2125adf1735c708bf9162504e2fe692b7352b5ccf5e44533e523a78038ce420d",
+            162, 444, 111,
+        ),
+        (
+            "9382297e6409a2d7158ecd7e280e382eeb7c99c47e9f81edb5f808d3a494b951",
+            "925c50e280be860dabe3b0e1f8bff913c4cfefec7d2ebb97b3014055205e45d6",
+            262, 637, 160,
+        ),
+    ]);
+
+    let key_path = &block.transactions[1];
+    assert_eq!(key_path.outputs[0].script_type, ScriptType::P2tr);
+    assert_eq!(
+        key_path.outputs[0].address.as_deref(),
+        Some("bc1p2l2wtqqs49kh468mu7jmty33lm39wrgvxe0k2z5yzlt8h2yygx3shm78u5"),
+    );
+    let witness = &key_path.witness.as_ref().expect("Key-path spend has a witness")[0];
+    let spend = extract_taproot_spend(&[], witness).expect("Key-path spend is recognized");
+    assert!(matches!(spend.path, TaprootPath::Key { ref signature } if signature.len() == 64));
+
+    let script_path = &block.transactions[2];
+    let witness = &script_path.witness.as_ref().expect("Script-path spend has a witness")[0];
+    let spend = extract_taproot_spend(&[], witness).expect("Script-path spend is recognized");
+    assert!(matches!(spend.path, TaprootPath::Script { leaf_version: 0xc0, ref script, .. } if script.len() == 34));
+    assert!(spend.annex.is_none());
+}
+
+#[test]
+fn only_the_genesis_coinbase_is_unspendable() {
+    // Every Bitcoin network starts from the same coinbase, whatever its genesis header
+    let reader = FileReader::in_memory(Network::Mainnet);
+    let genesis = reader.read_block_from_bytes(&fixture("genesis").into_inner()).unwrap();
+    let block_1 = reader.read_block_from_bytes(&fixture("block_1").into_inner()).unwrap();
+    for network in [Network::Mainnet, Network::Testnet3, Network::Signet, Network::Regtest] {
+        assert_eq!(network.params().genesis_coinbase_txid, "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        assert!(process_block(genesis.clone(), network, false).unwrap().transactions[0].outputs[0].unspendable, "{}", network);
+        assert!(!process_block(block_1.clone(), network, false).unwrap().transactions[0].outputs[0].unspendable, "{}", network);
+    }
+}
+
+#[test]
+fn rbf_transactions_are_counted() {
+    assert_eq!(parse_fixture("genesis").rbf_tx_count, 0);
+
+    let bytes = fixture("taproot").into_inner();
+    let mut block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).unwrap();
+    // One spend that signals and one that doesn't, next to the coinbase, which never does
+    block.transactions[1].inputs.iter_mut().for_each(|input| input.sequence = 0xfffffffd);
+    block.transactions[2].inputs.iter_mut().for_each(|input| input.sequence = 0xffffffff);
+    let txids: Vec<String> = block.transactions.iter().map(|tx| calculate_tx(tx).unwrap().0).collect();
+    block.merkle_root = compute_merkle_root(&txids).unwrap();
+
+    let block = process_block(block, Network::Mainnet, false).unwrap();
+    assert!(block.transactions[1].signals_rbf());
+    assert!(!block.transactions[2].signals_rbf());
+    assert_eq!(block.rbf_tx_count, 1);
+}
+
+#[test]
+fn empty_scripts_keep_the_txid() {
+    let block = empty_scripts_block();
+    let tx = &block.transactions[1];
+    assert_eq!(tx.txid, EMPTY_SCRIPTS_TXID);
+    assert_eq!(tx.size, 60);
+    assert_eq!(tx.inputs[0].script_sig, "");
+    assert_eq!(tx.outputs[0].script_pub_key, "");
+    assert_eq!(tx.outputs[0].script_type, ScriptType::NonStandard);
+    assert_eq!(tx.outputs[0].address, None);
+}
+
+#[test]
+fn invalid_hex_fails_the_block_instead_of_panicking() {
+    let bytes = fixture("genesis").into_inner();
+    let mut block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).unwrap();
+    block.transactions[0].inputs[0].script_sig = "not hex".to_string();
+
+    let error = calculate_tx(&block.transactions[0]).unwrap_err();
+    assert!(matches!(error, ParserError::InvalidHex { field: "scriptSig", .. }), "{}", error);
+    assert!(matches!(process_block(block.clone(), Network::Mainnet, false), Err(ParserError::InvalidHex { field: "scriptSig", .. })));
+
+    block.transactions[0].inputs[0].script_sig = String::new();
+    block.merkle_root = "zz".to_string();
+    assert_eq!(process_block(block, Network::Mainnet, false).unwrap_err().to_string(), "merkle root \"zz\" isn't valid hex");
+}
+
+#[test]
+fn versions_with_the_high_bit_set_stay_positive() {
+    let mut bytes = fixture("genesis").into_inner();
+    // The block's version leads the header, the coinbase's follows the header and transaction count
+    bytes[0..4].copy_from_slice(&0x8000_0004u32.to_le_bytes());
+    bytes[81..85].copy_from_slice(&0x8000_0001u32.to_le_bytes());
+    let mut block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).unwrap();
+    block.merkle_root = compute_merkle_root(&[calculate_tx(&block.transactions[0]).unwrap().0]).unwrap();
+
+    let block = process_block(block, Network::Mainnet, false).unwrap();
+    assert_eq!(block.version, 0x8000_0004);
+    assert_eq!(block.transactions[0].version, 0x8000_0001);
+    // Serialized back to the bytes it was read from, so the size check still holds
+    assert_eq!(block.transactions[0].size, 204);
+}
+
+#[test]
+fn transactions_hash_to_known_txids_and_wtxids() {
+    // Mainnet's first coinbases, and the synthetic blocks' transactions hashed from their bytes
+    let vectors = [
+        ("genesis", 0, "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b", "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b", 204, 204),
+        ("block_1", 0, "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098", "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098", 134, 134),
+        ("segwit", 0, "4f5b971f2382dcd437ffae5135905538c3a5c7cf92f0d5b72789cd91f12ceb82", "4ea077898ab62a93b4e51680d7b7e73e7e15fc4557a2f64844cbe821c1cb15f4", 178, 142),
+        ("segwit", 1, "352348128d84c48271cd10a54856b3efe65004f3b6192ae841d41c4aba1282cd", "ce6e422dac5bf8e53321e0d20584fc0746cd52ae1be38881d44c17e89e5d284c", 224, 114),
+        ("taproot", 1, "f3fcc707418b90b285dafa985c18f94ad417cfa07060b4044abc6280edf5f3cc", "2125adf1735c708bf9162504e2fe692b7352b5ccf5e44533e523a78038ce420d", 162, 94),
+        ("taproot", 2, "9382297e6409a2d7158ecd7e280e382eeb7c99c47e9f81edb5f808d3a494b951", "925c50e280be860dabe3b0e1f8bff913c4cfefec7d2ebb97b3014055205e45d6", 262, 125),
+    ];
+    for (name, index, txid, wtxid, size, stripped_size) in vectors {
+        let block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&fixture(name).into_inner()).unwrap();
+        let tx = &block.transactions[index];
+        assert_eq!(calculate_tx(tx).unwrap(), (txid.to_string(), wtxid.to_string(), size, stripped_size), "{} {}", name, index);
+        assert_eq!(serialize_tx(tx, false).unwrap().len(), stripped_size, "{} {}", name, index);
+        assert_eq!(calculate_wtxid(tx).unwrap(), wtxid, "{} {}", name, index);
+    }
+}
+
+#[test]
+fn witness_transactions_hash_to_a_different_wtxid() {
+    let bytes = fixture("segwit").into_inner();
+    let block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).unwrap();
+
+    let (txid, wtxid, size, stripped_size) = calculate_tx(&block.transactions[1]).unwrap();
+    assert_eq!(txid, "352348128d84c48271cd10a54856b3efe65004f3b6192ae841d41c4aba1282cd");
+    assert_eq!(wtxid, "ce6e422dac5bf8e53321e0d20584fc0746cd52ae1be38881d44c17e89e5d284c");
+    assert_ne!(txid, wtxid);
+    assert_eq!(wtxid, calculate_wtxid(&block.transactions[1]).unwrap());
+    assert!(size > stripped_size);
+}
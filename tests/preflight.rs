@@ -0,0 +1,83 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::database::Database;
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::preflight::preflight;
+use bitcoin_postgres_parser::sink::NullSink;
+use bitcoin_postgres_parser::{BlockSink, FileReader, Network, ParserError};
+
+use common::{blk_record, test_database};
+
+const GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+fn blocks_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-preflight-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn an_empty_blocks_directory_fails_preflight() {
+    let dir = blocks_dir("empty");
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::new(Metrics::default()));
+
+    let error = preflight(&file_reader, &NullSink, false).await.unwrap_err();
+    assert!(matches!(error, ParserError::Preflight(_)));
+    assert_eq!(error.to_string(), format!("Preflight check failed: No blk*.dat files in {}", dir.display()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn preflight_reads_the_first_block() {
+    let dir = blocks_dir("files");
+    let mut record = vec![0; 16];
+    record.extend(blk_record("genesis"));
+    fs::write(dir.join("blk00000.dat"), record).unwrap();
+    fs::write(dir.join("blk00001.dat"), blk_record("block_1")).unwrap();
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::new(Metrics::default()));
+
+    let report = preflight(&file_reader, &NullSink, false).await.unwrap();
+    assert_eq!((report.files, report.first_block.as_str()), (2, GENESIS));
+
+    // Past the padding, nothing looks like a testnet block
+    let file_reader = FileReader::new(dir.clone(), Network::Testnet3, Arc::new(Metrics::default()));
+    let error = preflight(&file_reader, &NullSink, false).await.unwrap_err();
+    assert!(error.to_string().ends_with("blk00000.dat holds no testnet3 block that parses"), "{}", error);
+
+    // Without padding, the magic gives it away
+    fs::write(dir.join("blk00000.dat"), blk_record("genesis")).unwrap();
+    let error = preflight(&file_reader, &NullSink, false).await.unwrap_err();
+    assert!(error.to_string().contains("Magic mismatch"), "{}", error);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn preflight_reports_tables_missing_a_column() {
+    let Some(pool) = test_database().await else {
+        return;
+    };
+    let dir = blocks_dir("schema");
+    fs::write(dir.join("blk00000.dat"), blk_record("genesis")).unwrap();
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::new(Metrics::default()));
+    let database = Database::new(pool.clone(), false, 1).with_table_prefix("test_preflight_schema_");
+    database.setup(true).await.unwrap();
+    pool.get().await.unwrap().batch_execute("ALTER TABLE test_preflight_schema_outputs DROP COLUMN unspendable").await.unwrap();
+
+    let error = preflight(&file_reader, &database, false).await.unwrap_err();
+    assert!(matches!(error, ParserError::Preflight(_)));
+    assert_eq!(
+        error.to_string(),
+        "Preflight check failed: The tables were created by another version, set RESET=true to recreate them: \
+         test_preflight_schema_outputs.unspendable is missing",
+    );
+
+    // Recreating the tables fixes them
+    preflight(&file_reader, &database, true).await.unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
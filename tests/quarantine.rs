@@ -0,0 +1,42 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::file_reader::RECORD_HEADER_SIZE;
+use bitcoin_postgres_parser::{process_block, FileReader, Network};
+
+use common::blk_record;
+
+#[test]
+fn corrupt_block_is_quarantined() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-quarantine-{}", std::process::id()));
+    let blocks_dir = dir.join("blocks");
+    fs::create_dir_all(&blocks_dir).unwrap();
+
+    // Block 1 claiming a second transaction that its record has no room for
+    let genesis = blk_record("genesis");
+    let mut corrupt = blk_record("block_1");
+    corrupt[RECORD_HEADER_SIZE as usize + 80] = 2;
+    fs::write(blocks_dir.join("blk00000.dat"), [genesis.clone(), corrupt.clone()].concat()).unwrap();
+
+    let quarantine = dir.join("failed");
+    let file_reader = FileReader::new(blocks_dir, Network::Mainnet, Arc::default()).with_quarantine(Some(quarantine.clone()));
+    let mut blocks = file_reader.read_file(0).unwrap();
+    assert_eq!(blocks.len(), 1);
+    let block = process_block(blocks.remove(0), Network::Mainnet, true).unwrap();
+    assert_eq!(block.block_hash, "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+
+    let name = format!("blk00000.dat.{}", genesis.len());
+    assert_eq!(fs::read(quarantine.join(format!("{}.bin", name))).unwrap(), corrupt);
+    let context = fs::read_to_string(quarantine.join(format!("{}.txt", name))).unwrap();
+    assert!(context.contains(&format!("offset: {}", genesis.len())), "{}", context);
+    assert!(context.contains("variant: MalformedBlock"), "{}", context);
+
+    // The quarantined record on its own fails the same way, and nothing else was quarantined
+    let reproduced = FileReader::new(quarantine.join(format!("{}.bin", name)), Network::Mainnet, Arc::default());
+    assert!(reproduced.read_file(0).unwrap().is_empty());
+    assert_eq!(fs::read_dir(&quarantine).unwrap().count(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
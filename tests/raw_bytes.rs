@@ -0,0 +1,48 @@
+mod common;
+
+use bitcoin_postgres_parser::{process_block, FileReader, Network};
+use sha2::{Digest, Sha256};
+
+use common::fixture;
+
+/// Double SHA256 as display-order hex, the way hashes are stored.
+fn hash(bytes: &[u8]) -> String {
+    let mut hash = Sha256::digest(Sha256::digest(bytes)).to_vec();
+    hash.reverse();
+    hex::encode(hash)
+}
+
+#[test]
+fn raw_bytes_rehash_to_the_stored_hashes() {
+    for name in ["genesis", "block_1", "segwit", "taproot"] {
+        let bytes = fixture(name).into_inner();
+        let reader = FileReader::in_memory(Network::Mainnet).with_raw_bytes(true);
+        let block = process_block(reader.read_block_from_bytes(&bytes).unwrap(), Network::Mainnet, true).unwrap();
+
+        let raw = block.raw.as_deref().expect("Raw block bytes are kept");
+        assert_eq!(raw, bytes.as_slice(), "{}", name);
+        assert_eq!(hash(&raw[..80]), block.block_hash, "{}", name);
+
+        // The transactions' bytes follow the header and transaction count, back to back
+        let transactions: Vec<u8> = block.transactions.iter().flat_map(|tx| tx.raw.clone().unwrap()).collect();
+        assert!(raw.ends_with(&transactions), "{}", name);
+        assert_eq!(raw.len() - transactions.len(), 81, "{}", name);
+
+        for tx in &block.transactions {
+            let raw = tx.raw.as_deref().unwrap();
+            assert_eq!(raw.len(), tx.size as usize);
+            assert_eq!(hash(raw), tx.wtxid, "{}", name);
+            if tx.witness.is_none() {
+                assert_eq!(hash(raw), tx.txid, "{}", name);
+            }
+        }
+    }
+}
+
+#[test]
+fn raw_bytes_are_not_kept_by_default() {
+    let bytes = fixture("segwit").into_inner();
+    let block = FileReader::in_memory(Network::Mainnet).read_block_from_bytes(&bytes).unwrap();
+    assert!(block.raw.is_none());
+    assert!(block.transactions.iter().all(|tx| tx.raw.is_none()));
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::chain::{Chain, PENDING_HEIGHT};
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::processing::{spawn_ordered_reader, Message};
+use bitcoin_postgres_parser::{process_block, FileReader, Network};
+use tokio::sync::mpsc;
+
+use common::{blk_record, parse_fixture};
+
+const GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+const BLOCK_1: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+const BLOCK_2: &str = "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd";
+
+/// Writes blk files made of the given fixtures, in that order, with zero padding at the end.
+fn blocks_dir(name: &str, files: &[&[&str]]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (index, fixtures) in files.iter().enumerate() {
+        let mut contents: Vec<u8> = fixtures.iter().flat_map(|fixture| blk_record(fixture)).collect();
+        contents.resize(contents.len() + 64, 0);
+        fs::write(dir.join(format!("blk{:05}.dat", index)), contents).unwrap();
+    }
+    dir
+}
+
+#[tokio::test]
+async fn shuffled_files_are_imported_in_chain_order() {
+    // Children ahead of their parents, and every block but block 1 stored twice
+    let dir = blocks_dir("reindex", &[&["block_2", "genesis"], &["block_1", "block_2", "genesis"]]);
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+
+    let headers = [file_reader.read_headers(0).unwrap(), file_reader.read_headers(1).unwrap()].concat();
+    assert_eq!(headers.len(), 5);
+
+    let mut chain = Chain::new(Network::Mainnet);
+    let (ordered, unlinked) = chain.order_headers(headers);
+    assert!(unlinked.is_empty());
+    let order: Vec<_> = ordered.iter().map(|header| (header.block_hash.as_str(), header.file_index, header.byte_offset)).collect();
+    assert_eq!(order, [
+        (GENESIS, 0, blk_record("block_2").len() as u64),
+        (BLOCK_1, 1, 0),
+        (BLOCK_2, 0, 0),
+    ]);
+
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(16);
+    spawn_ordered_reader(Arc::clone(&file_reader), ordered, sender, Arc::clone(&metrics), hash_pool, true);
+    let mut blocks = Vec::new();
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Block(block) => blocks.push(block),
+            Message::FileDone(_, rejected_blocks) => assert_eq!(rejected_blocks, 0),
+            Message::FileFailed(file_index, e) => panic!("Reading for file {} failed: {}", file_index, e),
+        }
+    }
+
+    // Every block is linked as soon as it arrives, so none is left pending
+    chain.assign_heights(&mut blocks);
+    let heights: Vec<_> = blocks.iter().map(|block| (block.block_hash.as_str(), block.height, block.active)).collect();
+    assert_eq!(heights, [(GENESIS, 0, true), (BLOCK_1, 1, true), (BLOCK_2, 2, true)]);
+    assert_eq!(blocks[2].blk_file, "blk00000.dat");
+    assert_eq!(blocks[2].total_supply, 2 * 50 * 100_000_000);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn headers_hold_every_header_field() {
+    let dir = blocks_dir("headers", &[&["block_2", "genesis", "block_1"]]);
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default());
+
+    let chain = Chain::new(Network::Mainnet);
+    let (mut ordered, _) = chain.order_headers(file_reader.read_headers(0).unwrap());
+    assert!(ordered.iter().all(|header| header.height == PENDING_HEIGHT));
+    chain.assign_header_heights(&mut ordered);
+    for (height, (header, fixture)) in ordered.iter().zip(["genesis", "block_1", "block_2"]).enumerate() {
+        let block = parse_fixture(fixture);
+        assert_eq!(
+            (header.block_hash.as_str(), header.version, header.previous_block.as_str(), header.merkle_root.as_str(), header.time, header.bits, header.nonce, header.height),
+            (block.block_hash.as_str(), block.version, block.previous_block.as_str(), block.merkle_root.as_str(), block.time, block.bits, block.nonce, height as i32),
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blocks_without_known_ancestry_are_left_out() {
+    let dir = blocks_dir("orphans", &[&["block_2", "genesis"]]);
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default());
+
+    let mut chain = Chain::new(Network::Mainnet);
+    let (ordered, unlinked) = chain.order_headers(file_reader.read_headers(0).unwrap());
+    assert_eq!(ordered.iter().map(|header| header.block_hash.as_str()).collect::<Vec<_>>(), [GENESIS]);
+    assert_eq!(unlinked.iter().map(|header| header.block_hash.as_str()).collect::<Vec<_>>(), [BLOCK_2]);
+
+    // Once the genesis block is linked, e.g. by an earlier run, its header is dropped
+    let genesis = file_reader.read_blocks_at(&[(0, ordered[0].byte_offset)]).unwrap().remove(0);
+    chain.assign_heights(&mut [process_block(genesis, Network::Mainnet, true).unwrap()]);
+    let (ordered, unlinked) = chain.order_headers(file_reader.read_headers(0).unwrap());
+    assert!(ordered.is_empty());
+    assert_eq!(unlinked.len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
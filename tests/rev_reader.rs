@@ -0,0 +1,82 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use bitcoin_postgres_parser::rev_reader::{parse_block_undo, rev_path, SpentOutput, UndoFile};
+use bitcoin_postgres_parser::{FileReader, Network};
+
+use common::{blk_record, fixture, parse_fixture};
+
+#[test]
+fn undo_data_fills_spent_outputs() {
+    let dir = std::env::temp_dir().join(format!("bitcoin-postgres-parser-undo-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // The rev file holds the blocks' undo data in the opposite order, as after a reorg
+    fs::write(dir.join("blk00000.dat"), [blk_record("segwit"), blk_record("taproot")].concat()).unwrap();
+    fs::write(rev_path(&dir.join("blk00000.dat")).unwrap(), fixture("undo").into_inner()).unwrap();
+
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::default()).with_undo_data(true);
+    let blocks = file_reader.read_file(0).unwrap();
+    let spent: Vec<Vec<_>> = blocks.iter()
+        .map(|block| block.transactions.iter()
+            .flat_map(|tx| &tx.inputs)
+            .map(|input| (input.spent_value, input.spent_address.as_deref()))
+            .collect())
+        .collect();
+    assert_eq!(spent, [
+        vec![(None, None), (Some(200_000), Some("bc1q05vghjryjsjc4htyyrhy9h2eg5f5ry4mqhwdmy"))],
+        vec![
+            (None, None),
+            (Some(100_000), Some("bc1pa9eyhm8k2vfs9qxhynz7uutnjvqsmf7ztj66jc8mjl9452mwpzgqq05l9j")),
+            (Some(90_000), Some("bc1pnm769z4czhdw4xvewz4673u7fv5jrgydl6fehxxm4kzfcrjfju5q75sm6l")),
+        ],
+    ]);
+
+    // Without undo data, inputs are left for the database to resolve
+    let blocks = FileReader::new(dir.clone(), Network::Mainnet, Arc::default()).read_file(0).unwrap();
+    assert!(blocks.iter().flat_map(|block| &block.transactions).flat_map(|tx| &tx.inputs).all(|input| input.spent_value.is_none()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn records_only_match_their_own_block() {
+    let mut undo = UndoFile::from_bytes(&fixture("undo").into_inner(), Network::Mainnet).unwrap();
+    assert_eq!(undo.len(), 2);
+
+    // Same shape as the segwit block, but a different parent, so the checksum doesn't match
+    let mut block = parse_fixture("segwit");
+    block.previous_block = parse_fixture("taproot").previous_block;
+    assert!(!undo.apply(&mut block));
+    assert!(!undo.apply(&mut parse_fixture("genesis")));
+
+    let mut block = parse_fixture("segwit");
+    assert!(undo.apply(&mut block));
+    assert_eq!(block.transactions[1].inputs[0].spent_value, Some(200_000));
+    // Each record is used once, e.g. for the first of two copies of a block
+    assert!(!undo.apply(&mut parse_fixture("segwit")));
+}
+
+#[test]
+fn decodes_every_compressed_script_form() {
+    // One transaction spending P2PKH, P2SH, compressed and uncompressed P2PK, and a script stored
+    // as it is, with amounts from 21 million BTC down to nothing
+    let undo = hex::decode(concat!(
+        "01050300320062e907b15cbf27d5425399ebf6f0fb50ebb88f1897b400008390e7ea3d01b472a266d0bd89c13706a4",
+        "132ccfb16f7c3b9fcb8358000103b7d61f8c08d355c4405197b8ba52ea104b5fa59ba7da1b9bea73be919f8ee3a813",
+        "000004b7d61f8c08d355c4405197b8ba52ea104b5fa59ba7da1b9bea73be919f8ee3a8d4b840008980dd400c6a0474",
+        "657374",
+    )).unwrap();
+    let spent = |height, is_coinbase, value, address: Option<&str>| SpentOutput { height, is_coinbase, value, address: address.map(String::from) };
+    assert_eq!(parse_block_undo(&undo, Network::Mainnet).unwrap(), [[
+        spent(1, true, 50 * 100_000_000, Some("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")),
+        spent(200_000, false, 123_456_789, Some("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")),
+        spent(300, false, 1, None),
+        spent(9, true, 0, None),
+        spent(700_000, false, 21_000_000 * 100_000_000, None),
+    ]]);
+
+    // Cut off in the middle of the last script
+    assert!(parse_block_undo(&undo[..undo.len() - 1], Network::Mainnet).is_err());
+}
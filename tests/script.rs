@@ -0,0 +1,104 @@
+use bitcoin_postgres_parser::script::{
+    bip34_height, classify_script, disassemble, extract_scriptsig_pubkey, extract_witness_pubkey, op_return_data, script_asm, KeySpend, Op, ScriptType,
+};
+
+#[test]
+fn bip34_heights_are_read_from_each_push_width() {
+    assert_eq!(bip34_height(&[0x00]), Some(0));
+    assert_eq!(bip34_height(&[0x51]), Some(1));
+    assert_eq!(bip34_height(&[0x60]), Some(16));
+    assert_eq!(bip34_height(&[0x01, 0x64]), Some(100));
+    assert_eq!(bip34_height(&[0x02, 0x10, 0x27]), Some(10_000));
+    // A zero byte keeps 128 from reading as -0
+    assert_eq!(bip34_height(&[0x02, 0x80, 0x00]), Some(128));
+    // Block 227931, the first with a BIP34 height, followed by the rest of its scriptSig
+    assert_eq!(bip34_height(&[0x03, 0x5b, 0x7a, 0x03, 0x04, 0x2f]), Some(227_931));
+
+    // A push running past the end of the script, a negative number and a non-push opcode
+    assert_eq!(bip34_height(&[0x03, 0x5b, 0x7a]), None);
+    assert_eq!(bip34_height(&[0x01, 0x80]), None);
+    assert_eq!(bip34_height(&[0x76]), None);
+    assert_eq!(bip34_height(&[]), None);
+}
+
+fn script(hex: &str) -> Vec<u8> {
+    hex::decode(hex).unwrap()
+}
+
+#[test]
+fn output_scripts_are_classified_by_template() {
+    let cases = [
+        // The genesis coinbase output, to an uncompressed key
+        ("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac", ScriptType::P2pk),
+        ("210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac", ScriptType::P2pk),
+        ("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac", ScriptType::P2pkh),
+        ("a914748284390f9e263a4b766a75d0633c50426eb87587", ScriptType::P2sh),
+        ("0014751e76e8199196d454941c45d1b3a323f1433bd6", ScriptType::P2wpkh),
+        ("00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262", ScriptType::P2wsh),
+        ("5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c", ScriptType::P2tr),
+        ("6a0b68656c6c6f20776f726c64", ScriptType::OpReturn),
+        ("6a", ScriptType::OpReturn),
+        // A key of the wrong length, a hash one byte short, witness version 2, a script that
+        // does nothing standard, and no script at all
+        ("200279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f817ac", ScriptType::NonStandard),
+        ("76a91362e907b15cbf27d5425399ebf6f0fb50ebb88f88ac", ScriptType::NonStandard),
+        ("5220a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c", ScriptType::NonStandard),
+        ("51", ScriptType::NonStandard),
+        ("", ScriptType::NonStandard),
+    ];
+    for (hex, expected) in cases {
+        assert_eq!(classify_script(&script(hex)), expected, "{}", hex);
+    }
+}
+
+#[test]
+fn assembly_shows_pushes_in_hex_and_opcodes_by_name() {
+    // OP_PUSHDATA1, 2 and 4, each pushing two bytes
+    assert_eq!(script_asm(&script("4c02abcd4d0200abcd4e02000000abcd")), "abcd abcd abcd");
+    assert_eq!(script_asm(&script("4c00")), "OP_0");
+    let long = [vec![0x4d, 0x00, 0x01], vec![0x55; 256]].concat();
+    assert_eq!(script_asm(&long), "55".repeat(256));
+
+    assert_eq!(script_asm(&script("00")), "OP_0");
+    assert_eq!(script_asm(&script("4f515260")), "OP_1NEGATE OP_1 OP_2 OP_16");
+    let numbers: Vec<String> = (1..=16).map(|n| format!("OP_{}", n)).collect();
+    assert_eq!(script_asm(&(0x51..=0x60).collect::<Vec<u8>>()), numbers.join(" "));
+
+    // Pushes running past the end of the script, in the length or in the data
+    assert_eq!(script_asm(&script("76a914abcd")), "OP_DUP OP_HASH160 [error]");
+    assert_eq!(script_asm(&script("4d01")), "[error]");
+    assert_eq!(script_asm(&script("4e0100")), "[error]");
+    assert_eq!(disassemble(&script("5103abcd")), [Op::Code(0x51), Op::Truncated(script("03abcd"))]);
+}
+
+#[test]
+fn op_return_data_is_read_push_by_push() {
+    // OP_RETURN "omni" OP_PUSHDATA1 <3 bytes> OP_0 OP_16
+    let pushes = op_return_data(&script("6a046f6d6e694c030102030060")).unwrap();
+    assert_eq!(pushes, [b"omni".to_vec(), vec![1, 2, 3], Vec::new(), vec![16]]);
+
+    // Reading stops at OP_DUP, leaving the push after it out
+    assert_eq!(op_return_data(&script("6a02010276020304")).unwrap(), [vec![1, 2]]);
+    assert_eq!(op_return_data(&script("6a")).unwrap(), Vec::<Vec<u8>>::new());
+    // A P2PKH script isn't an OP_RETURN at all
+    assert_eq!(op_return_data(&script("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac")), None);
+}
+
+#[test]
+fn key_spends_need_a_der_signature_with_its_sighash_byte() {
+    // SEQUENCE of two one-byte INTEGERs, then SIGHASH_ALL
+    let signature = script("300602010102010101");
+    let pubkey = [vec![0x02], vec![0x11; 32]].concat();
+    let spend = KeySpend { signature: signature.clone(), pubkey: pubkey.clone() };
+    let script_sig = |signature: &[u8]| [&[signature.len() as u8], signature, &[0x21], &pubkey[..]].concat();
+
+    assert_eq!(extract_scriptsig_pubkey(&script_sig(&signature)), Some(spend.clone()));
+    assert_eq!(extract_witness_pubkey(&[], &[signature.clone(), pubkey.clone()]), Some(spend));
+
+    // Not a DER sequence, a sequence whose length leaves no room for the sighash byte, and one
+    // cut short
+    for not_der in [script("310602010102010101"), script("3007020101020101"), script("3006020101")] {
+        assert_eq!(extract_scriptsig_pubkey(&script_sig(&not_der)), None);
+        assert_eq!(extract_witness_pubkey(&[], &[not_der, pubkey.clone()]), None);
+    }
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use bitcoin_postgres_parser::visitor::{visit_block, OpReturnCounter};
+use bitcoin_postgres_parser::{Block, Transaction, TransactionVisitor};
+
+use common::parse_fixture;
+
+/// Records every transaction it is called with, as block hash and txid.
+struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+impl TransactionVisitor for Recorder {
+    fn visit(&mut self, block: &Block, tx: &Transaction) {
+        self.0.lock().unwrap().push((block.block_hash.clone(), tx.txid.clone()));
+    }
+}
+
+#[test]
+fn visitors_see_each_transaction_once_in_block_order() {
+    let blocks = [parse_fixture("segwit"), parse_fixture("taproot")];
+    let (first, second) = (Arc::default(), Arc::default());
+    let mut visitors: Vec<Box<dyn TransactionVisitor>> = vec![Box::new(Recorder(Arc::clone(&first))), Box::new(Recorder(Arc::clone(&second)))];
+    for block in &blocks {
+        visit_block(&mut visitors, block);
+    }
+
+    let expected: Vec<(String, String)> = blocks
+        .iter()
+        .flat_map(|block| block.transactions.iter().map(|tx| (block.block_hash.clone(), tx.txid.clone())))
+        .collect();
+    assert_eq!(expected.len(), 5);
+    assert_eq!(*first.lock().unwrap(), expected);
+    assert_eq!(*second.lock().unwrap(), expected);
+}
+
+#[test]
+fn op_returns_are_counted() {
+    // The segwit coinbase's witness commitment is its only OP_RETURN
+    let mut counter = OpReturnCounter::default();
+    let block = parse_fixture("segwit");
+    for tx in &block.transactions {
+        counter.visit(&block, tx);
+    }
+    assert_eq!((counter.transactions, counter.op_return_transactions, counter.op_return_outputs), (2, 1, 1));
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin_postgres_parser::file_reader::RECORD_HEADER_SIZE;
+use bitcoin_postgres_parser::metrics::Metrics;
+use bitcoin_postgres_parser::processing::{spawn_watcher, Message};
+use bitcoin_postgres_parser::{Block, FileReader, Network};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::timeout;
+
+use common::blk_record;
+
+/// The blocks sent up to the next `FileDone`, with the file index it was sent for.
+async fn next_read(receiver: &mut Receiver<Message>) -> (Vec<Block>, usize) {
+    let mut blocks = Vec::new();
+    loop {
+        match timeout(Duration::from_secs(10), receiver.recv()).await.expect("Watcher sends within the timeout") {
+            Some(Message::Block(block)) => blocks.push(block),
+            Some(Message::FileDone(file_index, _)) => return (blocks, file_index),
+            Some(Message::FileFailed(file_index, e)) => panic!("Reading file {} failed: {}", file_index, e),
+            None => panic!("Watcher stopped"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn watcher_reads_only_appended_blocks() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("bitcoin-postgres-parser-watch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Nodes preallocate blk files, so new blocks overwrite zeros rather than growing the file
+    let genesis = blk_record("genesis");
+    let mut contents = genesis.clone();
+    contents.resize(4096, 0);
+    fs::write(dir.join("blk00000.dat"), &contents).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(16);
+    let _watcher = spawn_watcher(
+        file_reader,
+        (0, genesis.len() as u64),
+        Duration::from_millis(20),
+        sender,
+        Arc::clone(&metrics),
+        hash_pool,
+        false,
+    );
+
+    // Only padding follows the genesis block so far
+    let (blocks, file_index) = next_read(&mut receiver).await;
+    assert!(blocks.is_empty());
+    assert_eq!(file_index, 0);
+
+    let mut file = OpenOptions::new().write(true).open(dir.join("blk00000.dat")).unwrap();
+    file.seek(SeekFrom::Start(genesis.len() as u64)).unwrap();
+    file.write_all(&blk_record("block_1")).unwrap();
+    drop(file);
+
+    let (blocks, file_index) = next_read(&mut receiver).await;
+    assert_eq!(file_index, 0);
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].block_hash, "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+    assert_eq!(blocks[0].byte_offset as u64, genesis.len() as u64);
+    assert_eq!(blocks[0].size as u64 + RECORD_HEADER_SIZE, blk_record("block_1").len() as u64);
+    // The genesis block in front of it was never parsed
+    assert_eq!(metrics.blocks.load(Ordering::Relaxed), 1);
+
+    // A newer file is followed from its start
+    fs::write(dir.join("blk00001.dat"), &genesis).unwrap();
+    let (blocks, file_index) = next_read(&mut receiver).await;
+    assert_eq!(file_index, 1);
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].byte_offset, 0);
+    assert_eq!(metrics.blocks.load(Ordering::Relaxed), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn blocks_written_after_their_record_header_are_read_once_complete() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("bitcoin-postgres-parser-watch-unflushed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // The record header of block 1 is on disk, but its body is still the preallocated zeros
+    let genesis = blk_record("genesis");
+    let block_1 = blk_record("block_1");
+    let mut contents = [genesis.clone(), block_1[..RECORD_HEADER_SIZE as usize].to_vec()].concat();
+    contents.resize(4096, 0);
+    fs::write(dir.join("blk00000.dat"), &contents).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = Arc::new(FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics)));
+    let (blocks, end) = file_reader.read_file_from(&dir.join("blk00000.dat"), genesis.len() as u64).unwrap();
+    assert!(blocks.is_empty());
+    assert_eq!(end, genesis.len() as u64);
+
+    let hash_pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let (sender, mut receiver) = mpsc::channel(16);
+    let _watcher = spawn_watcher(
+        file_reader,
+        (0, genesis.len() as u64),
+        Duration::from_millis(20),
+        sender,
+        Arc::clone(&metrics),
+        hash_pool,
+        false,
+    );
+    let (blocks, _) = next_read(&mut receiver).await;
+    assert!(blocks.is_empty());
+
+    // Once the body is flushed, the block is read from where the header was
+    let mut file = OpenOptions::new().write(true).open(dir.join("blk00000.dat")).unwrap();
+    file.seek(SeekFrom::Start(genesis.len() as u64)).unwrap();
+    file.write_all(&block_1).unwrap();
+    drop(file);
+
+    let (blocks, _) = next_read(&mut receiver).await;
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].block_hash, "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+    assert_eq!(blocks[0].byte_offset as u64, genesis.len() as u64);
+    // Not knowing the block would be completed, nothing was counted as a parse error
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_block_that_never_parses_is_skipped_once_a_later_one_does() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("bitcoin-postgres-parser-watch-skipped-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Block 1's record with a zeroed body, and a complete block after it
+    let genesis = blk_record("genesis");
+    let mut zeroed = blk_record("block_1");
+    zeroed[RECORD_HEADER_SIZE as usize..].fill(0);
+    let block_2 = blk_record("block_2");
+    fs::write(dir.join("blk00000.dat"), [genesis.clone(), zeroed.clone(), block_2.clone()].concat()).unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    let file_reader = FileReader::new(dir.clone(), Network::Mainnet, Arc::clone(&metrics));
+    let (blocks, end) = file_reader.read_file_from(&dir.join("blk00000.dat"), 0).unwrap();
+    let offsets: Vec<i64> = blocks.iter().map(|block| block.byte_offset).collect();
+    assert_eq!(offsets, [0, (genesis.len() + zeroed.len()) as i64]);
+    assert_eq!(end, (genesis.len() + zeroed.len() + block_2.len()) as u64);
+    assert_eq!(metrics.parse_errors.load(Ordering::Relaxed), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}